@@ -0,0 +1,246 @@
+use regex_syntax::{
+    hir::{Class, Hir, HirKind, Literal, RepetitionKind, RepetitionRange},
+    utf8::Utf8Sequences,
+    ParserBuilder,
+};
+use std::collections::{BTreeSet, HashMap};
+
+/// What a `string`, `word` or `regex` variant matches.
+#[derive(Clone, Copy)]
+pub(crate) enum Pattern<'p> {
+    Literal(&'p str),
+    Regex(&'p str),
+}
+enum NfaState {
+    /// Consumes a byte in `start..=end`.
+    Range(u8, u8, usize),
+    Split(Vec<usize>),
+    Match(usize),
+}
+/// Thompson automaton over the UTF-8 bytes of the input.
+#[derive(Default)]
+struct Nfa {
+    states: Vec<NfaState>,
+}
+impl Nfa {
+    fn push(&mut self, state: NfaState) -> usize {
+        self.states.push(state);
+        self.states.len() - 1
+    }
+
+    fn bytes(&mut self, ranges: &[(u8, u8)], next: usize) -> usize {
+        ranges.iter().rev().fold(next, |next, &(start, end)| self.push(NfaState::Range(start, end, next)))
+    }
+
+    /// Compiles `hir` into states leading to `next`, returns the entry state.
+    fn compile(&mut self, hir: &Hir, next: usize) -> Result<usize, String> {
+        Ok(match hir.kind() {
+            HirKind::Empty => next,
+            HirKind::Literal(Literal::Unicode(c)) => {
+                let ranges: Vec<_> = c.to_string().bytes().map(|b| (b, b)).collect();
+                self.bytes(&ranges, next)
+            }
+            HirKind::Literal(Literal::Byte(b)) => self.push(NfaState::Range(*b, *b, next)),
+            HirKind::Class(Class::Unicode(class)) => {
+                let mut entries = Vec::new();
+                for range in class.iter() {
+                    for sequence in Utf8Sequences::new(range.start(), range.end()) {
+                        let ranges: Vec<_> = sequence.as_slice().iter().map(|range| (range.start, range.end)).collect();
+                        entries.push(self.bytes(&ranges, next));
+                    }
+                }
+                self.push(NfaState::Split(entries))
+            }
+            HirKind::Class(Class::Bytes(class)) => {
+                let entries = class.iter().map(|range| self.push(NfaState::Range(range.start(), range.end(), next))).collect();
+                self.push(NfaState::Split(entries))
+            }
+            HirKind::Anchor(_) | HirKind::WordBoundary(_) => return Err("anchors and word boundaries are not supported by the lexer".to_string()),
+            HirKind::Group(group) => self.compile(&group.hir, next)?,
+            HirKind::Concat(hirs) => {
+                let mut next = next;
+                for hir in hirs.iter().rev() {
+                    next = self.compile(hir, next)?;
+                }
+                next
+            }
+            HirKind::Alternation(hirs) => {
+                let entries = hirs.iter().map(|hir| self.compile(hir, next)).collect::<Result<_, _>>()?;
+                self.push(NfaState::Split(entries))
+            }
+            HirKind::Repetition(repetition) => {
+                let (min, max) = match &repetition.kind {
+                    RepetitionKind::ZeroOrOne => (0, Some(1)),
+                    RepetitionKind::ZeroOrMore => (0, None),
+                    RepetitionKind::OneOrMore => (1, None),
+                    RepetitionKind::Range(RepetitionRange::Exactly(n)) => (*n, Some(*n)),
+                    RepetitionKind::Range(RepetitionRange::AtLeast(n)) => (*n, None),
+                    RepetitionKind::Range(RepetitionRange::Bounded(min, max)) => (*min, Some(*max)),
+                };
+                let mut entry = match max {
+                    None => {
+                        let repeat = self.push(NfaState::Split(Vec::new()));
+                        let body = self.compile(&repetition.hir, repeat)?;
+                        self.states[repeat] = NfaState::Split(vec![body, next]);
+                        repeat
+                    }
+                    Some(max) => {
+                        let mut entry = next;
+                        for _ in min..max {
+                            let body = self.compile(&repetition.hir, entry)?;
+                            entry = self.push(NfaState::Split(vec![body, next]));
+                        }
+                        entry
+                    }
+                };
+                for _ in 0..min {
+                    entry = self.compile(&repetition.hir, entry)?;
+                }
+                entry
+            }
+        })
+    }
+
+    /// The `Range` and `Match` states reachable from `states` without consuming input.
+    fn closure(&self, states: impl IntoIterator<Item = usize>) -> BTreeSet<usize> {
+        let mut stack: Vec<_> = states.into_iter().collect();
+        let mut visited = BTreeSet::new();
+        let mut closure = BTreeSet::new();
+        while let Some(state) = stack.pop() {
+            if !visited.insert(state) {
+                continue;
+            }
+            match &self.states[state] {
+                NfaState::Split(next) => stack.extend(next),
+                _ => {
+                    closure.insert(state);
+                }
+            }
+        }
+        closure
+    }
+}
+/// Minimized DFA over bytes, state 0 is the dead state.
+pub(crate) struct Dfa {
+    pub(crate) start: usize,
+    /// Bytes no pattern tells apart share a class.
+    pub(crate) classes: [u8; 256],
+    pub(crate) class_count: usize,
+    /// `transitions[state * class_count + class]`.
+    pub(crate) transitions: Vec<usize>,
+    /// The first of the patterns matching when the automaton stops in each state.
+    pub(crate) accepts: Vec<Option<usize>>,
+}
+impl Dfa {
+    /// Builds the automaton recognizing all `patterns`, or returns the index of an invalid pattern with the reason.
+    pub(crate) fn new(patterns: &[Pattern]) -> Result<Self, (usize, String)> {
+        let mut nfa = Nfa::default();
+        let mut entries = Vec::new();
+        for (index, pattern) in patterns.iter().enumerate() {
+            let accept = nfa.push(NfaState::Match(index));
+            entries.push(match pattern {
+                Pattern::Literal(literal) => {
+                    let ranges: Vec<_> = literal.bytes().map(|b| (b, b)).collect();
+                    nfa.bytes(&ranges, accept)
+                }
+                Pattern::Regex(regex) => {
+                    let hir = ParserBuilder::new().build().parse(regex).map_err(|e| (index, e.to_string()))?;
+                    nfa.compile(&hir, accept).map_err(|e| (index, e))?
+                }
+            });
+        }
+        let (classes, class_count) = Self::byte_classes(&nfa);
+        let mut representatives = vec![0u8; class_count];
+        for byte in (0..=255u8).rev() {
+            representatives[classes[byte as usize] as usize] = byte;
+        }
+        let mut sets = vec![BTreeSet::new(), nfa.closure(entries)];
+        let mut set_index: HashMap<_, _> = sets.iter().cloned().enumerate().map(|(index, set)| (set, index)).collect();
+        let mut transitions = Vec::new();
+        let mut state = 0;
+        while state < sets.len() {
+            for &byte in &representatives {
+                let moved = nfa.closure(sets[state].iter().filter_map(|nfa_state| match nfa.states[*nfa_state] {
+                    NfaState::Range(start, end, next) if start <= byte && byte <= end => Some(next),
+                    _ => None,
+                }));
+                let next = *set_index.entry(moved.clone()).or_insert_with(|| {
+                    sets.push(moved);
+                    sets.len() - 1
+                });
+                transitions.push(next);
+            }
+            state += 1;
+        }
+        let accepts = sets
+            .iter()
+            .map(|set| {
+                set.iter()
+                    .filter_map(|nfa_state| match nfa.states[*nfa_state] {
+                        NfaState::Match(pattern) => Some(pattern),
+                        _ => None,
+                    })
+                    .min()
+            })
+            .collect();
+        Ok(Self { start: 1, classes, class_count, transitions, accepts }.minimize())
+    }
+
+    fn byte_classes(nfa: &Nfa) -> ([u8; 256], usize) {
+        let mut boundaries = [false; 257];
+        for state in &nfa.states {
+            if let NfaState::Range(start, end, _) = state {
+                boundaries[*start as usize] = true;
+                boundaries[*end as usize + 1] = true;
+            }
+        }
+        let mut classes = [0u8; 256];
+        let mut class = 0;
+        for byte in 1..256 {
+            if boundaries[byte] {
+                class += 1;
+            }
+            classes[byte] = class as u8;
+        }
+        (classes, class + 1)
+    }
+
+    /// Merges the states no input tells apart, refining the partition by accepted pattern until it is stable.
+    fn minimize(self) -> Self {
+        let Self { start, classes, class_count, transitions, accepts } = self;
+        let state_count = accepts.len();
+        let mut accept_blocks = HashMap::new();
+        let mut block: Vec<usize> = accepts
+            .iter()
+            .map(|accept| {
+                let len = accept_blocks.len();
+                *accept_blocks.entry(*accept).or_insert(len)
+            })
+            .collect();
+        let mut block_count = accept_blocks.len();
+        loop {
+            let mut signatures = HashMap::new();
+            let refined: Vec<usize> = (0..state_count)
+                .map(|state| {
+                    let next_blocks: Vec<_> = transitions[state * class_count..(state + 1) * class_count].iter().map(|next| block[*next]).collect();
+                    let len = signatures.len();
+                    *signatures.entry((block[state], next_blocks)).or_insert(len)
+                })
+                .collect();
+            block = refined;
+            if signatures.len() == block_count {
+                break;
+            }
+            block_count = signatures.len();
+        }
+        // blocks are numbered by their first state, so the dead state stays 0
+        let mut representatives = vec![usize::MAX; block_count];
+        for state in (0..state_count).rev() {
+            representatives[block[state]] = state;
+        }
+        let transitions =
+            representatives.iter().flat_map(|state| transitions[state * class_count..(state + 1) * class_count].iter().map(|next| block[*next])).collect();
+        let accepts = representatives.iter().map(|state| accepts[*state]).collect();
+        Self { start: block[start], classes, class_count, transitions, accepts }
+    }
+}
@@ -1,15 +1,16 @@
 extern crate quote;
+use dfa::{Dfa, Pattern};
 use lexical::to_ident;
-use std::{iter::Peekable, slice::Iter};
 
 use proc_macro::TokenStream;
 use quote::ToTokens;
-use synstructure::VariantInfo;
 extern crate proc_macro2;
 #[macro_use]
 extern crate synstructure;
 extern crate lazy_static;
 
+mod dfa;
+
 use proc_macro2::Span;
 use syn::{bracketed, parse::Parse, parse_macro_input, punctuated::Punctuated, spanned::Spanned, token::Bracket, ItemEnum, LitStr};
 #[derive(Debug)]
@@ -38,6 +39,8 @@ impl From<syn::Error> for Error {
 struct VariantAttr {
     match_kind: MatchKind,
     ignore: bool,
+    /// Try the `fn` before strings, words and regexes, for tokens starting like a symbol.
+    first: bool,
 }
 enum MatchKind {
     String(String),
@@ -78,6 +81,7 @@ fn parse_match_kind(s: &synstructure::Structure) -> Result<Vec<VariantAttr>, Err
         }
         let mut match_kind = None;
         let mut ignore = false;
+        let mut first = false;
         for nexted in metadata.nested {
             match nexted {
                 syn::NestedMeta::Meta(syn::Meta::NameValue(syn::MetaNameValue { path, lit, .. })) if path.is_ident("string") => {
@@ -163,13 +167,19 @@ fn parse_match_kind(s: &synstructure::Structure) -> Result<Vec<VariantAttr>, Err
                 syn::NestedMeta::Meta(syn::Meta::Path(path)) if path.is_ident("ignore") => {
                     ignore = true;
                 }
+                syn::NestedMeta::Meta(syn::Meta::Path(path)) if path.is_ident("first") => {
+                    first = true;
+                }
                 o => {
                     return Err(Error::new(o.span(), "unsupported metadata"));
                 }
             }
         }
+        if first && !matches!(match_kind, Some(MatchKind::Fn(_))) {
+            return Err(Error::new(v.ast().ident.span(), "only a 'fn' variant can be tried first"));
+        }
         if let Some(match_kind) = match_kind {
-            variants.push(VariantAttr { ignore, match_kind });
+            variants.push(VariantAttr { ignore, match_kind, first });
         } else {
             return Err(Error::new(
                 v.ast().ident.span(),
@@ -179,38 +189,6 @@ fn parse_match_kind(s: &synstructure::Structure) -> Result<Vec<VariantAttr>, Err
     }
     Ok(variants)
 }
-fn generate_match_string(iter: &mut Peekable<Iter<(&String, &VariantAttr, &VariantInfo)>>) -> proc_macro2::TokenStream {
-    if let Some(current) = iter.next() {
-        let current_str = &**current.0;
-        let current_ident = current.2.ast().ident.to_token_stream();
-        let mut sub_cases = Vec::new();
-        loop {
-            if let Some(next) = iter.peek() {
-                let next_str = next.0;
-                if next_str.starts_with(current_str) {
-                    sub_cases.push(generate_match_string(iter));
-                } else {
-                    break;
-                }
-            } else {
-                break;
-            }
-        }
-        let ignore = current.1.ignore;
-        quote! {
-          if chars.as_str().starts_with(#current_str){
-            #(#sub_cases;)*
-            if !#ignore{
-              tokens.push(Self::#current_ident);
-            }
-            chars=chars.as_str().split_at(#current_str.len()).1.chars();
-            continue;
-          }
-        }
-    } else {
-        quote! {}
-    }
-}
 fn do_lexical_derive(s: synstructure::Structure) -> Result<proc_macro2::TokenStream, Error> {
     let match_kind_list = parse_match_kind(&s)?;
     let variant_info_list = s.variants();
@@ -240,7 +218,8 @@ fn do_lexical_derive(s: synstructure::Structure) -> Result<proc_macro2::TokenStr
     });
     let emit_newline = if newline_ident.is_some() {
         quote! {
-          tokens.push(Self::#newline_ident);
+          let newline_start=source.len()-chars.as_str().len();
+          tokens.push(::lexical::Spanned::new(Self::#newline_ident,line_index.span(newline_start,newline_start+1)));
         }
     } else {
         quote! {}
@@ -254,7 +233,7 @@ fn do_lexical_derive(s: synstructure::Structure) -> Result<proc_macro2::TokenStr
     };
     let emit_whitespace = if let Some(whitespace_ident) = whitespace_ident {
         quote! {
-          tokens.push(Self::#whitespace_ident(whitespace_string));
+          tokens.push(::lexical::Spanned::new(Self::#whitespace_ident(whitespace_string),line_index.span(whitespace_start,token_start)));
           whitespace_string=String::new();
         }
     } else {
@@ -264,7 +243,8 @@ fn do_lexical_derive(s: synstructure::Structure) -> Result<proc_macro2::TokenStr
         let emit_indentation_increase = if let Some((indentation_increase_ident, ignore)) = indentation_increase_ident {
             quote! {
               if !#ignore{
-                tokens.push(Self::#indentation_increase_ident(indentation_string.len(),indentation_string));
+                let indentation_span=line_index.span(token_start-indentation_string.len(),token_start);
+                tokens.push(::lexical::Spanned::new(Self::#indentation_increase_ident(indentation_string.len(),indentation_string),indentation_span));
               }
             }
         } else {
@@ -273,7 +253,8 @@ fn do_lexical_derive(s: synstructure::Structure) -> Result<proc_macro2::TokenStr
         let emit_indentation_decrease = if let Some((indentation_decrease_ident, ignore)) = indentation_decrease_ident {
             quote! {
               if !#ignore{
-                tokens.push(Self::#indentation_decrease_ident(indentation_string.len(),indentation_string));
+                let indentation_span=line_index.span(token_start-indentation_string.len(),token_start);
+                tokens.push(::lexical::Spanned::new(Self::#indentation_decrease_ident(indentation_string.len(),indentation_string),indentation_span));
               }
             }
         } else {
@@ -299,33 +280,14 @@ fn do_lexical_derive(s: synstructure::Structure) -> Result<proc_macro2::TokenStr
     } else {
         quote! {}
     };
-    let mut string_variants: Vec<_> = variant_list
+    let dfa_variants: Vec<_> = variant_list
         .iter()
         .filter_map(|v| match &v.0.match_kind {
-            MatchKind::String(s) => Some((s, v.0, v.1)),
+            MatchKind::String(string) | MatchKind::Word(string) => Some((Pattern::Literal(string), v.0, v.1)),
+            MatchKind::Regex(regex) => Some((Pattern::Regex(regex), v.0, v.1)),
             _ => None,
         })
         .collect();
-    string_variants.sort_by(|a, b| a.0.cmp(b.0));
-    let mut string_variants_iter = string_variants.iter().peekable();
-    let mut match_string = Vec::new();
-    while string_variants_iter.peek().is_some() {
-        match_string.push(generate_match_string(&mut string_variants_iter));
-    }
-    let word_variants: Vec<_> = variant_list
-        .iter()
-        .filter(|v| match v.0.match_kind {
-            MatchKind::Word(_) => true,
-            _ => false,
-        })
-        .collect();
-    let regex_variants: Vec<_> = variant_list
-        .iter()
-        .filter(|v| match &v.0.match_kind {
-            MatchKind::Regex(_) => true,
-            _ => false,
-        })
-        .collect();
     let fn_variants: Vec<_> = variant_list
         .iter()
         .filter(|v| match &v.0.match_kind {
@@ -333,29 +295,31 @@ fn do_lexical_derive(s: synstructure::Structure) -> Result<proc_macro2::TokenStr
             _ => false,
         })
         .collect();
-    let regex_variant_count = regex_variants.len();
-    let regex_str = "^".to_string()
-        + &regex_variants
-            .iter()
-            .map(|v| match &v.0.match_kind {
-                MatchKind::Regex(regex) => {
-                    format!("(?P<{}>{})", v.1.ast().ident, regex)
-                }
-                _ => unreachable!(),
-            })
-            .collect::<Vec<_>>()
-            .join("|");
-    let regex_match_capturing = regex_variants
-        .iter()
-        .map(|v| {
-            let variant = v.1.ast().ident.to_token_stream();
-            let name = v.1.ast().ident.to_string();
-            let constructor = match v.1.ast().fields {
-                syn::Fields::Unit => quote! {Self::#variant},
-                syn::Fields::Unnamed(_unnamed) => {
+    let dfa_match = if !dfa_variants.is_empty() {
+        let patterns: Vec<_> = dfa_variants.iter().map(|v| v.0).collect();
+        let dfa = Dfa::new(&patterns).map_err(|(index, message)| Error::new(dfa_variants[index].2.ast().ident.span(), &message))?;
+        if dfa.accepts.len() > u16::MAX as usize {
+            return Err(Error::new(s.ast().ident.span(), "too many states in the lexer automaton"));
+        }
+        let start = dfa.start as u16;
+        let classes = dfa.classes.iter();
+        let class_count = dfa.class_count;
+        let transitions = dfa.transitions.iter().map(|next| proc_macro2::Literal::u16_unsuffixed(*next as u16));
+        let accepts = dfa.accepts.iter().map(|accept| match accept {
+            Some(index) => {
+                let index = proc_macro2::Literal::u16_unsuffixed(*index as u16);
+                quote! {Some(#index)}
+            }
+            None => quote! {None},
+        });
+        let match_arms = dfa_variants.iter().enumerate().map(|(index, v)| {
+            let variant = v.2.ast().ident.to_token_stream();
+            let constructor = match (v.0, v.2.ast().fields) {
+                (Pattern::Literal(_), _) | (_, syn::Fields::Unit) => quote! {Self::#variant},
+                (_, syn::Fields::Unnamed(_unnamed)) => {
                     quote! {Self::#variant(token_str.parse()?)}
                 }
-                syn::Fields::Named(named) => {
+                (_, syn::Fields::Named(named)) => {
                     if let Some(first_field) = named.named.first() {
                         let field_name = first_field.ident.as_ref().unwrap().to_token_stream();
                         quote! {Self::#variant{#field_name , FromStr::from_str(token_str)?}}
@@ -364,20 +328,39 @@ fn do_lexical_derive(s: synstructure::Structure) -> Result<proc_macro2::TokenStr
                     }
                 }
             };
-            let ignore = v.0.ignore;
+            let ignore = v.1.ignore;
             quote! {
-              if let Some(matches)=cap.name(&#name){
-                let token_str=matches.as_str();
-                if !#ignore{
-                    tokens.push(#constructor);
-                }
-                chars=chars.as_str().split_at(token_str.len()).1.chars();
-                continue;
+              #index=>if !#ignore{
+                tokens.push(::lexical::Spanned::with_lookahead(#constructor,line_index.span(token_start,token_start+len),lookahead));
               }
-
             }
-        })
-        .collect::<Vec<_>>();
+        });
+        quote! {
+          const DFA: ::lexical::_Dfa=::lexical::_Dfa{
+            start:#start,
+            classes:[#(#classes),*],
+            class_count:#class_count,
+            transitions:&[#(#transitions),*],
+            accepts:&[#(#accepts),*],
+          };
+          let (matched,read,input_end)=DFA.longest_match(chars.as_str());
+          lookahead=lookahead.max(token_start+read);
+          if partial&&input_end{
+            return Ok((tokens,token_start));
+          }
+          if let Some((index,len))=matched{
+            let token_str=&chars.as_str()[..len];
+            match index{
+              #(#match_arms,)*
+              _=>::std::unreachable!(),
+            }
+            chars=chars.as_str()[len..].chars();
+            continue;
+          }
+        }
+    } else {
+        quote! {}
+    };
     let fn_match_capturing = fn_variants
         .iter()
         .map(|v| {
@@ -400,93 +383,78 @@ fn do_lexical_derive(s: synstructure::Structure) -> Result<proc_macro2::TokenStr
                     }
                 }
             };
-            quote! {
+            let ignore = v.0.ignore;
+            let capturing = quote! {
               {
                   let mut iter=chars.clone();
-                  if let Some(matches)=#function(&mut iter){
-                    tokens.push(#constructor);
+                  let matches=#function(&mut iter);
+                  lookahead=lookahead.max(source.len()-iter.as_str().len());
+                  if partial&&iter.as_str().is_empty(){
+                    return Ok((tokens,token_start));
+                  }
+                  if let Some(matches)=matches{
+                    if !#ignore{
+                      tokens.push(::lexical::Spanned::with_lookahead(#constructor,line_index.span(token_start,source.len()-iter.as_str().len()),lookahead));
+                    }
                     chars=iter;
                     continue;
                   }
               }
-            }
+            };
+            (v.0.first, capturing)
         })
         .collect::<Vec<_>>();
-    let fn_match = if !fn_variants.is_empty() {
-        quote! {
-          #(#fn_match_capturing;)*
-        }
-    } else {
-        quote! {}
-    };
-    let regex_match = if !regex_variants.is_empty() {
-        quote! {
-          if let Some(cap)=REGEX.captures(chars.as_str()){
-            #(#regex_match_capturing;)*
-          }
-        }
-    } else {
-        quote! {}
-    };
-    let word_match = if regex_variant_count != 0 {
-        let mut cases = Vec::new();
-        for word_variant in word_variants.iter() {
-            let string = match &word_variant.0.match_kind {
-                MatchKind::Word(word) => word,
-                _ => unreachable!(),
-            };
-            let ident = word_variant.1.ast().ident.clone();
-            let ignore = word_variant.0.ignore;
-            cases.push(quote! {#string=>{
-              if !#ignore{
-                  tokens.push(Self::#ident);
-              }
-              chars=chars.as_str().split_at(token_str.len()).1.chars();
-              continue;
-            }});
-        }
-        quote! {
-          if let Some(cap)=WORD_REGEX.captures(chars.as_str()){
-            if let Some(matches)=cap.name("word"){
-              let token_str=matches.as_str();
-              match token_str{
-                #(#cases),*
-                _=>{}
-              }
-            }
-          }
-        }
-    } else {
-        quote! {}
-    };
+    let fn_first_match = fn_match_capturing.iter().filter(|(first, _)| *first).map(|(_, capturing)| capturing);
+    let fn_match = fn_match_capturing.iter().filter(|(first, _)| !*first).map(|(_, capturing)| capturing);
     let r = s.unbound_impl(
         quote!(::lexical::Lexical),
         quote! {
             #[allow(dead_code)]
-            fn parse(source:&str)->::lexical::_Fallible<Vec<Self>>{
-              ::lexical::_lazy_static::lazy_static!{
-                static ref WORD_REGEX:
-                  ::lexical::_regex::Regex=::lexical::_regex::RegexBuilder::new(
-                    r"^(?P<word>\p{L}+)"
-                  ).unicode(true).build().unwrap();
-              };
-              ::lexical::_lazy_static::lazy_static!{
-                static ref REGEX:
-                  ::lexical::_regex::Regex=::lexical::_regex::RegexBuilder::new(
-                    #regex_str
-                  ).unicode(true).build().unwrap();
-              };
+            fn parse_spanned(source:&str)->::lexical::_Fallible<Vec<::lexical::Spanned<Self>>>{
+              Self::relex(source,Vec::new(),&::lexical::TextEdit{start:0,old_end:0,new_end:source.len()})
+            }
+            #[allow(dead_code)]
+            fn relex(source:&str,old_tokens:Vec<::lexical::Spanned<Self>>,edit:&::lexical::TextEdit)->::lexical::_Fallible<Vec<::lexical::Spanned<Self>>>{
+              Ok(Self::__lex(source,old_tokens,edit,false)?.0)
+            }
+            #[allow(dead_code)]
+            fn parse_chunk(source:&str,eof:bool)->::lexical::_Fallible<(Vec<::lexical::Spanned<Self>>,usize)>{
+              Self::__lex(source,Vec::new(),&::lexical::TextEdit{start:0,old_end:0,new_end:source.len()},!eof)
+            }
+        },
+    );
+    let ident = &s.ast().ident;
+    let (impl_generics, ty_generics, where_clause) = s.ast().generics.split_for_impl();
+    let r = quote! {
+        #r
+        impl #impl_generics #ident #ty_generics #where_clause {
+            /// Lexing loop shared by `relex` and `parse_chunk`, when `partial` is set it stops before the first token
+            /// reaching the end of `source` and returns the offset to resume from.
+            #[doc(hidden)]
+            #[allow(dead_code)]
+            #[allow(unused_variables)]
+            fn __lex(source:&str,old_tokens:Vec<::lexical::Spanned<Self>>,edit:&::lexical::TextEdit,partial:bool)->::lexical::_Fallible<(Vec<::lexical::Spanned<Self>>,usize)>{
+              if partial&&#parse_indentatoin{
+                return Ok((Vec::new(),0));
+              }
               let mut indentation_stack=Vec::<usize>::new();
               indentation_stack.push(0);
                 let mut index=0;
-                let mut tokens=Vec::new();
+                let (mut relex,mut tokens,restart)=::lexical::_Relex::new(old_tokens,edit,#parse_indentatoin);
                 let mut whitespace_string=String::new();
                 let mut indentation_string = String::new();
                 let mut last_char_is_whitespace=false;
                 let mut last_char_is_newline=false;
-                let mut chars=source.chars();
+                let mut whitespace_start=0;
+                let mut whitespace_token_count=0;
+                let line_index=::lexical::LineIndex::new(source);
+                let mut chars=source[restart..].chars();
                 while let Some(b)=chars.clone().next(){
                   if b.is_ascii_whitespace(){
+                    if !last_char_is_whitespace{
+                      whitespace_start=source.len()-chars.as_str().len();
+                      whitespace_token_count=tokens.len();
+                    }
                     if b=='\n'{
                       #emit_newline
                     }
@@ -502,28 +470,43 @@ fn do_lexical_derive(s: synstructure::Structure) -> Result<proc_macro2::TokenStr
                     last_char_is_newline=b=='\n';
                     chars.next().unwrap();
                   }else{
+                    let token_start=source.len()-chars.as_str().len();
                     let mut has_emit_indentation=false;
                     #emit_parse_indentation
                     if last_char_is_whitespace{
                       #emit_whitespace
                     }
                     last_char_is_whitespace=false;
-                    // match string
-                    #(#match_string);*
-                    // match word
-                    #word_match
-                    // match regex
-                    #regex_match
+                    if relex.resync(token_start){
+                      relex.finish(&line_index,&mut tokens);
+                      return Ok((tokens,source.len()));
+                    }
+                    // end of the input read by the matchers tried at this token
+                    let mut lookahead=token_start;
+                    // match by function before anything else
+                    #(#fn_first_match)*
+                    // match strings, words and regexes by the longest match, then by the order of declaration
+                    #dfa_match
                     // match by function
-                    #fn_match
-                    Err(::lexical::_format_err!("unexpected lexical :{}",chars.clone().take(32).collect::<std::string::String>()))?;
+                    #(#fn_match)*
+                    if partial{
+                      return Ok((tokens,token_start));
+                    }
+                    Err(::lexical::LexicalError::new(
+                        line_index.span(token_start,token_start),
+                        chars.clone().take(32).collect::<std::string::String>()
+                    ))?;
                   }
                   index+=1;
                 }
-                Ok(tokens)
+                if partial&&last_char_is_whitespace{
+                  tokens.truncate(whitespace_token_count);
+                  return Ok((tokens,whitespace_start));
+                }
+                Ok((tokens,source.len()))
             }
-        },
-    );
+        }
+    };
     Ok(r)
 }
 fn lexical_derive(i: synstructure::Structure) -> TokenStream {
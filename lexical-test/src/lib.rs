@@ -3,7 +3,7 @@
 extern crate lexical_derive;
 #[cfg(test)]
 mod tests {
-    use lexical::Lexical;
+    use lexical::{Lexical, TextEdit};
     #[derive(Debug, PartialEq, Lexical)]
     enum LexicalImpl {
         #[lexical(word = "if")]
@@ -90,6 +90,93 @@ mod tests {
     fn lexical_parse_word_end() {
         assert_eq!(&*LexicalImpl::parse("if 123").unwrap(), &[If, Int(123)]);
     }
+    #[test]
+    fn lexical_parse_longest_match() {
+        assert_eq!(&*LexicalImpl::parse("ifelse if12").unwrap(), &[Identify("ifelse".into()), If, Int(12)]);
+    }
+    #[derive(Debug, PartialEq, Lexical)]
+    enum Overlapping {
+        #[lexical(regex = r"a+b?")]
+        First,
+        #[lexical(regex = r"a+")]
+        Second,
+        #[lexical(string = "aab")]
+        Third,
+        #[lexical(regex = r"[ab]+")]
+        Rest,
+    }
+    #[test]
+    fn lexical_parse_declaration_order() {
+        assert_eq!(&*Overlapping::parse("aa aab aaba b").unwrap(), &[Overlapping::First, Overlapping::First, Overlapping::Rest, Overlapping::Rest]);
+    }
+    #[test]
+    fn lexical_parse_spanned() {
+        let tokens = LexicalImpl::parse_spanned("if 123\nabc+=").unwrap();
+        let spans: Vec<_> = tokens.iter().map(|t| (t.span.start, t.span.end, t.span.line, t.span.column)).collect();
+        assert_eq!(&*spans, &[(0, 2, 1, 1), (3, 6, 1, 4), (6, 7, 1, 7), (7, 10, 2, 1), (10, 12, 2, 4)]);
+        assert_eq!(tokens[3].value, Identify("abc".into()));
+    }
+    #[test]
+    fn lexical_parse_error_location() {
+        let error = LexicalImpl::parse("if\n  1 ?").unwrap_err();
+        assert!(error.to_string().contains("2:5"), "{}", error);
+    }
+    fn parse_long_string(iter: &mut std::str::Chars) -> Option<String> {
+        let rest = iter.as_str().strip_prefix("[[")?;
+        let end = match rest.find("]]") {
+            Some(end) => end,
+            None => {
+                *iter = rest[rest.len()..].chars();
+                return None;
+            }
+        };
+        let value = rest[..end].to_string();
+        *iter = rest[end + 2..].chars();
+        Some(value)
+    }
+    #[derive(Debug, PartialEq, Lexical)]
+    enum LongBracket {
+        #[lexical(first, fn = "parse_long_string")]
+        Long(String),
+        #[lexical(string = "[")]
+        LeftBracket,
+        #[lexical(string = "]")]
+        RightBracket,
+        #[lexical(regex = r"[a-z]+")]
+        Name(String),
+    }
+    fn relex_then_parse<T: Lexical + std::fmt::Debug + PartialEq>(source: &str, start: usize, old_end: usize, text: &str) {
+        let new_source = format!("{}{}{}", &source[..start], text, &source[old_end..]);
+        let edit = TextEdit { start, old_end, new_end: start + text.len() };
+        let relexed = T::relex(&new_source, T::parse_spanned(source).unwrap(), &edit).unwrap();
+        assert_eq!(relexed, T::parse_spanned(&new_source).unwrap(), "{:?}", new_source);
+    }
+    #[test]
+    fn lexical_relex() {
+        relex_then_parse::<LongBracket>("a [b] c\nd", 3, 4, "bb");
+        relex_then_parse::<LongBracket>("a\n[[b\nc]] d\ne", 3, 4, "");
+        relex_then_parse::<LongBracket>("x [ y\nz ]] w", 3, 3, "[");
+        relex_then_parse::<LongBracket>("x\ny [[z\n]] w", 8, 10, "");
+        // the unfinished long bracket of the first line looked ahead up to the edit
+        relex_then_parse::<LongBracket>("a [[b\nc] d", 8, 8, "]");
+        relex_then_parse::<LexicalImpl>("if 1\n  abc\n+", 7, 8, "\n");
+    }
+    fn stream_then_parse<T: Lexical + std::fmt::Debug + PartialEq>(source: &str, chunk_size: usize) {
+        let streamed: Vec<_> = T::stream_chunks(source.as_bytes().chunks(chunk_size)).collect::<Result<_, _>>().unwrap();
+        assert_eq!(streamed, T::parse_spanned(source).unwrap(), "{:?} by {}", source, chunk_size);
+    }
+    #[test]
+    fn lexical_stream() {
+        for chunk_size in 1..8 {
+            stream_then_parse::<LongBracket>("a [b] c\nd [[e\n]f]] g\n[[h]]", chunk_size);
+            stream_then_parse::<LexicalImpl>("if 1\n  abc+=中文\n+", chunk_size);
+        }
+    }
+    #[test]
+    fn lexical_stream_error_location() {
+        let error = LongBracket::stream_chunks("a\nbb\ncc ?".as_bytes().chunks(2)).find_map(Result::err).unwrap();
+        assert!(error.to_string().contains("3:4"), "{}", error);
+    }
     #[derive(Lexical)]
     pub enum PL0 {
         #[lexical(word = "begin")]
@@ -1,12 +1,208 @@
 extern crate failure;
+#[macro_use]
 extern crate failure_derive;
 use failure::Fallible;
 pub use failure::{format_err as _format_err, Fallible as _Fallible};
 pub use lazy_static as _lazy_static;
 pub use regex as _regex;
 
+use std::{
+    cmp::Ordering,
+    fmt::{Display, Formatter},
+    io::Read,
+};
+
+mod stream;
+pub use stream::{ChunkReader, LexicalStream};
+
+/// Location of a token in the source, `start`/`end` are byte offsets, `line`/`column` start from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub value: T,
+    pub span: Span,
+    /// Byte offset of the end of the input read to lex the token, at least `span.end`. An edit from there on cannot
+    /// change the token.
+    pub lookahead: usize,
+}
+impl<T> Spanned<T> {
+    pub fn new(value: T, span: Span) -> Self {
+        Self { value, lookahead: span.end, span }
+    }
+
+    pub fn with_lookahead(value: T, span: Span, lookahead: usize) -> Self {
+        Self { value, span, lookahead: lookahead.max(span.end) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+/// Error returned when no variant matches the input at `span`, `text` is the beginning of the unmatched input.
+#[derive(Debug, Clone, PartialEq, Eq, Fail)]
+#[fail(display = "unexpected lexical at {} :{}", span, text)]
+pub struct LexicalError {
+    pub span: Span,
+    pub text: String,
+}
+impl LexicalError {
+    pub fn new(span: Span, text: String) -> Self {
+        Self { span, text }
+    }
+}
+/// Maps byte offsets of a source to line and column numbers.
+pub struct LineIndex<'s> {
+    source: &'s str,
+    line_starts: Vec<usize>,
+}
+impl<'s> LineIndex<'s> {
+    pub fn new(source: &'s str) -> Self {
+        let mut line_starts = vec![0];
+        line_starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+        Self { source, line_starts }
+    }
+
+    pub fn span(&self, start: usize, end: usize) -> Span {
+        let line = match self.line_starts.binary_search(&start) {
+            Ok(l) => l,
+            Err(l) => l - 1,
+        };
+        let column = self.source[self.line_starts[line]..start].chars().count() + 1;
+        Span { start, end, line: line + 1, column }
+    }
+}
+/// Replacement of the bytes `start..old_end` of a source by the bytes `start..new_end` of the edited source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct TextEdit {
+    pub start: usize,
+    pub old_end: usize,
+    pub new_end: usize,
+}
+impl TextEdit {
+    fn shift(&self, offset: usize) -> usize {
+        offset - self.old_end + self.new_end
+    }
+}
 pub trait Lexical: Sized {
-    fn parse(source: &str) -> Fallible<Vec<Self>>;
+    fn parse(source: &str) -> Fallible<Vec<Self>> {
+        Ok(Self::parse_spanned(source)?.into_iter().map(Spanned::into_inner).collect())
+    }
+    fn parse_spanned(source: &str) -> Fallible<Vec<Spanned<Self>>>;
+    /// Tokenizes `source`, the result of applying `edit` to a source whose tokens were `old_tokens`.
+    ///
+    /// The tokens are lexed again from the first one whose lookahead reaches the edit. A `fn` matcher of
+    /// `#[derive(Lexical)]` is taken to have read the input up to where it leaves its iterator and the character
+    /// after, whether it matches or not. The default implementation tokenizes the whole `source` again.
+    fn relex(source: &str, old_tokens: Vec<Spanned<Self>>, edit: &TextEdit) -> Fallible<Vec<Spanned<Self>>> {
+        let _ = (old_tokens, edit);
+        Self::parse_spanned(source)
+    }
+    /// Tokenizes the beginning of `source`, a prefix of the input unless `eof` is set. Returns the tokens that more
+    /// input cannot change and the offset where lexing resumes once more input is available.
+    ///
+    /// A `fn` matcher of `#[derive(Lexical)]` that runs out of input must leave its iterator at the end of the input,
+    /// whether it matches or not, so that the token is lexed again with more input. The default implementation waits
+    /// for the end of input.
+    fn parse_chunk(source: &str, eof: bool) -> Fallible<(Vec<Spanned<Self>>, usize)> {
+        if !eof {
+            return Ok((Vec::new(), 0));
+        }
+        Ok((Self::parse_spanned(source)?, source.len()))
+    }
+    /// Tokenizes the input read from `reader` chunk by chunk, only the text of unfinished tokens is kept in memory.
+    fn stream<R: Read>(reader: R) -> LexicalStream<Self, R> {
+        LexicalStream::new(reader)
+    }
+    fn stream_chunks<I: IntoIterator<Item = B>, B: AsRef<[u8]>>(chunks: I) -> LexicalStream<Self, ChunkReader<I::IntoIter>> {
+        LexicalStream::new(ChunkReader::new(chunks))
+    }
+}
+/// Bookkeeping of the `relex` generated by `#[derive(Lexical)]`.
+///
+/// Lexing restarts after the last old token before the first one whose lookahead reaches the edit, so a token whose
+/// match looked ahead across the edit, like an unfinished long bracket, is lexed again. It stops as soon as a new token
+/// starts where an old token after the edit started, the rest of the old tokens are then moved to their new place.
+#[doc(hidden)]
+pub struct _Relex<T> {
+    /// Old tokens after the edit, last first.
+    suffix: Vec<Spanned<T>>,
+    edit: TextEdit,
+}
+impl<T> _Relex<T> {
+    /// Returns the old tokens kept as they are and the offset where lexing restarts. Lexers carrying state from a
+    /// line to the next, like indentation, always restart from the beginning.
+    pub fn new(mut old_tokens: Vec<Spanned<T>>, edit: &TextEdit, stateful: bool) -> (Self, Vec<Spanned<T>>, usize) {
+        if stateful {
+            return (Self { suffix: Vec::new(), edit: *edit }, Vec::new(), 0);
+        }
+        // the character at the lookahead may have been peeked at
+        let kept = old_tokens.iter().take_while(|token| token.lookahead < edit.start).count();
+        let restart = kept.checked_sub(1).map_or(0, |last| old_tokens[last].span.end);
+        let mut suffix = old_tokens.split_off(kept);
+        suffix.retain(|token| token.span.start >= edit.old_end);
+        suffix.reverse();
+        (Self { suffix, edit: *edit }, old_tokens, restart)
+    }
+
+    /// Whether a token starting at `token_start` of the new source starts where an old one did.
+    pub fn resync(&mut self, token_start: usize) -> bool {
+        while let Some(token) = self.suffix.last() {
+            match self.edit.shift(token.span.start).cmp(&token_start) {
+                Ordering::Greater => return false,
+                Ordering::Equal => return true,
+                Ordering::Less => self.suffix.pop(),
+            };
+        }
+        false
+    }
+
+    /// Appends the remaining old tokens to `tokens` with their spans in the new source.
+    pub fn finish(self, line_index: &LineIndex, tokens: &mut Vec<Spanned<T>>) {
+        let Self { suffix, edit } = self;
+        tokens.extend(suffix.into_iter().rev().map(|token| {
+            Spanned::with_lookahead(token.value, line_index.span(edit.shift(token.span.start), edit.shift(token.span.end)), edit.shift(token.lookahead))
+        }));
+    }
+}
+/// Automaton `#[derive(Lexical)]` builds at compile time from the `string`, `word` and `regex` variants.
+#[doc(hidden)]
+pub struct _Dfa {
+    pub start: u16,
+    pub classes: [u8; 256],
+    pub class_count: usize,
+    /// `transitions[state * class_count + class]`, state 0 is the dead state.
+    pub transitions: &'static [u16],
+    /// The variant matched when stopping in each state, the first declared one among those of the longest match.
+    pub accepts: &'static [Option<u16>],
+}
+impl _Dfa {
+    /// Returns the variant and length of the longest non-empty match at the start of `input`, the length of the input
+    /// read, and whether the input ended before the automaton died, so that a longer match may follow more input.
+    pub fn longest_match(&self, input: &str) -> (Option<(usize, usize)>, usize, bool) {
+        let mut state = self.start as usize;
+        let mut matched = None;
+        for (index, byte) in input.bytes().enumerate() {
+            state = self.transitions[state * self.class_count + self.classes[byte as usize] as usize] as usize;
+            if state == 0 {
+                return (matched, index + 1, false);
+            }
+            if let Some(accept) = self.accepts[state] {
+                matched = Some((accept as usize, index + 1));
+            }
+        }
+        (matched, input.len(), true)
+    }
 }
 pub fn to_ident(token: &str) -> String {
     match token {
@@ -0,0 +1,130 @@
+use crate::{Lexical, LexicalError, Span, Spanned};
+use failure::{format_err, Fallible};
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Read},
+};
+
+const CHUNK_SIZE: usize = 8 * 1024;
+/// Iterator over the tokens of an input read chunk by chunk, see [`Lexical::stream`].
+pub struct LexicalStream<T, R> {
+    reader: R,
+    /// Input not tokenized yet.
+    buffer: Vec<u8>,
+    tokens: VecDeque<Spanned<T>>,
+    /// Byte offset, line and column of the first byte of `buffer` in the input, `line` and `column` start from 0.
+    offset: usize,
+    line: usize,
+    column: usize,
+    eof: bool,
+    failed: bool,
+}
+impl<T: Lexical, R: Read> LexicalStream<T, R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, buffer: Vec::new(), tokens: VecDeque::new(), offset: 0, line: 0, column: 0, eof: false, failed: false }
+    }
+
+    /// Moves a span of the buffered text to its place in the input.
+    fn shift(&self, span: Span) -> Span {
+        let column = if span.line == 1 { span.column + self.column } else { span.column };
+        Span { start: span.start + self.offset, end: span.end + self.offset, line: span.line + self.line, column }
+    }
+
+    /// Reads one more chunk and tokenizes the tokens it completes. Reads as much as is buffered when the buffer grows, so
+    /// that a lexer waiting for the end of input tokenizes it a logarithmic number of times.
+    fn fill(&mut self) -> Fallible<()> {
+        let len = self.buffer.len();
+        self.buffer.resize(len + CHUNK_SIZE.max(len), 0);
+        let read = loop {
+            match self.reader.read(&mut self.buffer[len..]) {
+                Ok(read) => break read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.buffer.truncate(len);
+                    return Err(e.into());
+                }
+            }
+        };
+        self.buffer.truncate(len + read);
+        self.eof = read == 0;
+        let source = match std::str::from_utf8(&self.buffer) {
+            Ok(source) => source,
+            Err(e) if e.error_len().is_none() && !self.eof => std::str::from_utf8(&self.buffer[..e.valid_up_to()]).unwrap(),
+            Err(e) => return Err(format_err!("invalid utf-8 at byte {}", self.offset + e.valid_up_to())),
+        };
+        let (tokens, consumed) = match T::parse_chunk(source, self.eof) {
+            Ok(o) => o,
+            Err(e) => {
+                return Err(match e.downcast::<LexicalError>() {
+                    Ok(e) => LexicalError::new(self.shift(e.span), e.text).into(),
+                    Err(e) => e,
+                })
+            }
+        };
+        let tokens: Vec<_> =
+            tokens.into_iter().map(|token| Spanned::with_lookahead(token.value, self.shift(token.span), token.lookahead + self.offset)).collect();
+        self.tokens.extend(tokens);
+        let consumed_source = &source[..consumed];
+        match consumed_source.rfind('\n') {
+            Some(last_newline) => {
+                self.line += consumed_source.matches('\n').count();
+                self.column = consumed_source[last_newline + 1..].chars().count();
+            }
+            None => self.column += consumed_source.chars().count(),
+        }
+        self.offset += consumed;
+        self.buffer.drain(..consumed);
+        Ok(())
+    }
+}
+impl<T: Lexical, R: Read> Iterator for LexicalStream<T, R> {
+    type Item = Fallible<Spanned<T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(token) = self.tokens.pop_front() {
+                return Some(Ok(token));
+            }
+            if self.eof || self.failed {
+                return None;
+            }
+            if let Err(e) = self.fill() {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+/// Reads the concatenation of byte chunks, like the pieces of a file received from the network.
+pub struct ChunkReader<I: Iterator> {
+    chunks: I,
+    current: Option<I::Item>,
+    position: usize,
+}
+impl<I: Iterator> ChunkReader<I> {
+    pub fn new(chunks: impl IntoIterator<IntoIter = I>) -> Self {
+        Self { chunks: chunks.into_iter(), current: None, position: 0 }
+    }
+}
+impl<I: Iterator<Item = B>, B: AsRef<[u8]>> Read for ChunkReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(chunk) = &self.current {
+                let rest = &chunk.as_ref()[self.position..];
+                if !rest.is_empty() {
+                    let len = rest.len().min(buf.len());
+                    buf[..len].copy_from_slice(&rest[..len]);
+                    self.position += len;
+                    return Ok(len);
+                }
+            }
+            match self.chunks.next() {
+                Some(chunk) => {
+                    self.current = Some(chunk);
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
@@ -14,7 +14,7 @@ use vm_core::{TypeLayout, TypeResource};
 use super::heap::{HeapFrameRef, HeapPage, HEAP_SEGMENT_SIZE, PAGES_PRE_SEGMENT};
 use crate::{
     heap::{AllocResultInner, AllocationStrategy, SingleTypeHeapRef, HEAP_PAGE_SIZE},
-    MemoryMMMU, RegistedType,
+    quota, MemoryMMMU, RegistedType,
 };
 #[derive(Fail, Debug)]
 pub enum AllocError {
@@ -236,6 +236,13 @@ impl GlobalSingleTypeHeapPool {
         Err(NoSpaceLeft(Backtrace::new()))
     }
 }
+/// An unsized object too large for the pages of its type gets memory of its own, the pools only take objects that
+/// fit in a page.
+#[cold]
+fn alloc_large_unsized(layout: TypeLayout, size: usize) -> AllocResult<NonNull<u8>> {
+    let layout = std::alloc::Layout::from_size_align(size, layout.align()).map_err(|e| format_err!("{}", e))?;
+    NonNull::new(unsafe { std::alloc::alloc(layout) }).ok_or_else(|| NoSpaceLeft(Backtrace::new()))
+}
 pub struct LocalHeapPool {
     pools: HashMap<CowArc<'static, RegistedType>, LocalSingleTypeHeapPool>,
 }
@@ -293,11 +300,12 @@ lazy_static! {
 // pub(crate) static GLOBALH_EAP: GlobalHeap = new_global_heap();
 #[inline(always)]
 pub fn try_alloc<'a>(ty: &RegistedType) -> AllocResult<NonNull<u8>> {
+    let layout = ty.get_layout()?;
+    quota::charge(layout.size());
     unsafe {
         match ty.allocation_strategy.load() {
             AllocationStrategy::Small | AllocationStrategy::SmallUnsized => LOCAL_HEAP_POOL.with(|this| {
                 let mut this = this.borrow_mut();
-                let layout = ty.get_layout()?;
                 let pools = this.get_single_type_memory_pools(ty, layout)?;
                 let pool = pools.get_one_available(ty, layout)?;
                 if let Some(AllocResultInner { ptr: oop, full }) = pool.alloc(layout) {
@@ -311,7 +319,6 @@ pub fn try_alloc<'a>(ty: &RegistedType) -> AllocResult<NonNull<u8>> {
             }),
             AllocationStrategy::Large => {
                 let pool = &ty.heap;
-                let layout = ty.get_layout()?;
                 pool.alloc(layout, ty, 0)
             }
         }
@@ -319,11 +326,16 @@ pub fn try_alloc<'a>(ty: &RegistedType) -> AllocResult<NonNull<u8>> {
 }
 #[inline(always)]
 pub fn try_alloc_unsized<'a>(ty: &RegistedType, len: usize) -> AllocResult<NonNull<u8>> {
+    let layout = ty.get_layout()?;
+    let size = layout.size() + layout.flexible_size() * len;
+    quota::charge(size);
+    if size > HEAP_PAGE_SIZE / 8 {
+        return alloc_large_unsized(layout, size);
+    }
     unsafe {
         match ty.allocation_strategy.load() {
             AllocationStrategy::Small | AllocationStrategy::SmallUnsized => LOCAL_HEAP_POOL.with(|this| {
                 let mut this = this.borrow_mut();
-                let layout = ty.get_layout()?;
                 let pools = this.get_single_type_memory_pools(ty, layout)?;
                 loop {
                     let pool = pools.get_one_available(ty, layout)?;
@@ -339,7 +351,6 @@ pub fn try_alloc_unsized<'a>(ty: &RegistedType, len: usize) -> AllocResult<NonNu
             }),
             AllocationStrategy::Large => {
                 let pool = &ty.heap;
-                let layout = ty.get_layout()?;
                 pool.alloc(layout, ty, len)
             }
         }
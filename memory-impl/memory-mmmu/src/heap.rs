@@ -293,21 +293,21 @@ impl LinkedListAllocator {
 
     pub unsafe fn alloc_unsized<'a>(&mut self, layout: TypeLayout, flexible_len: usize) -> AllocResult {
         let size = layout.size() + layout.flexible_size() * flexible_len;
-        let size = (size + layout.align() - 1) & !(layout.align() - 1);
-        let cell_count = size / Self::cell_size(layout);
+        let cell_size = Self::cell_size(layout);
+        let cell_count = (size + cell_size - 1) / cell_size;
         self.alloc_cell(layout, cell_count)
     }
 
     #[inline(always)]
     pub unsafe fn alloc_cell<'a>(&mut self, layout: TypeLayout, cell_count: usize) -> AllocResult {
-        let node_handle = &mut self.head;
+        let mut node_handle = &mut self.head;
         if self.left_cell_count < cell_count {
             return None;
         }
         loop {
-            if let Some(mut node) = (*node_handle).as_mut() {
+            if let Some(node) = (*node_handle).as_mut() {
                 if node.available_cell == cell_count {
-                    let ptr = NonNull::from(&node).cast();
+                    let ptr = NonNull::from(&mut *node).cast();
                     *node_handle = node.next;
                     self.left_cell_count -= cell_count;
                     return Some(AllocResultInner::new(ptr, self.left_cell_count == 0));
@@ -318,6 +318,7 @@ impl LinkedListAllocator {
                     self.left_cell_count -= cell_count;
                     return Some(AllocResultInner::new(ptr, self.left_cell_count == 0));
                 }
+                node_handle = &mut node.next;
             } else {
                 return None;
             }
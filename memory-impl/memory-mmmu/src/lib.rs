@@ -25,6 +25,7 @@ pub(crate) mod mark;
 pub(crate) mod metadata;
 pub(crate) mod object;
 pub(crate) mod plan;
+pub mod quota;
 pub(crate) mod references;
 pub(crate) mod runtime_instruction_set;
 pub(crate) mod scanner;
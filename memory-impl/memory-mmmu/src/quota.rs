@@ -0,0 +1,45 @@
+//! A budget of the bytes the current thread may allocate, for a runtime limiting the code it runs. The allocations
+//! are counted but not refused, the runtime polls [`allocation_quota`] and stops the code once it is negative.
+use std::{
+    cell::Cell,
+    convert::TryFrom,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+thread_local! {
+    static REMAINING: Cell<Option<i64>> = Cell::new(None);
+}
+/// The threads with a quota, allocations skip the thread local while there is none.
+static LIMITED_THREADS: AtomicUsize = AtomicUsize::new(0);
+/// The bytes the thread may still allocate, negative once it allocated more, `None` without a limit.
+pub fn allocation_quota() -> Option<i64> {
+    REMAINING.with(|remaining| remaining.get())
+}
+/// Sets the bytes the thread may still allocate and returns the previous quota.
+pub fn set_allocation_quota(quota: Option<i64>) -> Option<i64> {
+    let previous = REMAINING.with(|remaining| remaining.replace(quota));
+    match (previous, quota) {
+        (None, Some(_)) => {
+            LIMITED_THREADS.fetch_add(1, Ordering::Relaxed);
+        }
+        (Some(_), None) => {
+            LIMITED_THREADS.fetch_sub(1, Ordering::Relaxed);
+        }
+        _ => {}
+    }
+    previous
+}
+#[inline(always)]
+pub(crate) fn charge(bytes: usize) {
+    if LIMITED_THREADS.load(Ordering::Relaxed) != 0 {
+        charge_limited(bytes);
+    }
+}
+#[cold]
+fn charge_limited(bytes: usize) {
+    REMAINING.with(|remaining| {
+        if let Some(quota) = remaining.get() {
+            remaining.set(Some(quota.saturating_sub(i64::try_from(bytes).unwrap_or(i64::MAX))));
+        }
+    })
+}
@@ -35,6 +35,7 @@ pub unsafe extern "C" fn __memory_mmmu_lib_instruction_set_alloc_in_non_gc_heap(
     let ty = type_resource.as_ptr().cast::<RegistedType>().as_ref().unwrap_unchecked();
     let layout = ty.get_layout().unwrap();
     assert!(layout.flexible_size() == 0);
+    crate::quota::charge(layout.size());
     let ptr = std::alloc::alloc(Layout::from_size_align_unchecked(layout.size(), layout.align()));
     Pointer::new(NonNull::new_unchecked(ptr).cast())
 }
@@ -43,6 +44,7 @@ pub unsafe extern "C" fn __memory_mmmu_lib_instruction_set_alloc_unsized_in_non_
     let ty = type_resource.as_ptr().cast::<RegistedType>().as_ref().unwrap_unchecked();
     let layout = ty.get_layout().unwrap();
     assert!(layout.flexible_size() != 0);
+    crate::quota::charge(layout.size() + layout.flexible_size() * len.0);
     let ptr = std::alloc::alloc(Layout::from_size_align_unchecked(layout.size() + layout.flexible_size() * len.0, layout.align()));
     Pointer::new(NonNull::new_unchecked(ptr).cast())
 }
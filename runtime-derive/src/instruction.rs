@@ -592,6 +592,8 @@ impl MetadataDeclaration {
                     });
                     emit_value.push(quote! {
                         builder.codes().borrow_mut(token).align(<#value_type as vm_core::TypeDeclaration>::LAYOUT.align());
+                        let offset = builder.codes().borrow(token).len();
+                        builder.mark_constant(token, offset, <#value_type as vm_core::TypeDeclaration>::TYPE);
                         <<#value_type as vm_core::TypeDeclaration>::Impl as vm_core::MoveIntoObject<'l>>::append( #name, builder.codes(),token);
                     });
                 }
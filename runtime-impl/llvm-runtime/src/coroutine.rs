@@ -0,0 +1,274 @@
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+    ptr::{self, NonNull},
+};
+
+use failure::{format_err, Fallible};
+use vm_core::{CoroutineBody, CoroutineState, CoroutineTrait, CoroutineYielder};
+
+pub const DEFAULT_STACK_SIZE: usize = 8 << 20;
+const GUARD_SIZE: usize = 4096;
+/// How many stacks of finished coroutines a thread keeps for the next ones.
+const MAX_POOLED_STACKS: usize = 16;
+thread_local! {
+    /// The stacks of finished or dropped coroutines, without the memory they committed.
+    static STACK_POOL: RefCell<Vec<NativeStack>> = RefCell::new(Vec::new());
+}
+/// A mapped stack with an inaccessible page at its bottom, the memory is only committed once touched.
+pub struct NativeStack {
+    base: NonNull<u8>,
+    len: usize,
+}
+impl NativeStack {
+    pub fn new(len: usize) -> Fallible<Self> {
+        let len = Self::mapped_len(len);
+        unsafe {
+            let base =
+                libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE, -1, 0);
+            if base == libc::MAP_FAILED {
+                return Err(format_err!("failed to map a coroutine stack: {}", std::io::Error::last_os_error()));
+            }
+            let stack = Self { base: NonNull::new_unchecked(base.cast()), len };
+            if libc::mprotect(base, GUARD_SIZE, libc::PROT_NONE) != 0 {
+                return Err(format_err!("failed to protect a coroutine stack: {}", std::io::Error::last_os_error()));
+            }
+            Ok(stack)
+        }
+    }
+
+    /// The length of the mapping of a stack of `len` bytes, whole pages and the guard page.
+    fn mapped_len(len: usize) -> usize {
+        (len + GUARD_SIZE - 1) / GUARD_SIZE * GUARD_SIZE + GUARD_SIZE
+    }
+
+    /// Takes a stack of `len` bytes from the pool of the thread, or maps a new one.
+    pub fn take(len: usize) -> Fallible<Self> {
+        let mapped_len = Self::mapped_len(len);
+        let pooled = STACK_POOL.with(|pool| {
+            let mut pool = pool.borrow_mut();
+            let index = pool.iter().position(|stack| stack.len == mapped_len)?;
+            Some(pool.swap_remove(index))
+        });
+        match pooled {
+            Some(stack) => Ok(stack),
+            None => Self::new(len),
+        }
+    }
+
+    /// Gives the memory the stack committed back to the system and keeps the mapping in the pool of the thread, unless
+    /// the pool is full.
+    pub fn release(self) {
+        unsafe {
+            libc::madvise(self.base.as_ptr().add(GUARD_SIZE).cast(), self.len - GUARD_SIZE, libc::MADV_DONTNEED);
+        }
+        STACK_POOL.with(|pool| {
+            let mut pool = pool.borrow_mut();
+            if pool.len() < MAX_POOLED_STACKS {
+                pool.push(self);
+            }
+        });
+    }
+
+    /// The highest address of the stack, stacks grow downwards.
+    pub fn top(&self) -> NonNull<u8> {
+        unsafe { NonNull::new_unchecked(self.base.as_ptr().add(self.len)) }
+    }
+}
+impl Drop for NativeStack {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base.as_ptr().cast(), self.len);
+        }
+    }
+}
+struct CoroutineContext {
+    body: Cell<Option<CoroutineBody>>,
+    stack_pointer: Cell<usize>,
+    caller_stack_pointer: Cell<usize>,
+    running: Cell<bool>,
+    finished: Cell<bool>,
+    panic: Cell<Option<Box<dyn Any + Send>>>,
+}
+impl CoroutineYielder for CoroutineContext {
+    fn suspend(&self, value: usize) -> usize {
+        unsafe { __llvm_runtime_switch_stack(self.stack_pointer.as_ptr(), self.caller_stack_pointer.get(), value) }
+    }
+}
+/// A coroutine switching between native stacks, so the interpreter, the jit compiled code and native functions can all yield in the middle of a call.
+///
+/// The frames of a coroutine dropped before it finishes are never unwound. The stack goes back to the pool of the
+/// thread as soon as the coroutine finishes or is dropped.
+pub struct NativeCoroutine {
+    context: Box<CoroutineContext>,
+    stack: Option<NativeStack>,
+}
+impl NativeCoroutine {
+    pub fn new(body: CoroutineBody) -> Fallible<Self> {
+        Self::with_stack_size(body, DEFAULT_STACK_SIZE)
+    }
+
+    pub fn with_stack_size(body: CoroutineBody, stack_size: usize) -> Fallible<Self> {
+        let stack = NativeStack::take(stack_size)?;
+        let context = Box::new(CoroutineContext {
+            body: Cell::new(Some(body)),
+            stack_pointer: Cell::new(0),
+            caller_stack_pointer: Cell::new(0),
+            running: Cell::new(false),
+            finished: Cell::new(false),
+            panic: Cell::new(None),
+        });
+        context.stack_pointer.set(unsafe { initial_stack_pointer(&stack, &context) });
+        Ok(Self { context, stack: Some(stack) })
+    }
+}
+impl Drop for NativeCoroutine {
+    fn drop(&mut self) {
+        if let Some(stack) = self.stack.take() {
+            stack.release();
+        }
+    }
+}
+impl CoroutineTrait for NativeCoroutine {
+    fn resume(&mut self, value: usize) -> Fallible<CoroutineState> {
+        let context = &*self.context;
+        if context.finished.get() {
+            return Err(format_err!("cannot resume a finished coroutine"));
+        }
+        if context.running.replace(true) {
+            return Err(format_err!("cannot resume a running coroutine"));
+        }
+        let value = unsafe { __llvm_runtime_switch_stack(context.caller_stack_pointer.as_ptr(), context.stack_pointer.get(), value) };
+        context.running.set(false);
+        if let Some(payload) = context.panic.take() {
+            resume_unwind(payload);
+        }
+        if context.finished.get() {
+            // nothing runs on the stack of a finished coroutine anymore
+            if let Some(stack) = self.stack.take() {
+                stack.release();
+            }
+            return Ok(CoroutineState::Returned(value));
+        }
+        Ok(CoroutineState::Yielded(value))
+    }
+
+    fn is_finished(&self) -> bool {
+        self.context.finished.get()
+    }
+}
+/// Runs the body on the new stack and switches back for the last time, a panic is carried over to `resume`.
+extern "C" fn coroutine_main(value: usize, context: &CoroutineContext) -> ! {
+    let body = context.body.take().unwrap();
+    let value = match catch_unwind(AssertUnwindSafe(|| body(context, value))) {
+        Ok(value) => value,
+        Err(payload) => {
+            context.panic.set(Some(payload));
+            0
+        }
+    };
+    context.finished.set(true);
+    unsafe {
+        __llvm_runtime_switch_stack(context.stack_pointer.as_ptr(), context.caller_stack_pointer.get(), value);
+    }
+    unreachable!("a finished coroutine was resumed")
+}
+#[cfg(not(target_arch = "x86_64"))]
+compile_error!("native coroutines are only implemented for x86_64");
+std::arch::global_asm!(
+    // switch_stack(save: *mut usize, to: usize, value: usize) -> usize
+    // saves the callee saved registers on the current stack, switches to the stack `to` and returns `value` there
+    ".text",
+    ".global __llvm_runtime_switch_stack",
+    ".p2align 4",
+    "__llvm_runtime_switch_stack:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "mov rax, rdx",
+    "ret",
+    // the first switch to a coroutine returns here, with the context in r12 and `coroutine_main` in r13
+    ".global __llvm_runtime_coroutine_start",
+    ".p2align 4",
+    "__llvm_runtime_coroutine_start:",
+    "mov rdi, rax",
+    "mov rsi, r12",
+    "call r13",
+    "ud2",
+);
+extern "C" {
+    fn __llvm_runtime_switch_stack(save: *mut usize, to: usize, value: usize) -> usize;
+    fn __llvm_runtime_coroutine_start();
+}
+/// Lays out the registers `__llvm_runtime_switch_stack` pops, so the first switch enters `__llvm_runtime_coroutine_start` with an aligned stack.
+unsafe fn initial_stack_pointer(stack: &NativeStack, context: &CoroutineContext) -> usize {
+    let top = stack.top().as_ptr().cast::<usize>();
+    // r15, r14, r13, r12, rbx, rbp and the return address
+    let frame =
+        [0, 0, coroutine_main as *const () as usize, context as *const CoroutineContext as usize, 0, 0, __llvm_runtime_coroutine_start as *const () as usize];
+    let stack_pointer = top.sub(frame.len());
+    stack_pointer.copy_from_nonoverlapping(frame.as_ptr(), frame.len());
+    stack_pointer as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+    #[test]
+    fn resume_and_suspend() {
+        let mut coroutine = NativeCoroutine::new(Box::new(|yielder, value| {
+            let value = yielder.suspend(value + 1);
+            let value = yielder.suspend(value * 2);
+            value + 100
+        }))
+        .unwrap();
+        assert!(matches!(coroutine.resume(1).unwrap(), CoroutineState::Yielded(2)));
+        assert!(matches!(coroutine.resume(5).unwrap(), CoroutineState::Yielded(10)));
+        assert!(!coroutine.is_finished());
+        assert!(matches!(coroutine.resume(7).unwrap(), CoroutineState::Returned(107)));
+        assert!(coroutine.is_finished());
+        assert!(coroutine.resume(0).is_err());
+    }
+    #[test]
+    fn drop_while_suspended() {
+        let reached = Rc::new(Cell::new(0));
+        let body_reached = reached.clone();
+        let mut coroutine = NativeCoroutine::new(Box::new(move |yielder, _| {
+            body_reached.set(1);
+            yielder.suspend(0);
+            body_reached.set(2);
+            0
+        }))
+        .unwrap();
+        assert!(matches!(coroutine.resume(0).unwrap(), CoroutineState::Yielded(0)));
+        drop(coroutine);
+        assert_eq!(reached.get(), 1);
+        // the stack of the dropped coroutine runs the next one
+        let mut coroutine = NativeCoroutine::new(Box::new(|yielder, value| yielder.suspend(value) + 1)).unwrap();
+        assert!(matches!(coroutine.resume(3).unwrap(), CoroutineState::Yielded(3)));
+        assert!(matches!(coroutine.resume(4).unwrap(), CoroutineState::Returned(5)));
+    }
+    #[test]
+    fn reuse_stacks_of_finished_coroutines() {
+        let mut coroutine = NativeCoroutine::new(Box::new(|_, value| value)).unwrap();
+        let top = coroutine.stack.as_ref().unwrap().top();
+        assert!(matches!(coroutine.resume(1).unwrap(), CoroutineState::Returned(1)));
+        assert!(coroutine.stack.is_none());
+        let coroutine = NativeCoroutine::new(Box::new(|_, value| value)).unwrap();
+        assert_eq!(coroutine.stack.as_ref().unwrap().top(), top);
+    }
+}
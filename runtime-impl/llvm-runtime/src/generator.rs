@@ -930,6 +930,11 @@ impl<'ctx, 'm> LLVMFunctionBuilder<'ctx> {
                     let value = get!(args, llvm_type, 0);
                     let value_int = bitcast_to_int(value, &value_type, context, builder, usize_type)?;
                     let value_i64 = builder.build_int_cast(value_int, usize_type, "value_casted");
+                    if self.ip.is_none() {
+                        // the compiled function returns the value when it finds the mark after the instruction
+                        let jump_to = self.function.get_first_param().unwrap().into_pointer_value();
+                        builder.build_store(jump_to, usize_type.const_all_ones());
+                    }
                     builder.build_return(Some(&value_i64));
                 }
                 // fn<const ty:TYPE>()->(out:Pointer<Type>)
@@ -1613,6 +1618,10 @@ impl<'ctx, 'm> LLVMFunctionBuilder<'ctx> {
                 let llvm_basic_block_builder = basic_blocks.get(&*basic_block.id).unwrap();
                 (|| {
                     let mut variables: HashMap<String, Operand> = llvm_basic_block_builder.borrow().variables.clone();
+                    // the declared phis are variables of the block, they flow to the following blocks like the others
+                    for (phi_name, (phi, phi_type, _phi_instruction)) in llvm_basic_block_builder.borrow().phis.iter() {
+                        variables.insert(phi_name.clone(), Operand::Value(phi.as_basic_value(), phi_type.clone()));
+                    }
                     let mut builder = context.create_builder();
                     let phi_builder = context.create_builder();
                     builder.position_at_end(llvm_basic_block_builder.borrow().block);
@@ -2330,7 +2339,8 @@ impl<'ctx, 'm> LLVMFunctionBuilder<'ctx> {
     }
 
     fn generate_instruction_jit(
-        instruction_type: &InstructionType, global: Rc<RefCell<GlobalBuilder<'static>>>, state_instruction_type: Option<&StatefulInstruction>, name: &str,
+        instruction_type: &InstructionType, instruction_count: usize, global: Rc<RefCell<GlobalBuilder<'static>>>,
+        state_instruction_type: Option<(&StatefulInstruction, usize)>, name: &str,
     ) -> Result<(JITInstruction, FunctionValue<'static>)> {
         let (context, module) = {
             let global_ref = global.borrow();
@@ -2385,6 +2395,17 @@ impl<'ctx, 'm> LLVMFunctionBuilder<'ctx> {
         //     let (new_layout, _offset) = layout.extend(Layout::from_size_align(state_type_bytes, state_type_bytes)?)?;
         //     *layout = new_layout;
         // };
+        // the state of a stateful instruction is its opcode, which is passed by pointer after the constants
+        let opcode_size = match instruction_count {
+            0..=0xff => 1,
+            0x100..=0xffff => 2,
+            0x10000..=0xffffffff => 4,
+            _ => 8,
+        };
+        let opcode_type = context.custom_width_int_type(8 * opcode_size);
+        if state_instruction_type.is_some() {
+            args.push(opcode_type.ptr_type(AddressSpace::Generic).into());
+        }
         let mut operand_offset_list = Vec::new();
         let mut operand_types = Vec::new();
         let constant_size = constant_layout.size();
@@ -2399,7 +2420,7 @@ impl<'ctx, 'm> LLVMFunctionBuilder<'ctx> {
         let function_type = usize_type.fn_type(&args, false);
         let function = module.add_function(name, function_type, None);
         function.set_call_conventions(8); // fastcc
-        function.add_attribute(AttributeLoc::Function, context.create_enum_attribute(Attribute::get_named_enum_kind_id("alwaysinline"), 1));
+        function.add_attribute(AttributeLoc::Function, context.create_enum_attribute(Attribute::get_named_enum_kind_id("alwaysinline"), 0));
         let basic_block = context.append_basic_block(function, "entry");
         let builder = context.create_builder();
         builder.position_at_end(basic_block);
@@ -2425,18 +2446,8 @@ impl<'ctx, 'm> LLVMFunctionBuilder<'ctx> {
                 JITConstantKind::State => todo!(),
             }
         }
-        if let Some(stateful) = state_instruction_type {
-            let state_type = match stateful.statuses.len() {
-                0 => unreachable!(),
-                1..=0xff => Type::Int(IntKind::U8),
-                0x100..=0xffff => Type::Int(IntKind::U16),
-                0x10000..=0xffffffff => Type::Int(IntKind::U32),
-                _ => Type::Int(IntKind::U64),
-            };
-            constant_list.push(Constant::Ptr(function.get_nth_param(constant_list.len() as u32).unwrap().into_pointer_value(), state_type))
-        }
         let mut operand_list = Vec::new();
-        let operand_index_start = generics_metadatas.len() + 1;
+        let operand_index_start = generics_metadatas.len() + 1 + usize::from(state_instruction_type.is_some());
         for (index, (operand_metadata, _offset)) in metadata.operands.iter().zip(&operand_offset_list).enumerate() {
             let arg_index = operand_index_start + index;
             let operand_arg = function.get_nth_param(arg_index as u32).ok_or(GenericIndexOutOfRange(index))?;
@@ -2446,6 +2457,11 @@ impl<'ctx, 'm> LLVMFunctionBuilder<'ctx> {
         let exit_block_builder = context.create_builder();
         exit_block_builder.position_at_end(exit);
         let ip_phi = exit_block_builder.build_phi(context.i64_type(), "ip");
+        let current_instruction = if let Some((stateful, _start)) = &state_instruction_type {
+            InstructionType::Stateful(CowArc::new((*stateful).clone()))
+        } else {
+            instruction_type.clone()
+        };
         let mut this = LLVMFunctionBuilder {
             context,
             builder,
@@ -2454,16 +2470,16 @@ impl<'ctx, 'm> LLVMFunctionBuilder<'ctx> {
             global,
             function,
             instruction_type: instruction_type.clone(),
-            current_instruction: instruction_type.clone(),
+            current_instruction,
             termined: false,
             state_stack: Default::default(),
             exit,
             returned: false,
             ip_phi: Some(ip_phi),
         };
-        if let Some(stateful) = state_instruction_type {
-            this.state_stack
-                .push(StateInstructionBuilder { instruction: stateful.clone(), state_kind: StateKind::StateConstant(constant_list.last().unwrap().clone()) });
+        if let Some((stateful, start)) = state_instruction_type {
+            let opcode = function.get_nth_param(generics_metadatas.len() as u32 + 1).unwrap().into_pointer_value();
+            this.state_stack.push(StateInstructionBuilder { instruction: stateful.clone(), state_kind: StateKind::Opcode(opcode, start) });
         }
         this.generate_instruction_core(instruction_type, &constant_list, &mut operand_list)?;
         for operand in operand_list {
@@ -2487,6 +2503,8 @@ impl<'ctx, 'm> LLVMFunctionBuilder<'ctx> {
             function_name: function.get_name().to_str().unwrap().into(),
             align: constant_layout.align(),
             is_returned: this.returned,
+            is_stateful: state_instruction_type.is_some(),
+            is_make_slice: matches!(instruction_type, InstructionType::Bootstrap(BootstrapInstruction::MakeSlice)),
             operand_types,
             constant_size,
             constants: jit_constants,
@@ -2509,8 +2527,9 @@ impl<'ctx, 'm> LLVMFunctionBuilder<'ctx> {
                         let state_instruction: InstructionType = InstructionType::Complex(CowArc::new(state.instruction.clone()));
                         let (jit_instruction, function_value) = Self::generate_instruction_jit(
                             &state_instruction,
+                            instruction_count,
                             global.clone(),
-                            Some(stateful_instruction),
+                            Some((stateful_instruction, *start)),
                             &format!("instruction_{}", instruction.get_name()),
                         )
                         .map_err(|e| ErrorWhileGenerateInstruction(start + index, Box::new(e)))?;
@@ -2522,9 +2541,14 @@ impl<'ctx, 'm> LLVMFunctionBuilder<'ctx> {
                     }
                 }
                 _ => {
-                    let (jit_instruction, function_value) =
-                        Self::generate_instruction_jit(instruction, global.clone(), None, &format!("instruction_{}", instruction.get_name()))
-                            .map_err(|e| ErrorWhileGenerateInstruction(index, Box::new(e)))?;
+                    let (jit_instruction, function_value) = Self::generate_instruction_jit(
+                        instruction,
+                        instruction_count,
+                        global.clone(),
+                        None,
+                        &format!("instruction_{}", instruction.get_name()),
+                    )
+                    .map_err(|e| ErrorWhileGenerateInstruction(index, Box::new(e)))?;
                     function_value_list.push(function_value);
                     if !function_value.verify(true) {
                         return Err(LLVMVerifyFailed(function_value.print_to_string().to_string()));
@@ -13,16 +13,16 @@ use inkwell::{
     values::{CallableValue, PointerValue},
 };
 use libffi::middle::{Callback, Cif, Closure, Type};
-use util::CowArc;
 use vm_core::{
-    DynRuntimeTrait, FunctionType, IntKind, ObjectBuilder, ObjectBuilderImport, ObjectBuilderInner, ObjectRef, RelocationKind, ResourceConverter,
-    SymbolBuilder, Tuple, _ghost_cell::GhostToken,
+    DynRuntimeTrait, FunctionType, ObjectBuilder, ObjectBuilderImport, ObjectBuilderInner, ObjectRef, RelocationKind, ResourceConverter, SymbolBuilder,
+    _ghost_cell::GhostToken,
 };
 
 use std::{any::Any, cell::RefCell, fmt::Debug, marker::PhantomData, rc::Rc, sync::RwLock};
 
 use crate::{
     context::RuntimeContext,
+    coroutine::NativeCoroutine,
     generator::{GlobalBuilder, LLVMFunctionBuilder},
 };
 use failure::format_err;
@@ -33,7 +33,7 @@ use runtime::{
     mem::MemoryInstructionSetProvider,
 };
 use util::AsAny;
-use vm_core::{Component, ExecutableResourceTrait, Resource, ResourceError, RuntimeTrait};
+use vm_core::{Component, CoroutineBody, CoroutineTrait, ExecutableResourceTrait, Resource, ResourceError, RuntimeTrait};
 
 pub struct RawInterpreter {
     binder: FunctionBinder,
@@ -149,6 +149,10 @@ impl<S: InstructionSet, M: MemoryInstructionSetProvider> DynRuntimeTrait<Functio
     fn upload_dyn(&self, resource: &dyn ExecutableResourceTrait<FunctionPack<S>>, input: FunctionPack<S>) -> Fallible<()> {
         self.upload(resource.as_any().downcast_ref().ok_or_else(|| format_err!("wrone implements type"))?, input)
     }
+
+    fn create_coroutine(&self, body: CoroutineBody) -> Fallible<Box<dyn CoroutineTrait>> {
+        Ok(Box::new(NativeCoroutine::new(body)?))
+    }
 }
 
 #[derive(Getters, Default)]
@@ -319,18 +323,16 @@ impl FunctionBinder {
         for arg_type in &function_type.args {
             args_type.push(convert_type(arg_type));
         }
-        if let Some(va_arg_type) = function_type.va_arg() {
-            args_type.push(convert_type(&vm_core::Type::Tuple(Tuple::Normal(CowArc::Owned(
-                vec![vm_core::Type::Pointer(CowArc::new(va_arg_type.clone())), vm_core::Type::Int(IntKind::Usize)].into(),
-            )))))
+        // the slice of the variable arguments is passed as its pointer and length, which the entry point stores in
+        // two registers after the other arguments
+        if function_type.va_arg().is_some() {
+            args_type.push(Type::pointer());
+            args_type.push(Type::usize());
         }
         let ret_type = if let Some(ret) = &function_type.return_type { convert_type(ret) } else { Type::void() };
+        let arg_count = args_type.len();
         let cif = Cif::new(args_type, ret_type);
-        let callback = if function_type.va_arg().is_some() {
-            self.enter_points.get(function_type.args().len()).copied().unwrap_or(self.enter_point_multi_arg)
-        } else {
-            self.enter_points_va_arg.get(function_type.args().len()).copied().unwrap_or(self.enter_point_multi_arg_va_arg)
-        };
+        let callback = self.enter_points.get(arg_count).copied().unwrap_or(self.enter_point_multi_arg);
         GhostToken::new(|mut token| {
             let object_builder = ObjectBuilder::default();
             let metadata_memory: &mut MaybeUninit<FunctionMetadata> = object_builder.borrow_mut(&mut token).receive();
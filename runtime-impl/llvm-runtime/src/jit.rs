@@ -1,7 +1,7 @@
-use crate::context::RuntimeContext;
+use crate::{context::RuntimeContext, coroutine::NativeCoroutine};
 use std::{
     alloc::{Layout, LayoutError},
-    cell::RefCell,
+    cell::{Cell, RefCell},
     collections::{HashMap, HashSet},
     convert::TryInto,
     fmt::Debug,
@@ -24,8 +24,8 @@ use inkwell::{
     passes::{PassManager, PassManagerBuilder},
     support::LLVMString,
     types::BasicType,
-    values::{FunctionValue, PointerValue},
-    AddressSpace,
+    values::FunctionValue,
+    AddressSpace, IntPredicate,
 };
 use runtime::{
     code::FunctionPack,
@@ -35,8 +35,8 @@ use runtime::{
 
 use util_derive::AsAny;
 use vm_core::{
-    Component, DynRuntimeTrait, ExecutableResourceTrait, FunctionType, ObjectBuilder, ObjectRef, Resource, ResourceConverter, ResourceError, RuntimeTrait,
-    SymbolBuilder, Type, _ghost_cell::GhostToken,
+    Component, CoroutineBody, CoroutineTrait, DynRuntimeTrait, ExecutableResourceTrait, FunctionType, ObjectBuilder, ObjectBuilderImport, ObjectBuilderInner,
+    ObjectRef, RelocationKind, Resource, ResourceConverter, ResourceError, RuntimeTrait, SymbolBuilder, Type, _ghost_cell::GhostToken,
 };
 
 #[derive(Debug, Fail)]
@@ -105,6 +105,10 @@ pub struct JITInstruction {
     pub(crate) function_name: Box<str>,
     pub(crate) align: usize,
     pub(crate) is_returned: bool,
+    /// Takes the pointer to its opcode after the constants, the opcode is its state.
+    pub(crate) is_stateful: bool,
+    /// `MakeSlice`, whose operands are counted by its first constant, is compiled in place.
+    pub(crate) is_make_slice: bool,
     pub(crate) operand_types: Vec<Type>,
     pub(crate) constant_size: usize,
     pub(crate) constants: Vec<JITConstantKind>,
@@ -112,6 +116,8 @@ pub struct JITInstruction {
 pub struct RawJITCompiler {
     instructions: Vec<JITInstruction>,
     context: RuntimeContext,
+    /// Number of the functions compiled so far, which names them apart in the execution engine.
+    function_count: Cell<usize>,
 }
 unsafe impl Send for RawJITCompiler {}
 
@@ -144,45 +150,78 @@ impl RawJITCompiler {
         }
         context.set_execution_engine(Some(execution_engine));
         context.set_module(Some(Rc::unwrap_or_clone(module)));
-        let this = Self { instructions: jit_instructions, context };
+        let this = Self { instructions: jit_instructions, context, function_count: Cell::new(0) };
         Ok(this)
     }
 
-    pub fn generate_function<'ctx>(&self, ir: &ObjectRef, function_type: &FunctionType) -> Result<(Module<'ctx>, FunctionValue<'ctx>)> {
+    pub fn generate_function<'ctx>(&self, ir: &ObjectRef, function_type: &FunctionType, register_count: u16) -> Result<(Module<'ctx>, FunctionValue<'ctx>)> {
         let context: &'static Context = unsafe { self.context.context() };
-        let module = context.create_module("jit_function_");
+        let function_index = self.function_count.get();
+        self.function_count.set(function_index + 1);
+        let module = context.create_module(&format!("jit_function_{}", function_index));
         let usize_type = context.custom_width_int_type(usize::BITS);
-        let mut regs = HashMap::<(u16, Type), PointerValue<'ctx>>::new();
+        let i64_type = context.i64_type();
         let mut instruction_function_decl_cache = HashMap::new();
         let function_llvm_type = function_type_to_llvm_type(function_type, context)?;
-        let function = module.add_function("jited_ir_", function_llvm_type, None);
+        let function = module.add_function(&format!("jited_ir_{}", function_index), function_llvm_type, None);
         let mut blocks = HashMap::<usize, JITBasicBlock<'ctx>>::new();
         let entry_block = context.append_basic_block(function, "entry");
         let entry_builder = context.create_builder();
         entry_builder.position_at_end(entry_block);
+        // the registers are words of one array like the ones of the interpreter, a register is read as any type and
+        // the arrays made by `MakeSlice` span several of them
+        let registers = entry_builder.build_array_alloca(i64_type, i64_type.const_int(register_count.max(1).into(), false), "registers");
+        let registers = entry_builder.build_address_space_cast(registers, i64_type.ptr_type(AddressSpace::Local), "registers_local");
+        let register_pointer = |builder: &inkwell::builder::Builder<'ctx>, reg: u64, llvm_type: inkwell::types::BasicTypeEnum<'ctx>, name: &str| {
+            let pointer = unsafe { builder.build_in_bounds_gep(registers, &[i64_type.const_int(reg, false)], name) };
+            builder.build_pointer_cast(pointer, llvm_type.ptr_type(AddressSpace::Local), name)
+        };
         let mut params_layout = Layout::new::<()>();
-        for (param_index, param_type) in function_type.args.iter().enumerate() {
-            let llvm_type = vm_type_to_llvm_type(param_type, context)?;
-            let param_layout: Layout = param_type.get_layout()?.into();
+        let mut param_layouts = Vec::with_capacity(function_type.args.len() + 1);
+        for param_type in function_type.args.iter() {
+            param_layouts.push(Layout::from(param_type.get_layout()?));
+        }
+        // the slice of the variable arguments is one parameter of the function, it takes two registers like in the
+        // interpreter
+        if function_type.va_arg().is_some() {
+            param_layouts.push(Layout::new::<(usize, usize)>());
+        }
+        for (param_index, param_layout) in param_layouts.into_iter().enumerate() {
             let param_layout = Layout::from_size_align(param_layout.size().max(size_of::<usize>()), param_layout.align().max(align_of::<usize>()))?;
             let (new_layout, offset) = params_layout.extend(param_layout)?;
             params_layout = new_layout;
             let reg = offset / size_of::<usize>();
-            let reg_pointer = entry_builder.build_alloca(llvm_type, &format!("reg_param_{}", param_index));
-            let reg_pointer =
-                entry_builder.build_address_space_cast(reg_pointer, llvm_type.ptr_type(AddressSpace::Local), &format!("reg_param_local_{}", param_index));
             let param = function.get_nth_param(param_index.try_into()?).ok_or(ParamIndexOutOfBound(param_index))?;
-            entry_builder.build_store(reg_pointer, param);
-            regs.insert((reg.try_into()?, param_type.clone()), reg_pointer);
+            entry_builder.build_store(register_pointer(&entry_builder, reg.try_into()?, param.get_type(), &format!("reg_param_{}", param_index)), param);
         }
         let jump_to = entry_builder.build_alloca(usize_type, "jump_to");
-        let jump_to = entry_builder.build_address_space_cast(jump_to, usize_type.ptr_type(AddressSpace::Local), "jump_to");
-        let block = context.append_basic_block(function, "entry");
+        let jump_to = entry_builder.build_address_space_cast(jump_to, usize_type.ptr_type(AddressSpace::Local), "jump_to_local");
+        let block = context.append_basic_block(function, "block_0");
         let builder = context.create_builder();
         builder.position_at_end(block);
         let mut error_block = None;
+        // the constants are passed as pointers into the byte code, which is kept alive by the compiled function
         let locked_ir = ir.lock().unwrap();
-        let mut ir_buffer = locked_ir.get_buffer().clone();
+        let ir_buffer = locked_ir.get_buffer();
+        let read = |offset: usize| -> Result<NonNull<u8>> { ir_buffer.try_get_ptr::<u8>(offset).ok_or(OffsetOutOfBound(offset)) };
+        let read_u16 = |offset: usize| -> Result<u16> {
+            ir_buffer.try_get_ptr::<u16>(offset).map(|p| unsafe { p.as_ptr().read_unaligned() }).ok_or(OffsetOutOfBound(offset))
+        };
+        let read_usize = |offset: usize| -> Result<usize> {
+            ir_buffer.try_get_ptr::<usize>(offset).map(|p| unsafe { p.as_ptr().read_unaligned() }).ok_or(OffsetOutOfBound(offset))
+        };
+        let build_return = |builder: &inkwell::builder::Builder<'ctx>, ret: inkwell::values::CallSiteValue<'ctx>| -> Result<()> {
+            match (function_type.return_type(), ret.try_as_basic_value().left()) {
+                (Some(return_type), Some(ret)) => {
+                    let ret = bitcast_from_int(ret.into_int_value(), context, builder, vm_type_to_llvm_type(return_type, context)?)?;
+                    builder.build_return(Some(&ret));
+                }
+                _ => {
+                    builder.build_return(None);
+                }
+            }
+            Ok(())
+        };
         let mut tasks = vec![0usize];
         let mut finished_task = HashSet::new();
         let opcode_size = match self.instructions.len() {
@@ -191,6 +230,7 @@ impl RawJITCompiler {
             0x10000..=0xffffffff => 4,
             _ => 8,
         };
+        let opcode_type = context.custom_width_int_type((opcode_size * 8).try_into()?);
         let mut ip = 0;
         blocks.insert(ip, JITBasicBlock { llvm_block: block });
         let first_block = block;
@@ -202,27 +242,67 @@ impl RawJITCompiler {
             let block = blocks.get(&block_start).unwrap();
             ip = block_start;
             builder.position_at_end(block.llvm_block);
-            while ip < ir_buffer.len() {
-                let opcode = match opcode_size {
-                    1 => unsafe { ir_buffer.get::<u8>(ip) as usize },
-                    2 => unsafe { ir_buffer.get::<u16>(ip) as usize },
-                    4 => unsafe { ir_buffer.get::<u32>(ip).try_into()? },
-                    8 => unsafe { ir_buffer.get::<u64>(ip).try_into()? },
-                    _ => unreachable!(),
+            loop {
+                // the code reached the start of another block, which goes on from there
+                if ip != block_start {
+                    if let Some(next_block) = blocks.get(&ip) {
+                        builder.build_unconditional_branch(next_block.llvm_block);
+                        if !finished_task.contains(&ip) {
+                            tasks.push(ip);
+                        }
+                        break;
+                    }
+                }
+                let opcode_pointer = read(ip)?.as_ptr();
+                let opcode = unsafe {
+                    match opcode_size {
+                        1 => opcode_pointer.read() as usize,
+                        2 => opcode_pointer.cast::<u16>().read_unaligned() as usize,
+                        4 => opcode_pointer.cast::<u32>().read_unaligned().try_into()?,
+                        8 => opcode_pointer.cast::<u64>().read_unaligned().try_into()?,
+                        _ => unreachable!(),
+                    }
                 };
                 let jit_instruction = self.instructions.get(opcode).ok_or(OpcodeOutOfBound(opcode))?;
                 let constant_start = (ip + opcode_size + (jit_instruction.align - 1)) & !(jit_instruction.align - 1);
+                if jit_instruction.is_make_slice {
+                    // the elements are copied into the registers following `array_reg`, the slice is the pointer to
+                    // them and their count
+                    let len = read_usize(constant_start)?;
+                    let size = read_usize(constant_start + size_of::<usize>())?;
+                    let operand_start = constant_start + 2 * size_of::<usize>();
+                    let array_reg = read_u16(operand_start + 2 * len)?;
+                    let slice_reg = read_u16(operand_start + 2 * (len + 1))?;
+                    let i8_type = context.i8_type();
+                    let array_pointer = register_pointer(&builder, array_reg.into(), i8_type.into(), &format!("array_{}", ip));
+                    for index in 0..len {
+                        let element_reg = read_u16(operand_start + 2 * index)?;
+                        let element_pointer = register_pointer(&builder, element_reg.into(), i8_type.into(), &format!("element_{}_{}", ip, index));
+                        let target_pointer = unsafe {
+                            builder.build_in_bounds_gep(array_pointer, &[usize_type.const_int((index * size).try_into()?, false)], &format!("target_{}_{}", ip, index))
+                        };
+                        builder
+                            .build_memcpy(target_pointer, 1, element_pointer, 8, usize_type.const_int(size.try_into()?, false))
+                            .map_err(|e| LLVMError(e.to_string()))?;
+                    }
+                    let array_address = builder.build_ptr_to_int(array_pointer, i64_type, &format!("array_address_{}", ip));
+                    builder.build_store(register_pointer(&builder, slice_reg.into(), i64_type.into(), &format!("slice_{}", ip)), array_address);
+                    builder.build_store(
+                        register_pointer(&builder, u64::from(slice_reg) + 1, i64_type.into(), &format!("slice_len_{}", ip)),
+                        i64_type.const_int(len.try_into()?, false),
+                    );
+                    ip = operand_start + 2 * (len + 2);
+                    continue;
+                }
                 let instruction_function = self.execution_engine()?.get_function_value(&jit_instruction.function_name).unwrap();
                 let params = instruction_function.get_type().get_param_types();
                 let mut args = Vec::with_capacity(params.len());
                 args.push(jump_to.into());
                 let mut goto_list = Vec::new();
-                for (index, (&llvm_type, constants)) in
-                    params.get(0..jit_instruction.constants.len()).unwrap().iter().zip(jit_instruction.constants.iter()).enumerate()
-                {
+                for (index, constants) in jit_instruction.constants.iter().enumerate() {
                     match constants {
                         JITConstantKind::Const(value_type, constant_offset) => {
-                            let ptr: NonNull<u8> = ir_buffer.get_ptr(constant_start + constant_offset);
+                            let ptr = read(constant_start + constant_offset)?;
                             let value_llvm_type = vm_type_to_llvm_type(value_type, context)?;
                             let pointer_value = usize_type.const_int((ptr.as_ptr() as usize).try_into()?, false);
                             let pointer_value =
@@ -230,7 +310,7 @@ impl RawJITCompiler {
                             args.push(pointer_value.into());
                         }
                         JITConstantKind::Mut(value_type, constant_offset) => {
-                            let ptr: NonNull<u8> = ir_buffer.get_ptr(constant_start + constant_offset);
+                            let ptr = read(constant_start + constant_offset)?;
                             let value_llvm_type = vm_type_to_llvm_type(value_type, context)?;
                             let pointer_value = usize_type.const_int((ptr.as_ptr() as usize).try_into()?, false);
                             let pointer_value =
@@ -238,54 +318,46 @@ impl RawJITCompiler {
                             args.push(pointer_value.into());
                         }
                         JITConstantKind::BasicBlock(constant_offset) => {
-                            let offset: i32 = unsafe {
-                                ir_buffer.try_get(constant_start + constant_offset).ok_or_else(|| OffsetOutOfBound(constant_start + constant_offset))?
-                            };
+                            let offset = ir_buffer
+                                .try_get_ptr::<i32>(constant_start + constant_offset)
+                                .map(|p| unsafe { p.as_ptr().read_unaligned() })
+                                .ok_or_else(|| OffsetOutOfBound(constant_start + constant_offset))?;
                             let target = (constant_start + constant_offset).overflowing_add_signed(offset as isize).0;
                             goto_list.push(target);
                             args.push(usize_type.const_int(target.try_into()?, false).into());
                         }
-                        JITConstantKind::State => {
-                            let global =
-                                module.add_global(llvm_type.into_pointer_type().get_element_type().into_int_type(), Some(AddressSpace::Global), "state");
-                            args.push(global.as_pointer_value().into());
-                        }
+                        JITConstantKind::State => {}
                     }
                 }
+                // a stateful instruction keeps its state in its opcode like in the interpreter
+                if jit_instruction.is_stateful {
+                    let opcode_address = usize_type.const_int((opcode_pointer as usize).try_into()?, false);
+                    args.push(builder.build_int_to_ptr(opcode_address, opcode_type.ptr_type(AddressSpace::Generic), &format!("opcode_{}", ip)).into());
+                }
+                let operand_start = constant_start + ((jit_instruction.constant_size + 1) & (!1));
                 for (index, operand_type) in jit_instruction.operand_types.iter().enumerate() {
-                    let reg = unsafe {
-                        ir_buffer
-                            .try_get(constant_start + jit_instruction.constant_size + 2 * index)
-                            .ok_or(OffsetOutOfBound(constant_start + jit_instruction.constant_size + 2 * index))?
-                    };
-                    let reg_pointer = match regs.entry((reg, operand_type.clone())) {
-                        std::collections::hash_map::Entry::Occupied(o) => *o.get(),
-                        std::collections::hash_map::Entry::Vacant(v) => {
-                            let reg_type = vm_type_to_llvm_type(operand_type, context)?;
-                            let reg_pointer = entry_builder.build_alloca(reg_type, &format!("reg_{}_", reg));
-                            let reg_pointer =
-                                entry_builder.build_address_space_cast(reg_pointer, reg_type.ptr_type(AddressSpace::Local), &format!("reg_{}_pointer", reg));
-                            *v.insert(reg_pointer)
-                        }
-                    };
-                    args.push(reg_pointer.into());
+                    let reg = read_u16(operand_start + 2 * index)?;
+                    let reg_type = vm_type_to_llvm_type(operand_type, context)?;
+                    args.push(register_pointer(&builder, reg.into(), reg_type, &format!("reg_{}_pointer", reg)).into());
                 }
                 let instruction_function_decl = instruction_function_decl_cache
                     .entry(opcode)
                     .or_insert_with(|| module.add_function(&jit_instruction.function_name, instruction_function.get_type(), None));
                 let ret = builder.build_call(*instruction_function_decl, &args, &format!("call_{}", ip));
                 if jit_instruction.is_returned {
-                    if let Some(ret) = ret.try_as_basic_value().left() {
-                        if let Some(return_type) = function_type.return_type() {
-                            let ret = bitcast_from_int(ret.into_int_value(), context, &builder, vm_type_to_llvm_type(return_type, context)?)?;
-                            builder.build_return(Some(&ret));
-                        }
-                    } else {
-                        builder.build_return(None);
-                    }
-                } else if !goto_list.is_empty() {
-                    let jump_to_value = builder.build_load(jump_to, "jump_to_value").into_int_value();
-                    let mut switch_cases = Vec::with_capacity(goto_list.len());
+                    build_return(&builder, ret)?;
+                    break;
+                }
+                // an instruction returning from the function, like when it raises an error, sets `jump_to` to the
+                // largest value
+                let jump_to_value = builder.build_load(jump_to, "jump_to_value").into_int_value();
+                let return_block = context.append_basic_block(function, &format!("return_{}", ip));
+                let return_builder = context.create_builder();
+                return_builder.position_at_end(return_block);
+                build_return(&return_builder, ret)?;
+                let return_value = usize_type.const_all_ones();
+                if !goto_list.is_empty() {
+                    let mut switch_cases = Vec::with_capacity(goto_list.len() + 1);
                     for goto in goto_list {
                         let goto_block = blocks
                             .entry(goto)
@@ -295,6 +367,7 @@ impl RawJITCompiler {
                         }
                         switch_cases.push((usize_type.const_int(goto.try_into()?, false), goto_block.llvm_block));
                     }
+                    switch_cases.push((return_value, return_block));
                     let else_block = error_block.get_or_insert_with(|| {
                         let error_block = context.append_basic_block(function, "error_block");
                         let error_builder = context.create_builder();
@@ -305,7 +378,11 @@ impl RawJITCompiler {
                     builder.build_switch(jump_to_value, *else_block, &switch_cases);
                     break;
                 }
-                ip = constant_start + ((jit_instruction.constant_size + 1) & (!1)) + 2 * jit_instruction.operand_types.len();
+                let next_block = context.append_basic_block(function, &format!("next_{}", ip));
+                let is_return = builder.build_int_compare(IntPredicate::EQ, jump_to_value, return_value, &format!("is_return_{}", ip));
+                builder.build_conditional_branch(is_return, return_block, next_block);
+                builder.position_at_end(next_block);
+                ip = operand_start + 2 * jit_instruction.operand_types.len();
             }
         }
         entry_builder.build_unconditional_branch(first_block);
@@ -316,11 +393,14 @@ impl RawJITCompiler {
         Ok((module, function))
     }
 
-    pub fn wrap_function(&self, function: FunctionValue<'static>, output: ObjectRef) -> Fallible<ObjectRef> {
+    pub fn wrap_function(&self, function: FunctionValue<'static>, ir: ObjectRef, output: ObjectRef) -> Fallible<ObjectRef> {
         let address = self.execution_engine()?.get_function_address(&function.get_name().to_string_lossy())?;
         GhostToken::new(|mut token| {
             let builder = ObjectBuilder::default();
             builder.borrow_mut(&mut token).push(address);
+            // the compiled code points into the constants of the byte code, so the function keeps it
+            builder.borrow_mut(&mut token).push(0usize);
+            ObjectBuilderInner::set_import(&builder, &mut token, size_of::<usize>(), ObjectBuilderImport::ObjectRef(ir), RelocationKind::UsizePtrAbsolute, 0);
             builder.borrow_mut(&mut token).add_symbol(SymbolBuilder::default().offset(0).symbol_kind(vm_core::SymbolKind::Value).build().unwrap());
             builder.take(&mut token).build_into(output)
         })
@@ -347,7 +427,7 @@ impl<S: InstructionSet, M: MemoryInstructionSetProvider> JITCompiler<S, M> {
 
     pub fn compile(&self, pack: FunctionPack<S>) -> Fallible<ObjectRef> {
         let raw = self.raw().lock().map_err(|_| LockFailed())?;
-        let (module, function_value) = raw.generate_function(pack.byte_code(), pack.function_type())?;
+        let (module, function_value) = raw.generate_function(pack.byte_code(), pack.function_type(), pack.register_count())?;
 
         module.link_in_module(raw.root_module()?.clone()).map_err(|e| OtherError(format_err!("llvm error:{}", e)))?;
         let pass_manager_builder = PassManagerBuilder::create();
@@ -363,7 +443,7 @@ impl<S: InstructionSet, M: MemoryInstructionSetProvider> JITCompiler<S, M> {
         })?;
 
         raw.execution_engine()?.add_module(&module).map_err(|_| AddModuleError())?;
-        let function = raw.wrap_function(function_value, pack.output.unwrap_or_default())?;
+        let function = raw.wrap_function(function_value, pack.byte_code().clone(), pack.output.unwrap_or_default())?;
         Ok(function)
     }
 }
@@ -423,4 +503,8 @@ impl<S: InstructionSet, M: MemoryInstructionSetProvider> DynRuntimeTrait<Functio
     fn upload_dyn(&self, resource: &dyn ExecutableResourceTrait<FunctionPack<S>>, input: FunctionPack<S>) -> Fallible<()> {
         self.upload(resource.as_any().downcast_ref().ok_or_else(|| format_err!("wrone implements type"))?, input)
     }
+
+    fn create_coroutine(&self, body: CoroutineBody) -> Fallible<Box<dyn CoroutineTrait>> {
+        Ok(Box::new(NativeCoroutine::new(body)?))
+    }
 }
@@ -2,11 +2,13 @@
 #![feature(arc_unwrap_or_clone)]
 #![feature(iterator_try_collect)]
 mod context;
+mod coroutine;
 mod generator;
 mod interpreter;
 mod jit;
 mod raw_llvm;
 
+pub use coroutine::*;
 pub use interpreter::*;
 pub use jit::*;
 pub use raw_llvm::*;
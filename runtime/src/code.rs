@@ -8,7 +8,7 @@ use failure::Fallible;
 use ghost_cell::{GhostCell, GhostToken};
 use smallvec::SmallVec;
 use util::CowSlice;
-use vm_core::{self, FunctionType, ObjectBuilder, ObjectBuilderImport, ObjectBuilderInner, ObjectRef, SymbolBuilder, TypeDeclaration};
+use vm_core::{self, FunctionType, ObjectBuilder, ObjectBuilderImport, ObjectBuilderInner, ObjectRef, SymbolBuilder, Type, TypeDeclaration};
 
 use crate::instructions::InstructionSet;
 
@@ -31,6 +31,10 @@ pub struct FunctionPack<S: 'static> {
     pub register_count: u16,
     #[builder(default)]
     pub output: Option<ObjectRef>,
+    /// The offsets in `byte_code` of the constant operands of its instructions, with their types.
+    #[builder(default)]
+    #[getset(get = "pub")]
+    pub constants: Vec<(usize, Type)>,
 }
 
 impl<S> Debug for FunctionPack<S> {
@@ -103,19 +107,26 @@ impl<'l, S> FunctionBuilder<'l, S> {
         let blocks = self.blocks;
         let remote_constants = self.remote_constants;
         let mut buffer = ObjectBuilder::default();
+        let mut constants = Vec::new();
         for block in blocks {
+            let block_len = (**block.codes).borrow(token).len();
+            let block_constants = std::mem::take(block.constants.borrow_mut(token));
             buffer = ObjectBuilder::merge(token, buffer, block.codes);
+            // the block is appended at the end of the buffer
+            let block_offset = (**buffer).borrow(token).len() - block_len;
+            constants.extend(block_constants.into_iter().map(|(offset, ty)| (block_offset + offset, ty)));
         }
         buffer = ObjectBuilder::merge(token, buffer, remote_constants);
         buffer.borrow_mut(token).add_symbol(SymbolBuilder::default().offset(0).build()?);
         let object = buffer.take(token).build()?;
-        Ok(FunctionPack { _ph: PhantomData, byte_code: object, function_type, register_count, output: Some(output) })
+        Ok(FunctionPack { _ph: PhantomData, byte_code: object, function_type, register_count, output: Some(output), constants })
     }
 }
 #[derive(Getters)]
 pub struct BlockBuilder<'l, S> {
     #[getset(get = "pub")]
     codes: ObjectBuilder<'l>,
+    constants: Rc<GhostCell<'l, Vec<(usize, Type)>>>,
     phantom_data: PhantomData<fn(S) -> S>,
 }
 
@@ -135,7 +146,7 @@ impl<'l, S> Debug for BlockBuilder<'l, S> {
 
 impl<'l, S> Clone for BlockBuilder<'l, S> {
     fn clone(&self) -> Self {
-        Self { codes: self.codes.clone(), phantom_data: self.phantom_data }
+        Self { codes: self.codes.clone(), constants: self.constants.clone(), phantom_data: self.phantom_data }
     }
 }
 impl<'l, S> Default for BlockBuilder<'l, S> {
@@ -143,7 +154,7 @@ impl<'l, S> Default for BlockBuilder<'l, S> {
         let mut object_builder = ObjectBuilderInner::default();
         object_builder.add_symbol(SymbolBuilder::default().offset(0).build().unwrap());
         let codes = object_builder.into();
-        Self { codes, phantom_data: PhantomData }
+        Self { codes, constants: Rc::new(GhostCell::new(Vec::new())), phantom_data: PhantomData }
     }
 }
 impl<'l, S: InstructionSet> BlockBuilder<'l, S> {
@@ -171,6 +182,11 @@ impl<'l, S: InstructionSet> BlockBuilder<'l, S> {
         b.push(register.reg());
     }
 
+    /// Records that a constant of type `ty` was written at `offset` of the block.
+    pub fn mark_constant(&self, token: &mut GhostToken<'l>, offset: usize, ty: Type) {
+        self.constants.borrow_mut(token).push((offset, ty));
+    }
+
     pub unsafe fn push_block_offset(&self, token: &mut GhostToken<'l>, block: &BlockBuilder<'l, S>) {
         let import = if self.codes() == block.codes() {
             ObjectBuilderImport::Reflexive
@@ -0,0 +1,113 @@
+use proc_macro2::{Ident, Literal, TokenStream as TokenStream2};
+
+use syn::{Error, Result};
+extern crate proc_macro2;
+
+use super::{
+    lalr1::{init_stacks, reduce_values, shift_values, Grammar},
+    lr1::{generate_entry, stack_ident, terminal_patten},
+    parse::*,
+    production::*,
+};
+struct SyntaxEarley<'t> {
+    grammar: Grammar<'t>,
+}
+impl<'t> SyntaxEarley<'t> {
+    fn generate(&self, name: Ident) -> Result<TokenStream2> {
+        let Self { grammar } = self;
+        let Syntax { start, token_type, stream, .. } = grammar.syntax;
+        let terminals = grammar.terminals();
+        let mut nonterminals: Vec<_> = grammar.syntax.productions.keys().cloned().collect();
+        nonterminals.sort();
+        let nonterminal_index = |nonterminal: &NonTerminal| Literal::usize_unsuffixed(nonterminals.iter().position(|n| &**n == nonterminal).unwrap());
+        let productions = grammar.productions.iter().map(|production| {
+            let left_part = nonterminal_index(&production.left_part);
+            let symbols = production.right_part.iter().map(|(symbol, _)| match symbol {
+                Symbol::Terminal(terminal) => {
+                    let index = Literal::usize_unsuffixed(terminals.iter().position(|t| t == terminal).unwrap());
+                    quote! {::syntax::earley::Symbol::Terminal(#index)}
+                }
+                Symbol::NonTerminal(nonterminal) => {
+                    let index = nonterminal_index(nonterminal);
+                    quote! {::syntax::earley::Symbol::NonTerminal(#index)}
+                }
+            });
+            quote! {(#left_part,&[#(#symbols),*])}
+        });
+        let nullable = nonterminals.iter().map(|nonterminal| grammar.nullable.contains(nonterminal));
+        let start_index = nonterminal_index(start);
+        let terminal_displays = terminals.iter().map(|terminal| &terminal.display);
+        let terminal_indexes = terminals.iter().enumerate().map(|(index, terminal)| {
+            let patten = terminal_patten(token_type, terminal);
+            quote! {#patten=>#index,}
+        });
+        let stack_map = grammar.value_stacks();
+        let stacks_init = init_stacks(&stack_map);
+        let shift_values = shift_values(token_type, &terminals, &stack_map);
+        let reductions = grammar.productions.iter().enumerate().map(|(production_index, production)| {
+            let (span, reduce) = reduce_values(production, &stack_map);
+            let stack_ident = stack_ident(&production.left_part.ident);
+            quote_spanned! {span=>
+              #production_index=>{
+                #reduce
+                match result{
+                  Ok(r)=>#stack_ident.push(r),
+                  Err(e)=>return Err(e),
+                }
+              }
+            }
+        });
+        let start_stack_ident = stack_ident(&start.ident);
+        let root_output_unwraped = start.output.as_ref().map(|output| quote! {#output}).unwrap_or(quote! {()});
+        let body = quote! {
+            const GRAMMAR: ::syntax::earley::Grammar=::syntax::earley::Grammar{
+              start:#start_index,
+              productions:&[#(#productions),*],
+              nullable:&[#(#nullable),*],
+              terminals:&[#(#terminal_displays),*],
+            };
+            #[allow(unreachable_patterns)]
+            fn terminal_index(token:&#token_type)->usize{
+              match token{
+                #(#terminal_indexes)*
+                _=>usize::MAX,
+              }
+            }
+            #(#stacks_init)*
+            let (tokens,spans):(::syntax::_Vec<#token_type>,::syntax::_Vec<Option<::syntax::Span>>)=
+              tokens.collect::<::syntax::_Fallible<::syntax::_Vec<_>>>()?.into_iter().unzip();
+            let span_at=|position:usize|spans.get(position).or_else(||spans.last()).cloned().flatten();
+            let terminal_indexes: ::syntax::_Vec<_>=tokens.iter().map(terminal_index).collect();
+            let events=match GRAMMAR.parse(&terminal_indexes){
+              Ok(events)=>events,
+              Err((position,expected))=>{
+                return Err(::syntax::SyntaxError::new(tokens.get(position).map(|t|format!("{:?}",t)),span_at(position),&expected).into());
+              }
+            };
+            let mut iter=tokens.into_iter();
+            for event in events{
+              match event{
+                ::syntax::earley::Event::Shift(_)=>match iter.next(){
+                  #(#shift_values)*
+                  _=>{}
+                },
+                ::syntax::earley::Event::Reduce(production)=>match production{
+                  #(#reductions)*
+                  _=>::syntax::_unreachable!(),
+                },
+              }
+            }
+            #start_stack_ident.pop().ok_or_else(||::syntax::_format_err!("wrone state"))
+        };
+        Ok(generate_entry(&name, token_type, &root_output_unwraped, *stream, body))
+    }
+}
+pub(crate) fn do_generate_parser(syntax_declaration: SyntaxDeclaration) -> Result<TokenStream2> {
+    if let Some(span) = syntax_declaration.lr_only_span() {
+        return Err(Error::new(span, "error recovery and precedence are only supported by LR parsers"));
+    }
+    let name = syntax_declaration.ident.clone();
+    let syntax = parse_syntax_declaration(syntax_declaration)?;
+    let syntax_earley = SyntaxEarley { grammar: Grammar::new(&syntax) };
+    syntax_earley.generate(name)
+}
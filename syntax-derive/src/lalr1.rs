@@ -0,0 +1,682 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    iter::FromIterator,
+    rc::Rc,
+};
+
+use proc_macro2::{Ident, Literal, Span, TokenStream as TokenStream2};
+
+use syn::{spanned::Spanned, Error, Result, Type};
+extern crate proc_macro2;
+
+use super::{
+    lr1::{
+        display_item, format_conflict, generate_entry, generate_recovery, resolve_shift_reduce, stack_ident, terminal_patten, wrap_recovery, ConflictAction,
+        Resolution,
+    },
+    parse::*,
+    production::*,
+};
+/// A production with a dot, both by index so that states are numbered the same way on every expansion.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+struct Item {
+    production: usize,
+    position: usize,
+}
+impl Item {
+    fn add_position(self) -> Self {
+        Self { position: self.position + 1, ..self }
+    }
+}
+/// Lookahead of an item while computing the LALR(1) lookaheads, `Propagate` stands for the lookaheads of the kernel item being closed.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+enum Lookahead {
+    Terminal(Option<Rc<Terminal>>),
+    Propagate,
+}
+type ItemCluster = BTreeMap<Item, BTreeSet<Lookahead>>;
+#[derive(Clone, Copy, Hash, PartialEq, Eq)]
+enum Action {
+    Shift(usize),
+    Reduce(usize),
+}
+struct State {
+    kernel: Vec<Item>,
+    transitions: BTreeMap<(bool, String), (Symbol, usize)>,
+    /// The state and symbol this state was first reached from, the automaton is built breadth first so following them gives a shortest prefix.
+    parent: Option<(usize, Symbol)>,
+}
+fn symbol_key(symbol: &Symbol) -> (bool, String) {
+    match symbol {
+        Symbol::Terminal(terminal) => (false, terminal.name.clone()),
+        Symbol::NonTerminal(nonterminal) => (true, nonterminal.name.clone()),
+    }
+}
+pub(crate) type StackMap = HashMap<Symbol, Option<Type>, ahash::RandomState>;
+pub(crate) fn init_stacks(stack_map: &StackMap) -> Vec<TokenStream2> {
+    let mut stacks_init = Vec::new();
+    for (symbol, output) in stack_map {
+        let stack_ident = stack_ident(symbol.get_ident());
+        let span = symbol.get_ident().span();
+        let constructor = output.as_ref().map_or_else(|| quote! {std::vec::Vec::new()}, |o| quote! {std::vec::Vec::< #o >::new()});
+        stacks_init.push(quote_spanned! {span=>
+          #[allow(non_snake_case)]
+          let mut #stack_ident = #constructor;
+        });
+    }
+    stacks_init
+}
+/// Match arms on the next token pushing its value, for the terminals that have a stack.
+pub(crate) fn shift_values(token_type: &Type, terminals: &[Rc<Terminal>], stack_map: &StackMap) -> Vec<TokenStream2> {
+    terminals
+        .iter()
+        .filter(|terminal| stack_map.contains_key(&Symbol::Terminal((*terminal).clone())))
+        .map(|terminal| {
+            let ident = &terminal.ident;
+            let stack_ident = stack_ident(ident);
+            match terminal.variant_kind {
+                VariantKind::None => quote! {Some(#token_type::#ident)=>#stack_ident.push(()),},
+                VariantKind::Truple => quote! {Some(#token_type::#ident(value))=>#stack_ident.push(value),},
+            }
+        })
+        .collect()
+}
+/// Pops the values of the right part and binds the callback's result to `result`, spanned on the callback.
+pub(crate) fn reduce_values(production: &Production, stack_map: &StackMap) -> (Span, TokenStream2) {
+    let left_part = &production.left_part;
+    let span = production.callback.as_ref().map(|e| e.span()).unwrap_or_else(|| left_part.ident.span());
+    let mut pop_values = Vec::new();
+    for (symbol, value_ident) in production.right_part.iter().rev() {
+        if stack_map.contains_key(symbol) {
+            let value_ident = value_ident.clone().unwrap_or_else(|| quote! {_});
+            let stack_ident = stack_ident(symbol.get_ident());
+            pop_values.push(quote_spanned! {span=>
+              let #value_ident=#stack_ident.pop().unwrap();
+            });
+        }
+    }
+    let callback = match (&production.callback, &left_part.output) {
+        (Some(callback), _) => quote_spanned! {span=>#callback},
+        (None, Some(_)) => quote_spanned! {span=>Ok(::syntax::_Default::default())},
+        (None, None) => quote_spanned! {span=>Ok(())},
+    };
+    (
+        span,
+        quote_spanned! {span=>
+          #(#pop_values)*
+          let result=#callback;
+        },
+    )
+}
+pub(crate) struct Grammar<'t> {
+    pub(crate) syntax: &'t Syntax,
+    /// Productions ordered by the name of their left part, then by declaration.
+    pub(crate) productions: Vec<Rc<Production>>,
+    production_indexes: HashMap<Rc<NonTerminal>, Vec<usize>, ahash::RandomState>,
+    first: HashMap<Rc<NonTerminal>, BTreeSet<Rc<Terminal>>, ahash::RandomState>,
+    pub(crate) nullable: HashSet<Rc<NonTerminal>, ahash::RandomState>,
+}
+impl<'t> Grammar<'t> {
+    pub(crate) fn new(syntax: &'t Syntax) -> Self {
+        let mut nonterminals: Vec<_> = syntax.productions.keys().cloned().collect();
+        nonterminals.sort();
+        let mut productions = Vec::new();
+        let mut production_indexes = HashMap::with_hasher(ahash::RandomState::with_seed(0));
+        for nonterminal in nonterminals {
+            let indexes: &mut Vec<usize> = production_indexes.entry(nonterminal.clone()).or_default();
+            for production in &syntax.productions[&nonterminal] {
+                indexes.push(productions.len());
+                productions.push(production.clone());
+            }
+        }
+        let mut this = Self {
+            syntax,
+            productions,
+            production_indexes,
+            first: HashMap::with_hasher(ahash::RandomState::with_seed(0)),
+            nullable: HashSet::with_hasher(ahash::RandomState::with_seed(0)),
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for production in &this.productions {
+                let (first, nullable) = this.first_of(production.right_part.iter().map(|(symbol, _)| symbol));
+                let left_part_first = this.first.entry(production.left_part.clone()).or_default();
+                let count = left_part_first.len();
+                left_part_first.extend(first);
+                changed |= count != left_part_first.len();
+                if nullable {
+                    changed |= this.nullable.insert(production.left_part.clone());
+                }
+            }
+        }
+        this
+    }
+
+    /// Terminals used by the grammar, sorted by how they are displayed so that expected terminals come out sorted.
+    pub(crate) fn terminals(&self) -> Vec<Rc<Terminal>> {
+        let mut terminals: Vec<_> = self
+            .productions
+            .iter()
+            .flat_map(|production| production.right_part.iter())
+            .filter_map(|(symbol, _)| match symbol {
+                Symbol::Terminal(terminal) => Some(terminal.clone()),
+                Symbol::NonTerminal(_) => None,
+            })
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        terminals.sort_by(|a, b| (&a.display, &a.name).cmp(&(&b.display, &b.name)));
+        terminals
+    }
+
+    /// Every nonterminal and every terminal bound to a value gets a stack of values, in step with the parsed symbols.
+    pub(crate) fn value_stacks(&self) -> StackMap {
+        let mut stack_map = HashMap::with_hasher(ahash::RandomState::with_seed(0));
+        for nonterminal in self.syntax.productions.keys() {
+            stack_map.insert(Symbol::NonTerminal(nonterminal.clone()), nonterminal.output.clone());
+        }
+        for production in &self.productions {
+            for (symbol, value_ident) in &production.right_part {
+                if value_ident.is_some() {
+                    stack_map.entry(symbol.clone()).or_insert(None);
+                }
+            }
+        }
+        stack_map
+    }
+
+    /// FIRST set of a symbol sequence and whether the whole sequence may derive the empty string.
+    fn first_of<'s>(&self, symbols: impl Iterator<Item = &'s Symbol>) -> (BTreeSet<Rc<Terminal>>, bool) {
+        let mut first = BTreeSet::new();
+        for symbol in symbols {
+            match symbol {
+                Symbol::Terminal(terminal) => {
+                    first.insert(terminal.clone());
+                    return (first, false);
+                }
+                Symbol::NonTerminal(nonterminal) => {
+                    first.extend(self.first.get(nonterminal).into_iter().flatten().cloned());
+                    if !self.nullable.contains(nonterminal) {
+                        return (first, false);
+                    }
+                }
+            }
+        }
+        (first, true)
+    }
+
+    fn next_symbol(&self, item: Item) -> Option<&Symbol> {
+        self.productions[item.production].right_part.get(item.position).map(|(symbol, _)| symbol)
+    }
+
+    fn productions_of(&self, nonterminal: &NonTerminal) -> impl Iterator<Item = usize> + '_ {
+        self.production_indexes.get(nonterminal).into_iter().flatten().copied()
+    }
+
+    fn closure0(&self, kernel: &[Item]) -> Vec<Item> {
+        let mut items = kernel.to_vec();
+        let mut expanded = HashSet::<&NonTerminal, ahash::RandomState>::with_hasher(ahash::RandomState::with_seed(0));
+        let mut index = 0;
+        while index < items.len() {
+            if let Some(Symbol::NonTerminal(nonterminal)) = self.next_symbol(items[index]) {
+                if expanded.insert(nonterminal) {
+                    items.extend(self.productions_of(nonterminal).map(|production| Item { production, position: 0 }));
+                }
+            }
+            index += 1;
+        }
+        items
+    }
+
+    fn closure1(&self, mut items: ItemCluster) -> ItemCluster {
+        let mut tasks: Vec<_> = items.keys().copied().collect();
+        while let Some(item) = tasks.pop() {
+            let production = &self.productions[item.production];
+            if let Some((Symbol::NonTerminal(nonterminal), _)) = production.right_part.get(item.position) {
+                let (first, nullable) = self.first_of(production.right_part[item.position + 1..].iter().map(|(symbol, _)| symbol));
+                let mut lookaheads: BTreeSet<_> = first.into_iter().map(|terminal| Lookahead::Terminal(Some(terminal))).collect();
+                if nullable {
+                    lookaheads.extend(items[&item].iter().cloned());
+                }
+                for production in self.productions_of(nonterminal) {
+                    let new_item = Item { production, position: 0 };
+                    let is_new = !items.contains_key(&new_item);
+                    let new_item_lookaheads = items.entry(new_item).or_default();
+                    let count = new_item_lookaheads.len();
+                    new_item_lookaheads.extend(lookaheads.iter().cloned());
+                    if is_new || count != new_item_lookaheads.len() {
+                        tasks.push(new_item);
+                    }
+                }
+            }
+        }
+        items
+    }
+
+    fn build_lr0_states(&self) -> Vec<State> {
+        let start_kernel: Vec<_> = self.productions_of(&self.syntax.start).map(|production| Item { production, position: 0 }).collect();
+        let mut state_map = HashMap::<Vec<Item>, usize, ahash::RandomState>::with_hasher(ahash::RandomState::with_seed(0));
+        state_map.insert(start_kernel.clone(), 0);
+        let mut states = vec![State { kernel: start_kernel, transitions: BTreeMap::new(), parent: None }];
+        let mut id = 0;
+        while id < states.len() {
+            let mut kernels = BTreeMap::<(bool, String), (Symbol, Vec<Item>)>::new();
+            for item in self.closure0(&states[id].kernel) {
+                if let Some(symbol) = self.next_symbol(item) {
+                    kernels.entry(symbol_key(symbol)).or_insert_with(|| (symbol.clone(), Vec::new())).1.push(item.add_position());
+                }
+            }
+            for (key, (symbol, mut kernel)) in kernels {
+                kernel.sort();
+                kernel.dedup();
+                let next = match state_map.get(&kernel) {
+                    Some(next) => *next,
+                    None => {
+                        let next = states.len();
+                        state_map.insert(kernel.clone(), next);
+                        states.push(State { kernel, transitions: BTreeMap::new(), parent: Some((id, symbol.clone())) });
+                        next
+                    }
+                };
+                states[id].transitions.insert(key, (symbol, next));
+            }
+            id += 1;
+        }
+        states
+    }
+
+    /// Computes the lookaheads of every kernel item by spontaneous generation and propagation.
+    fn build_lookaheads(&self, states: &[State]) -> Vec<BTreeMap<Item, BTreeSet<Option<Rc<Terminal>>>>> {
+        let mut lookaheads: Vec<BTreeMap<_, BTreeSet<_>>> =
+            states.iter().map(|state| state.kernel.iter().map(|item| (*item, BTreeSet::new())).collect()).collect();
+        for item_lookaheads in lookaheads[0].values_mut() {
+            item_lookaheads.insert(None);
+        }
+        let mut propagation = HashMap::<(usize, Item), Vec<(usize, Item)>, ahash::RandomState>::with_hasher(ahash::RandomState::with_seed(0));
+        for (id, state) in states.iter().enumerate() {
+            for kernel_item in &state.kernel {
+                let items = self.closure1(BTreeMap::from_iter([(*kernel_item, BTreeSet::from_iter([Lookahead::Propagate]))]));
+                for (item, item_lookaheads) in items {
+                    if let Some(symbol) = self.next_symbol(item) {
+                        let target = (state.transitions[&symbol_key(symbol)].1, item.add_position());
+                        for lookahead in item_lookaheads {
+                            match lookahead {
+                                Lookahead::Terminal(terminal) => {
+                                    lookaheads[target.0].get_mut(&target.1).unwrap().insert(terminal);
+                                }
+                                Lookahead::Propagate => propagation.entry((id, *kernel_item)).or_default().push(target),
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let mut tasks: Vec<_> = states.iter().enumerate().flat_map(|(id, state)| state.kernel.iter().map(move |item| (id, *item))).collect();
+        while let Some(source) = tasks.pop() {
+            let source_lookaheads = lookaheads[source.0][&source.1].clone();
+            for target in propagation.get(&source).into_iter().flatten() {
+                let target_lookaheads = lookaheads[target.0].get_mut(&target.1).unwrap();
+                let count = target_lookaheads.len();
+                target_lookaheads.extend(source_lookaheads.iter().cloned());
+                if count != target_lookaheads.len() {
+                    tasks.push(*target);
+                }
+            }
+        }
+        lookaheads
+    }
+}
+/// The LALR(1) automaton, with terminals and nonterminals numbered for the compressed tables.
+struct SyntaxLALR1<'t> {
+    grammar: Grammar<'t>,
+    states: Vec<State>,
+    /// Action of every state by terminal index, index 0 is the end of input.
+    actions: Vec<BTreeMap<usize, Action>>,
+    terminals: Vec<Rc<Terminal>>,
+}
+impl<'t> SyntaxLALR1<'t> {
+    fn new(syntax: &'t Syntax, span: Span) -> Result<Self> {
+        let grammar = Grammar::new(syntax);
+        let states = grammar.build_lr0_states();
+        let lookaheads = grammar.build_lookaheads(&states);
+        let terminals = grammar.terminals();
+        let terminal_index =
+            |terminal: &Option<Rc<Terminal>>| terminal.as_ref().map_or(0, |terminal| terminals.iter().position(|t| t == terminal).unwrap() + 1);
+        let mut actions = Vec::new();
+        for (id, kernel_lookaheads) in lookaheads.into_iter().enumerate() {
+            let kernel =
+                kernel_lookaheads.into_iter().map(|(item, item_lookaheads)| (item, item_lookaheads.into_iter().map(Lookahead::Terminal).collect())).collect();
+            let items = grammar.closure1(kernel);
+            let mut state_actions = BTreeMap::new();
+            let mut nonassoc = BTreeSet::new();
+            for (item, item_lookaheads) in &items {
+                let new_actions: Vec<_> = match grammar.next_symbol(*item) {
+                    Some(Symbol::Terminal(terminal)) => {
+                        vec![(Some(terminal.clone()), Action::Shift(states[id].transitions[&symbol_key(&Symbol::Terminal(terminal.clone()))].1))]
+                    }
+                    Some(Symbol::NonTerminal(_)) => vec![],
+                    None => item_lookaheads
+                        .iter()
+                        .filter_map(|lookahead| match lookahead {
+                            Lookahead::Terminal(terminal) => Some((terminal.clone(), Action::Reduce(item.production))),
+                            Lookahead::Propagate => None,
+                        })
+                        .collect(),
+                };
+                for (terminal, action) in new_actions {
+                    let index = terminal_index(&terminal);
+                    if nonassoc.contains(&index) {
+                        continue;
+                    }
+                    match state_actions.insert(index, action) {
+                        Some(conflict_action) if conflict_action != action => {
+                            let resolution = match (action, conflict_action, &terminal) {
+                                (Action::Shift(_), Action::Reduce(production), Some(terminal))
+                                | (Action::Reduce(production), Action::Shift(_), Some(terminal)) => {
+                                    resolve_shift_reduce(terminal, &grammar.productions[production])
+                                }
+                                _ => None,
+                            };
+                            match resolution {
+                                Some(Resolution::Shift) | Some(Resolution::Reduce) => {
+                                    let keep_shift = resolution == Some(Resolution::Shift);
+                                    let kept = if matches!(action, Action::Shift(_)) == keep_shift { action } else { conflict_action };
+                                    state_actions.insert(index, kept);
+                                    continue;
+                                }
+                                Some(Resolution::Error) => {
+                                    state_actions.remove(&index);
+                                    nonassoc.insert(index);
+                                    continue;
+                                }
+                                None => {}
+                            }
+                            let describe_action = |action: Action| match action {
+                                Action::Shift(_) => ConflictAction::Shift(
+                                    items
+                                        .keys()
+                                        .filter(|item| matches!(grammar.next_symbol(**item), Some(Symbol::Terminal(t)) if Some(t) == terminal.as_ref()))
+                                        .map(|item| display_item(&grammar.productions[item.production], item.position))
+                                        .collect(),
+                                ),
+                                Action::Reduce(production) => {
+                                    let production = &grammar.productions[production];
+                                    ConflictAction::Reduce(display_item(production, production.right_part.len()))
+                                }
+                            };
+                            let mut prefix = Vec::new();
+                            let mut parent = &states[id].parent;
+                            while let Some((parent_id, symbol)) = parent {
+                                prefix.insert(0, symbol.clone());
+                                parent = &states[*parent_id].parent;
+                            }
+                            return Err(Error::new(
+                                terminal.as_ref().map(|t| t.ident.span()).unwrap_or(span),
+                                format_conflict(&terminal, describe_action(action), describe_action(conflict_action), &prefix),
+                            ));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            actions.push(state_actions);
+        }
+        Ok(Self { grammar, states, actions, terminals })
+    }
+
+    fn generate(&self, name: Ident) -> Result<TokenStream2> {
+        let Self { grammar, states, actions, terminals } = self;
+        let Syntax { start, token_type, recover, stream, .. } = grammar.syntax;
+        let stack_map = grammar.value_stacks();
+        let stacks_init = init_stacks(&stack_map);
+        // Identical action rows are emitted once, every row is sorted by terminal index for a binary search.
+        let mut rows = Vec::new();
+        let mut row_map = HashMap::<&BTreeMap<usize, Action>, usize, ahash::RandomState>::with_hasher(ahash::RandomState::with_seed(0));
+        let row_of_state: Vec<_> = actions
+            .iter()
+            .map(|state_actions| {
+                Literal::usize_unsuffixed(*row_map.entry(state_actions).or_insert_with(|| {
+                    rows.push(state_actions);
+                    rows.len() - 1
+                }))
+            })
+            .collect();
+        let action_rows = rows.iter().map(|row| {
+            let entries = row.iter().map(|(terminal, action)| {
+                let terminal = Literal::usize_unsuffixed(*terminal);
+                match action {
+                    Action::Shift(next) => {
+                        let next = Literal::usize_unsuffixed(*next);
+                        quote! {(#terminal,::syntax::_Action::Shift(#next))}
+                    }
+                    Action::Reduce(production) => {
+                        let production = Literal::usize_unsuffixed(*production);
+                        quote! {(#terminal,::syntax::_Action::Reduce(#production))}
+                    }
+                }
+            });
+            quote! {&[#(#entries),*]}
+        });
+        // Goto is stored by nonterminal as the most frequent target plus the states going elsewhere.
+        let mut gotos = BTreeMap::<String, (Rc<NonTerminal>, BTreeMap<usize, usize>)>::new();
+        for (id, state) in states.iter().enumerate() {
+            for (symbol, next) in state.transitions.values() {
+                if let Symbol::NonTerminal(nonterminal) = symbol {
+                    gotos.entry(nonterminal.name.clone()).or_insert_with(|| (nonterminal.clone(), BTreeMap::new())).1.insert(id, *next);
+                }
+            }
+        }
+        let nonterminal_index = |nonterminal: &NonTerminal| gotos.keys().position(|name| name == &nonterminal.name);
+        let goto_rows = gotos.values().map(|(_, targets)| {
+            let mut counts = BTreeMap::<usize, usize>::new();
+            for next in targets.values() {
+                *counts.entry(*next).or_default() += 1;
+            }
+            let default = counts.iter().max_by_key(|(next, count)| (**count, std::cmp::Reverse(**next))).map(|(next, _)| *next).unwrap();
+            let entries = targets.iter().filter(|(_, next)| **next != default).map(|(state, next)| {
+                let (state, next) = (Literal::usize_unsuffixed(*state), Literal::usize_unsuffixed(*next));
+                quote! {(#state,#next)}
+            });
+            quote! {(&[#(#entries),*],#default)}
+        });
+        let terminal_displays = terminals.iter().map(|terminal| &terminal.display);
+        let terminal_indexes = terminals.iter().enumerate().map(|(index, terminal)| {
+            let patten = terminal_patten(token_type, terminal);
+            let index = index + 1;
+            quote! {Some(#patten)=>#index,}
+        });
+        let terminal_count = terminals.len() + 1;
+        let shift_values = shift_values(token_type, terminals, &stack_map);
+        let reduced: BTreeSet<_> = actions
+            .iter()
+            .flat_map(|state_actions| state_actions.values())
+            .filter_map(|action| match action {
+                Action::Reduce(production) => Some(*production),
+                Action::Shift(_) => None,
+            })
+            .collect();
+        let mut reductions = Vec::new();
+        for production_index in reduced {
+            let production = &grammar.productions[production_index];
+            let left_part = &production.left_part;
+            let (span, reduce) = reduce_values(production, &stack_map);
+            let pop_count = production.right_part.len();
+            // a left recursive start symbol is also reduced at the bottom of the stack before more input, which goes on
+            let at_end = quote! {state_stack.len()==#pop_count+1&&iter.peek()?.is_none()};
+            let accept = if left_part == start {
+                quote! {
+                  if #at_end{
+                    return result;
+                  }
+                }
+            } else {
+                quote! {}
+            };
+            let goto = match nonterminal_index(left_part) {
+                Some(nonterminal_index) => quote_spanned! {span=>
+                  state_stack.truncate(state_stack.len()-#pop_count);
+                  let state=*state_stack.last().ok_or_else(||::syntax::_format_err!("wrone state"))?;
+                  state_stack.push(goto(#nonterminal_index,state));
+                },
+                None => quote! {
+                  return Err(::syntax::_format_err!("state stack is not empty,pop_count:{},state stack:{:?}",#pop_count,&state_stack));
+                },
+            };
+            let push_value = match nonterminal_index(left_part) {
+                Some(_) => {
+                    let stack_ident = stack_ident(&left_part.ident);
+                    quote_spanned! {span=>
+                      match result{
+                        Ok(r)=>#stack_ident.push(r),
+                        Err(e)=>return Err(e),
+                      }
+                    }
+                }
+                None => quote! {},
+            };
+            // once a syntax error is recovered from, the parse only looks for more of them without running the callbacks,
+            // whose state does not match the stacks left by the recovery
+            let recognize = if recover.is_empty() {
+                quote! {}
+            } else {
+                let accept = if left_part == start {
+                    quote! {
+                      if #at_end{
+                        return Err(::syntax::_format_err!("syntax error"));
+                      }
+                    }
+                } else {
+                    quote! {}
+                };
+                quote! {
+                  if !syntax_errors.is_empty(){
+                    #accept
+                    #goto
+                    continue;
+                  }
+                }
+            };
+            reductions.push(quote_spanned! {span=>
+              #production_index=>{
+                #recognize
+                #reduce
+                #accept
+                #push_value
+                #goto
+              }
+            });
+        }
+        let on_error = if recover.is_empty() {
+            quote! {
+              return Err(error.into());
+            }
+        } else {
+            let sync_pattens = recover.iter().map(|terminal| terminal_patten(token_type, terminal)).collect();
+            let mut pop_value_map = BTreeMap::<String, (Ident, Vec<usize>)>::new();
+            for (id, state) in states.iter().enumerate() {
+                if let Some((_, symbol)) = state.parent.as_ref().filter(|(_, symbol)| stack_map.contains_key(symbol)) {
+                    let stack_ident = stack_ident(symbol.get_ident());
+                    pop_value_map.entry(stack_ident.to_string()).or_insert_with(|| (stack_ident, Vec::new())).1.push(id);
+                }
+            }
+            let pop_values = pop_value_map.into_values().map(|(stack_ident, ids)| quote! {#(#ids)|* => {#stack_ident.pop();},}).collect();
+            let recover_state = quote! {
+              |state:usize|action(state,terminal_index(Some(token))).is_some()
+            };
+            generate_recovery(sync_pattens, recover_state, pop_values)
+        };
+        let root_output_unwraped = start.output.as_ref().map(|output| quote! {#output}).unwrap_or(quote! {()});
+        let parse = quote! {
+          const ACTION_ROWS:&[&[(usize,::syntax::_Action)]]=&[#(#action_rows),*];
+          const ACTION_ROW_OF_STATE:&[usize]=&[#(#row_of_state),*];
+          const GOTO:&[(&[(usize,usize)],usize)]=&[#(#goto_rows),*];
+          const TERMINALS:&[Option<&str>]=&[None,#(Some(#terminal_displays)),*];
+          #[allow(unreachable_patterns)]
+          fn terminal_index(token:Option<&#token_type>)->usize{
+            match token{
+              None=>0,
+              #(#terminal_indexes)*
+              _=>#terminal_count,
+            }
+          }
+          fn action(state:usize,terminal:usize)->Option<::syntax::_Action>{
+            let row=ACTION_ROWS[ACTION_ROW_OF_STATE[state]];
+            row.binary_search_by_key(&terminal,|(terminal,_)|*terminal).ok().map(|index|row[index].1)
+          }
+          fn goto(nonterminal:usize,state:usize)->usize{
+            let (row,default)=GOTO[nonterminal];
+            row.binary_search_by_key(&state,|(state,_)|*state).map(|index|row[index].1).unwrap_or(default)
+          }
+          #(#stacks_init)*
+          let mut iter=::syntax::_TokenIter::new(tokens);
+          let mut state_stack=vec![0];
+          loop{
+            let state=*state_stack.last().ok_or_else(||::syntax::_format_err!("wrone state"))?;
+            let position=iter.position();
+            let error=match action(state,terminal_index(iter.peek()?)){
+              Some(::syntax::_Action::Shift(next))=>{
+                state_stack.push(next);
+                match iter.next(){
+                  #(#shift_values)*
+                  _=>{}
+                }
+                continue;
+              }
+              Some(::syntax::_Action::Reduce(production))=>{
+                match production{
+                  #(#reductions)*
+                  _=>::syntax::_unreachable!(),
+                }
+                continue;
+              }
+              None=>{
+                let expected: ::syntax::_Vec<_>=ACTION_ROWS[ACTION_ROW_OF_STATE[state]].iter().map(|(terminal,_)|TERMINALS[*terminal]).collect();
+                ::syntax::SyntaxError::new(iter.peek()?.map(|t|format!("{:?}",t)),iter.span()?,&expected)
+              }
+            };
+            #on_error
+          }
+          ::syntax::_unreachable!();
+        };
+        let body = if recover.is_empty() { parse } else { wrap_recovery(parse, &root_output_unwraped) };
+        Ok(generate_entry(&name, token_type, &root_output_unwraped, *stream, body))
+    }
+}
+pub(crate) fn do_generate_parser(syntax_declaration: SyntaxDeclaration) -> Result<TokenStream2> {
+    let name = syntax_declaration.ident.clone();
+    let span = name.span();
+    let syntax = parse_syntax_declaration(syntax_declaration)?;
+    let syntax_lalr1 = SyntaxLALR1::new(&syntax, span)?;
+    syntax_lalr1.generate(name)
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn conflict_of(declaration: &str) -> String {
+        let syntax = parse_syntax_declaration(syn::parse_str(declaration).unwrap()).unwrap();
+        match SyntaxLALR1::new(&syntax, Span::call_site()) {
+            Ok(_) => panic!("no conflict in {}", declaration),
+            Err(error) => error.to_string(),
+        }
+    }
+    #[test]
+    fn describe_shift_reduce_conflict() {
+        let message =
+            conflict_of("parser:Token->f64{ syn=>f64->{ [expr(v)]=>Ok(v); }, expr=>f64->{ [Float(v)]=>Ok(v); | [expr(v1),Add,expr(v2)]=>Ok(v1+v2); }, }");
+        assert_eq!(
+            message,
+            "shift/reduce conflict on lookahead `Add`\n  shift:  expr -> expr . Add expr\n  reduce: expr -> expr Add expr .\nexample: `expr Add expr` followed by `Add`"
+        );
+    }
+    #[test]
+    fn describe_reduce_reduce_conflict() {
+        // an LR(1) grammar: the states after `Sub Float` and `Mul Float` only conflict once merged with their lookaheads
+        let message = conflict_of("parser:Token->(){ syn->{ [Sub,a,Add] | [Mul,b,Add] | [Sub,b,Div] | [Mul,a,Div] }, a->{ [Float] }, b->{ [Float] }, }");
+        assert_eq!(
+            message,
+            "reduce/reduce conflict on lookahead `Add`\n  reduce: a -> Float .\n  reduce: b -> Float .\nexample: `Mul Float` followed by `Add`"
+        );
+    }
+}
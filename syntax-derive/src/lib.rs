@@ -1,3 +1,5 @@
+pub(crate) mod earley;
+pub(crate) mod lalr1;
 pub(crate) mod ll1;
 pub(crate) mod lr1;
 pub(crate) mod parse;
@@ -22,7 +24,7 @@ pub fn lr1_analyser(input: TokenStream) -> TokenStream {
     let input_clone = input.clone();
     let syntax_declaration = parse_macro_input!(input_clone as SyntaxDeclaration);
     util::cache_proc_macro(util::cache_meta!(), input.into(), |_| {
-        lr1::do_generate_parser(syntax_declaration, false).unwrap_or_else(|err| err.to_compile_error())
+        lr1::do_generate_parser(syntax_declaration).unwrap_or_else(|err| err.to_compile_error())
     })
     .into()
 }
@@ -31,7 +33,13 @@ pub fn lalr1_analyser(input: TokenStream) -> TokenStream {
     let input_clone = input.clone();
     let syntax_declaration = parse_macro_input!(input_clone as SyntaxDeclaration);
     util::cache_proc_macro(util::cache_meta!(), input.into(), |_| {
-        lr1::do_generate_parser(syntax_declaration, true).unwrap_or_else(|err| err.to_compile_error())
+        lalr1::do_generate_parser(syntax_declaration).unwrap_or_else(|err| err.to_compile_error())
     })
     .into()
 }
+#[proc_macro]
+pub fn earley_analyser(input: TokenStream) -> TokenStream {
+    let input_clone = input.clone();
+    let syntax_declaration = parse_macro_input!(input_clone as SyntaxDeclaration);
+    util::cache_proc_macro(util::cache_meta!(), input.into(), |_| earley::do_generate_parser(syntax_declaration).unwrap_or_else(|err| err.to_compile_error())).into()
+}
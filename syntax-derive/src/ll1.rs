@@ -126,6 +126,9 @@ fn link_ll1(syntax: SyntaxDeclaration) -> Result<SyntaxLL1> {
 }
 pub(crate) fn do_generate_recursive_predictive_parser(syntax_declaration: SyntaxDeclaration) -> Result<TokenStream2> {
     let span = syntax_declaration.brace.span;
+    if let Some(span) = syntax_declaration.lr_only_span() {
+        return Err(Error::new(span, "error recovery and precedence are only supported by LR parsers"));
+    }
     let name = syntax_declaration.ident.clone();
     let syntax = link_ll1(syntax_declaration)?;
     let parser = syntax.generate(name).map_err(|e| Error::new(span, e))?;
@@ -84,6 +84,8 @@ struct Node {
     source_items: ItemCluster,
     items: ItemCluster,
     action_map: HashMap<Option<Rc<Terminal>>, Action, ahash::RandomState>,
+    /// Lookaheads left without action by `#[nonassoc(..)]`.
+    nonassoc: HashSet<Option<Rc<Terminal>>, ahash::RandomState>,
     goto_map: HashMap<Rc<NonTerminal>, Rc<RefCell<Node>>, ahash::RandomState>,
     id: usize,
 }
@@ -118,7 +120,7 @@ struct SyntaxLR1 {
 impl SyntaxLR1 {
     pub fn generate(&self, name: Ident) -> Result<TokenStream2> {
         let Self { syntax, nodes } = self;
-        let Syntax { productions: _productions, start, token_type } = syntax;
+        let Syntax { productions: _productions, start, token_type, recover, stream } = syntax;
         let mut stack_map = HashMap::<Symbol, Option<Type>, ahash::RandomState>::with_hasher(ahash::RandomState::with_seed(0));
         for (nonterminal, _productions) in &syntax.productions {
             stack_map.insert(Symbol::NonTerminal(nonterminal.clone()), nonterminal.output.clone()).ok_or(()).expect_err(&format!("{}:{}", file!(), line!()));
@@ -216,7 +218,18 @@ impl SyntaxLR1 {
                         }
                     };
                     let goto_function_ident = Ident::new(&format!("goto_{}", production.left_part.ident), production.left_part.ident.span());
+                    let recognize = if recover.is_empty() {
+                        quote! {}
+                    } else {
+                        quote! {
+                          if !syntax_errors.is_empty(){
+                            #goto_function_ident(&mut state_stack,#pop_count)?;
+                            continue;
+                          }
+                        }
+                    };
                     quote_spanned! {span=>
+                      #recognize
                       #(#pop_values)*
                       #emit
                       #goto_function_ident(&mut state_stack,#pop_count)?;
@@ -238,6 +251,26 @@ impl SyntaxLR1 {
 
                     let pop_count = production.right_part.len();
                     let goto_function_ident = Ident::new(&format!("goto_{}", production.left_part.ident), production.left_part.ident.span());
+                    let goto = if goto_case_map.contains_key(&production.left_part.ident) {
+                        quote! {#goto_function_ident(&mut state_stack,#pop_count)?;}
+                    } else {
+                        quote! {
+                          return Err(::syntax::_format_err!("state stack is not empty,pop_count:{},state stack:{:?}",#pop_count,&state_stack));
+                        }
+                    };
+                    let recognize = if recover.is_empty() {
+                        quote! {}
+                    } else {
+                        quote! {
+                          if !syntax_errors.is_empty(){
+                            if state_stack.len()==#pop_count+1&&iter.peek()?.is_none(){
+                              return Err(::syntax::_format_err!("syntax error"));
+                            }
+                            #goto
+                            continue;
+                          }
+                        }
+                    };
                     let reduce = if goto_case_map.contains_key(&production.left_part.ident) {
                         let emit = if stack_map.contains_key(&Symbol::NonTerminal(production.left_part.clone())) {
                             let stack_ident =
@@ -258,21 +291,21 @@ impl SyntaxLR1 {
                         };
                         quote! {
                             #emit
-                            #goto_function_ident(&mut state_stack,#pop_count)?;
+                            #goto
                         }
                     } else {
-                        quote! {
-                          return Err(::syntax::_format_err!("state stack is not empty,pop_count:{},state stack:{:?}",#pop_count,&state_stack));
-                        }
+                        goto
                     };
                     quote! {
+                      #recognize
                       #(#pop_values)*
                       let result=#production_parser_callback;
                       if state_stack.len()!=#pop_count+1{
                         #reduce
                       }else{
-                        if iter.next().is_some(){
-                          return Err(::syntax::_format_err!("except EOF"));
+                        let span=iter.span()?;
+                        if let Some(token)=iter.next(){
+                          return Err(::syntax::SyntaxError::new(Some(format!("{:?}",token)),span,&[None]).into());
                         }
                         return result;
                       }
@@ -326,29 +359,53 @@ impl SyntaxLR1 {
                 quote! {
                   #patten=>{
                     #tokens
+                    continue;
                   },
                 }
             })
             .collect::<Vec<_>>();
         for (node_id, node_cell) in nodes.iter().enumerate() {
             let node = node_cell.try_borrow().unwrap();
-            let mut excepts = Vec::new();
-            for (terminal, _operation) in &node.action_map {
-                excepts.push(terminal.as_ref().map(|t| t.ident.to_string()).unwrap_or_else(|| "no thing".to_string()));
-            }
-            let except_string = excepts.join("/");
-            let node_string = format!("{:?}", &node);
+            let mut excepts: Vec<_> = node.action_map.keys().map(|terminal| terminal.as_ref().map(|t| t.display.clone())).collect();
+            excepts.sort();
+            let excepts = excepts.iter().map(|except| except.as_ref().map_or_else(|| quote! {None}, |except| quote! {Some(#except)}));
             state_transition.push(quote! {(#node_id,input)=>{
-                return Err(::syntax::_format_err!(
-                        "except {} ,got {:?},loop:{},states:{:?},node:{}",
-                        #except_string,
-                        input,
-                        loop_count-1,
-                        &*state_stack,
-                        #node_string,
-                    ))
+                ::syntax::SyntaxError::new(input.map(|t|format!("{:?}",t)),span,&[#(#excepts),*])
             }});
         }
+        let on_error = if recover.is_empty() {
+            quote! {
+              return Err(error.into());
+            }
+        } else {
+            let sync_pattens = recover.iter().map(|terminal| terminal_patten(token_type, terminal)).collect();
+            let mut recover_states = Vec::new();
+            let mut pop_value_map = BTreeMap::<String, (Ident, Vec<usize>)>::new();
+            for node_cell in nodes {
+                let node = node_cell.try_borrow().unwrap();
+                for terminal in recover {
+                    if node.action_map.contains_key(&Some(terminal.clone())) {
+                        let id = node.id;
+                        let patten = terminal_patten(token_type, terminal);
+                        recover_states.push(quote! {(#id,#patten)});
+                    }
+                }
+                let accessing_symbol =
+                    node.source_items.keys().next().and_then(|item| item.position.checked_sub(1).map(|p| item.production.right_part[p].0.clone()));
+                if let Some(symbol) = accessing_symbol.filter(|symbol| stack_map.contains_key(symbol)) {
+                    let stack_ident = stack_ident(symbol.get_ident());
+                    pop_value_map.entry(stack_ident.to_string()).or_insert_with(|| (stack_ident, Vec::new())).1.push(node.id);
+                }
+            }
+            let pop_values = pop_value_map.into_values().map(|(stack_ident, ids)| quote! {#(#ids)|* => {#stack_ident.pop();},}).collect();
+            let recover_state = quote! {
+              |state:usize|match (state,token){
+                #(#recover_states)|* => true,
+                _ => false,
+              }
+            };
+            generate_recovery(sync_pattens, recover_state, pop_values)
+        };
         let goto_function_list: Vec<_> = goto_case_map
             .iter()
             .map(|(nonterminal_ident, goto_cases)| {
@@ -387,39 +444,33 @@ impl SyntaxLR1 {
         }
         let root_output = &start.output;
         let root_output_unwraped: TokenStream2 = root_output.as_ref().map(|output| quote! {#output}).unwrap_or(quote! {()});
-        Ok(quote! {
-          #[allow(non_snake_case)]
-          #[allow(unused_mut)]
-          #[allow(unused_variables)]
-          #[allow(dead_code)]
-          #[allow(unreachable_code)]
-          let mut #name=|tokens:Vec<#token_type>|->::syntax::_Fallible<#root_output_unwraped>{
+        let parse = quote! {
             #(#goto_function_list)*
             #(#stacks_init)*
-            let mut iter=tokens.into_iter().peekable();
+            let mut iter=::syntax::_TokenIter::new(tokens);
             let mut state_stack=Vec::new();
             state_stack.push(0);
-            let mut loop_count=0;
             loop{
-              loop_count+=1;
               let state=state_stack.last().ok_or_else(||::syntax::_format_err!("wrone state"))?;
-              let input=iter.peek();
-              match (*state,input){
+              let position=iter.position();
+              let span=iter.span()?;
+              let input=iter.peek()?;
+              let error=match (*state,input){
                 #(#state_transition)*
-                (o,i)=>{
-                        if let Some(input)=i {
-                            return Err(::syntax::_format_err!("wrone syntax,loop:{},states:{:?},input:{:?}",loop_count-1,&*state_stack,input))
-                        }else{
-                            return Err(::syntax::_format_err!("wrone syntax,got none,loop:{},states:{:?}",loop_count-1,&*state_stack))
-                        }
-                    }
-                };
+                (_,input)=>{
+                    ::syntax::SyntaxError::new(input.map(|t|format!("{:?}",t)),span,&[])
+                }
+              };
+              #on_error
             }
             ::syntax::_unreachable!();
-          };
-        })
+        };
+        let body = if recover.is_empty() { parse } else { wrap_recovery(parse, &root_output_unwraped) };
+        Ok(generate_entry(&name, token_type, &root_output_unwraped, *stream, body))
     }
 }
+/// A lookahead with both of its actions, and the node where they met.
+type Conflict = (Option<Rc<Terminal>>, Action, Action, Rc<RefCell<Node>>);
 struct StateMachineBuilder<'t> {
     productions: &'t ProductionMap,
     start: &'t Rc<NonTerminal>,
@@ -427,8 +478,9 @@ struct StateMachineBuilder<'t> {
     nodes: Vec<Rc<RefCell<Node>>>,
     process_stack: Vec<Rc<RefCell<Node>>>,
     items_map: HashMap<ItemCluster, Rc<RefCell<Node>>, ahash::RandomState>,
+    /// The first unresolved conflict, reported once the automaton is complete so its example prefix is a shortest one.
+    conflict: Option<Conflict>,
     span: Span,
-    is_lalr: bool,
 }
 impl<'t> StateMachineBuilder<'t> {
     fn closure(&self, mut items: ItemCluster) -> ItemCluster {
@@ -490,131 +542,55 @@ impl<'t> StateMachineBuilder<'t> {
     }
 
     fn add_node(&mut self, items: ItemCluster, source_items: ItemCluster) -> Result<Rc<RefCell<Node>>> {
-        if !self.is_lalr {
-            let node = match self.items_map.entry(items) {
-                Entry::Vacant(v) => {
-                    let items = v.key().clone();
-                    let new_node = Node {
-                        items,
-                        source_items,
-                        action_map: HashMap::with_hasher(ahash::RandomState::with_seed(0)),
-                        goto_map: HashMap::with_hasher(ahash::RandomState::with_seed(0)),
-                        id: self.nodes.len(),
-                    };
-                    let new_node_wraped = Rc::new(RefCell::new(new_node));
-                    v.insert(new_node_wraped.clone());
-                    self.process_stack.push(new_node_wraped.clone());
-                    self.nodes.push(new_node_wraped.clone());
-                    new_node_wraped
-                }
-                Entry::Occupied(o) => o.get().clone(),
-            };
-            Ok(node)
-        } else {
-            let lr_items = items.keys().map(|lr0item| (lr0item.clone(), Default::default())).collect();
-            let (node, items) = match self.items_map.entry(lr_items) {
-                Entry::Vacant(v) => {
-                    let new_node = Node {
-                        items,
-                        source_items,
-                        action_map: HashMap::with_hasher(ahash::RandomState::with_seed(0)),
-                        goto_map: HashMap::with_hasher(ahash::RandomState::with_seed(0)),
-                        id: self.nodes.len(),
-                    };
-                    let new_node_wraped = Rc::new(RefCell::new(new_node));
-                    v.insert(new_node_wraped.clone());
-                    self.process_stack.push(new_node_wraped.clone());
-                    self.nodes.push(new_node_wraped.clone());
-                    (new_node_wraped, None)
-                }
-                Entry::Occupied(mut o) => {
-                    let node_cell = o.get_mut();
-                    (node_cell.clone(), Some(items))
-                }
-            };
-            if let Some(items) = items {
-                self.add_outlooks(items, node.clone())?;
+        let node = match self.items_map.entry(items) {
+            Entry::Vacant(v) => {
+                let items = v.key().clone();
+                let new_node = Node {
+                    items,
+                    source_items,
+                    action_map: HashMap::with_hasher(ahash::RandomState::with_seed(0)),
+                    nonassoc: HashSet::with_hasher(ahash::RandomState::with_seed(0)),
+                    goto_map: HashMap::with_hasher(ahash::RandomState::with_seed(0)),
+                    id: self.nodes.len(),
+                };
+                let new_node_wraped = Rc::new(RefCell::new(new_node));
+                v.insert(new_node_wraped.clone());
+                self.process_stack.push(new_node_wraped.clone());
+                self.nodes.push(new_node_wraped.clone());
+                new_node_wraped
             }
-            Ok(node)
-        }
+            Entry::Occupied(o) => o.get().clone(),
+        };
+        Ok(node)
     }
 
-    fn add_outlooks(&self, new_items: ItemCluster, node_cell: Rc<RefCell<Node>>) -> Result<()> {
-        let mut tasks = vec![(new_items, node_cell)];
-        while let Some((mut new_items, node_cell)) = tasks.pop() {
-            {
-                let node = node_cell.borrow();
-                new_items.retain(|item, outlooks| {
-                    let exist_outlooks = node.items.get(item).unwrap_or_else(|| panic!("None error:{}:{}", file!(), line!()));
-                    outlooks.retain(|outlook| !exist_outlooks.contains(outlook));
-                    !outlooks.is_empty()
-                });
-            }
-            if new_items.is_empty() {
-                continue;
-            }
-            {
-                {
-                    let mut node = node_cell.borrow_mut();
-                    for (item, outlooks) in new_items.iter() {
-                        node.items.get_mut(item).unwrap_or_else(|| panic!("None error:{}:{}", file!(), line!())).extend(outlooks.iter().cloned());
+    fn add_action(&mut self, terminal: Option<Rc<Terminal>>, action: Action, node_cell: Rc<RefCell<Node>>) -> Result<()> {
+        if node_cell.borrow().nonassoc.contains(&terminal) {
+            return Ok(());
+        }
+        let conflict_action = node_cell.borrow_mut().action_map.insert(terminal.clone(), action.clone());
+        if let Some(conflict_action) = conflict_action {
+            if action != conflict_action {
+                let resolution = match (&action, &conflict_action, &terminal) {
+                    (Action::Shift(_), Action::Reduce(production), Some(terminal)) | (Action::Reduce(production), Action::Shift(_), Some(terminal)) => {
+                        resolve_shift_reduce(terminal, production)
                     }
-                }
-                let mut new_actions = Vec::new();
-                for (item, outlooks) in &node_cell.borrow().items {
-                    if item.next_symbol().is_none() {
-                        for outlook in outlooks {
-                            if item.position == item.production.right_part.len() && &item.production.left_part == self.start {
-                                new_actions.push((None, Action::Accept(item.production.clone())));
-                            } else {
-                                new_actions.push((outlook.clone(), Action::Reduce(item.production.clone())));
-                            }
-                        }
+                    _ => None,
+                };
+                let mut node = node_cell.borrow_mut();
+                match resolution {
+                    Some(Resolution::Shift) | Some(Resolution::Reduce) => {
+                        let keep_shift = resolution == Some(Resolution::Shift);
+                        let kept = if matches!(action, Action::Shift(_)) == keep_shift { action } else { conflict_action };
+                        node.action_map.insert(terminal, kept);
                     }
-                }
-                for (terminal, action) in new_actions {
-                    self.add_action(terminal, action, node_cell.clone())?;
-                }
-                for (terminal, action) in node_cell.borrow().action_map.iter() {
-                    match action {
-                        Action::Shift(shift_node_cell) => {
-                            let outlooks: BTreeMap<_, _> = {
-                                new_items
-                                    .iter()
-                                    .filter_map(|(item, outlooks)| {
-                                        if matches!(item.clone().next_symbol().as_ref(), Some(Symbol::Terminal(next_terminal)) if Some(next_terminal)==terminal.as_ref()) {
-                                            if outlooks.is_empty() {
-                                                None
-                                            } else {
-                                                Some((item.clone().add_position(), outlooks.clone()))
-                                            }
-                                        } else {
-                                            None
-                                        }
-                                    })
-                                    .collect()
-                            };
-                            tasks.push((self.closure(outlooks), shift_node_cell.clone()));
-                        }
-                        Action::Reduce(_production) => {}
-                        Action::Accept(_) => {}
+                    Some(Resolution::Error) => {
+                        node.action_map.remove(&terminal);
+                        node.nonassoc.insert(terminal);
                     }
-                }
-                {
-                    let node = node_cell.borrow();
-                    for (non_terminal, goto_node_cell) in node.goto_map.iter() {
-                        let goto_new_items = node
-                            .items
-                            .iter()
-                            .filter_map(|(item, outlooks)| {
-                                if matches!(item.next_symbol(),Some(Symbol::NonTerminal(n))if &n==non_terminal) {
-                                    Some((item.clone().add_position(), outlooks.clone()))
-                                } else {
-                                    None
-                                }
-                            })
-                            .collect();
-                        tasks.push((self.closure(goto_new_items), goto_node_cell.clone()));
+                    None => {
+                        drop(node);
+                        self.conflict.get_or_insert((terminal, conflict_action, action, node_cell));
                     }
                 }
             }
@@ -622,17 +598,55 @@ impl<'t> StateMachineBuilder<'t> {
         Ok(())
     }
 
-    fn add_action(&self, terminal: Option<Rc<Terminal>>, action: Action, node_cell: Rc<RefCell<Node>>) -> Result<()> {
-        let mut node = node_cell.borrow_mut();
-        if let Some(conflict_action) = node.action_map.insert(terminal.clone(), action.clone()) {
-            if action != conflict_action {
-                return Err(Error::new(
-                    terminal.as_ref().map(|t| t.ident.span()).unwrap_or_else(|| self.span),
-                    format!("action conflict,terminal:{:?},action:{:?},conflict action:{:?},node:{:#?}", &terminal, &action, conflict_action, &node),
-                ));
+    fn describe_conflict(&self, terminal: &Option<Rc<Terminal>>, action: &Action, conflict_action: &Action, node_cell: &Rc<RefCell<Node>>) -> String {
+        let node = node_cell.borrow();
+        let describe_action = |action: &Action| match action {
+            Action::Shift(_) => ConflictAction::Shift(
+                node.items
+                    .keys()
+                    .filter(|item| matches!(item.next_symbol(), Some(Symbol::Terminal(t)) if Some(&t) == terminal.as_ref()))
+                    .map(|item| display_item(&item.production, item.position))
+                    .collect(),
+            ),
+            Action::Reduce(production) | Action::Accept(production) => ConflictAction::Reduce(display_item(production, production.right_part.len())),
+        };
+        format_conflict(terminal, describe_action(action), describe_action(conflict_action), &self.example_prefix(node.id))
+    }
+
+    /// Finds a shortest sequence of symbols leading from the start state to the node `target`.
+    fn example_prefix(&self, target: usize) -> Vec<Symbol> {
+        let mut parents: HashMap<usize, (usize, Symbol), ahash::RandomState> = HashMap::with_hasher(ahash::RandomState::with_seed(0));
+        let mut queue = std::collections::VecDeque::from(vec![0]);
+        while let Some(id) = queue.pop_front() {
+            if id == target {
+                break;
+            }
+            let node = self.nodes[id].borrow();
+            let edges = node
+                .action_map
+                .iter()
+                .filter_map(|(terminal, action)| match (terminal, action) {
+                    (Some(terminal), Action::Shift(next)) => Some((Symbol::Terminal(terminal.clone()), next.clone())),
+                    _ => None,
+                })
+                .chain(node.goto_map.iter().map(|(nonterminal, next)| (Symbol::NonTerminal(nonterminal.clone()), next.clone())));
+            let mut edges: Vec<_> = edges.map(|(symbol, next)| (next.borrow().id, symbol)).collect();
+            edges.sort_by_key(|(next_id, _)| *next_id);
+            for (next_id, symbol) in edges {
+                if next_id != 0 && !parents.contains_key(&next_id) {
+                    parents.insert(next_id, (id, symbol));
+                    queue.push_back(next_id);
+                }
             }
         }
-        Ok(())
+        let mut prefix = Vec::new();
+        let mut id = target;
+        while let Some((parent, symbol)) = parents.get(&id) {
+            prefix.push(symbol.clone());
+            id = *parent;
+        }
+        prefix.reverse();
+        prefix
     }
 
     fn add_goto(&self, nonterminal: Rc<NonTerminal>, goto: Rc<RefCell<Node>>, node_cell: Rc<RefCell<Node>>) -> Result<()> {
@@ -704,13 +718,13 @@ impl<'t> StateMachineBuilder<'t> {
         Ok(())
     }
 
-    fn build_state_machine(syntax: &'t Syntax, span: Span, is_lalr: bool) -> Result<Vec<Rc<RefCell<Node>>>> {
-        let Syntax { productions, start, token_type: _token_type } = syntax;
+    fn build_state_machine(syntax: &'t Syntax, span: Span) -> Result<Vec<Rc<RefCell<Node>>>> {
+        let Syntax { productions, start, .. } = syntax;
         let first_set = first_set(productions);
         let process_stack = Vec::new();
         let nodes = Vec::new();
         let items_map = HashMap::with_hasher(ahash::RandomState::with_seed(0));
-        let mut this = Self { productions, start, first_set, nodes, process_stack, items_map, is_lalr, span };
+        let mut this = Self { productions, start, first_set, nodes, process_stack, items_map, conflict: None, span };
         let source_items = ItemCluster::from_iter(
             productions.get(start).unwrap().iter().map(|production| (LR0Item { production: production.clone(), position: 0 }, BTreeSet::from_iter([None]))),
         );
@@ -719,6 +733,7 @@ impl<'t> StateMachineBuilder<'t> {
             items,
             source_items,
             action_map: HashMap::with_hasher(ahash::RandomState::with_seed(0)),
+            nonassoc: HashSet::with_hasher(ahash::RandomState::with_seed(0)),
             goto_map: HashMap::with_hasher(ahash::RandomState::with_seed(0)),
             id: 0,
         };
@@ -726,14 +741,203 @@ impl<'t> StateMachineBuilder<'t> {
         this.nodes.push(start_node_wrap.clone());
         this.process_stack.push(start_node_wrap);
         this.process_loop()?;
+        if let Some((terminal, action, conflict_action, node_cell)) = &this.conflict {
+            return Err(Error::new(
+                terminal.as_ref().map(|t| t.ident.span()).unwrap_or(this.span),
+                this.describe_conflict(terminal, action, conflict_action, node_cell),
+            ));
+        }
         Ok(this.nodes)
     }
 }
-pub(crate) fn do_generate_parser(syntax_declaration: SyntaxDeclaration, is_lalr: bool) -> Result<TokenStream2> {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Resolution {
+    Shift,
+    Reduce,
+    /// Neither, the lookahead becomes a syntax error.
+    Error,
+}
+/// Settles a shift/reduce conflict the way yacc does, `None` if the terminal or the production has no precedence.
+pub(crate) fn resolve_shift_reduce(terminal: &Terminal, production: &Production) -> Option<Resolution> {
+    let (terminal_level, associativity) = terminal.precedence?;
+    let (production_level, _) = production.precedence?;
+    Some(match production_level.cmp(&terminal_level) {
+        Ordering::Greater => Resolution::Reduce,
+        Ordering::Less => Resolution::Shift,
+        Ordering::Equal => match associativity {
+            Associativity::Left => Resolution::Reduce,
+            Associativity::Right => Resolution::Shift,
+            Associativity::NonAssoc => Resolution::Error,
+        },
+    })
+}
+/// One side of a conflict, shifted items or the reduced production, already rendered by [`display_item`].
+pub(crate) enum ConflictAction {
+    Shift(Vec<String>),
+    Reduce(String),
+}
+impl ConflictAction {
+    fn describe(&self) -> String {
+        match self {
+            Self::Shift(items) => format!("  shift:  {}", items.join("\n          ")),
+            Self::Reduce(item) => format!("  reduce: {}", item),
+        }
+    }
+}
+/// Explains a conflict in grammar terms: the kind, the lookahead, both productions and a prefix of symbols leading to the state.
+/// The shift comes first and two reductions are sorted, so the message does not depend on the order the items were visited in.
+pub(crate) fn format_conflict(terminal: &Option<Rc<Terminal>>, action: ConflictAction, conflict_action: ConflictAction, prefix: &[Symbol]) -> String {
+    let (action, conflict_action) = match (&action, &conflict_action) {
+        (ConflictAction::Reduce(_), ConflictAction::Shift(_)) => (conflict_action, action),
+        (ConflictAction::Reduce(item), ConflictAction::Reduce(conflict_item)) if item > conflict_item => (conflict_action, action),
+        _ => (action, conflict_action),
+    };
+    let lookahead = terminal.as_ref().map(|t| format!("`{}`", t.display)).unwrap_or_else(|| "<eof>".to_string());
+    let kind = if matches!(action, ConflictAction::Shift(_)) || matches!(conflict_action, ConflictAction::Shift(_)) {
+        "shift/reduce"
+    } else {
+        "reduce/reduce"
+    };
+    let prefix: Vec<_> = prefix.iter().map(display_symbol).collect();
+    format!(
+        "{} conflict on lookahead {}\n{}\n{}\nexample: `{}` followed by {}",
+        kind,
+        lookahead,
+        action.describe(),
+        conflict_action.describe(),
+        prefix.join(" "),
+        lookahead
+    )
+}
+pub(crate) fn stack_ident(ident: &Ident) -> Ident {
+    Ident::new(&format!("stack_{}", ident.to_string().to_lowercase()), ident.span())
+}
+pub(crate) fn terminal_patten(token_type: &Type, terminal: &Terminal) -> TokenStream2 {
+    let ident = &terminal.ident;
+    match terminal.variant_kind {
+        VariantKind::None => quote! {#token_type::#ident},
+        VariantKind::Truple => quote! {#token_type::#ident(_)},
+    }
+}
+/// Panic mode: skip to the next recovery terminal, then pop states until `recover_state` accepts one of them.
+/// `pop_values` are match arms popping the value pushed along with each state.
+pub(crate) fn generate_recovery(sync_pattens: Vec<TokenStream2>, recover_state: TokenStream2, pop_values: Vec<TokenStream2>) -> TokenStream2 {
+    quote! {
+      if last_error_position==Some(position){
+        iter.next();
+      }else{
+        syntax_errors.push(error);
+      }
+      loop{
+        let position=iter.position();
+        let token=iter.peek()?.ok_or_else(||::syntax::_format_err!("unexpected end of input"))?;
+        if let #(#sync_pattens)|* = token{
+          let recover_state=#recover_state;
+          if let Some(depth)=state_stack.iter().rposition(|state|recover_state(*state)){
+            while state_stack.len()>depth+1{
+              match state_stack.pop().unwrap(){
+                #(#pop_values)*
+                _=>{}
+              }
+            }
+            last_error_position=Some(position);
+            break;
+          }
+        }
+        iter.next();
+      }
+    }
+}
+/// Runs `parse` collecting syntax errors, errors that abort the recovering parse are dropped in favor of the syntax errors collected before them.
+pub(crate) fn wrap_recovery(parse: TokenStream2, root_output: &TokenStream2) -> TokenStream2 {
+    quote! {
+      let mut syntax_errors=::syntax::_Vec::<::syntax::SyntaxError>::new();
+      let mut last_error_position=None;
+      let result=(||->::syntax::_Fallible<#root_output>{#parse})();
+      match result{
+        Ok(output) if syntax_errors.is_empty()=>Ok(output),
+        Ok(_)=>Err(::syntax::SyntaxErrors(syntax_errors).into()),
+        Err(error)=>match error.downcast::<::syntax::SyntaxError>(){
+          Ok(error)=>{
+            syntax_errors.push(error);
+            Err(::syntax::SyntaxErrors(syntax_errors).into())
+          }
+          Err(error) if syntax_errors.is_empty()=>Err(error),
+          Err(_)=>Err(::syntax::SyntaxErrors(syntax_errors).into()),
+        },
+      }
+    }
+}
+/// Declares the parser closure running `body` on `tokens`, an iterator of fallible tokens with their location. The
+/// closure takes that iterator with `#[stream]` and a `Vec` of tokens or spanned tokens otherwise.
+pub(crate) fn generate_entry(name: &Ident, token_type: &Type, root_output: &TokenStream2, stream: bool, body: TokenStream2) -> TokenStream2 {
+    let token_iter = quote! {&mut dyn ::std::iter::Iterator<Item=::syntax::_Fallible<(#token_type,Option<::syntax::Span>)>>};
+    let (tokens, body) = if stream {
+        (quote! {tokens:#token_iter}, body)
+    } else {
+        let body = quote! {
+          let tokens:#token_iter=&mut tokens.into_iter().map(|token|::syntax::_Fallible::Ok(::syntax::SyntaxToken::<#token_type>::split_span(token)));
+          #body
+        };
+        (quote! {tokens: ::syntax::_Vec<_>}, body)
+    };
+    quote! {
+      #[allow(non_snake_case)]
+      #[allow(unused_mut)]
+      #[allow(unused_variables)]
+      #[allow(dead_code)]
+      #[allow(unreachable_code)]
+      let mut #name=|#tokens|->::syntax::_Fallible<#root_output>{
+        #body
+      };
+    }
+}
+pub(crate) fn display_symbol(symbol: &Symbol) -> String {
+    match symbol {
+        Symbol::Terminal(terminal) => terminal.display.clone(),
+        Symbol::NonTerminal(nonterminal) => nonterminal.name.clone(),
+    }
+}
+/// Renders a production with a dot at `position`, e.g. `expr -> expr . + Float`.
+pub(crate) fn display_item(production: &Production, position: usize) -> String {
+    let mut symbols: Vec<_> = production.right_part.iter().map(|(symbol, _)| display_symbol(symbol)).collect();
+    symbols.insert(position, ".".to_string());
+    format!("{} -> {}", production.left_part.name, symbols.join(" "))
+}
+pub(crate) fn do_generate_parser(syntax_declaration: SyntaxDeclaration) -> Result<TokenStream2> {
     let name = syntax_declaration.ident.clone();
     let span = name.span();
     let syntax = parse_syntax_declaration(syntax_declaration)?;
-    let nodes = StateMachineBuilder::build_state_machine(&syntax, span, is_lalr)?;
+    let nodes = StateMachineBuilder::build_state_machine(&syntax, span)?;
     let syntax_lr1 = SyntaxLR1 { syntax, nodes };
     syntax_lr1.generate(name)
 }
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn conflict_of(declaration: &str) -> String {
+        let syntax = parse_syntax_declaration(syn::parse_str(declaration).unwrap()).unwrap();
+        match StateMachineBuilder::build_state_machine(&syntax, Span::call_site()) {
+            Ok(_) => panic!("no conflict in {}", declaration),
+            Err(error) => error.to_string(),
+        }
+    }
+    #[test]
+    fn describe_shift_reduce_conflict() {
+        let message =
+            conflict_of("parser:Token->f64{ syn=>f64->{ [expr(v)]=>Ok(v); }, expr=>f64->{ [Float(v)]=>Ok(v); | [expr(v1),Add,expr(v2)]=>Ok(v1+v2); }, }");
+        assert_eq!(
+            message,
+            "shift/reduce conflict on lookahead `Add`\n  shift:  expr -> expr . Add expr\n  reduce: expr -> expr Add expr .\nexample: `expr Add expr` followed by `Add`"
+        );
+    }
+    #[test]
+    fn describe_reduce_reduce_conflict() {
+        let message = conflict_of("parser:Token->(){ syn->{ [Sub,a,Add] | [Sub,b,Add] }, a->{ [Float] }, b->{ [Float] }, }");
+        assert_eq!(
+            message,
+            "reduce/reduce conflict on lookahead `Add`\n  reduce: a -> Float .\n  reduce: b -> Float .\nexample: `Sub Float` followed by `Add`"
+        );
+    }
+}
@@ -8,13 +8,26 @@ use syn::{
     parenthesized,
     parse::{Parse},
     punctuated::Punctuated,
+    spanned::Spanned,
     token::{Brace, Bracket, Paren},
-    Expr, Type,
+    Attribute, Expr, Type,
 };
 extern crate proc_macro2;
 use proc_macro2::TokenStream as TokenStream2;
 use syn::Error;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Associativity {
+    Left,
+    Right,
+    NonAssoc,
+}
 pub(crate) struct SyntaxDeclaration {
+    /// Terminals listed in `#[recover(..)]`, the parser resynchronizes on them after a syntax error.
+    pub(crate) recover: Vec<SymbolDeclaration>,
+    /// `#[left(..)]`, `#[right(..)]` and `#[nonassoc(..)]` in declaration order, later ones bind tighter.
+    pub(crate) precedence: Vec<(Associativity, Vec<SymbolDeclaration>)>,
+    /// `#[stream]`, the parser takes an iterator of fallible tokens instead of a `Vec`.
+    pub(crate) stream: bool,
     pub(crate) ident: Ident,
     _after_name: Token!(:),
     pub(crate) lexical: Type,
@@ -23,10 +36,41 @@ pub(crate) struct SyntaxDeclaration {
     pub(crate) brace: Brace,
     pub(crate) nonterminals: Punctuated<NonTerminalDeclaration, Token!(,)>,
 }
+impl SyntaxDeclaration {
+    /// Span of the first declaration that only LR parsers understand.
+    pub(crate) fn lr_only_span(&self) -> Option<proc_macro2::Span> {
+        let prec = self.nonterminals.iter().flat_map(|nonterminal| nonterminal.productions.iter()).find_map(|production| production.prec.as_ref());
+        self.recover.iter().chain(self.precedence.iter().flat_map(|(_, symbols)| symbols.iter())).chain(prec).next().map(|symbol| symbol.ident.span())
+    }
+}
 impl Parse for SyntaxDeclaration {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
         let content;
+        let mut recover = Vec::new();
+        let mut precedence = Vec::new();
+        let mut stream = false;
+        for attribute in input.call(Attribute::parse_outer)? {
+            if attribute.path.is_ident("stream") && attribute.tokens.is_empty() {
+                stream = true;
+                continue;
+            }
+            let symbols = attribute.parse_args_with(Punctuated::<SymbolDeclaration, Token!(,)>::parse_terminated)?.into_iter().collect();
+            let associativity = match attribute.path.get_ident().map(|ident| ident.to_string()).as_deref() {
+                Some("recover") => {
+                    recover.extend(symbols);
+                    continue;
+                }
+                Some("left") => Associativity::Left,
+                Some("right") => Associativity::Right,
+                Some("nonassoc") => Associativity::NonAssoc,
+                _ => return Err(Error::new(attribute.span(), "except `#[recover(..)]`, `#[stream]`, `#[left(..)]`, `#[right(..)]` or `#[nonassoc(..)]`")),
+            };
+            precedence.push((associativity, symbols));
+        }
         Ok(Self {
+            recover,
+            precedence,
+            stream,
             ident: input.parse()?,
             _after_name: input.parse()?,
             lexical: input.parse()?,
@@ -57,6 +101,8 @@ impl Parse for NonTerminalDeclaration {
     }
 }
 pub(crate) struct ProductionDeclaration {
+    /// `#[prec(..)]`, the production takes the precedence of this symbol instead of its last terminal.
+    pub(crate) prec: Option<SymbolDeclaration>,
     pub(crate) _bracket: Bracket,
     pub(crate) symbols: Punctuated<SymbolDeclaration, Token!(,)>,
     pub(crate) callback: CallbackDeclaration,
@@ -64,7 +110,14 @@ pub(crate) struct ProductionDeclaration {
 impl Parse for ProductionDeclaration {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
         let content;
-        Ok(Self { _bracket: bracketed!(content in input), symbols: content.parse_terminated(SymbolDeclaration::parse)?, callback: input.parse()? })
+        let mut prec = None;
+        for attribute in input.call(Attribute::parse_outer)? {
+            if !attribute.path.is_ident("prec") {
+                return Err(Error::new(attribute.span(), "except `#[prec(..)]`"));
+            }
+            prec = Some(attribute.parse_args()?);
+        }
+        Ok(Self { prec, _bracket: bracketed!(content in input), symbols: content.parse_terminated(SymbolDeclaration::parse)?, callback: input.parse()? })
     }
 }
 pub(crate) enum CallbackDeclaration {
@@ -83,12 +136,15 @@ impl Parse for CallbackDeclaration {
 }
 pub(crate) struct SymbolDeclaration {
     pub(crate) ident: Ident,
+    /// The symbol as written in the grammar, `end` for `t!(end)`.
+    pub(crate) display: String,
     pub(crate) value: MatchValueDeclaration,
 }
 impl Parse for SymbolDeclaration {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
         let ident: Ident = input.call(Ident::parse_any)?;
         let lookahead = input.lookahead1();
+        let mut display = ident.to_string();
         let symbol = if lookahead.peek(Token!(!)) {
             if &*ident.to_string() != "t" {
                 return Err(Error::new(ident.span(), "except 't'"));
@@ -96,7 +152,8 @@ impl Parse for SymbolDeclaration {
             let _: Token!(!) = input.parse()?;
             let content;
             let _ = parenthesized!(content in input);
-            let r = format_ident!("{}", to_ident(&content.to_string()));
+            display = content.to_string().replace(' ', "");
+            let r = format_ident!("{}", to_ident(&display));
             while !content.is_empty() {
                 let _: TokenTree = content.parse()?;
             }
@@ -104,7 +161,7 @@ impl Parse for SymbolDeclaration {
         } else {
             ident
         };
-        Ok(Self { ident: symbol, value: input.parse()? })
+        Ok(Self { ident: symbol, display, value: input.parse()? })
     }
 }
 pub(crate) enum MatchValueDeclaration {
@@ -22,8 +22,12 @@ pub(crate) enum VariantKind {
 pub(crate) struct Terminal {
     pub(crate) ident: Ident,
     pub(crate) name: String,
+    pub(crate) display: String,
     pub(crate) variant_kind: VariantKind,
+    pub(crate) precedence: Option<Precedence>,
 }
+/// Level and associativity from the precedence declarations, a higher level binds tighter.
+pub(crate) type Precedence = (usize, Associativity);
 impl Debug for Terminal {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(&self.ident.to_string())
@@ -101,6 +105,7 @@ pub(crate) struct Production {
     pub(crate) left_part: Rc<NonTerminal>,
     pub(crate) right_part: Vec<(Symbol, Option<TokenStream2>)>,
     pub(crate) callback: Option<Expr>,
+    pub(crate) precedence: Option<Precedence>,
 }
 impl Debug for Production {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -254,8 +259,18 @@ pub(crate) struct Syntax {
     pub(crate) productions: ProductionMap,
     pub(crate) start: Rc<NonTerminal>,
     pub(crate) token_type: Type,
+    pub(crate) recover: Vec<Rc<Terminal>>,
+    pub(crate) stream: bool,
 }
 pub(crate) fn parse_syntax_declaration(syntax: SyntaxDeclaration) -> Result<Syntax> {
+    let mut precedences = HashMap::with_hasher(ahash::RandomState::with_seed(0));
+    for (level, (associativity, symbols)) in syntax.precedence.iter().enumerate() {
+        for symbol_declaration in symbols {
+            if precedences.insert(symbol_declaration.ident.clone(), (level, *associativity)).is_some() {
+                return Err(Error::new(symbol_declaration.ident.span(), "precedence is declared more than once"));
+            }
+        }
+    }
     let mut nonterminals = HashMap::with_hasher(ahash::RandomState::with_seed(0));
     for nonterminal_declaration in &syntax.nonterminals {
         let nonterminal = NonTerminal {
@@ -293,7 +308,13 @@ pub(crate) fn parse_syntax_declaration(syntax: SyntaxDeclaration) -> Result<Synt
                             } else {
                                 VariantKind::None
                             };
-                            Rc::new(Terminal { ident: symbol_declaration.ident.clone(), name: symbol_declaration.ident.to_string(), variant_kind })
+                            Rc::new(Terminal {
+                                ident: symbol_declaration.ident.clone(),
+                                name: symbol_declaration.ident.to_string(),
+                                display: symbol_declaration.display.clone(),
+                                variant_kind,
+                                precedence: precedences.get(&symbol_declaration.ident).copied(),
+                            })
                         })
                         .clone();
                     Symbol::Terminal(terminal)
@@ -304,7 +325,14 @@ pub(crate) fn parse_syntax_declaration(syntax: SyntaxDeclaration) -> Result<Synt
                 CallbackDeclaration::None => None,
                 CallbackDeclaration::Some { callback, .. } => Some(callback.clone()),
             };
-            productions.push(Rc::new(Production { left_part: left_part.clone(), right_part, callback: callback.map(|c| *c) }));
+            let precedence = match &production_declaration.prec {
+                Some(prec) => Some(*precedences.get(&prec.ident).ok_or_else(|| Error::new(prec.ident.span(), "precedence is not declared"))?),
+                None => right_part.iter().rev().find_map(|(symbol, _)| match symbol {
+                    Symbol::Terminal(terminal) => Some(terminal.precedence),
+                    Symbol::NonTerminal(_) => None,
+                }).flatten(),
+            };
+            productions.push(Rc::new(Production { left_part: left_part.clone(), right_part, callback: callback.map(|c| *c), precedence }));
         }
         production_map.insert(left_part, productions);
     }
@@ -325,5 +353,12 @@ pub(crate) fn parse_syntax_declaration(syntax: SyntaxDeclaration) -> Result<Synt
             _ => return Err(Error::new(s.span(), "the output type of start nonterminal is not equal with the output type of the function")),
         },
     }
-    Ok(Syntax { productions: production_map, start: root, token_type: syntax.lexical })
+    let mut recover = Vec::new();
+    for symbol_declaration in &syntax.recover {
+        let terminal = terminals
+            .get(&symbol_declaration.ident)
+            .ok_or_else(|| Error::new(symbol_declaration.ident.span(), "except a terminal used by the syntax"))?;
+        recover.push(terminal.clone());
+    }
+    Ok(Syntax { productions: production_map, start: root, token_type: syntax.lexical, recover, stream: syntax.stream })
 }
@@ -1,13 +1,18 @@
 #[cfg(test)]
 mod test {
     use failure::Fallible;
-    use syntax_derive::{lalr1_analyser, recursive_predictive_analysis};
+    use syntax::{Span, Spanned, SyntaxError, SyntaxErrors};
+    use syntax_derive::{earley_analyser, lalr1_analyser, recursive_predictive_analysis};
 
     #[derive(Debug)]
     enum LexicalDemo {
         Float(f64),
         Add,
         Sub,
+        Mul,
+        Pow,
+        Less,
+        Semicolon,
     }
     use LexicalDemo::*;
 
@@ -48,4 +53,180 @@ mod test {
         assert_eq!(r, 3.0);
         Ok(())
     }
+    #[test]
+    fn test_lalr1_left_recursive_start() -> Fallible<()> {
+        lalr1_analyser! {
+          parser:LexicalDemo->String{
+            list=>f64 ->{
+              [Float(v1)]=>Ok(v1);
+              | [list(v2),Add,Float(v1)]=>Ok(v1+v2);
+            },
+          }
+        };
+        assert_eq!(parser(vec![Float(1.0)])?, 1.0);
+        assert_eq!(parser(vec![Float(1.0), Add, Float(2.0), Add, Float(3.0)])?, 6.0);
+        assert!(parser(vec![Float(1.0), Add]).is_err());
+        Ok(())
+    }
+    #[test]
+    fn test_lalr1_empty_production() -> Fallible<()> {
+        lalr1_analyser! {
+          parser:LexicalDemo->String{
+            syn=>f64->{[sign(s),list(v)]=>Ok(s*v);},
+            sign=>f64->{
+              []=>Ok(1.0);
+              | [Sub]=>Ok(-1.0);
+            },
+            list=>f64->{
+              []=>Ok(0.0);
+              | [list(v1),Float(v2),separator]=>Ok(v1+v2);
+            },
+            separator->{ []|[Add] },
+          }
+        };
+        assert_eq!(parser(vec![])?, 0.0);
+        assert_eq!(parser(vec![Sub, Float(1.0), Add, Float(2.0)])?, -3.0);
+        assert_eq!(parser(vec![Float(1.0), Float(2.0), Add])?, 3.0);
+        Ok(())
+    }
+    #[test]
+    fn test_lalr1_syntax_error() -> Fallible<()> {
+        lalr1_analyser! {
+          parser:LexicalDemo->String{
+            syn=>f64->{[expr(v)]=>Ok(v);},
+            expr=>f64 ->{
+              [Float(v1)]=>Ok(v1);
+              | [expr(v2),t!(+),Float(v1)]=>Ok(v1+v2);
+            },
+          }
+        };
+        let span = |start, column| Span { start, end: start + 1, line: 1, column };
+        let error = parser(vec![Spanned::new(Float(2.0), span(0, 1)), Spanned::new(Add, span(2, 3)), Spanned::new(Sub, span(4, 5))]).unwrap_err();
+        let error = error.downcast::<SyntaxError>()?;
+        assert_eq!(error, SyntaxError { token: Some("Sub".to_string()), span: Some(span(4, 5)), expected: vec![Some("Float")] });
+        assert_eq!(error.to_string(), "`Float` expected near Sub at line 1:5");
+        let error = parser(vec![Spanned::new(Float(2.0), span(0, 1)), Spanned::new(Float(1.0), span(2, 3))]).unwrap_err();
+        assert_eq!(error.downcast::<SyntaxError>()?.expected, vec![None, Some("+")]);
+        Ok(())
+    }
+    #[test]
+    fn test_lalr1_stream() -> Fallible<()> {
+        lalr1_analyser! {
+          #[stream]
+          parser:LexicalDemo->String{
+            syn=>f64->{[expr(v)]=>Ok(v);},
+            expr=>f64 ->{
+              [Float(v1)]=>Ok(v1);
+              | [expr(v2),Add,Float(v1)]=>Ok(v1+v2);
+            },
+          }
+        };
+        let tokens = (0..100).flat_map(|_| vec![Add, Float(1.0)]).skip(1).map(Ok);
+        assert_eq!(parser(&mut syntax::token_stream::<LexicalDemo, _, _>(tokens))?, 100.0);
+        let tokens = vec![Ok(Float(1.0)), Ok(Add), Err(failure::format_err!("unexpected lexical"))];
+        let error = parser(&mut syntax::token_stream::<LexicalDemo, _, _>(tokens)).unwrap_err();
+        assert_eq!(error.to_string(), "unexpected lexical");
+        Ok(())
+    }
+    #[test]
+    fn test_lalr1_error_recovery() -> Fallible<()> {
+        lalr1_analyser! {
+          #[recover(t!(;))]
+          parser:LexicalDemo->String{
+            syn=>f64->{[block(v)]=>Ok(v);},
+            block=>f64->{
+              [stat(v)]=>Ok(v);
+              | [block(v1),t!(;),stat(v2)]=>Ok(v1+v2);
+            },
+            stat=>f64 ->{
+              [Float(v1)]=>Ok(v1);
+              | [stat(v1),t!(+),Float(v2)]=>Ok(v1+v2);
+            },
+          }
+        };
+        assert_eq!(parser(vec![Float(1.0), Semicolon, Float(2.0), Add, Float(3.0)])?, 6.0);
+        let error = parser(vec![Float(1.0), Add, Semicolon, Float(2.0), Semicolon, Add, Float(3.0), Semicolon, Float(4.0), Add, Float(5.0)]).unwrap_err();
+        let SyntaxErrors(errors) = error.downcast::<SyntaxErrors>()?;
+        assert_eq!(
+            errors,
+            vec![
+                SyntaxError { token: Some("Semicolon".to_string()), span: None, expected: vec![Some("Float")] },
+                SyntaxError { token: Some("Add".to_string()), span: None, expected: vec![Some("Float")] },
+            ]
+        );
+        let error = parser(vec![Float(1.0), Add]).unwrap_err();
+        assert_eq!(error.downcast::<SyntaxErrors>()?.0, vec![SyntaxError { token: None, span: None, expected: vec![Some("Float")] }]);
+        Ok(())
+    }
+    #[test]
+    fn test_lalr1_recovery_skips_callbacks() -> Fallible<()> {
+        let reduced = std::cell::Cell::new(0);
+        lalr1_analyser! {
+          #[recover(t!(;))]
+          parser:LexicalDemo->String{
+            syn=>f64->{[block(v)]=>Ok(v);},
+            block=>f64->{
+              [stat(v)]=>Ok(v);
+              | [block(v1),t!(;),stat(v2)]=>Ok(v1+v2);
+            },
+            stat=>f64 ->{
+              [Float(v1)]=>{ reduced.set(reduced.get() + 1); Ok(v1) };
+              | [stat(v1),t!(+),Float(v2)]=>Ok(v1+v2);
+            },
+          }
+        };
+        let error = parser(vec![Float(1.0), Add, Semicolon, Float(2.0), Semicolon, Float(3.0), Add]).unwrap_err();
+        assert_eq!(error.downcast::<SyntaxErrors>()?.0.len(), 2);
+        assert_eq!(reduced.get(), 1);
+        Ok(())
+    }
+    #[test]
+    fn test_lalr1_precedence() -> Fallible<()> {
+        lalr1_analyser! {
+          #[nonassoc(Less)]
+          #[left(Add,Sub)]
+          #[left(Mul)]
+          #[right(Neg)]
+          #[right(Pow)]
+          parser:LexicalDemo->String{
+            syn=>String->{[expr(v)]=>Ok(v);},
+            expr=>String ->{
+              [Float(v)]=>Ok(v.to_string());
+              | [expr(v1),Less,expr(v2)]=>Ok(format!("({}<{})",v1,v2));
+              | [expr(v1),Add,expr(v2)]=>Ok(format!("({}+{})",v1,v2));
+              | [expr(v1),Sub,expr(v2)]=>Ok(format!("({}-{})",v1,v2));
+              | [expr(v1),Mul,expr(v2)]=>Ok(format!("({}*{})",v1,v2));
+              | [expr(v1),Pow,expr(v2)]=>Ok(format!("({}^{})",v1,v2));
+              | #[prec(Neg)] [Sub,expr(v)]=>Ok(format!("(-{})",v));
+            },
+          }
+        };
+        assert_eq!(parser(vec![Float(1.0), Sub, Float(2.0), Add, Float(3.0), Mul, Float(4.0)])?, "((1-2)+(3*4))");
+        assert_eq!(parser(vec![Float(1.0), Pow, Float(2.0), Pow, Float(3.0)])?, "(1^(2^3))");
+        assert_eq!(parser(vec![Sub, Float(1.0), Pow, Float(2.0), Mul, Float(3.0)])?, "((-(1^2))*3)");
+        assert_eq!(parser(vec![Float(1.0), Add, Float(2.0), Less, Float(3.0)])?, "((1+2)<3)");
+        let error = parser(vec![Float(1.0), Less, Float(2.0), Less, Float(3.0)]).unwrap_err();
+        assert_eq!(error.downcast::<SyntaxError>()?.token, Some("Less".to_string()));
+        Ok(())
+    }
+    #[test]
+    fn test_earley() -> Fallible<()> {
+        earley_analyser! {
+          parser:LexicalDemo->String{
+            syn=>String->{[expr(v)]=>Ok(v);},
+            expr=>String ->{
+              [Float(v)]=>Ok(v.to_string());
+              | [expr(v1),Sub,expr(v2)]=>Ok(format!("({}-{})",v1,v2));
+              | [Sub,expr(v)]=>Ok(format!("(-{})",v));
+            },
+          }
+        };
+        assert_eq!(parser(vec![Float(1.0), Sub, Float(2.0), Sub, Float(3.0)])?, "((1-2)-3)");
+        assert_eq!(parser(vec![Sub, Float(1.0), Sub, Sub, Float(2.0)])?, "((-1)-(-2))");
+        let error = parser(vec![Float(1.0), Sub]).unwrap_err();
+        assert_eq!(error.downcast::<SyntaxError>()?, SyntaxError { token: None, span: None, expected: vec![Some("Float"), Some("Sub")] });
+        let error = parser(vec![Float(1.0), Float(2.0)]).unwrap_err();
+        assert_eq!(error.downcast::<SyntaxError>()?.expected, vec![None, Some("Sub")]);
+        Ok(())
+    }
 }
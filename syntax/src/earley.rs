@@ -0,0 +1,189 @@
+//! Earley recognizer behind `earley_analyser!`, it accepts any context free grammar including ambiguous ones.
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Symbol {
+    Terminal(usize),
+    NonTerminal(usize),
+}
+/// Step of the derivation chosen by [`Grammar::parse`], in the order an LR parser would take them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Consume the token at this position.
+    Shift(usize),
+    /// Reduce by the production with this index.
+    Reduce(usize),
+}
+/// Position of the failing token and the terminals that were expected there, `None` stands for the end of input.
+pub type EarleyError = (usize, Vec<Option<&'static str>>);
+pub struct Grammar {
+    pub start: usize,
+    /// Left part and right part of every production. Alternatives of a nonterminal are tried in this order.
+    pub productions: &'static [(usize, &'static [Symbol])],
+    pub nullable: &'static [bool],
+    pub terminals: &'static [&'static str],
+}
+/// A production with a dot and the position where it started.
+type Item = (usize, usize, usize);
+enum Child {
+    Terminal(usize),
+    NonTerminal(usize, usize, usize),
+}
+enum Task {
+    Shift(usize),
+    Expand(usize, usize, usize),
+    Reduce(usize, (usize, usize, usize)),
+}
+impl Grammar {
+    /// Recognizes `tokens`, given as terminal indexes, and picks one derivation among the possible ones.
+    ///
+    /// Ambiguities are resolved by preferring the alternative declared first, then by giving the leftmost symbols
+    /// of a production the longest input, which makes `e -> e + e` left associative.
+    pub fn parse(&self, tokens: &[usize]) -> Result<Vec<Event>, EarleyError> {
+        let sets = self.recognize(tokens)?;
+        let mut completed = vec![HashSet::new(); sets.len()];
+        for (end, set) in sets.iter().enumerate() {
+            for (production, dot, origin) in set {
+                let (left_part, right_part) = self.productions[*production];
+                if *dot == right_part.len() {
+                    completed[end].insert((left_part, *origin));
+                }
+            }
+        }
+        let mut events = Vec::new();
+        let mut in_progress = HashSet::new();
+        let mut tasks = vec![Task::Expand(self.start, 0, tokens.len())];
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Shift(position) => events.push(Event::Shift(position)),
+                Task::Reduce(production, span) => {
+                    in_progress.remove(&span);
+                    events.push(Event::Reduce(production));
+                }
+                Task::Expand(nonterminal, start, end) => {
+                    in_progress.insert((nonterminal, start, end));
+                    let (production, children) = (0..self.productions.len())
+                        .filter(|production| self.productions[*production].0 == nonterminal)
+                        .filter(|production| sets[end].contains(&(*production, self.productions[*production].1.len(), start)))
+                        .find_map(|production| {
+                            self.split(production, start, end, tokens, &sets, &completed, &in_progress).map(|children| (production, children))
+                        })
+                        .ok_or_else(|| (start, Vec::new()))?;
+                    tasks.push(Task::Reduce(production, (nonterminal, start, end)));
+                    for child in children {
+                        tasks.push(match child {
+                            Child::Terminal(position) => Task::Shift(position),
+                            Child::NonTerminal(nonterminal, start, end) => Task::Expand(nonterminal, start, end),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    fn recognize(&self, tokens: &[usize]) -> Result<Vec<HashSet<Item>>, EarleyError> {
+        let mut sets: Vec<Vec<Item>> = vec![Vec::new(); tokens.len() + 1];
+        let mut seen: Vec<HashSet<Item>> = vec![HashSet::new(); tokens.len() + 1];
+        let add = |sets: &mut Vec<Vec<Item>>, seen: &mut Vec<HashSet<Item>>, position: usize, item: Item| {
+            if seen[position].insert(item) {
+                sets[position].push(item);
+            }
+        };
+        for (production, (left_part, _)) in self.productions.iter().enumerate() {
+            if *left_part == self.start {
+                add(&mut sets, &mut seen, 0, (production, 0, 0));
+            }
+        }
+        for position in 0..=tokens.len() {
+            let mut index = 0;
+            while index < sets[position].len() {
+                let (production, dot, origin) = sets[position][index];
+                let (left_part, right_part) = self.productions[production];
+                match right_part.get(dot) {
+                    Some(Symbol::NonTerminal(nonterminal)) => {
+                        for (predicted, (predicted_left_part, _)) in self.productions.iter().enumerate() {
+                            if predicted_left_part == nonterminal {
+                                add(&mut sets, &mut seen, position, (predicted, 0, position));
+                            }
+                        }
+                        // Step over a nullable nonterminal right away, its empty completion may already have been processed.
+                        if self.nullable[*nonterminal] {
+                            add(&mut sets, &mut seen, position, (production, dot + 1, origin));
+                        }
+                    }
+                    Some(Symbol::Terminal(terminal)) => {
+                        if tokens.get(position) == Some(terminal) {
+                            add(&mut sets, &mut seen, position + 1, (production, dot + 1, origin));
+                        }
+                    }
+                    None => {
+                        let mut waiting = 0;
+                        while waiting < sets[origin].len() {
+                            let (waiting_production, waiting_dot, waiting_origin) = sets[origin][waiting];
+                            if self.productions[waiting_production].1.get(waiting_dot) == Some(&Symbol::NonTerminal(left_part)) {
+                                add(&mut sets, &mut seen, position, (waiting_production, waiting_dot + 1, waiting_origin));
+                            }
+                            waiting += 1;
+                        }
+                    }
+                }
+                index += 1;
+            }
+            let accepted = |set: &HashSet<Item>| {
+                set.iter().any(|(production, dot, origin)| {
+                    let (left_part, right_part) = self.productions[*production];
+                    left_part == self.start && *dot == right_part.len() && *origin == 0
+                })
+            };
+            if (position < tokens.len() && sets[position + 1].is_empty()) || (position == tokens.len() && !accepted(&seen[position])) {
+                let mut expected: Vec<_> = sets[position]
+                    .iter()
+                    .filter_map(|(production, dot, _)| match self.productions[*production].1.get(*dot) {
+                        Some(Symbol::Terminal(terminal)) => Some(Some(self.terminals[*terminal])),
+                        _ => None,
+                    })
+                    .collect();
+                if position < tokens.len() && accepted(&seen[position]) {
+                    expected.push(None);
+                }
+                expected.sort_unstable();
+                expected.dedup();
+                return Err((position, expected));
+            }
+        }
+        Ok(seen)
+    }
+
+    /// Splits the input between `start` and `end` over the right part of `production`, from the last symbol to the first.
+    /// Children come out last first.
+    #[allow(clippy::too_many_arguments)]
+    fn split(
+        &self, production: usize, start: usize, end: usize, tokens: &[usize], sets: &[HashSet<Item>], completed: &[HashSet<(usize, usize)>],
+        in_progress: &HashSet<(usize, usize, usize)>,
+    ) -> Option<Vec<Child>> {
+        let mut children = Vec::new();
+        let mut end = end;
+        for (dot, symbol) in self.productions[production].1.iter().enumerate().rev() {
+            match symbol {
+                Symbol::Terminal(terminal) => {
+                    if end == start || tokens[end - 1] != *terminal || !sets[end - 1].contains(&(production, dot, start)) {
+                        return None;
+                    }
+                    end -= 1;
+                    children.push(Child::Terminal(end));
+                }
+                Symbol::NonTerminal(nonterminal) => {
+                    let middle = (start..=end).rev().find(|middle| {
+                        sets[*middle].contains(&(production, dot, start))
+                            && completed[end].contains(&(*nonterminal, *middle))
+                            && !in_progress.contains(&(*nonterminal, *middle, end))
+                    })?;
+                    children.push(Child::NonTerminal(*nonterminal, middle, end));
+                    end = middle;
+                }
+            }
+        }
+        Some(children)
+    }
+}
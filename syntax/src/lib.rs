@@ -1,8 +1,130 @@
+#[macro_use]
+extern crate failure_derive;
 use failure::Fallible;
-use std::{iter::Peekable, slice::Iter};
+use std::{
+    fmt::{Display, Formatter},
+    iter::Peekable,
+    slice::Iter,
+};
+
+pub use lexical::{Span, Spanned};
+pub mod earley;
 
 pub type _Iter<'a, T> = Peekable<Iter<'a, T>>;
 pub type _Fallible<T> = Fallible<T>;
 pub type _Vec<T> = Vec<T>;
 pub use failure::format_err as _format_err;
 pub use std::{default::Default as _Default, unreachable as _unreachable};
+
+/// Entry of the compressed action table emitted by `lalr1_analyser!`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum _Action {
+    Shift(usize),
+    Reduce(usize),
+}
+/// Input of a generated parser, either a bare token or a token with its location.
+pub trait SyntaxToken<T> {
+    fn split_span(self) -> (T, Option<Span>);
+}
+impl<T> SyntaxToken<T> for T {
+    fn split_span(self) -> (T, Option<Span>) {
+        (self, None)
+    }
+}
+impl<T> SyntaxToken<T> for Spanned<T> {
+    fn split_span(self) -> (T, Option<Span>) {
+        (self.value, Some(self.span))
+    }
+}
+/// Adapts an iterator of tokens or spanned tokens, like a `lexical::LexicalStream`, to the input of a parser declared
+/// with `#[stream]`.
+pub fn token_stream<T, S: SyntaxToken<T>, I: IntoIterator<Item = Fallible<S>>>(tokens: I) -> impl Iterator<Item = Fallible<(T, Option<Span>)>> {
+    tokens.into_iter().map(|token| token.map(SyntaxToken::<T>::split_span))
+}
+/// Input of the generated LR parsers, pulls one token at a time and remembers the location of the last one.
+#[doc(hidden)]
+pub struct _TokenIter<'t, T> {
+    tokens: &'t mut dyn Iterator<Item = Fallible<(T, Option<Span>)>>,
+    peeked: Option<Option<(T, Option<Span>)>>,
+    position: usize,
+    last_span: Option<Span>,
+}
+impl<'t, T> _TokenIter<'t, T> {
+    pub fn new(tokens: &'t mut dyn Iterator<Item = Fallible<(T, Option<Span>)>>) -> Self {
+        Self { tokens, peeked: None, position: 0, last_span: None }
+    }
+
+    pub fn peek(&mut self) -> Fallible<Option<&T>> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.tokens.next().transpose()?);
+        }
+        Ok(self.peeked.as_ref().unwrap().as_ref().map(|(token, _)| token))
+    }
+
+    /// Location of the next token, or of the last one once the input is exhausted.
+    pub fn span(&mut self) -> Fallible<Option<Span>> {
+        self.peek()?;
+        Ok(self.peeked.as_ref().unwrap().as_ref().map_or(self.last_span, |(_, span)| *span))
+    }
+
+    /// Index of the next token.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+impl<'t, T> Iterator for _TokenIter<'t, T> {
+    type Item = T;
+
+    /// Errors of the underlying iterator are only reported by `peek`.
+    fn next(&mut self) -> Option<T> {
+        self.peek().ok()?;
+        let (token, span) = self.peeked.take()??;
+        self.position += 1;
+        self.last_span = span;
+        Some(token)
+    }
+}
+/// Error returned by a generated LR parser when no action exists for the current token.
+#[derive(Debug, Clone, PartialEq, Eq, Fail)]
+pub struct SyntaxError {
+    /// The offending token, `None` if the input ended unexpectedly.
+    pub token: Option<String>,
+    pub span: Option<Span>,
+    /// Terminals accepted by the action table in the failing state, `None` stands for the end of input.
+    pub expected: Vec<Option<&'static str>>,
+}
+impl SyntaxError {
+    pub fn new(token: Option<String>, span: Option<Span>, expected: &[Option<&'static str>]) -> Self {
+        Self { token, span, expected: expected.to_vec() }
+    }
+}
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let display_terminal = |t: &Option<&str>| t.map(|t| format!("`{}`", t)).unwrap_or_else(|| "<eof>".to_string());
+        match self.expected.len() {
+            0 => write!(f, "unexpected symbol")?,
+            1 => write!(f, "{} expected", display_terminal(&self.expected[0]))?,
+            _ => write!(f, "one of {} expected", self.expected.iter().map(display_terminal).collect::<Vec<_>>().join(", "))?,
+        }
+        match (&self.token, &self.span) {
+            (Some(token), Some(span)) => write!(f, " near {} at line {}", token, span),
+            (Some(token), None) => write!(f, " near {}", token),
+            (None, Some(span)) => write!(f, " near <eof> at line {}", span),
+            (None, None) => write!(f, " near <eof>"),
+        }
+    }
+}
+/// Every syntax error found by a parser declared with `#[recover(..)]`, in source order.
+#[derive(Debug, Clone, PartialEq, Eq, Fail)]
+pub struct SyntaxErrors(pub Vec<SyntaxError>);
+impl Display for SyntaxErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (index, error) in self.0.iter().enumerate() {
+            if index != 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
@@ -44,6 +44,14 @@ pub struct Relocation {
     relocation_kind: RelocationKind,
 }
 impl Relocation {
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn relocation_kind(&self) -> &RelocationKind {
+        &self.relocation_kind
+    }
+
     pub fn relocate(&self, buffer: &mut UnsafeBuffer, arg: *const u8) {
         let offset = self.offset;
         let arg = arg as isize;
@@ -170,6 +178,10 @@ impl Object {
         &self.buffer
     }
 
+    pub fn get_relocations(&self) -> &[(Relocation, ObjectImport)] {
+        &self.relocations
+    }
+
     pub fn replace(
         this: &ObjectRef, mut buffer: UnsafeBuffer, symbols: Vec<Symbol<ObjectExport>>, relocations: Vec<(Relocation, ObjectImport)>,
     ) -> Fallible<(UnsafeBuffer, Vec<Symbol<ObjectExport>>, Vec<(Relocation, ObjectImport)>)> {
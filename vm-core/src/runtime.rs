@@ -18,4 +18,27 @@ pub trait DynRuntimeTrait<M> {
     fn create_dyn(&self, input: M) -> Fallible<Arc<dyn ExecutableResourceTrait<M>>>;
 
     fn upload_dyn(&self, resource: &dyn ExecutableResourceTrait<M>, input: M) -> Fallible<()>;
+
+    /// Creates a coroutine running `body` on its own stack, the code it calls can come from this runtime or from native functions.
+    fn create_coroutine(&self, body: CoroutineBody) -> Fallible<Box<dyn CoroutineTrait>>;
+}
+/// How a coroutine gave the control back to the code resuming it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoroutineState {
+    Yielded(usize),
+    Returned(usize),
+}
+/// Suspends the running coroutine, given to its body.
+pub trait CoroutineYielder {
+    /// Gives `value` to the code resuming the coroutine and returns the value of the next resume.
+    fn suspend(&self, value: usize) -> usize;
+}
+/// The body of a coroutine, called with the value of the first resume, it returns the value of the last one.
+pub type CoroutineBody = Box<dyn FnOnce(&dyn CoroutineYielder, usize) -> usize>;
+/// A function which can suspend itself in any frame and continue from there later.
+pub trait CoroutineTrait {
+    /// Continues the coroutine until it yields or returns, resuming a finished or running coroutine is an error.
+    fn resume(&mut self, value: usize) -> Fallible<CoroutineState>;
+
+    fn is_finished(&self) -> bool;
 }
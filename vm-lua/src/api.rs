@@ -0,0 +1,412 @@
+//! A typed interface for embedding Lua. [`ToLua`] and [`FromLua`] convert Rust values to and from Lua values, and
+//! [`LuaStateReference::call`] calls a Lua function with converted arguments, returning its results as a tuple.
+use std::{collections::HashMap, hash::Hash, rc::Rc};
+
+use failure::{Error, Fallible};
+use vm_core::{ObjectRef, Pointer, UnsizedArray};
+
+use crate::{
+    built_in::{load_chunk, read_string, return_values, to_boolean, to_number, to_string, type_name, with_env},
+    call_function, close,
+    error::LuaError,
+    get_field,
+    mem::{LuaClosureReference, LuaClosureRustType, LuaStateReference, LuaTable, LuaValue, LuaValueImpl},
+    native_closure_values, new_boolean, new_float, new_integer, new_meta_functions, new_native_closure, new_string, new_table, set_field, table_entries,
+    table_length,
+    user_data::{LuaUserData, UserData},
+};
+
+/// Converts a Rust value to a Lua value.
+pub trait ToLua {
+    fn to_lua(self, state: &LuaStateReference) -> Fallible<LuaValueImpl>;
+}
+/// Converts a Lua value to a Rust value, numbers and strings are converted to each other like Lua does.
+pub trait FromLua: Sized {
+    fn from_lua(state: &LuaStateReference, value: LuaValueImpl) -> Fallible<Self>;
+}
+/// Converts Rust values to the arguments or the results of a function, a tuple gives a value for each element.
+pub trait ToLuaMulti {
+    fn to_lua_multi(self, state: &LuaStateReference) -> Fallible<Vec<LuaValueImpl>>;
+}
+/// Converts the arguments or the results of a function to Rust values, the missing values are nil.
+pub trait FromLuaMulti: Sized {
+    fn from_lua_multi(state: &LuaStateReference, values: Vec<LuaValueImpl>) -> Fallible<Self>;
+}
+/// Any number of values of the same type, as arguments or results.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Variadic<T>(pub Vec<T>);
+/// A Lua function or closure held by Rust code.
+#[derive(Clone)]
+pub struct Function {
+    state: LuaStateReference,
+    value: LuaValueImpl,
+}
+impl Function {
+    pub fn call<R: FromLuaMulti>(&self, args: impl ToLuaMulti) -> Fallible<R> {
+        let rets = call_closing(&self.state, &self.value, &args.to_lua_multi(&self.state)?)?;
+        R::from_lua_multi(&self.state, rets)
+    }
+
+    pub fn value(&self) -> &LuaValueImpl {
+        &self.value
+    }
+}
+/// Calls a function from Rust, the `<close>` variables it left in scope when it raised an error are closed like
+/// `pcall` does.
+fn call_closing(state: &LuaStateReference, function: &LuaValueImpl, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let to_be_closed = close::to_be_closed_len(state);
+    call_function(function, args).or_else(|e| {
+        let error = LuaError::from_error(e).into_value(state.clone())?;
+        Err(LuaError::Value(close::close_on_error(state, to_be_closed, error)).into())
+    })
+}
+/// A Rust closure called by Lua, with the values of its arguments.
+pub type Callback = dyn Fn(&LuaStateReference, &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>>;
+/// The userdata owning the callback of a closure made by [`LuaStateReference::create_function`], the only value the
+/// closure carries. Like the other userdata it is finalized by [`crate::close_state`], which drops the callback.
+struct RustCallback(Rc<Callback>);
+impl LuaUserData for RustCallback {
+    fn name() -> &'static str {
+        "callback"
+    }
+}
+static CALL_CALLBACK: LuaClosureRustType = call_callback;
+extern "C" fn call_callback(state: LuaStateReference, closure: LuaClosureReference, args: &[LuaValueImpl]) -> Pointer<UnsizedArray<LuaValue>> {
+    return_values(run_callback(state, closure, args))
+}
+/// Runs the callback a closure made by [`LuaStateReference::create_function`] owns.
+fn run_callback(state: LuaStateReference, closure: LuaClosureReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let user_data = UserData::from_lua(&state, unsafe { native_closure_values(&closure) }[0].clone())?;
+    // the callback may be called again while it runs
+    let callback = user_data.borrow::<RustCallback>()?.0.clone();
+    callback(&state, args)
+}
+/// What [`LuaStateReference::call`] can call.
+pub trait IntoFunction {
+    fn into_function(self, state: &LuaStateReference) -> Fallible<LuaValueImpl>;
+}
+/// A global function, or a field of global tables like `string.format`.
+impl IntoFunction for &str {
+    fn into_function(self, state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        let mut names = self.split('.');
+        let mut value = get_field(unsafe { state.as_pointer().as_ref().get_global() }, &new_string(state.as_pointer(), names.next().unwrap().as_bytes())?);
+        for name in names {
+            let table = value.read_table().ok_or_else(|| format_err!("attempt to index a {} value ({})", type_name(&value), self))?;
+            value = get_field(table, &new_string(state.as_pointer(), name.as_bytes())?);
+        }
+        if value.read_function().is_none() && value.read_closure().is_none() {
+            return Err(format_err!("attempt to call a {} value ({})", type_name(&value), self));
+        }
+        Ok(value)
+    }
+}
+impl IntoFunction for LuaValueImpl {
+    fn into_function(self, _state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        Ok(self)
+    }
+}
+impl IntoFunction for &Function {
+    fn into_function(self, _state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        Ok(self.value.clone())
+    }
+}
+/// The main function of a chunk loaded by [`crate::load_code`].
+impl IntoFunction for &ObjectRef {
+    fn into_function(self, state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        crate::chunk_function(state.clone(), self.clone())
+    }
+}
+impl LuaStateReference {
+    /// Calls a function with the arguments converted from `args`, the Lua errors it raises are returned.
+    pub fn call<R: FromLuaMulti>(&self, function: impl IntoFunction, args: impl ToLuaMulti) -> Fallible<R> {
+        let function = function.into_function(self)?;
+        let rets = call_closing(self, &function, &args.to_lua_multi(self)?)?;
+        R::from_lua_multi(self, rets)
+    }
+
+    /// Compiles a chunk of source code, whose `_ENV` is `env` or else the global table, see [`crate::new_sandbox`].
+    pub fn load(&self, code: &str, env: Option<Pointer<LuaTable>>) -> Fallible<Function> {
+        let mut value = load_chunk(self, code.as_bytes(), code, "t")?;
+        if let Some(env) = env {
+            value = with_env(self.clone(), value, LuaValueImpl::encode_table(env))?;
+        }
+        Ok(Function { state: self.clone(), value })
+    }
+
+    /// Runs a chunk of source code and converts the values it returns.
+    pub fn eval<R: FromLuaMulti>(&self, code: &str) -> Fallible<R> {
+        self.load(code, None)?.call(())
+    }
+
+    pub fn global_value<T: FromLua>(&self, name: &str) -> Fallible<T> {
+        let global = unsafe { self.as_pointer().as_ref().get_global() };
+        T::from_lua(self, get_field(global, &new_string(self.as_pointer(), name.as_bytes())?))
+    }
+
+    pub fn set_global_value(&self, name: &str, value: impl ToLua) -> Fallible<()> {
+        let value = value.to_lua(self)?;
+        crate::add_global(self.clone(), new_string(self.as_pointer(), name.as_bytes())?, value)
+    }
+
+    /// Makes a Lua function of a Rust closure, which can capture values unlike the functions of
+    /// [`crate::add_global_function`]. The arguments and the results are converted, an error returned by the closure
+    /// is raised as a Lua error.
+    ///
+    /// The function is a native closure like the ones the libraries make, not another kind of function value:
+    /// the Rust closure is boxed in a userdata the native closure carries, which Lua code cannot reach. The objects of
+    /// a state are not collected, so the Rust closure is dropped when the state is closed, not when the function
+    /// becomes unreachable, see [`crate::close_state`].
+    pub fn create_function<A, R, F>(&self, function: F) -> Fallible<Function>
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti,
+        F: Fn(&LuaStateReference, A) -> Fallible<R> + 'static,
+    {
+        self.create_raw_function(move |state, args| function(state, A::from_lua_multi(state, args.to_vec())?)?.to_lua_multi(state))
+    }
+
+    /// Same as `create_function`, for a closure taking and returning the values as they are.
+    pub fn create_raw_function(&self, function: impl Fn(&LuaStateReference, &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> + 'static) -> Fallible<Function> {
+        let callback = RustCallback(Rc::new(function)).to_lua(self)?;
+        let value = new_native_closure(self.clone(), &CALL_CALLBACK, &[callback])?;
+        Ok(Function { state: self.clone(), value })
+    }
+
+    /// Makes a global function of a Rust closure, see `create_function`.
+    pub fn set_global_function<A, R, F>(&self, name: &str, function: F) -> Fallible<()>
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti,
+        F: Fn(&LuaStateReference, A) -> Fallible<R> + 'static,
+    {
+        let function = self.create_function(function)?;
+        self.set_global_value(name, function)
+    }
+}
+fn nil() -> LuaValueImpl {
+    LuaValueImpl::encode_nil(())
+}
+fn conversion_error(value: &LuaValueImpl, to: &str) -> Error {
+    format_err!("cannot convert a {} value to {}", type_name(value), to)
+}
+impl ToLua for LuaValueImpl {
+    fn to_lua(self, _state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        Ok(self)
+    }
+}
+impl FromLua for LuaValueImpl {
+    fn from_lua(_state: &LuaStateReference, value: LuaValueImpl) -> Fallible<Self> {
+        Ok(value)
+    }
+}
+impl ToLua for bool {
+    fn to_lua(self, _state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        Ok(new_boolean(self))
+    }
+}
+/// Like a condition, only `nil` and `false` are false.
+impl FromLua for bool {
+    fn from_lua(_state: &LuaStateReference, value: LuaValueImpl) -> Fallible<Self> {
+        Ok(to_boolean(&value))
+    }
+}
+impl ToLua for i64 {
+    fn to_lua(self, _state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        new_integer(self)
+    }
+}
+impl FromLua for i64 {
+    fn from_lua(_state: &LuaStateReference, value: LuaValueImpl) -> Fallible<Self> {
+        let number = to_number(&value).ok_or_else(|| conversion_error(&value, "i64"))?;
+        number.as_integer().ok_or_else(|| format_err!("number has no integer representation"))
+    }
+}
+macro_rules! integer_conversions {
+    ($($ty:ty),*) => {$(
+        impl ToLua for $ty {
+            fn to_lua(self, _state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+                new_integer(i64::try_from(self).map_err(|_| format_err!("{} does not fit in a Lua integer", self))?)
+            }
+        }
+        impl FromLua for $ty {
+            fn from_lua(state: &LuaStateReference, value: LuaValueImpl) -> Fallible<Self> {
+                let integer = i64::from_lua(state, value)?;
+                <$ty>::try_from(integer).map_err(|_| format_err!("{} is out of the range of {}", integer, stringify!($ty)))
+            }
+        }
+    )*};
+}
+integer_conversions!(i8, i16, i32, isize, u8, u16, u32, u64, usize);
+impl ToLua for f32 {
+    fn to_lua(self, _state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        new_float(self.into())
+    }
+}
+impl FromLua for f32 {
+    fn from_lua(_state: &LuaStateReference, value: LuaValueImpl) -> Fallible<Self> {
+        Ok(to_number(&value).ok_or_else(|| conversion_error(&value, "f32"))?.as_float() as f32)
+    }
+}
+impl ToLua for f64 {
+    fn to_lua(self, _state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        new_float(self)
+    }
+}
+impl FromLua for f64 {
+    fn from_lua(_state: &LuaStateReference, value: LuaValueImpl) -> Fallible<Self> {
+        Ok(to_number(&value).ok_or_else(|| conversion_error(&value, "f64"))?.as_float())
+    }
+}
+impl ToLua for &str {
+    fn to_lua(self, state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        new_string(state.as_pointer(), self.as_bytes())
+    }
+}
+impl ToLua for String {
+    fn to_lua(self, state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        new_string(state.as_pointer(), self.as_bytes())
+    }
+}
+impl FromLua for String {
+    fn from_lua(_state: &LuaStateReference, value: LuaValueImpl) -> Fallible<Self> {
+        let bytes = match read_string(&value) {
+            Some(bytes) => bytes,
+            None if to_number(&value).is_some() => to_string(&value),
+            None => return Err(conversion_error(&value, "String")),
+        };
+        String::from_utf8(bytes).map_err(|_| format_err!("string is not valid UTF-8"))
+    }
+}
+/// `None` is nil.
+impl<T: ToLua> ToLua for Option<T> {
+    fn to_lua(self, state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        match self {
+            Some(value) => value.to_lua(state),
+            None => Ok(nil()),
+        }
+    }
+}
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(state: &LuaStateReference, value: LuaValueImpl) -> Fallible<Self> {
+        match value.read_nil() {
+            Some(_) => Ok(None),
+            None => Ok(Some(T::from_lua(state, value)?)),
+        }
+    }
+}
+impl ToLua for Pointer<LuaTable> {
+    fn to_lua(self, _state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        Ok(LuaValueImpl::encode_table(self))
+    }
+}
+impl FromLua for Pointer<LuaTable> {
+    fn from_lua(_state: &LuaStateReference, value: LuaValueImpl) -> Fallible<Self> {
+        value.read_table().ok_or_else(|| conversion_error(&value, "table"))
+    }
+}
+/// A sequence from 1.
+impl<T: ToLua> ToLua for Vec<T> {
+    fn to_lua(self, state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        let table = new_table(new_meta_functions()?, 0, true)?.as_pointer();
+        for (index, value) in self.into_iter().enumerate() {
+            set_field(table.clone(), new_integer(index as i64 + 1)?, value.to_lua(state)?)?;
+        }
+        Ok(LuaValueImpl::encode_table(table))
+    }
+}
+/// The values from 1 to the length of a table, without its `__index` and `__len` meta functions.
+impl<T: FromLua> FromLua for Vec<T> {
+    fn from_lua(state: &LuaStateReference, value: LuaValueImpl) -> Fallible<Self> {
+        let table = value.read_table().ok_or_else(|| conversion_error(&value, "Vec"))?;
+        (1..=table_length(table.clone())?).map(|index| T::from_lua(state, get_field(table.clone(), &new_integer(index)?))).collect()
+    }
+}
+impl<K: ToLua, V: ToLua> ToLua for HashMap<K, V> {
+    fn to_lua(self, state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        let table = new_table(new_meta_functions()?, self.len(), true)?.as_pointer();
+        for (key, value) in self {
+            let key = key.to_lua(state)?;
+            if key.read_nil().is_some() {
+                return Err(format_err!("table index is nil"));
+            }
+            set_field(table.clone(), key, value.to_lua(state)?)?;
+        }
+        Ok(LuaValueImpl::encode_table(table))
+    }
+}
+impl<K: FromLua + Eq + Hash, V: FromLua> FromLua for HashMap<K, V> {
+    fn from_lua(state: &LuaStateReference, value: LuaValueImpl) -> Fallible<Self> {
+        let table = value.read_table().ok_or_else(|| conversion_error(&value, "HashMap"))?;
+        table_entries(table)?.into_iter().map(|(key, value)| Ok((K::from_lua(state, key)?, V::from_lua(state, value)?))).collect()
+    }
+}
+impl ToLua for Function {
+    fn to_lua(self, _state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        Ok(self.value)
+    }
+}
+impl FromLua for Function {
+    fn from_lua(state: &LuaStateReference, value: LuaValueImpl) -> Fallible<Self> {
+        if value.read_function().is_none() && value.read_closure().is_none() {
+            return Err(conversion_error(&value, "function"));
+        }
+        Ok(Function { state: state.clone(), value })
+    }
+}
+impl<T: ToLua> ToLuaMulti for T {
+    fn to_lua_multi(self, state: &LuaStateReference) -> Fallible<Vec<LuaValueImpl>> {
+        Ok(vec![self.to_lua(state)?])
+    }
+}
+/// The first value.
+impl<T: FromLua> FromLuaMulti for T {
+    fn from_lua_multi(state: &LuaStateReference, values: Vec<LuaValueImpl>) -> Fallible<Self> {
+        T::from_lua(state, values.into_iter().next().unwrap_or_else(nil))
+    }
+}
+impl ToLuaMulti for () {
+    fn to_lua_multi(self, _state: &LuaStateReference) -> Fallible<Vec<LuaValueImpl>> {
+        Ok(Vec::new())
+    }
+}
+/// Ignores the values.
+impl FromLuaMulti for () {
+    fn from_lua_multi(_state: &LuaStateReference, _values: Vec<LuaValueImpl>) -> Fallible<Self> {
+        Ok(())
+    }
+}
+impl<T: ToLua> ToLuaMulti for Variadic<T> {
+    fn to_lua_multi(self, state: &LuaStateReference) -> Fallible<Vec<LuaValueImpl>> {
+        self.0.into_iter().map(|value| value.to_lua(state)).collect()
+    }
+}
+impl<T: FromLua> FromLuaMulti for Variadic<T> {
+    fn from_lua_multi(state: &LuaStateReference, values: Vec<LuaValueImpl>) -> Fallible<Self> {
+        Ok(Variadic(values.into_iter().map(|value| T::from_lua(state, value)).collect::<Fallible<_>>()?))
+    }
+}
+macro_rules! tuple_conversions {
+    ($($name:ident),+) => {
+        impl<$($name: ToLua),+> ToLuaMulti for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn to_lua_multi(self, state: &LuaStateReference) -> Fallible<Vec<LuaValueImpl>> {
+                let ($($name,)+) = self;
+                Ok(vec![$($name.to_lua(state)?),+])
+            }
+        }
+        impl<$($name: FromLua),+> FromLuaMulti for ($($name,)+) {
+            fn from_lua_multi(state: &LuaStateReference, values: Vec<LuaValueImpl>) -> Fallible<Self> {
+                let mut values = values.into_iter();
+                Ok(($(<$name>::from_lua(state, values.next().unwrap_or_else(nil))?,)+))
+            }
+        }
+    };
+}
+tuple_conversions!(A);
+tuple_conversions!(A, B);
+tuple_conversions!(A, B, C);
+tuple_conversions!(A, B, C, D);
+tuple_conversions!(A, B, C, D, E);
+tuple_conversions!(A, B, C, D, E, F);
+tuple_conversions!(A, B, C, D, E, F, G);
+tuple_conversions!(A, B, C, D, E, F, G, H);
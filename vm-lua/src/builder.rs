@@ -2,6 +2,7 @@ use super::ir::I64ToF64;
 use super::{ir, ir::*, lua_lexical::*};
 use crate::error::LuaVMError;
 use crate::instruction::{BreakPoint, CallFunction0VaSliceRet1, CallFunctionVaSliceRet1, ForInLoopJump, GetField, GetRet, GetVaArgs, NewUpValue, Return0VaSlice, ReturnVaSlice, SetElement, SetUpRef, SetUpValue};
+use crate::instruction::{CloseVariables, PushToBeClosed};
 use crate::instruction::{GetArg, InlineCacheLineImpl};
 use crate::{instruction::{BranchIf, ConstM1, ConstNil, ConstZero, F64ToValue, I64ToValue}, mem::*};
 use e::{Goto, F64, U8};
@@ -69,6 +70,15 @@ pub struct LuaExpr<'l> {
     register: LuaRegister<'l>,
     #[builder(default)]
     lifetime: ExprLifeTimeKind,
+    /// The values after the first one, which the expression gives to the list it ends.
+    #[builder(default)]
+    rest: Option<LuaExprRest>,
+}
+/// The values of a call or of `...` after the first one.
+#[derive(Clone, Debug)]
+pub enum LuaExprRest {
+    VaArgs,
+    Rets(Register<Pointer<UnsizedArray<LuaValue>>>),
 }
 
 impl<'l> LuaExpr<'l> {
@@ -76,6 +86,7 @@ impl<'l> LuaExpr<'l> {
         Rc::new(Self {
             lifetime: Default::default(),
             register: LuaRegister::Value(reg, None),
+            rest: None,
         })
     }
     pub fn value_reg(&self) -> &Register<LuaValue> {
@@ -90,6 +101,8 @@ pub type LuaExprRef<'l> = Rc<LuaExpr<'l>>;
 pub enum VaArgs<'l> {
     VaArgs(),
     FunctionCall(LuaExprRef<'l>, Box<LuaExprList<'l>>),
+    /// A call already made, its first value and its results.
+    Rets(LuaExprRef<'l>, Register<Pointer<UnsizedArray<LuaValue>>>),
 }
 #[derive(Debug, Builder)]
 #[builder(pattern = "owned")]
@@ -162,20 +175,58 @@ impl VarAttribute {
         })
     }
 }
+fn check_attributes(names: &[(String, VarAttribute)]) -> Fallible<()> {
+    if names.iter().filter(|(_, attr)| attr.is_close).count() > 1 {
+        return Err(LuaVMError::SyntaxError(format_err!("multiple to-be-closed variables in local list")).into());
+    }
+    Ok(())
+}
 pub type LuaScoptRef<'l> = Rc<GhostCell<'l, LuaScopt<'l>>>;
 pub struct LuaScopt<'l> {
     variables: HashMap<String, LuaVariable<'l>>,
     kind: ScoptKind<'l>,
+    /// The names of the locals in the order they are declared.
+    locals: Vec<String>,
+    /// How many `<close>` variables are declared.
+    to_be_closed: usize,
+    labels: HashMap<String, LuaLabel<'l>>,
+    /// A `goto` resolved by a label of the scope jumps into the scope of a local, which is only allowed when
+    /// the label ends the block.
+    jump_into_local: Option<LuaJumpIntoLocal<'l>>,
+    /// The block ends with a return, which already closed the `<close>` variables.
+    returned: bool,
 }
 impl<'l> LuaScopt<'l> {
     pub fn new(kind: ScoptKind<'l>) -> Self {
         Self {
             variables: HashMap::new(),
             kind,
+            locals: Vec::new(),
+            to_be_closed: 0,
+            labels: HashMap::new(),
+            jump_into_local: None,
+            returned: false,
         }
     }
 }
 #[derive(Clone)]
+pub struct LuaLabel<'l> {
+    block: LuaBlockRef<'l>,
+    to_be_closed: usize,
+}
+/// A `goto` waiting for its label, with the scopes it is in and how many locals and `<close>` variables each
+/// had at the `goto`.
+pub struct LuaGoto<'l> {
+    block: LuaBlockRef<'l>,
+    scopts: Vec<(LuaScoptRef<'l>, usize, usize)>,
+}
+pub struct LuaJumpIntoLocal<'l> {
+    label_block: LuaBlockRef<'l>,
+    /// How many blocks the function had after the label.
+    blocks: usize,
+    message: String,
+}
+#[derive(Clone)]
 pub struct LuaVariable<'l> {
     expr: LuaExprRef<'l>,
     attributes: VarAttribute,
@@ -208,8 +259,7 @@ pub struct LuaFunctionBuilder<'l> {
     current_scopt: LuaScoptRef<'l>,
     blocks: Vec<LuaBlockRef<'l>>,
     current_block: LuaBlockRef<'l>,
-    goto_blocks: HashMap<String, Vec<LuaBlockRef<'l>>>,
-    label_blocks: HashMap<String, LuaBlockRef<'l>>,
+    pending_gotos: HashMap<String, Vec<LuaGoto<'l>>>,
     child_closure_slot_map: HashMap<(String, usize), usize>,
     new_child_closure_slot_map: HashMap<String, (usize, usize)>,
     parent_closure_slot_map: HashMap<String, usize>,
@@ -229,8 +279,7 @@ impl<'l> LuaFunctionBuilder<'l> {
             scopts: vec![new_scopt],
             current_block: new_block.clone(),
             blocks: vec![new_block],
-            goto_blocks: Default::default(),
-            label_blocks: Default::default(),
+            pending_gotos: Default::default(),
             child_closure_slot_map: Default::default(),
             parent_closure_slot_map: Default::default(),
             new_child_closure_slot_map: Default::default(),
@@ -251,6 +300,12 @@ impl<'l> LuaFunctionBuilder<'l> {
         self.current_scopt = new_scopt_wraped.clone();
         new_scopt_wraped
     }
+    fn check_gotos(&self) -> Fallible<()> {
+        match self.pending_gotos.keys().next() {
+            Some(name) => Err(LuaVMError::SyntaxError(format_err!("no visible label '{}' for <goto>", name)).into()),
+            None => Ok(()),
+        }
+    }
 }
 #[macro_export]
 macro_rules! unique_operate_type {
@@ -266,12 +321,28 @@ macro_rules! binary_operate_type {
 }
 pub const LUA_STATE_REG: Register<LuaStateReference> = Register::new_const(0);
 pub const LUA_ARGS_REG: Register<Slice<LuaValue>> = Register::new_const(1);
-pub const LUA_CLOSURE_REG: Register<LuaClosureReference> = Register::new_const(2);
-pub const LUA_UP_VALUES_REG: Register<LuaUpValueReference> = Register::new_const(3);
-pub const LUA_PIN_REG_COUNT: u16 = 4;
+/// The closure of a function made in a chunk, which is passed before its arguments.
+pub const LUA_CLOSURE_REG: Register<LuaClosureReference> = Register::new_const(1);
+pub const LUA_CLOSURE_ARGS_REG: Register<Slice<LuaValue>> = Register::new_const(2);
+pub const LUA_UP_VALUES_REG: Register<LuaUpValueReference> = Register::new_const(4);
+pub const LUA_PIN_REG_COUNT: u16 = 5;
+
+/// The name of the environment the global names are looked up in, a local of every chunk.
+pub const ENV: &str = "_ENV";
 
-pub(crate) fn new_ctx<'l>(token: ghost_cell::GhostToken<'l>, lua_state: LuaStateReference) -> LuaContext<'l> {
-    LuaContext::new(token, lua_state)
+pub(crate) fn new_ctx(token: ghost_cell::GhostToken<'_>, lua_state: LuaStateReference) -> Fallible<LuaContext<'_>> {
+    let mut ctx = LuaContext::new(token, lua_state);
+    // a chunk is a vararg function, `...` are the values it is called with
+    let main_function = ctx.current_function.clone();
+    let main_function = main_function.borrow_mut(ctx.token_mut());
+    main_function.va_param = true;
+    main_function.register_pool = BuddyRegisterPool::reserve_range(0..LUA_PIN_REG_COUNT + 2);
+    GetVaArgs::emit(&ctx.current_builder, &mut ctx.token, Usize(0), &LUA_ARGS_REG, &Register::new_const(LUA_PIN_REG_COUNT))?;
+    let env = ctx.alloc_register()?;
+    GetEnv::emit(&ctx.current_builder, &mut ctx.token, &LUA_STATE_REG, &env)?;
+    CheckQuotas::emit(&ctx.current_builder, &mut ctx.token, &LUA_STATE_REG)?;
+    ctx.add_local(ENV.to_string(), Default::default(), LuaExpr::new_value(env))?;
+    Ok(ctx)
 }
 pub struct LuaContext<'l> {
     pub token: GhostToken<'l>,
@@ -281,7 +352,6 @@ pub struct LuaContext<'l> {
     pub current_scopt: LuaScoptRef<'l>,
     pub current_block: LuaBlockRef<'l>,
     pub current_builder: BlockBuilder<'l, LuaInstructionSet>,
-    pub shape_map: HashMap<(Vec<String>, usize), LuaShapeReference>,
     pub lua_state: LuaStateReference,
 }
 impl<'l> LuaContext<'l> {
@@ -299,7 +369,6 @@ impl<'l> LuaContext<'l> {
             current_scopt: new_scopt,
             current_block: new_block,
             current_builder: new_builder,
-            shape_map: Default::default(),
             packs: Default::default(),
             lua_state,
         }
@@ -344,8 +413,9 @@ impl<'l> LuaContext<'l> {
                         )
                     } else {
                         let child_closure_slot_map = &mut function.borrow_mut(&mut self.token).child_closure_slot_map;
-                        let slot = child_closure_slot_map.len();
-                        child_closure_slot_map.insert((name.clone(), scopt_index), slot);
+                        // a variable keeps its slot once a closure uses it
+                        let len = child_closure_slot_map.len();
+                        let slot = *child_closure_slot_map.entry((name.clone(), scopt_index)).or_insert(len);
                         function
                             .borrow_mut(&mut self.token)
                             .new_child_closure_slot_map
@@ -372,6 +442,7 @@ impl<'l> LuaContext<'l> {
             }
         }
         trace!("get global value {}", &name);
+        let env = self.env()?;
         let reg = self.alloc_register()?;
         let name = self.const_string_value(name)?;
         let cache = self.empty_inline_cache_line()?;
@@ -380,7 +451,7 @@ impl<'l> LuaContext<'l> {
             &mut self.token,
             name,
             cache,
-            &LUA_STATE_REG,
+            env.value_reg(),
             &reg,
         )?;
         Ok(Rc::new(
@@ -394,6 +465,11 @@ impl<'l> LuaContext<'l> {
         for (closure_index, function) in self.closure_stack.clone().iter().rev().enumerate() {
             for (scopt_index, scopt) in function.borrow(self.token()).scopts.clone().iter().enumerate().rev() {
                 if let Some(variable) = scopt.borrow(self.token()).variables.get(&name).cloned() {
+                    if variable.attributes.is_const || variable.attributes.is_close {
+                        return Err(
+                            LuaVMError::SyntaxError(format_err!("attempt to assign to const variable '{}'", name)).into()
+                        );
+                    }
                     if closure_index == 0 {
                         let operate_kind = Self::trans_binary_type(true, true, &value, &variable.expr);
                         let from = Self::transform_expr(self, value.clone(), operate_kind)?;
@@ -430,8 +506,8 @@ impl<'l> LuaContext<'l> {
                     } else {
                         let value = self.to_value(value)?;
                         let child_closure_slot_map = &mut function.borrow_mut(&mut self.token).child_closure_slot_map;
-                        let slot = child_closure_slot_map.len();
-                        child_closure_slot_map.insert((name.clone(), scopt_index), slot);
+                        let len = child_closure_slot_map.len();
+                        let slot = *child_closure_slot_map.entry((name.clone(), scopt_index)).or_insert(len);
                         function
                             .borrow_mut(&mut self.token)
                             .new_child_closure_slot_map
@@ -454,14 +530,36 @@ impl<'l> LuaContext<'l> {
         match &value.register {
             LuaRegister::Value(r, _) => {
                 trace!("put global value {:?}<-{:?}", &name, &value);
+                let env = self.env()?;
                 let name = self.const_string_value(name)?;
                 let cache = self.empty_inline_cache_line()?;
-                SetGlobal::emit(&self.current_builder, &mut self.token, name, cache, &LUA_STATE_REG, r)?;
+                SetGlobal::emit(&self.current_builder, &mut self.token, name, cache, env.value_reg(), r)?;
             }
             _ => unreachable!(),
         }
         Ok(())
     }
+    /// The `_ENV` in scope, which is the one of the chunk unless a local shadows it. The functions of the chunk read
+    /// it from their closure, which takes it when it is made.
+    fn env(&mut self) -> Fallible<LuaExprRef<'l>> {
+        let declared_in = self.closure_stack.iter().rev().enumerate().find_map(|(closure_index, function)| {
+            let scopts = &function.borrow(self.token()).scopts;
+            let scopt_index = scopts.iter().rposition(|scopt| scopt.borrow(self.token()).variables.contains_key(ENV))?;
+            Some((closure_index, scopt_index))
+        });
+        match declared_in {
+            None => Err(format_err!("no {} in scope", ENV)),
+            Some((closure_index, 0)) if closure_index != 0 && closure_index == self.closure_stack.len() - 1 => {
+                let reg = self.alloc_register()?;
+                GetClosureEnv::emit(&self.current_builder, &mut self.token, &LUA_CLOSURE_REG, &reg)?;
+                Ok(LuaExpr::new_value(reg))
+            }
+            Some(_) => {
+                let env = self.get_value(ENV.to_string())?;
+                self.to_value(env)
+            }
+        }
+    }
     pub fn insert_break_point(&mut self, block: &LuaBlockRef<'l>) -> Fallible<()> {
         let builder = &block.borrow(self.token()).clone().builder().clone();
         BreakPoint::emit(builder, &mut self.token)?;
@@ -541,13 +639,22 @@ impl<'l> LuaContext<'l> {
         let reg = self.alloc_register()?;
         let va_args = self.va_args()?;
         GetArg::emit(&self.current_builder, &mut self.token, Usize(0), &va_args, &reg)?;
-        Ok(LuaExpr::new_value(reg))
+        Ok(Rc::new(
+            LuaExprBuilder::default()
+                .register(LuaRegister::Value(reg, None))
+                .rest(Some(LuaExprRest::VaArgs))
+                .build()?,
+        ))
     }
     pub fn const_table(&mut self, table_decl: Vec<(LuaTableKey<'l>, LuaExprRef<'l>)>) -> Fallible<LuaExprRef<'l>> {
         let reg = self.alloc_register()?;
         let mut string_key_values = Vec::new();
         let mut int_key_values = Vec::new();
         let mut expr_key_values = Vec::new();
+        let rest = match table_decl.last() {
+            Some((LuaTableKey::None, value)) => value.rest.clone(),
+            _ => None,
+        };
         for (key, value) in table_decl {
             match key {
                 LuaTableKey::None => int_key_values.push(value),
@@ -556,57 +663,13 @@ impl<'l> LuaContext<'l> {
             }
         }
         string_key_values.sort_by(|(k0, _), (k1, _)| k0.cmp(k1));
+        let array_len = int_key_values.len();
         if int_key_values.is_empty() && string_key_values.is_empty() {
             MakeTable0::emit(&self.current_builder, &mut self.token, &LUA_STATE_REG, &reg)?;
         } else {
-            let shape = if int_key_values.is_empty() {
-                let keys = (
-                    string_key_values.iter().map(|(k, _v)| k.clone()).collect(),
-                    int_key_values.len(),
-                );
-                if let Some(shape) = self.shape_map.get(&keys) {
-                    shape.clone()
-                } else {
-                    let shape = crate::new_shape(crate::new_meta_functions()?, false)?;
-                    unsafe {
-                        let hash_map = shape.as_pointer().as_ref().ref_fields().get().as_mut().unwrap();
-                        for (key, _expr) in string_key_values.iter() {
-                            let key = self.const_string_value(key.clone())?;
-                            let len = hash_map.len();
-                            let mut slot_metadata = MaybeUninit::<LuaSlotMetadataImpl>::zeroed();
-                            let slot_metadata_ref = slot_metadata.assume_init_mut();
-                            slot_metadata_ref.set_slot(Usize(len));
-                            hash_map.insert(key, slot_metadata.assume_init_read());
-                        }
-                    }
-                    self.shape_map.insert(keys, shape.clone());
-                    shape
-                }
-            } else {
-                let shape = crate::new_shape(crate::new_meta_functions()?, true)?;
-                unsafe {
-                    let hash_map = shape.as_pointer().as_ref().ref_fields().get().as_mut().unwrap();
-                    for (key, _expr) in string_key_values.iter() {
-                        let key = self.const_string_value(key.to_owned())?;
-                        let len = hash_map.len();
-                        let mut slot_metadata = MaybeUninit::<LuaSlotMetadataImpl>::zeroed();
-                        let slot_metadata_ref = slot_metadata.assume_init_mut();
-                        slot_metadata_ref.set_slot(Usize(len));
-                        hash_map.insert(key, slot_metadata.assume_init_read());
-                    }
-                    for (key, _expr) in int_key_values.iter().enumerate() {
-                        assert!(key + 1 < 1 << (usize::BITS - 4));
-                        let key = LuaValueImpl::encode_integer(I64((1 + (key as i64)) << 4));
-                        let len = hash_map.len();
-                        let mut slot_metadata = MaybeUninit::<LuaSlotMetadataImpl>::zeroed();
-                        let slot_metadata_ref = slot_metadata.assume_init_mut();
-                        slot_metadata_ref.set_slot(Usize(len));
-                        hash_map.insert(key, slot_metadata.assume_init_read());
-                    }
-                }
-                shape
-            };
-            let fast_len = string_key_values.len() + int_key_values.len();
+            let keys: Vec<String> = string_key_values.iter().map(|(k, _v)| k.clone()).collect();
+            let shape = crate::constructor_shape(&self.lua_state, keys)?;
+            let fast_len = string_key_values.len();
             let base_len = LuaTableImpl::LAYOUT.size();
             let var_len = LuaTableImpl::LAYOUT.flexible_size();
             let table_mem_size = base_len + fast_len * var_len;
@@ -616,7 +679,7 @@ impl<'l> LuaContext<'l> {
             for (_key, value) in string_key_values {
                 fields.push(self.to_value(value)?.value_reg().clone());
             }
-            for (_key, value) in int_key_values.into_iter().enumerate() {
+            for value in int_key_values {
                 fields.push(self.to_value(value)?.value_reg().clone());
             }
             let array_reg = self.alloc_array::<LuaValue>(fields.len())?;
@@ -628,6 +691,7 @@ impl<'l> LuaContext<'l> {
                 &mut self.token,
                 shape.as_pointer(),
                 Usize(fast_len),
+                Usize(array_len),
                 &fields_reg,
                 &reg,
             )?;
@@ -645,6 +709,17 @@ impl<'l> LuaContext<'l> {
                 value.value_reg(),
             )?;
         }
+        // all the values of the call or `...` ending the positional fields, from the index of its first one
+        match rest {
+            None => {}
+            Some(LuaExprRest::VaArgs) => {
+                let va_args = self.va_args()?;
+                SetListVaSlice::emit(&self.current_builder, &mut self.token, Usize(array_len), &reg, &va_args)?;
+            }
+            Some(LuaExprRest::Rets(rets)) => {
+                SetListVA::emit(&self.current_builder, &mut self.token, Usize(array_len), &reg, &rets)?;
+            }
+        }
         Ok(LuaExpr::new_value(reg))
     }
     pub fn emit_return(&mut self, exprs: Option<LuaExprList<'l>>) -> Fallible<()> {
@@ -652,6 +727,10 @@ impl<'l> LuaContext<'l> {
         if let Some(exprs) = exprs {
             let LuaExprList { exprs, va_arg } = exprs;
             let exprs: Vec<_> = exprs.into_iter().map(|arg| self.to_value(arg)).try_collect()?;
+            // a call in the last expression is made before closing
+            if !matches!(va_arg, Some(VaArgs::FunctionCall(..) | VaArgs::Rets(..))) {
+                self.close_function_variables()?;
+            }
             match va_arg {
                 Some(VaArgs::VaArgs()) => {
                     let va_args = self.va_args()?;
@@ -693,8 +772,9 @@ impl<'l> LuaContext<'l> {
                         }
                     };
                 }
-                Some(VaArgs::FunctionCall(function, args)) => {
-                    let va_args = self.emit_call(function, *args)?;
+                Some(va_arg @ (VaArgs::FunctionCall(..) | VaArgs::Rets(..))) => {
+                    let va_args = self.emit_va_rets(va_arg)?;
+                    self.close_function_variables()?;
                     match exprs.len() {
                         0 => Return0VA::emit(&self.current_builder, &mut self.token, &va_args)?,
                         1 => Return1VA::emit(&self.current_builder, &mut self.token, exprs[0].value_reg(), &va_args)?,
@@ -760,17 +840,70 @@ impl<'l> LuaContext<'l> {
                 },
             }
         } else {
+            self.close_function_variables()?;
             Return0::emit(&self.current_builder, &mut self.token)?;
         }
         Ok(())
     }
+    /// Closes the `<close>` variables and the up values of the scopes of the current function before it returns.
+    fn close_function_variables(&mut self) -> Fallible<()> {
+        for (scopt_index, scopt) in self.current_function().scopts.clone().iter().enumerate() {
+            self.close_up_values(scopt, scopt_index)?;
+        }
+        let count = self.current_function().scopts.iter().map(|scopt| scopt.borrow(self.token()).to_be_closed).sum();
+        self.close_variables(count)
+    }
+    /// Moves the variables of the scope `scopt_index` that closures use into their up values, the closures keep them
+    /// after the registers are reused.
+    fn close_up_values(&mut self, scopt: &LuaScoptRef<'l>, scopt_index: usize) -> Fallible<()> {
+        // the slots stay allocated, the closures made in the scope keep using them
+        for ((name, _scopt_index), slot) in self
+            .current_function()
+            .child_closure_slot_map
+            .iter()
+            .filter(|((_name, var_scopt_index), _)| *var_scopt_index == scopt_index)
+            .map(|(key, slot)| (key.clone(), *slot))
+            .collect::<Vec<_>>()
+        {
+            let mut expr = scopt
+                .borrow_mut(self.token_mut())
+                .variables
+                .get_mut(&name)
+                .unwrap()
+                .expr
+                .clone();
+            if !matches!(&expr.register, LuaRegister::Value(_, _)) {
+                expr = self.to_value(expr)?;
+            }
+            SetUpValue::emit(
+                &self.current_builder,
+                &mut self.token,
+                Usize(slot),
+                &LUA_UP_VALUES_REG,
+                expr.value_reg(),
+            )?;
+        }
+        Ok(())
+    }
+    fn close_variables(&mut self, count: usize) -> Fallible<()> {
+        if count != 0 {
+            CloseVariables::emit(&self.current_builder, &mut self.token, Usize(count), &LUA_STATE_REG)?;
+        }
+        Ok(())
+    }
     pub fn return_(
         &mut self,
         exprs: Option<LuaExprList<'l>>,
         (pre_block, post_block): (LuaBlockRef<'l>, LuaBlockRef<'l>),
     ) -> Fallible<(LuaBlockRef<'l>, LuaBlockRef<'l>)> {
+        // the values are computed before the block is split, the return ends the same block
+        let pre_builder = pre_block.borrow(self.token()).builder().clone();
+        let post_builder = std::mem::replace(&mut self.current_builder, pre_builder);
         self.emit_return(exprs)?;
-        Ok((pre_block, post_block))
+        self.current_builder = post_builder;
+        self.current_scopt_mut().returned = true;
+        // the statement the block belongs to continues from a block that is never reached
+        Ok((post_block, self.new_block().clone()))
     }
     pub fn new_function(&mut self, parameters: Vec<String>, va_param: bool) -> Fallible<LuaFunctionBuilderRef<'l>> {
         let new_function = LuaFunctionBuilder::new();
@@ -786,16 +919,18 @@ impl<'l> LuaContext<'l> {
             let new_function = new_function.borrow_mut(self.token_mut());
             new_function.parameters = parameters.clone();
             new_function.va_param = va_param;
+            // the parameters and the slice of the varargs after them, which takes two registers
             new_function.register_pool = BuddyRegisterPool::reserve_range(
-                0..(LUA_PIN_REG_COUNT as usize + new_function.parameters.len()).try_into()?,
+                0..(LUA_PIN_REG_COUNT as usize + new_function.parameters.len() + 2).try_into()?,
             );
         }
+        CheckQuotas::emit(&self.current_builder, &mut self.token, &LUA_STATE_REG)?;
         let va_args_reg = Register::new_const((LUA_PIN_REG_COUNT as usize + parameters.len()).try_into()?);
         GetVaArgs::emit(
             &self.current_builder,
             &mut self.token,
             Usize(parameters.len().try_into()?),
-            &LUA_ARGS_REG,
+            &LUA_CLOSURE_ARGS_REG,
             &va_args_reg,
         )?;
         for (index, param) in parameters.into_iter().enumerate() {
@@ -804,8 +939,8 @@ impl<'l> LuaContext<'l> {
             GetArg::emit(
                 &self.current_builder,
                 &mut self.token,
-                Usize(reg_index as usize),
-                &LUA_ARGS_REG,
+                Usize(index),
+                &LUA_CLOSURE_ARGS_REG,
                 &reg,
             )?;
             new_scopt
@@ -830,6 +965,7 @@ impl<'l> LuaContext<'l> {
     ) -> Fallible<LuaFunctionBuilderRef<'l>> {
         debug!("finish_function");
         let function = self.closure_stack.pop().unwrap();
+        function.borrow(self.token()).check_gotos()?;
         function.borrow_mut(self.token_mut()).blocks.pop().unwrap();
         let builder = finish_block.borrow(self.token()).builder().clone();
         Return0::emit(&builder, &mut self.token)?;
@@ -849,33 +985,34 @@ impl<'l> LuaContext<'l> {
         let scopt_index = current_function.scopts.len();
         let current_scopt = current_function.scopts.last().unwrap().clone();
         current_function.current_scopt = current_scopt.clone();
-        for ((name, _scopt_index), slot) in current_function
-            .child_closure_slot_map
-            .drain_filter(|(_name, var_scopt_index), _| *var_scopt_index == scopt_index)
-            .collect::<HashMap<_, _>>()
-        {
-            let mut expr = scopt
-                .borrow_mut(self.token_mut())
-                .variables
-                .get_mut(&name)
-                .unwrap()
-                .expr
-                .clone();
-            if !matches!(&expr.register, LuaRegister::Value(_, _)) {
-                expr = self.to_value(expr)?;
-            }
-            SetUpValue::emit(
-                &self.current_builder,
-                &mut self.token,
-                Usize(slot),
-                &LUA_UP_VALUES_REG,
-                expr.value_reg(),
-            )?;
+        // the scope is left at the end of its last block
+        let last_builder = block_split.0.borrow(self.token()).builder().clone();
+        let post_builder = std::mem::replace(&mut self.current_builder, last_builder);
+        self.close_up_values(&scopt, scopt_index)?;
+        let (to_be_closed, returned) = {
+            let scopt = scopt.borrow(self.token());
+            (scopt.to_be_closed, scopt.returned)
+        };
+        if !returned {
+            self.close_variables(to_be_closed)?;
         }
+        self.current_builder = post_builder;
         self.current_scopt = current_scopt;
         Ok(block_split)
     }
+    // [] at the end of a stat_list
+    pub fn end_statements(&mut self) -> Fallible<()> {
+        if let Some(jump) = self.current_scopt_mut().jump_into_local.take() {
+            let label_ends_block = jump.blocks == self.current_function().blocks.len()
+                && jump.label_block.borrow(self.token()).builder().borrow(self.token()).len() == 0;
+            if !label_ends_block {
+                return Err(LuaVMError::SyntaxError(format_err!("{}", jump.message)).into());
+            }
+        }
+        Ok(())
+    }
     pub fn pack(mut self) -> Fallible<Vec<FunctionPack<LuaInstructionSet>>> {
+        self.current_function().check_gotos()?;
         let mut function_builder = FunctionBuilder::new();
         match self.current_function().child_closure_slot_map.len() {
             0 => {}
@@ -885,7 +1022,7 @@ impl<'l> LuaContext<'l> {
                 NewUpValue::emit(&entry_block, &mut self.token, Usize(o), &LUA_UP_VALUES_REG)?;
                 let first_block_builder = first_block.borrow(self.token()).builder().clone();
                 Goto::emit(&entry_block, &mut self.token, &first_block_builder)?;
-                function_builder.add_block(first_block_builder);
+                function_builder.add_block(entry_block);
             }
         }
         for block in self.current_function().blocks.clone().iter() {
@@ -921,7 +1058,7 @@ impl<'l> LuaContext<'l> {
                 NewUpValue::emit(&entry_block, &mut self.token, Usize(o), &LUA_UP_VALUES_REG)?;
                 let first_block_builder = first_block.borrow(self.token()).builder().clone();
                 Goto::emit(&entry_block, &mut self.token, &first_block_builder)?;
-                function_builder.add_block(first_block_builder);
+                function_builder.add_block(entry_block);
             }
         }
         for block in function.borrow(self.token_mut()).blocks.clone().iter() {
@@ -931,7 +1068,7 @@ impl<'l> LuaContext<'l> {
             );
             function_builder.add_block(block.borrow(&mut self.token).builder.clone());
         }
-        let reg_count = self.current_function().register_pool.borrow().max_allocated();
+        let reg_count = function.borrow(self.token()).register_pool.borrow().max_allocated();
         let obj_builder = ObjectBuilder::default();
         obj_builder.borrow_mut(self.token_mut()).receive::<usize>();
         obj_builder
@@ -978,6 +1115,7 @@ impl<'l> LuaContext<'l> {
                 expr.value_reg(),
             )?;
         }
+        let env = self.env()?;
         let value_reg = self.alloc_register()?;
         match self.current_function().parent_closure_slot_map.len() {
             0 => {
@@ -986,6 +1124,7 @@ impl<'l> LuaContext<'l> {
                     &mut self.token,
                     SymbolRef::new(obj, 0),
                     &LUA_STATE_REG,
+                    env.value_reg(),
                     &LUA_UP_VALUES_REG,
                     &value_reg,
                 )?;
@@ -995,7 +1134,7 @@ impl<'l> LuaContext<'l> {
                     &self.current_builder,
                     &mut self.token,
                     SymbolRef::new(obj, 0),
-                    &LUA_STATE_REG,
+                    env.value_reg(),
                     &LUA_UP_VALUES_REG,
                     &LUA_CLOSURE_REG,
                     &value_reg,
@@ -1049,15 +1188,43 @@ impl<'l> LuaContext<'l> {
         }
         Ok(())
     }
+    pub fn expr_list(&mut self, expr: LuaExprRef<'l>) -> Fallible<LuaExprList<'l>> {
+        self.extend_expr_list(LuaExprList::new(), expr)
+    }
+    /// Appends the expression, whose values after the first one go to the list when it is a call or `...`, until
+    /// another expression follows it.
     pub fn extend_expr_list(&mut self, exprs: LuaExprList<'l>, expr: LuaExprRef<'l>) -> Fallible<LuaExprList<'l>> {
         let mut exprs = self.va_arg_to_arg(exprs)?;
-        exprs.exprs.push(expr);
+        match &expr.rest {
+            None => exprs.exprs.push(expr),
+            Some(LuaExprRest::VaArgs) => exprs.va_arg = Some(VaArgs::VaArgs()),
+            Some(LuaExprRest::Rets(rets)) => exprs.va_arg = Some(VaArgs::Rets(expr.clone(), rets.clone())),
+        }
         Ok(exprs)
     }
+    /// The expression in parentheses, which only has its first value.
+    pub fn single_value(&mut self, expr: LuaExprRef<'l>) -> Fallible<LuaExprRef<'l>> {
+        Ok(match &expr.rest {
+            None => expr,
+            Some(_) => Rc::new(LuaExpr { rest: None, ..(*expr).clone() }),
+        })
+    }
+    /// The results of the call ending a list, which is made unless it is made already.
+    fn emit_va_rets(&mut self, va_arg: VaArgs<'l>) -> Fallible<Register<Pointer<UnsizedArray<LuaValue>>>> {
+        match va_arg {
+            VaArgs::FunctionCall(function, args) => self.emit_call(function, *args),
+            VaArgs::Rets(_ret0, rets) => Ok(rets),
+            VaArgs::VaArgs() => unreachable!(),
+        }
+    }
     pub fn va_arg_to_arg(&mut self, mut exprs: LuaExprList<'l>) -> Fallible<LuaExprList<'l>> {
         if let Some(va_arg) = exprs.va_arg.take() {
             match va_arg {
-                VaArgs::VaArgs() => unreachable!(),
+                VaArgs::VaArgs() => {
+                    let arg0 = self.const_va_arg0()?;
+                    exprs.exprs.push(arg0);
+                }
+                VaArgs::Rets(ret0, _rets) => exprs.exprs.push(ret0),
                 call => {
                     let ret0 = self.get_from_slice(0, LuaExprList {
                         exprs: vec![],
@@ -1106,8 +1273,9 @@ impl<'l> LuaContext<'l> {
                         .build()?,
                 ));
             }
-            (o, Some(VaArgs::FunctionCall(function, args))) => {
-                let rets = self.emit_call(function, *args)?;
+            (1, Some(VaArgs::Rets(ret0, _rets))) => list.push(ret0),
+            (o, Some(va_arg @ (VaArgs::FunctionCall(..) | VaArgs::Rets(..)))) => {
+                let rets = self.emit_va_rets(va_arg)?;
                 for i in 0..o {
                     let reg = self.alloc_register()?;
                     GetRet::emit(&self.current_builder, &mut self.token, Usize(i), &rets, &reg)?;
@@ -1246,8 +1414,8 @@ impl<'l> LuaContext<'l> {
                     }
                 }
             }
-            (_, Some(VaArgs::FunctionCall(last_function, args))) => {
-                let va_args = self.emit_call(last_function, *args)?;
+            (_, Some(va_arg @ (VaArgs::FunctionCall(..) | VaArgs::Rets(..)))) => {
+                let va_args = self.emit_va_rets(va_arg)?;
                 match exprs.len() {
                     0 => CallFunction0VA::emit(
                         &self.current_builder,
@@ -1417,8 +1585,8 @@ impl<'l> LuaContext<'l> {
                     }
                 }
             }
-            (_, Some(VaArgs::FunctionCall(last_function, args))) => {
-                let va_args = self.emit_call(last_function, *args)?;
+            (_, Some(va_arg @ (VaArgs::FunctionCall(..) | VaArgs::Rets(..)))) => {
+                let va_args = self.emit_va_rets(va_arg)?;
                 match exprs.len() {
                     0 => CallFunction0VaRet1::emit(
                         &self.current_builder,
@@ -1482,6 +1650,8 @@ impl<'l> LuaContext<'l> {
     pub fn get_from_slice(&mut self, index: usize, c: LuaExprList<'l>) -> Fallible<LuaExprRef<'l>> {
         if let Some(expr) = c.exprs.get(index) {
             Ok(expr.clone())
+        } else if let (true, Some(VaArgs::Rets(ret0, _rets))) = (index == c.exprs.len(), &c.va_arg) {
+            Ok(ret0.clone())
         } else {
             let reg = self.alloc_register()?;
             match (index - c.exprs.len(), c.va_arg) {
@@ -1490,8 +1660,8 @@ impl<'l> LuaContext<'l> {
                     let va_args = self.va_args()?;
                     GetArg::emit(&self.current_builder, &mut self.token, Usize(index), &va_args, &reg)?
                 }
-                (index @ 1.., Some(VaArgs::FunctionCall(f, args))) => {
-                    let rets = self.emit_call(f, *args)?;
+                (index @ 1.., Some(va_arg @ (VaArgs::FunctionCall(..) | VaArgs::Rets(..)))) => {
+                    let rets = self.emit_va_rets(va_arg)?;
                     GetRet::emit(&self.current_builder, &mut self.token, Usize(index), &rets, &reg)?;
                 }
                 (0, Some(VaArgs::FunctionCall(function, args))) => self.emit_call_ret1(function, *args, &reg)?,
@@ -1504,6 +1674,24 @@ impl<'l> LuaContext<'l> {
             ))
         }
     }
+    /// The first result of a call in an expression, which keeps the others for the list it may end.
+    pub fn call_value(&mut self, c: LuaExprList<'l>) -> Fallible<LuaExprRef<'l>> {
+        let LuaExprList { exprs, va_arg } = c;
+        match va_arg {
+            Some(VaArgs::FunctionCall(function, args)) if exprs.is_empty() => {
+                let rets = self.emit_call(function, *args)?;
+                let reg = self.alloc_register()?;
+                GetRet0::emit(&self.current_builder, &mut self.token, &rets, &reg)?;
+                Ok(Rc::new(
+                    LuaExprBuilder::default()
+                        .register(LuaRegister::Value(reg, None))
+                        .rest(Some(LuaExprRest::Rets(rets)))
+                        .build()?,
+                ))
+            }
+            va_arg => self.get_from_slice(0, LuaExprList { exprs, va_arg }),
+        }
+    }
     pub fn stat_call(&mut self, p: LuaExprRef<'l>, c: LuaExprList<'l>) -> Fallible<LuaExprRef<'l>> {
         let call = self.call(p, c)?;
         self.get_from_slice(0, call)
@@ -1559,15 +1747,19 @@ impl<'l> LuaContext<'l> {
     // [t!(break)]=>cxt.break_();
     pub fn break_(&mut self) -> Fallible<()> {
         trace!("break_");
-        let old_block = self.current_block.clone();
-        let _new_block = self.new_block();
-        let function = self.current_function();
-        let _scopt = &function.current_scopt;
-        for scopt in &function.scopts.clone() {
-            match &scopt.borrow(&self.token).kind.clone() {
+        let mut to_be_closed = 0;
+        for scopt in self.current_function().scopts.clone().iter().rev() {
+            let (kind, scopt_to_be_closed) = {
+                let scopt = scopt.borrow(self.token());
+                (scopt.kind.clone(), scopt.to_be_closed)
+            };
+            to_be_closed += scopt_to_be_closed;
+            match kind {
                 ScoptKind::Other => {}
                 ScoptKind::Loop { break_block } => {
-                    self.branch(&old_block, break_block)?;
+                    self.close_variables(to_be_closed)?;
+                    let (old_block, _new_block) = self.split_block()?;
+                    self.branch(&old_block, &break_block)?;
                     return Ok(());
                 }
             }
@@ -1577,36 +1769,90 @@ impl<'l> LuaContext<'l> {
     // [label(l)]=>cxt.define_label(l);
     pub fn define_label(&mut self, name: String) -> Fallible<()> {
         trace!("define_label");
-        let function = self.current_function_mut();
-        let _old_block = function.current_block.clone();
-        let new_block = function.new_block();
-        function
-            .goto_blocks
-            .entry(name.clone())
-            .or_insert_with(Default::default)
-            .push(new_block.clone());
-        if let Some(goto_blocks_of_this_label) = function.goto_blocks.get(&name) {
-            let goto_blocks_of_this_label = goto_blocks_of_this_label.clone();
-            for goto_block in goto_blocks_of_this_label.iter() {
-                self.branch(goto_block, &new_block)?;
+        if self.current_function().scopts.iter().any(|scopt| scopt.borrow(self.token()).labels.contains_key(&name)) {
+            return Err(LuaVMError::SyntaxError(format_err!("label '{}' already defined", name)).into());
+        }
+        let (old_block, label_block) = self.split_block()?;
+        self.branch(&old_block, &label_block)?;
+        let scopt = self.current_scopt.clone();
+        let (locals, to_be_closed) = (self.current_scopt().locals.len(), self.current_scopt().to_be_closed);
+        self.current_scopt_mut()
+            .labels
+            .insert(name.clone(), LuaLabel { block: label_block.clone(), to_be_closed });
+        let gotos = self.current_function_mut().pending_gotos.remove(&name).unwrap_or_default();
+        for goto in gotos {
+            // the label is visible when the `goto` is in its scope
+            let index = match goto.scopts.iter().position(|(goto_scopt, ..)| Rc::ptr_eq(goto_scopt, &scopt)) {
+                Some(index) => index,
+                None => {
+                    self.current_function_mut().pending_gotos.entry(name.clone()).or_insert_with(Default::default).push(goto);
+                    continue;
+                }
+            };
+            let goto_locals = goto.scopts[index].1;
+            if locals > goto_locals {
+                let message =
+                    format!("<goto {}> jumps into the scope of local '{}'", name, self.current_scopt().locals[goto_locals]);
+                if let Some(jump) = &self.current_scopt().jump_into_local {
+                    return Err(LuaVMError::SyntaxError(format_err!("{}", jump.message)).into());
+                }
+                let blocks = self.current_function().blocks.len();
+                self.current_scopt_mut().jump_into_local =
+                    Some(LuaJumpIntoLocal { label_block: label_block.clone(), blocks, message });
             }
+            let to_be_closed = goto.scopts[index + 1..].iter().map(|(_, _, to_be_closed)| to_be_closed).sum();
+            let goto_builder = goto.block.borrow(self.token()).builder().clone();
+            let builder = std::mem::replace(&mut self.current_builder, goto_builder);
+            self.close_variables(to_be_closed)?;
+            self.current_builder = builder;
+            self.branch(&goto.block, &label_block)?;
         }
         Ok(())
     }
+    /// Burns fuel at the end of `block`, before it jumps back, see [`crate::quota`].
+    fn check_quotas(&mut self, block: &LuaBlockRef<'l>) -> Fallible<()> {
+        let builder = block.borrow(self.token()).builder().clone();
+        CheckQuotas::emit(&builder, &mut self.token, &LUA_STATE_REG)?;
+        Ok(())
+    }
     // [t!(goto),Name(n)]=>cxt.goto(n);
     pub fn goto(&mut self, name: String) -> Fallible<()> {
         trace!("goto");
-        let function = self.current_function_mut();
-        let old_block = function.current_block.clone();
-        let _new_block = function.new_block();
-        if let Some(label_block) = function.label_blocks.get(&name).cloned() {
-            self.branch(&old_block, &label_block)?;
-        } else {
-            function
-                .goto_blocks
-                .entry(name)
-                .or_insert_with(Default::default)
-                .push(old_block);
+        let scopts = self.current_function().scopts.clone();
+        let label = scopts
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(index, scopt)| Some((index, scopt.borrow(self.token()).labels.get(&name)?.clone())));
+        match label {
+            Some((index, label)) => {
+                // jumping back leaves the scopes inside the one of the label and the locals declared after the label
+                let to_be_closed = scopts[index..].iter().map(|scopt| scopt.borrow(self.token()).to_be_closed).sum::<usize>()
+                    - label.to_be_closed;
+                self.close_variables(to_be_closed)?;
+                let current_block = self.current_block.clone();
+                self.check_quotas(&current_block)?;
+                let (old_block, _new_block) = self.split_block()?;
+                self.branch(&old_block, &label.block)?;
+            }
+            None => {
+                let scopts = scopts
+                    .into_iter()
+                    .map(|scopt| {
+                        let (locals, to_be_closed) = {
+                            let scopt = scopt.borrow(self.token());
+                            (scopt.locals.len(), scopt.to_be_closed)
+                        };
+                        (scopt, locals, to_be_closed)
+                    })
+                    .collect();
+                let (old_block, _new_block) = self.split_block()?;
+                self.current_function_mut()
+                    .pending_gotos
+                    .entry(name)
+                    .or_insert_with(Default::default)
+                    .push(LuaGoto { block: old_block, scopts });
+            }
         }
         Ok(())
     }
@@ -1636,11 +1882,12 @@ impl<'l> LuaContext<'l> {
             loop_block_begin.borrow(self.token()).builder(),
             loop_block_end.borrow(self.token()).builder(),
         );
+        self.check_quotas(&loop_block_end)?;
         self.branch(&loop_block_end, &predicate_block_begin)?;
         self.branch(&pre_block_end, &predicate_block_begin)?;
         Ok(())
     }
-    // [t!(repeat),block_split(s),block(b),t!(until),expr_wraped(e)]=>ctx.repeat(s,b,e);
+    // [repeat_prepare,block_split(s),loop_scopt_begin,block_inner(b),t!(until),block_split(p),expr(e)]=>ctx.repeat(s,b,(p,e));
     pub fn repeat(
         &mut self,
         (pre_block_end, loop_block_begin): (LuaBlockRef<'l>, LuaBlockRef<'l>),
@@ -1648,12 +1895,15 @@ impl<'l> LuaContext<'l> {
         ((predicate_block_end, post_block_begin), predicate_expr): ((LuaBlockRef<'l>, LuaBlockRef<'l>), LuaExprRef<'l>),
     ) -> Fallible<()> {
         trace!("repeat");
+        // the condition can see the locals of the body
+        let (predicate_block_end, post_block_begin) = self.finish_scopt((predicate_block_end, post_block_begin))?;
         self.branch_if(
             predicate_expr,
             &predicate_block_end,
             &loop_block_begin,
             &post_block_begin,
         )?;
+        self.check_quotas(&loop_block_end)?;
         self.branch(&loop_block_end, &predicate_block_begin)?;
         self.branch(&pre_block_end, &loop_block_begin)?;
         Ok(())
@@ -1667,15 +1917,15 @@ impl<'l> LuaContext<'l> {
         (rhs_block_end, post_block_begin): (LuaBlockRef<'l>, LuaBlockRef<'l>),
     ) -> Fallible<LuaExprRef<'l>> {
         trace!("or");
+        let post_builder = self.current_builder.clone();
+        // the result is the register of the left operand, the right operand is moved into it at the end of its block
+        self.current_builder = lhs_block_end.borrow(self.token()).builder().clone();
+        let lhs = self.to_writable_value(lhs)?;
+        self.current_builder = rhs_block_end.borrow(self.token()).builder().clone();
         let rhs = self.to_value(rhs)?;
-        let lhs = self.to_value(lhs)?;
+        MoveValue::emit(&self.current_builder, &mut self.token, rhs.value_reg(), lhs.value_reg())?;
+        self.current_builder = post_builder;
         self.branch_if(lhs.clone(), &lhs_block_end, &post_block_begin, &rhs_block_begin)?;
-        MoveValue::emit(
-            &mut self.current_builder,
-            &mut self.token,
-            rhs.value_reg(),
-            lhs.value_reg(),
-        )?;
         self.branch(&rhs_block_end, &post_block_begin)?;
         Ok(lhs)
     }
@@ -1688,15 +1938,15 @@ impl<'l> LuaContext<'l> {
         (rhs_block_end, post_block_begin): (LuaBlockRef<'l>, LuaBlockRef<'l>),
     ) -> Fallible<LuaExprRef<'l>> {
         trace!("and");
+        let post_builder = self.current_builder.clone();
+        // the result is the register of the left operand, the right operand is moved into it at the end of its block
+        self.current_builder = lhs_block_end.borrow(self.token()).builder().clone();
+        let lhs = self.to_writable_value(lhs)?;
+        self.current_builder = rhs_block_end.borrow(self.token()).builder().clone();
         let rhs = self.to_value(rhs)?;
-        let lhs = self.to_value(lhs)?;
+        MoveValue::emit(&self.current_builder, &mut self.token, rhs.value_reg(), lhs.value_reg())?;
+        self.current_builder = post_builder;
         self.branch_if(lhs.clone(), &lhs_block_end, &rhs_block_begin, &post_block_begin)?;
-        MoveValue::emit(
-            &mut self.current_builder,
-            &mut self.token,
-            rhs.value_reg(),
-            lhs.value_reg(),
-        )?;
         self.branch(&rhs_block_end, &post_block_begin)?;
         Ok(lhs)
     }
@@ -1804,6 +2054,8 @@ impl<'l> LuaContext<'l> {
         (loop_block_end, post_block_begin): (LuaBlockRef<'l>, LuaBlockRef<'l>),
     ) -> Fallible<()> {
         trace!("for_");
+        let (loop_block_end, post_block_begin) = self.finish_scopt((loop_block_end, post_block_begin))?;
+        self.check_quotas(&loop_block_end)?;
         let state_reg = state.value_reg();
         let predicate_block_begin = &predicate_block_begin.borrow(self.token()).builder().clone();
         let predicate_block_end = &predicate_block_end.borrow(self.token()).builder().clone();
@@ -1878,6 +2130,8 @@ impl<'l> LuaContext<'l> {
         (loop_block_end, post_block_begin): (LuaBlockRef<'l>, LuaBlockRef<'l>),
     ) -> Fallible<()> {
         trace!("for_step");
+        let (loop_block_end, post_block_begin) = self.finish_scopt((loop_block_end, post_block_begin))?;
+        self.check_quotas(&loop_block_end)?;
         let state_reg = state.value_reg();
         debug!("for_ {:?} = {:?},{:?},{:?}", &state_reg, &start, &end, &step);
         let predicate_block_begin = &predicate_block_begin.borrow(self.token()).builder().clone();
@@ -1948,6 +2202,8 @@ impl<'l> LuaContext<'l> {
         let iter = self.to_value(state_less_iter[0].clone())?;
         let iterable = self.to_value(state_less_iter[1].clone())?;
         let state = self.to_writable_value(state_less_iter[2].clone())?;
+        // the first variable is the control variable, the state the iterator is called with
+        self.add_local(vars.remove(0), Default::default(), state.clone())?;
         let exprs = LuaExprList {
             exprs: vec![iter, iterable, state],
             va_arg: None,
@@ -1971,6 +2227,8 @@ impl<'l> LuaContext<'l> {
         ),
         (loop_block_end, post_block_begin): (LuaBlockRef<'l>, LuaBlockRef<'l>),
     ) -> Fallible<()> {
+        let (loop_block_end, post_block_begin) = self.finish_scopt((loop_block_end, post_block_begin))?;
+        self.check_quotas(&loop_block_end)?;
         self.branch(&loop_block_end, &predicate_block_begin)?;
         self.branch(&init_block_end, &predicate_block_begin)?;
         let predicate_block_begin = &predicate_block_begin.borrow(self.token()).builder().clone();
@@ -2036,9 +2294,15 @@ impl<'l> LuaContext<'l> {
     pub fn set_function(&mut self, name: String, body: LuaFunctionBuilderRef<'l>) -> Fallible<()> {
         trace!("set_function");
         let function = self.const_function(body)?;
-        self.add_local(name, Default::default(), function)
+        self.put_value(name, function)
+    }
+    // [t!(local),t!(function),Name(n)]=>ctx.local_function_name(n);
+    /// Declares the local before the body is read, so the function can call itself.
+    pub fn local_function_name(&mut self, name: String) -> Fallible<String> {
+        self.local_variable(vec![(name.clone(), Default::default())])?;
+        Ok(name)
     }
-    // [t!(local),t!(function),Name(n),function_boby(f)]=>ctx.local_function(n,f);
+    // [local_function_name(n),function_boby(f)]=>ctx.local_function(n,f);
     pub fn local_function(&mut self, name: String, body: LuaFunctionBuilderRef<'l>) -> Fallible<()> {
         trace!("local_function");
         let function = self.const_function(body)?;
@@ -2068,14 +2332,16 @@ impl<'l> LuaContext<'l> {
                 ))
             }
             LuaVar::Element(t, k) => {
+                let table = self.to_value(t)?;
+                let key = self.to_value(k)?;
                 let reg = self.alloc_register()?;
                 let cache = self.empty_inline_cache_line()?;
                 GetElement::emit(
                     &self.current_builder,
                     &mut self.token,
                     cache,
-                    t.value_reg(),
-                    k.value_reg(),
+                    table.value_reg(),
+                    key.value_reg(),
                     &reg,
                 )?;
                 Ok(Rc::new(
@@ -2086,7 +2352,20 @@ impl<'l> LuaContext<'l> {
             }
         }
     }
-    pub fn add_local(&mut self, name: String, attr: VarAttribute, expr: LuaExprRef<'l>) -> Fallible<()> {
+    pub fn add_local(&mut self, name: String, attr: VarAttribute, mut expr: LuaExprRef<'l>) -> Fallible<()> {
+        if attr.is_close {
+            expr = self.to_value(expr)?;
+            let name_value = self.const_string(name.clone())?;
+            PushToBeClosed::emit(
+                &self.current_builder,
+                &mut self.token,
+                &LUA_STATE_REG,
+                name_value.value_reg(),
+                expr.value_reg(),
+            )?;
+            self.current_scopt_mut().to_be_closed += 1;
+        }
+        self.current_scopt_mut().locals.push(name.clone());
         self.current_scopt_mut().variables.insert(name, LuaVariable {
             expr,
             attributes: attr,
@@ -2097,6 +2376,7 @@ impl<'l> LuaContext<'l> {
     // [t!(local),att_name_list(a)]=>ctx.local_variable(a);
     pub fn local_variable(&mut self, names: Vec<(std::string::String, VarAttribute)>) -> Fallible<()> {
         trace!("local_variable");
+        check_attributes(&names)?;
         for (name, attr) in names {
             let reg = self.alloc_register()?;
             ConstNil::emit(&self.current_builder, &mut self.token, &reg)?;
@@ -2111,6 +2391,7 @@ impl<'l> LuaContext<'l> {
         exprs: LuaExprList<'l>,
     ) -> Fallible<()> {
         trace!("local_variable_with_values");
+        check_attributes(&names)?;
         let len = names.len();
         for ((name, attr), expr) in names.into_iter().zip(self.expr_list_to_vec(exprs, len)?) {
             self.add_local(name, attr, expr)?;
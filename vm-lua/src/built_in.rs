@@ -1,14 +1,276 @@
 
 use std::ptr::NonNull;
 
-use failure::Fallible;
+use runtime_extra::Bool;
+use failure::{Error, Fallible};
 use vm_core::{Direct, Pointer, UnsizedArray};
 
-use crate::mem::{LuaFunctionRustType, LuaValue, LuaValueImpl};
+use crate::mem::{LuaClosureReference, LuaClosureRustType, LuaFunctionRustType, LuaTable, LuaTableReference, LuaValue, LuaValueArrayReference, LuaValueImpl};
 
-use crate::{instruction::extend_to_buffer, mem::LuaStateReference};
+use crate::{
+    error::{raise, LuaError},
+    host::{host, StandardStream},
+    instruction::extend_to_buffer,
+    mem::LuaStateReference,
+};
+pub mod coroutine;
+pub mod io;
+pub mod math;
+pub mod os;
+pub mod package;
+pub mod pattern;
+pub mod string;
+pub mod table;
 static EMPTY_RETURN_INNER: UnsizedArray<LuaValue> = UnsizedArray::empty();
 pub fn empty_return() -> Pointer<UnsizedArray<LuaValue>> { Pointer::new(NonNull::from(&EMPTY_RETURN_INNER)) }
+/// Returns the results of a built-in function, an error is raised as a Lua error.
+pub fn return_values(result: Fallible<Vec<LuaValueImpl>>) -> Pointer<UnsizedArray<LuaValue>> {
+    let values = match result {
+        Ok(values) => values,
+        Err(e) => return raise(LuaError::from_error(e)),
+    };
+    if values.is_empty() {
+        return empty_return();
+    }
+    let alloc_values = || -> Fallible<_> {
+        let mut rets = Pointer::<UnsizedArray<LuaValue>>::new(LuaValueArrayReference::get()?.alloc_unsized(values.len())?.cast());
+        unsafe {
+            rets.as_ref_mut().set_len(values.len());
+            rets.as_ref_mut().as_slice_mut().clone_from_slice(&values);
+        }
+        Ok(rets)
+    };
+    match alloc_values() {
+        Ok(rets) => rets,
+        Err(e) => raise(LuaError::from_error(e)),
+    }
+}
+/// Makes the `(name, native function)` pairs of built-in functions written as
+/// `fn(LuaStateReference, &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>>`.
+macro_rules! native_functions {
+    ($($name:literal => $function:path),* $(,)?) => {
+        &[$((
+            $name,
+            &({
+                extern "C" fn native_function(
+                    state: $crate::mem::LuaStateReference,
+                    args: &[$crate::mem::LuaValueImpl],
+                ) -> ::vm_core::Pointer<::vm_core::UnsizedArray<$crate::mem::LuaValue>> {
+                    $crate::built_in::return_values($function(state, args))
+                }
+                native_function
+            } as $crate::mem::LuaFunctionRustType),
+        )),*]
+    };
+}
+pub(crate) use native_functions;
+/// The name `type` returns for a value.
+pub fn type_name(value: &LuaValueImpl) -> &'static str {
+    if value.read_nil().is_some() {
+        "nil"
+    } else if value.read_boolean().is_some() {
+        "boolean"
+    } else if value.read_integer().is_some() || value.read_big_int().is_some() || value.read_float().is_some() || value.read_big_float().is_some() {
+        "number"
+    } else if value.read_string().is_some() {
+        "string"
+    } else if value.read_table().is_some() {
+        "table"
+    } else if value.read_thread().is_some() {
+        "thread"
+    } else if value.read_user_data().is_some() || value.read_light_user_data().is_some() {
+        "userdata"
+    } else {
+        "function"
+    }
+}
+/// A number value, or a string converted to a number.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    Integer(i64),
+    Float(f64),
+}
+impl Number {
+    pub fn as_float(self) -> f64 {
+        match self {
+            Number::Integer(i) => i as f64,
+            Number::Float(f) => f,
+        }
+    }
+
+    /// The integer of the same value, if any.
+    pub fn as_integer(self) -> Option<i64> {
+        match self {
+            Number::Integer(i) => Some(i),
+            Number::Float(f) if f.floor() == f && f >= -(2f64.powi(63)) && f < 2f64.powi(63) => Some(f as i64),
+            Number::Float(_) => None,
+        }
+    }
+
+    /// The Lua value of the number, keeping its subtype.
+    pub fn into_value(self) -> Fallible<LuaValueImpl> {
+        match self {
+            Number::Integer(i) => crate::new_integer(i),
+            Number::Float(f) => crate::new_float(f),
+        }
+    }
+
+    /// Reads a numeral the way the lexer does, with optional surrounding spaces and sign.
+    pub fn parse(text: &[u8]) -> Option<Number> {
+        let text = std::str::from_utf8(text).ok()?.trim_matches(|c: char| c.is_ascii_whitespace());
+        let (negative, digits) = match text.as_bytes().first()? {
+            b'-' => (true, &text[1..]),
+            b'+' => (false, &text[1..]),
+            _ => (false, text),
+        };
+        if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+            let number = parse_hex(hex)?;
+            return Some(match number {
+                Number::Integer(i) if negative => Number::Integer(i.wrapping_neg()),
+                Number::Float(f) if negative => Number::Float(-f),
+                number => number,
+            });
+        }
+        if !digits.starts_with(|c: char| c.is_ascii_digit() || c == '.') || !digits.bytes().all(|c| c.is_ascii_digit() || b".eE+-".contains(&c)) {
+            return None;
+        }
+        if digits.bytes().all(|c| c.is_ascii_digit()) {
+            if let Ok(i) = text.trim_start_matches('+').parse::<i64>() {
+                return Some(Number::Integer(i));
+            }
+        }
+        digits.parse::<f64>().ok().map(|f| Number::Float(if negative { -f } else { f }))
+    }
+}
+fn parse_hex(hex: &str) -> Option<Number> {
+    let (mantissa, exponent) = match hex.find(|c| c == 'p' || c == 'P') {
+        Some(p) => (&hex[..p], Some(hex[p + 1..].parse::<i32>().ok()?)),
+        None => (hex, None),
+    };
+    let (integer_part, fraction_part) = match mantissa.find('.') {
+        Some(dot) => (&mantissa[..dot], Some(&mantissa[dot + 1..])),
+        None => (mantissa, None),
+    };
+    if integer_part.is_empty() && fraction_part.map_or(true, str::is_empty) {
+        return None;
+    }
+    if fraction_part.is_none() && exponent.is_none() {
+        // hexadecimal integers wrap around
+        return integer_part.chars().try_fold(0i64, |i, c| Some(i.wrapping_mul(16).wrapping_add(c.to_digit(16)? as i64))).map(Number::Integer);
+    }
+    let mut value = 0f64;
+    for c in integer_part.chars() {
+        value = value * 16.0 + c.to_digit(16)? as f64;
+    }
+    let mut scale = 1.0 / 16.0;
+    for c in fraction_part.unwrap_or("").chars() {
+        value += c.to_digit(16)? as f64 * scale;
+        scale /= 16.0;
+    }
+    Some(Number::Float(value * 2f64.powi(exponent.unwrap_or(0))))
+}
+/// Reads a number without converting strings.
+pub fn read_number(value: &LuaValueImpl) -> Option<Number> {
+    unsafe {
+        if let Some(i) = value.read_integer() {
+            Some(Number::Integer(i.0 >> 4))
+        } else if let Some(i) = value.read_big_int() {
+            Some(Number::Integer(i.as_ref().get_value().0))
+        } else if let Some(f) = value.read_float() {
+            Some(Number::Float(f64::from_le_bytes(i64::to_le_bytes(f.0))))
+        } else {
+            value.read_big_float().map(|f| Number::Float(f.as_ref().get_value().0))
+        }
+    }
+}
+/// Reads a number, converting strings like arithmetic does.
+pub fn to_number(value: &LuaValueImpl) -> Option<Number> {
+    read_number(value).or_else(|| read_string(value).and_then(|s| Number::parse(&s)))
+}
+pub fn read_string(value: &LuaValueImpl) -> Option<Vec<u8>> {
+    value.read_string().map(|s| unsafe { s.as_ref().ref_data().as_slice().iter().map(|d| d.0).collect() })
+}
+/// Converts a value to a string like `tostring` does.
+pub fn to_string(value: &LuaValueImpl) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    unsafe {
+        extend_to_buffer(&mut buffer, Direct(value.clone()));
+    }
+    buffer
+}
+/// Formats a float like `%.14g`, with a `.0` suffix when it looks like an integer.
+pub fn format_float(value: f64) -> String {
+    if value.is_nan() {
+        return if value.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if value.is_infinite() {
+        return if value < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+    let mut formatted = string::format_general(value.abs(), 14, false);
+    if value.is_sign_negative() {
+        formatted.insert(0, '-');
+    }
+    if formatted.bytes().all(|c| c.is_ascii_digit() || c == b'-') {
+        formatted.push_str(".0");
+    }
+    formatted
+}
+pub fn to_boolean(value: &LuaValueImpl) -> bool {
+    value.read_nil().is_none() && value.read_boolean().map_or(true, |b| b.0 != 0)
+}
+/// The error of a bad argument of the built-in function `function`, `index` starts from 1.
+pub fn argument_error(index: usize, function: &str, message: &str) -> Error {
+    format_err!("bad argument #{} to '{}' ({})", index, function, message)
+}
+fn type_error(args: &[LuaValueImpl], index: usize, function: &str, expected: &str) -> Error {
+    let got = args.get(index - 1).map_or("no value", type_name);
+    argument_error(index, function, &format!("{} expected, got {}", expected, got))
+}
+/// Checks the argument `index` is a string or a number, which is converted.
+pub fn check_string(args: &[LuaValueImpl], index: usize, function: &str) -> Fallible<Vec<u8>> {
+    match args.get(index - 1) {
+        Some(value) if value.read_string().is_some() || read_number(value).is_some() => Ok(to_string(value)),
+        _ => Err(type_error(args, index, function, "string")),
+    }
+}
+pub fn check_number(args: &[LuaValueImpl], index: usize, function: &str) -> Fallible<Number> {
+    args.get(index - 1).and_then(to_number).ok_or_else(|| type_error(args, index, function, "number"))
+}
+/// Checks the argument `index` is a number with an integer value.
+pub fn check_integer(args: &[LuaValueImpl], index: usize, function: &str) -> Fallible<i64> {
+    check_number(args, index, function)?.as_integer().ok_or_else(|| argument_error(index, function, "number has no integer representation"))
+}
+pub fn check_table(args: &[LuaValueImpl], index: usize, function: &str) -> Fallible<Pointer<LuaTable>> {
+    args.get(index - 1).and_then(|value| value.read_table()).ok_or_else(|| type_error(args, index, function, "table"))
+}
+pub fn opt_integer(args: &[LuaValueImpl], index: usize, function: &str, default: i64) -> Fallible<i64> {
+    match args.get(index - 1) {
+        None => Ok(default),
+        Some(value) if value.read_nil().is_some() => Ok(default),
+        Some(_) => check_integer(args, index, function),
+    }
+}
+/// Compares two values like `<` does without meta functions.
+pub fn less_than(a: &LuaValueImpl, b: &LuaValueImpl) -> Fallible<bool> {
+    match (read_number(a), read_number(b)) {
+        (Some(Number::Integer(a)), Some(Number::Integer(b))) => return Ok(a < b),
+        (Some(a), Some(b)) => return Ok(a.as_float() < b.as_float()),
+        _ => {}
+    }
+    match (read_string(a), read_string(b)) {
+        (Some(a), Some(b)) => Ok(a < b),
+        _ if type_name(a) == type_name(b) => Err(format_err!("attempt to compare two {} values", type_name(a))),
+        _ => Err(format_err!("attempt to compare {} with {}", type_name(a), type_name(b))),
+    }
+}
+/// Makes a library table holding `functions`.
+pub fn new_library(state: LuaStateReference, functions: &[(&str, &LuaFunctionRustType)]) -> Fallible<LuaTableReference> {
+    let library = crate::new_table(crate::new_meta_functions()?, functions.len(), true)?;
+    for (name, function) in functions {
+        crate::set_field(library.as_pointer(), crate::new_string(state.as_pointer(), name.as_bytes())?, crate::new_function(state.clone(), function)?)?;
+    }
+    Ok(library)
+}
 
 pub extern "C" fn print(_state: LuaStateReference, args: &[LuaValueImpl]) -> Pointer<UnsizedArray<LuaValue>> {
     let mut buffer = Vec::new();
@@ -24,25 +286,388 @@ pub extern "C" fn print(_state: LuaStateReference, args: &[LuaValueImpl]) -> Poi
     empty_return()
 }
 pub extern "C" fn exec_lua(state: LuaStateReference, args: &[LuaValueImpl]) -> Pointer<UnsizedArray<LuaValue>> {
-    if let Some(v) = args[0].read_string() {
-        let code = unsafe {
-            let mut buffer = Vec::new();
-            buffer.extend(v.as_ref().ref_data().as_slice().iter().map(|d| d.0));
-            String::from_utf8_lossy(&buffer).to_string()
-        };
-        crate::run_code(state, code.as_ref()).unwrap();
+    return_values(args.first().and_then(read_string).ok_or_else(|| format_err!("code is not a string")).and_then(|code| {
+        crate::run_code(state, &String::from_utf8_lossy(&code))?;
+        Ok(Vec::new())
+    }))
+}
+static IPAIRS_STEP: LuaFunctionRustType = ipairs_step;
+/// Returns the iterator of `for i, v in ipairs(t)`, which stops at the first nil value.
+fn ipairs(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let table = check_table(args, 1, "ipairs")?;
+    Ok(vec![crate::new_function(state, &IPAIRS_STEP)?, LuaValueImpl::encode_table(table), crate::new_integer(0)?])
+}
+extern "C" fn ipairs_step(_state: LuaStateReference, args: &[LuaValueImpl]) -> Pointer<UnsizedArray<LuaValue>> {
+    return_values(ipairs_next(args))
+}
+fn ipairs_next(args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let index = check_integer(args, 2, "ipairs")? + 1;
+    let value = crate::get_field(check_table(args, 1, "ipairs")?, &crate::new_integer(index)?);
+    if value.read_nil().is_some() {
+        return Ok(vec![value]);
+    }
+    Ok(vec![crate::new_integer(index)?, value])
+}
+fn check_any<'a>(args: &'a [LuaValueImpl], index: usize, function: &str) -> Fallible<&'a LuaValueImpl> {
+    args.get(index - 1).ok_or_else(|| argument_error(index, function, "value expected"))
+}
+/// Compares two values like `==` does without meta functions.
+pub fn raw_equal(a: &LuaValueImpl, b: &LuaValueImpl) -> bool {
+    match (read_number(a), read_number(b)) {
+        (Some(Number::Float(a)), Some(Number::Float(b))) => a == b,
+        (Some(a), Some(b)) => a.as_integer().is_some() && a.as_integer() == b.as_integer(),
+        (Some(_), None) | (None, Some(_)) => false,
+        // strings are interned
+        (None, None) => a == b,
+    }
+}
+/// Compares two values like `==` does, the `__eq` meta function is only used for two tables or two userdata.
+pub fn equal(a: &LuaValueImpl, b: &LuaValueImpl) -> Fallible<bool> {
+    if raw_equal(a, b) {
+        return Ok(true);
+    }
+    let meta_functions = |value: &LuaValueImpl| unsafe {
+        if let Some(table) = value.read_table() {
+            Some(table.as_ref().get_shape().as_ref().get_meta_functions())
+        } else {
+            value.read_user_data().map(|user_data| user_data.as_ref().get_meta_functions())
+        }
+    };
+    let meta_function = match (meta_functions(a), meta_functions(b)) {
+        (Some(a_meta), Some(b_meta)) if a.read_table().is_some() == b.read_table().is_some() => unsafe {
+            let meta_function = a_meta.as_ref().get_eq();
+            if meta_function.read_nil().is_some() {
+                b_meta.as_ref().get_eq()
+            } else {
+                meta_function
+            }
+        },
+        _ => return Ok(false),
+    };
+    if meta_function.read_nil().is_some() {
+        return Ok(false);
+    }
+    let results = crate::call_function(&meta_function, &[a.clone(), b.clone()])?;
+    Ok(results.first().map_or(false, to_boolean))
+}
+/// The metatable of a value without looking at its `__metatable` field.
+fn raw_metatable(state: &LuaStateReference, value: &LuaValueImpl) -> Option<Pointer<LuaTable>> {
+    if let Some(table) = value.read_table() {
+        crate::get_metatable(table)
+    } else if value.read_string().is_some() {
+        crate::metatable_of_meta_functions(unsafe { state.as_pointer().as_ref().get_string_meta_functions() })
+    } else if let Some(user_data) = value.read_user_data() {
+        crate::user_data::metatable_of(&user_data)
     } else {
-        panic!("code is not a string");
+        None
     }
-    empty_return()
 }
+fn getmetatable(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let metatable = match raw_metatable(&state, check_any(args, 1, "getmetatable")?) {
+        Some(metatable) => metatable,
+        None => return Ok(vec![LuaValueImpl::encode_nil(())]),
+    };
+    let protected = crate::get_field(metatable.clone(), &crate::new_string(state.as_pointer(), b"__metatable")?);
+    if protected.read_nil().is_none() {
+        return Ok(vec![protected]);
+    }
+    Ok(vec![LuaValueImpl::encode_table(metatable)])
+}
+fn setmetatable(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let table = check_table(args, 1, "setmetatable")?;
+    let metatable = match args.get(1) {
+        Some(value) if value.read_table().is_some() || value.read_nil().is_some() => value.read_table(),
+        _ => return Err(argument_error(2, "setmetatable", "nil or table expected")),
+    };
+    if let Some(current) = crate::get_metatable(table.clone()) {
+        if crate::get_field(current, &crate::new_string(state.as_pointer(), b"__metatable")?).read_nil().is_none() {
+            return Err(format_err!("cannot change a protected metatable"));
+        }
+    }
+    crate::set_metatable(table.clone(), metatable)?;
+    Ok(vec![LuaValueImpl::encode_table(table)])
+}
+fn rawequal(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let (a, b) = (check_any(args, 1, "rawequal")?, check_any(args, 2, "rawequal")?);
+    Ok(vec![crate::new_boolean(raw_equal(a, b))])
+}
+fn rawlen(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let len = match args.first() {
+        Some(value) if value.read_table().is_some() => crate::table_length(value.read_table().unwrap())?,
+        Some(value) if value.read_string().is_some() => unsafe { value.read_string().unwrap().as_ref().ref_data().len() as i64 },
+        _ => return Err(argument_error(1, "rawlen", "table or string expected")),
+    };
+    Ok(vec![crate::new_integer(len)?])
+}
+fn rawget(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let table = check_table(args, 1, "rawget")?;
+    Ok(vec![crate::get_field(table, check_any(args, 2, "rawget")?)])
+}
+fn rawset(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let table = check_table(args, 1, "rawset")?;
+    let key = check_any(args, 2, "rawset")?;
+    let value = check_any(args, 3, "rawset")?;
+    if key.read_nil().is_some() {
+        return Err(format_err!("index is nil"));
+    }
+    if matches!(read_number(key), Some(Number::Float(f)) if f.is_nan()) {
+        return Err(format_err!("index is NaN"));
+    }
+    crate::set_field(table.clone(), key.clone(), value.clone())?;
+    Ok(vec![LuaValueImpl::encode_table(table)])
+}
+/// Raises its first argument as the error value. Unlike the reference implementation, the compiled code keeps no line
+/// information, so a string message never gets the `chunk:line:` prefix of the function at the given level. The level
+/// must still be an integer when given, but any level raises the message as it is.
+fn error(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    opt_integer(args, 2, "error", 1)?;
+    Err(LuaError::Value(args.first().cloned().unwrap_or_else(|| LuaValueImpl::encode_nil(()))).into())
+}
+/// Calls a function catching the errors it raises, the `<close>` variables it left in scope are closed with the error.
+fn pcall(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let function = check_any(args, 1, "pcall")?;
+    let to_be_closed = crate::close::to_be_closed_len(&state);
+    match crate::call_function(function, &args[1..]) {
+        Ok(rets) => Ok(std::iter::once(crate::new_boolean(true)).chain(rets).collect()),
+        Err(e) => {
+            let error = LuaError::from_error(e).into_value(state.clone())?;
+            Ok(vec![crate::new_boolean(false), crate::close::close_on_error(&state, to_be_closed, error)])
+        }
+    }
+}
+/// Like `pcall`, the results of the message handler called with the error follow `false`. The handler gets the
+/// error left once the `<close>` variables the function left are closed.
+fn xpcall(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let function = check_any(args, 1, "xpcall")?;
+    let handler = check_any(args, 2, "xpcall")?;
+    let to_be_closed = crate::close::to_be_closed_len(&state);
+    let error = match crate::call_function(function, &args[2..]) {
+        Ok(rets) => return Ok(std::iter::once(crate::new_boolean(true)).chain(rets).collect()),
+        Err(e) => LuaError::from_error(e).into_value(state.clone())?,
+    };
+    let error = crate::close::close_on_error(&state, to_be_closed, error);
+    let handled = match crate::call_function(handler, &[error]) {
+        Ok(rets) => rets,
+        Err(e) => vec![LuaError::from_error(e).into_value(state)?],
+    };
+    Ok(std::iter::once(crate::new_boolean(false)).chain(handled).collect())
+}
+fn opt_string(args: &[LuaValueImpl], index: usize, function: &str) -> Fallible<Option<Vec<u8>>> {
+    match args.get(index - 1) {
+        None => Ok(None),
+        Some(value) if value.read_nil().is_some() => Ok(None),
+        Some(_) => check_string(args, index, function).map(Some),
+    }
+}
+/// How a chunk is named in messages, `=name` and `@filename` are shown without their prefix and other names are the
+/// source of the chunk.
+pub fn chunk_id(chunk_name: &str) -> String {
+    if let Some(name) = chunk_name.strip_prefix('=').or_else(|| chunk_name.strip_prefix('@')) {
+        return name.to_string();
+    }
+    let line = chunk_name.lines().next().unwrap_or("");
+    match line.char_indices().nth(45) {
+        Some((end, _)) => format!("[string \"{}...\"]", &line[..end]),
+        None if line.len() < chunk_name.len() => format!("[string \"{}...\"]", line),
+        None => format!("[string \"{}\"]", line),
+    }
+}
+/// Loads source or precompiled code as a function, `mode` says which of them is allowed like the argument of `load`.
+pub fn load_chunk(state: &LuaStateReference, code: &[u8], chunk_name: &str, mode: &str) -> Fallible<LuaValueImpl> {
+    let binary = crate::dump::is_binary_chunk(code);
+    let pack = match (binary, mode.contains(if binary { 'b' } else { 't' })) {
+        (true, false) => return Err(format_err!("attempt to load a binary chunk (mode is '{}')", mode)),
+        (false, false) => return Err(format_err!("attempt to load a text chunk (mode is '{}')", mode)),
+        (true, true) => crate::dump::undump(state, code),
+        (false, true) => crate::pack_code(state.clone(), &String::from_utf8_lossy(code)),
+    };
+    crate::new_chunk_function(state.clone(), pack.map_err(|e| format_err!("{}: {}", chunk_id(chunk_name), e))?)
+}
+/// Reads the code of a chunk from a file opened by the host, skipping its first line when it starts with `#`.
+pub fn read_chunk_file(state: &LuaStateReference, filename: &str) -> Fallible<Vec<u8>> {
+    let mut file = host(state).open(filename, "r").map_err(|e| format_err!("cannot open {}: {}", filename, e))?;
+    let mut code = file.read_to_end().map_err(|e| format_err!("cannot read {}: {}", filename, e))?;
+    if code.starts_with(b"#") {
+        // the line break is kept so the lines are still counted from the first one
+        let end = code.iter().position(|c| *c == b'\n').unwrap_or(code.len());
+        code.drain(..end);
+    }
+    Ok(code)
+}
+static CALL_WITH_ENV: LuaClosureRustType = call_with_env;
+/// Compiles a chunk given as a string or by a function returning its pieces, the error is returned after `nil`
+/// instead of being raised.
+fn load(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    load_with_modes(state, args, "bt")
+}
+/// Same as `load`, the mode asked for is narrowed to the kinds of chunks in `allowed_modes`.
+fn load_with_modes(state: LuaStateReference, args: &[LuaValueImpl], allowed_modes: &str) -> Fallible<Vec<LuaValueImpl>> {
+    let chunk = check_any(args, 1, "load")?;
+    let code = if chunk.read_string().is_some() {
+        to_string(chunk)
+    } else if chunk.read_function().is_some() || chunk.read_closure().is_some() {
+        let mut code = Vec::new();
+        loop {
+            let piece = match crate::call_function(chunk, &[]) {
+                Ok(rets) => rets.into_iter().next().unwrap_or_else(|| LuaValueImpl::encode_nil(())),
+                Err(e) => return Ok(vec![LuaValueImpl::encode_nil(()), LuaError::from_error(e).into_value(state)?]),
+            };
+            match read_string(&piece) {
+                Some(piece) if !piece.is_empty() => code.extend(piece),
+                None if piece.read_nil().is_none() => {
+                    let message = crate::new_string(state.as_pointer(), b"reader function must return a string")?;
+                    return Ok(vec![LuaValueImpl::encode_nil(()), message]);
+                }
+                _ => break,
+            }
+        }
+        code
+    } else {
+        return Err(type_error(args, 1, "load", "string"));
+    };
+    let chunk_name = match opt_string(args, 2, "load")? {
+        Some(chunk_name) => String::from_utf8_lossy(&chunk_name).into_owned(),
+        None if chunk.read_string().is_some() => String::from_utf8_lossy(&code).into_owned(),
+        None => "=(load)".to_string(),
+    };
+    let mode = opt_string(args, 3, "load")?.map_or_else(|| "bt".to_string(), |mode| String::from_utf8_lossy(&mode).into_owned());
+    let mode: String = mode.chars().filter(|c| allowed_modes.contains(*c)).collect();
+    let function = match load_chunk(&state, &code, &chunk_name, &mode) {
+        Ok(function) => function,
+        Err(e) => return Ok(vec![LuaValueImpl::encode_nil(()), crate::new_string(state.as_pointer(), e.to_string().as_bytes())?]),
+    };
+    if args.len() < 4 {
+        return Ok(vec![function]);
+    }
+    let env = check_any(args, 4, "load")?.clone();
+    Ok(vec![with_env(state, function, env)?])
+}
+/// Makes a function entering the chunk of `function` with `env` as its `_ENV`, the functions the chunk defines
+/// keep it.
+pub fn with_env(state: LuaStateReference, function: LuaValueImpl, env: LuaValueImpl) -> Fallible<LuaValueImpl> {
+    crate::new_native_closure(state, &CALL_WITH_ENV, &[function, env])
+}
+extern "C" fn call_with_env(state: LuaStateReference, closure: LuaClosureReference, args: &[LuaValueImpl]) -> Pointer<UnsizedArray<LuaValue>> {
+    return_values(run_with_env(state, closure, args))
+}
+/// The chunk takes the environment of the state when it is entered, it is cleared after in case it was not taken.
+fn run_with_env(state: LuaStateReference, closure: LuaClosureReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let values = unsafe { crate::native_closure_values(&closure) };
+    let (function, env) = (values[0].clone(), values[1].clone());
+    let mut state_pointer = state.as_pointer();
+    unsafe {
+        state_pointer.as_ref_mut().set_chunk_env(env);
+        state_pointer.as_ref_mut().set_chunk_env_given(Bool(true));
+    }
+    let rets = crate::call_function(&function, args);
+    unsafe {
+        state_pointer.as_ref_mut().set_chunk_env(LuaValueImpl::encode_nil(()));
+        state_pointer.as_ref_mut().set_chunk_env_given(Bool(false));
+    }
+    rets
+}
+/// Same as `load` with a string, from Lua 5.1.
+fn loadstring(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    loadstring_with_modes(state, args, "bt")
+}
+fn loadstring_with_modes(state: LuaStateReference, args: &[LuaValueImpl], allowed_modes: &str) -> Fallible<Vec<LuaValueImpl>> {
+    check_string(args, 1, "loadstring")?;
+    load_with_modes(state, &args[..args.len().min(2)], allowed_modes)
+}
+/// Runs the file `filename`, or the standard input without it, returning the values of the chunk and raising its
+/// errors.
+fn dofile(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    crate::call_function(&load_file(&state, args, "bt")?, &[])
+}
+fn load_file(state: &LuaStateReference, args: &[LuaValueImpl], mode: &str) -> Fallible<LuaValueImpl> {
+    let (chunk_name, code) = match opt_string(args, 1, "dofile")? {
+        Some(filename) => {
+            let filename = String::from_utf8_lossy(&filename).into_owned();
+            let code = read_chunk_file(state, &filename)?;
+            (format!("@{}", filename), code)
+        }
+        None => {
+            let code = host(state).standard_stream(StandardStream::Input)?.read_to_end()?;
+            ("=stdin".to_string(), code)
+        }
+    };
+    load_chunk(state, &code, &chunk_name, mode)
+}
+static SANDBOX_LOAD: LuaClosureRustType = sandbox_load;
+static SANDBOX_LOADSTRING: LuaClosureRustType = sandbox_loadstring;
+static SANDBOX_DOFILE: LuaClosureRustType = sandbox_dofile;
+static SANDBOX_GETMETATABLE: LuaClosureRustType = sandbox_getmetatable;
+/// The value a sandbox gets for the global `name` of the state. The loaders enter their chunks with the sandbox
+/// instead of the global table and only load source code, the precompiled chunks are not checked. `getmetatable`
+/// hides the metatable strings share with the state.
+pub fn sandbox_global(state: LuaStateReference, name: &str, value: LuaValueImpl, sandbox: &Pointer<LuaTable>) -> Fallible<LuaValueImpl> {
+    let function: &'static LuaClosureRustType = match name {
+        "load" => &SANDBOX_LOAD,
+        "loadstring" => &SANDBOX_LOADSTRING,
+        "dofile" => &SANDBOX_DOFILE,
+        "getmetatable" => &SANDBOX_GETMETATABLE,
+        _ => return Ok(value),
+    };
+    crate::new_native_closure(state, function, &[LuaValueImpl::encode_table(sandbox.clone())])
+}
+/// The sandbox a function made by [`sandbox_global`] belongs to.
+fn sandbox_of(closure: &LuaClosureReference) -> LuaValueImpl {
+    unsafe { crate::native_closure_values(closure)[0].clone() }
+}
+extern "C" fn sandbox_load(state: LuaStateReference, closure: LuaClosureReference, args: &[LuaValueImpl]) -> Pointer<UnsizedArray<LuaValue>> {
+    let args = &args[..args.len().min(4)];
+    return_values(load_with_modes(state.clone(), args, "t").and_then(|rets| if args.len() < 4 { in_sandbox(state, &closure, rets) } else { Ok(rets) }))
+}
+extern "C" fn sandbox_loadstring(state: LuaStateReference, closure: LuaClosureReference, args: &[LuaValueImpl]) -> Pointer<UnsizedArray<LuaValue>> {
+    return_values(loadstring_with_modes(state.clone(), args, "t").and_then(|rets| in_sandbox(state, &closure, rets)))
+}
+/// Gives the sandbox to the function loaded, the error message after `nil` is left as it is.
+fn in_sandbox(state: LuaStateReference, closure: &LuaClosureReference, mut rets: Vec<LuaValueImpl>) -> Fallible<Vec<LuaValueImpl>> {
+    if rets[0].read_nil().is_none() {
+        rets[0] = with_env(state, rets[0].clone(), sandbox_of(closure))?;
+    }
+    Ok(rets)
+}
+extern "C" fn sandbox_dofile(state: LuaStateReference, closure: LuaClosureReference, args: &[LuaValueImpl]) -> Pointer<UnsizedArray<LuaValue>> {
+    return_values(load_file(&state, args, "t").and_then(|function| {
+        let function = with_env(state, function, sandbox_of(&closure))?;
+        crate::call_function(&function, &[])
+    }))
+}
+extern "C" fn sandbox_getmetatable(state: LuaStateReference, _closure: LuaClosureReference, args: &[LuaValueImpl]) -> Pointer<UnsizedArray<LuaValue>> {
+    return_values(match args.first() {
+        Some(value) if value.read_string().is_some() => Ok(vec![LuaValueImpl::encode_nil(())]),
+        _ => getmetatable(state, args),
+    })
+}
+const BASE_FUNCTIONS: &[(&str, &LuaFunctionRustType)] = native_functions![
+    "dofile" => dofile,
+    "error" => error,
+    "getmetatable" => getmetatable,
+    "ipairs" => ipairs,
+    "load" => load,
+    "loadstring" => loadstring,
+    "pcall" => pcall,
+    "rawequal" => rawequal,
+    "rawget" => rawget,
+    "rawlen" => rawlen,
+    "rawset" => rawset,
+    "setmetatable" => setmetatable,
+    "xpcall" => xpcall,
+];
 pub const DEFAULT_BUILT_IN_FUNCTIONS: &[(&str, &LuaFunctionRustType)] = &[
     ("print", &(print as LuaFunctionRustType)),
     ("exec_lua", &(exec_lua as LuaFunctionRustType)),
 ];
 pub fn register_built_in_functions(state: LuaStateReference) -> Fallible<()> {
-    for (name, function) in DEFAULT_BUILT_IN_FUNCTIONS {
+    for (name, function) in DEFAULT_BUILT_IN_FUNCTIONS.iter().chain(BASE_FUNCTIONS) {
         crate::add_global_function(state.clone(), name, function)?;
     }
-    Ok(())
+    string::register(state.clone())?;
+    table::register(state.clone())?;
+    math::register(state.clone())?;
+    coroutine::register(state.clone())?;
+    io::register(state.clone())?;
+    os::register(state.clone())?;
+    package::register(state)
 }
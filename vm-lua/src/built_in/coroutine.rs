@@ -0,0 +1,182 @@
+//! The coroutine library, each coroutine runs on its own native stack so it can yield in the middle of any call.
+//! https://www.lua.org/manual/5.4/manual.html#6.2
+use std::{cell::RefCell, mem, ptr::NonNull};
+
+use failure::Fallible;
+use vm_core::{CoroutineState, CoroutineYielder, Pointer, UnsizedArray};
+
+use super::{native_functions, return_values, type_error};
+use crate::{call_function, close::close_on_error, error::LuaError, mem::*, native_closure_values, new_boolean, new_native_closure, new_string, new_thread};
+
+pub const COROUTINE_FUNCTIONS: &[(&str, &LuaFunctionRustType)] = native_functions![
+    "create" => create,
+    "isyieldable" => isyieldable,
+    "resume" => resume,
+    "running" => running,
+    "status" => status,
+    "wrap" => wrap,
+    "yield" => yield_,
+];
+/// What the body of a coroutine returns when its function raised an error, which is then the only value transferred.
+const RETURN_ERROR: usize = 1;
+const RETURN_VALUES: usize = 0;
+thread_local! {
+    /// The threads being resumed, the last one is running.
+    static RESUMED: RefCell<Vec<Pointer<LuaThread>>> = RefCell::new(Vec::new());
+}
+pub fn register(state: LuaStateReference) -> Fallible<()> {
+    let library = super::new_library(state.clone(), COROUTINE_FUNCTIONS)?;
+    crate::add_global(state.clone(), new_string(state.as_pointer(), b"coroutine")?, LuaValueImpl::encode_table(library.as_pointer()))
+}
+fn nil() -> LuaValueImpl {
+    LuaValueImpl::encode_nil(())
+}
+fn check_thread(args: &[LuaValueImpl], index: usize, function: &str) -> Fallible<Pointer<LuaThread>> {
+    args.get(index - 1).and_then(|value| value.read_thread()).ok_or_else(|| type_error(args, index, function, "coroutine"))
+}
+fn check_function(args: &[LuaValueImpl], index: usize, function: &str) -> Fallible<LuaValueImpl> {
+    match args.get(index - 1) {
+        Some(value) if value.read_function().is_some() || value.read_closure().is_some() => Ok(value.clone()),
+        _ => Err(type_error(args, index, function, "function")),
+    }
+}
+/// The values passed by the last resume, yield or return of `thread`.
+#[allow(clippy::mut_from_ref)]
+fn transfer(thread: &Pointer<LuaThread>) -> &mut Vec<LuaValueImpl> {
+    unsafe { &mut *thread.as_ref().ref_transfer().get() }
+}
+fn current_thread() -> Option<Pointer<LuaThread>> {
+    RESUMED.with(|resumed| resumed.borrow().last().cloned())
+}
+/// The thread running now, the main thread when no coroutine is resumed.
+pub fn running_thread(state: &LuaStateReference) -> Pointer<LuaThread> {
+    current_thread().unwrap_or_else(|| unsafe { state.as_pointer().as_ref().get_main_thread() })
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Running,
+    Suspended,
+    Normal,
+    Dead,
+}
+impl Status {
+    fn name(self) -> &'static [u8] {
+        match self {
+            Status::Running => b"running",
+            Status::Suspended => b"suspended",
+            Status::Normal => b"normal",
+            Status::Dead => b"dead",
+        }
+    }
+}
+fn status_of(thread: &Pointer<LuaThread>) -> Status {
+    RESUMED.with(|resumed| {
+        let resumed = resumed.borrow();
+        let coroutine = unsafe { &*thread.as_ref().ref_coroutine().get() };
+        match (resumed.iter().rposition(|t| t.as_ptr() == thread.as_ptr()), coroutine) {
+            (Some(index), _) if index + 1 == resumed.len() => Status::Running,
+            (Some(_), _) => Status::Normal,
+            // the main thread
+            (None, None) if resumed.is_empty() => Status::Running,
+            (None, None) => Status::Normal,
+            (None, Some(coroutine)) if coroutine.is_finished() => Status::Dead,
+            (None, Some(_)) => Status::Suspended,
+        }
+    })
+}
+/// Makes a thread whose coroutine calls `function` with the values of the first resume.
+fn new_coroutine(state: &LuaStateReference, function: LuaValueImpl) -> Fallible<LuaThreadReference> {
+    let thread = new_thread(function.clone(), None)?;
+    let body_thread = thread.as_pointer();
+    let body_state = state.clone();
+    let runtime = unsafe { state.as_pointer().as_ref().ref_runtime().clone() };
+    let coroutine = runtime.create_coroutine(Box::new(move |yielder: &dyn CoroutineYielder, _| {
+        // the yielder lives until the body returns, after which the thread can not be resumed
+        let yielder: NonNull<dyn CoroutineYielder + '_> = NonNull::from(yielder);
+        unsafe {
+            body_thread.as_ref().ref_yielder().set(Some(mem::transmute(yielder)));
+        }
+        let args = mem::take(transfer(&body_thread));
+        match call_function(&function, &args) {
+            Ok(rets) => {
+                *transfer(&body_thread) = rets;
+                RETURN_VALUES
+            }
+            Err(e) => {
+                let error = LuaError::from_error(e).into_value(body_state.clone()).unwrap_or_else(|_| nil());
+                *transfer(&body_thread) = vec![close_on_error(&body_state, 0, error)];
+                RETURN_ERROR
+            }
+        }
+    }))?;
+    unsafe {
+        *thread.as_pointer().as_ref().ref_coroutine().get() = Some(coroutine);
+    }
+    Ok(thread)
+}
+/// Runs `thread` until it yields or returns, an error raised in it is the `Err` value.
+fn resume_thread(state: &LuaStateReference, thread: Pointer<LuaThread>, args: &[LuaValueImpl]) -> Fallible<Result<Vec<LuaValueImpl>, LuaValueImpl>> {
+    match status_of(&thread) {
+        Status::Suspended => {}
+        Status::Dead => return Ok(Err(new_string(state.as_pointer(), b"cannot resume dead coroutine")?)),
+        Status::Running | Status::Normal => return Ok(Err(new_string(state.as_pointer(), b"cannot resume non-suspended coroutine")?)),
+    }
+    *transfer(&thread) = args.to_vec();
+    RESUMED.with(|resumed| resumed.borrow_mut().push(thread.clone()));
+    let result = unsafe { (*thread.as_ref().ref_coroutine().get()).as_mut().unwrap().resume(0) };
+    RESUMED.with(|resumed| resumed.borrow_mut().pop());
+    let values = mem::take(transfer(&thread));
+    match result? {
+        CoroutineState::Returned(RETURN_ERROR) => Ok(Err(values.into_iter().next().unwrap_or_else(nil))),
+        CoroutineState::Returned(_) | CoroutineState::Yielded(_) => Ok(Ok(values)),
+    }
+}
+fn create(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let function = check_function(args, 1, "create")?;
+    Ok(vec![LuaValueImpl::encode_thread(new_coroutine(&state, function)?.as_pointer())])
+}
+fn resume(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let thread = check_thread(args, 1, "resume")?;
+    Ok(match resume_thread(&state, thread, &args[1..])? {
+        Ok(values) => std::iter::once(new_boolean(true)).chain(values).collect(),
+        Err(error) => vec![new_boolean(false), error],
+    })
+}
+/// Switches back to the resuming thread, the values of the next resume are returned.
+fn yield_(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let thread = current_thread().ok_or_else(|| format_err!("attempt to yield from outside a coroutine"))?;
+    *transfer(&thread) = args.to_vec();
+    unsafe {
+        let yielder = thread.as_ref().ref_yielder().get().unwrap();
+        yielder.as_ref().suspend(0);
+    }
+    Ok(mem::take(transfer(&thread)))
+}
+fn status(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let thread = check_thread(args, 1, "status")?;
+    Ok(vec![new_string(state.as_pointer(), status_of(&thread).name())?])
+}
+fn running(state: LuaStateReference, _args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    Ok(vec![LuaValueImpl::encode_thread(running_thread(&state)), new_boolean(current_thread().is_none())])
+}
+fn isyieldable(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let yieldable = match args.first() {
+        Some(_) => status_of(&check_thread(args, 1, "isyieldable")?) == Status::Running && current_thread().is_some(),
+        None => current_thread().is_some(),
+    };
+    Ok(vec![new_boolean(yieldable)])
+}
+static WRAP_STEP: LuaClosureRustType = wrap_step;
+/// Returns a function resuming a new coroutine, which raises the errors of the coroutine instead of returning them.
+fn wrap(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let function = check_function(args, 1, "wrap")?;
+    let thread = new_coroutine(&state, function)?;
+    Ok(vec![new_native_closure(state, &WRAP_STEP, &[LuaValueImpl::encode_thread(thread.as_pointer())])?])
+}
+extern "C" fn wrap_step(state: LuaStateReference, closure: LuaClosureReference, args: &[LuaValueImpl]) -> Pointer<UnsizedArray<LuaValue>> {
+    return_values(wrap_resume(state, closure, args))
+}
+fn wrap_resume(state: LuaStateReference, closure: LuaClosureReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let thread = unsafe { native_closure_values(&closure) }[0].read_thread().unwrap();
+    resume_thread(&state, thread, args)?.map_err(|error| LuaError::Value(error).into())
+}
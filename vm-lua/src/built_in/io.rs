@@ -0,0 +1,437 @@
+//! The io library, files are opened by the host of the state and kept by the state while they are open. A file is a
+//! table with the metatable of files, its methods are in the `__index` of the metatable.
+//! https://www.lua.org/manual/5.4/manual.html#6.8
+use std::{collections::HashMap, io::SeekFrom};
+
+use failure::{Error, Fallible};
+use vm_core::{Pointer, UnsizedArray};
+
+use super::{argument_error, check_string, native_functions, opt_integer, read_number, read_string, return_values, to_string, type_error, Number};
+use crate::{
+    host::{host, HostFile, StandardStream},
+    mem::*,
+    native_closure_values, new_boolean, new_integer, new_native_closure, new_string, set_field,
+};
+
+pub const IO_FUNCTIONS: &[(&str, &LuaFunctionRustType)] = native_functions![
+    "close" => close,
+    "flush" => flush,
+    "input" => input,
+    "lines" => lines,
+    "open" => open,
+    "output" => output,
+    "read" => read,
+    "type" => type_,
+    "write" => write,
+];
+const FILE_METHODS: &[(&str, &LuaFunctionRustType)] = native_functions![
+    "close" => file_close,
+    "flush" => file_flush,
+    "lines" => file_lines,
+    "read" => file_read,
+    "seek" => file_seek,
+    "setvbuf" => file_setvbuf,
+    "write" => file_write,
+];
+const META_FUNCTIONS: &[(&str, &LuaFunctionRustType)] = native_functions![
+    "__close" => close_if_open,
+];
+/// How many bytes are read from a file at once.
+const READ_CHUNK: usize = 4096;
+/// The longest numeral `read("n")` reads.
+const MAX_NUMERAL: usize = 200;
+enum Handle {
+    File(Box<dyn HostFile>),
+    /// Asked to the host when it is first used, so the host of the state can be set after the library is registered.
+    Standard(StandardStream, Option<Box<dyn HostFile>>),
+}
+enum Format {
+    Number,
+    Line,
+    LineWithEnd,
+    All,
+    Count(usize),
+}
+pub struct OpenFile {
+    handle: Handle,
+    /// Read from the file but not by the script yet.
+    buffer: Vec<u8>,
+}
+#[derive(Default)]
+pub struct Files {
+    /// The open files by the address of their tables.
+    open: HashMap<usize, OpenFile>,
+    metatable: Option<Pointer<LuaTable>>,
+    input: Option<Pointer<LuaTable>>,
+    output: Option<Pointer<LuaTable>>,
+}
+impl OpenFile {
+    fn file(&mut self, state: &LuaStateReference) -> Fallible<&mut dyn HostFile> {
+        match &mut self.handle {
+            Handle::File(file) => Ok(&mut **file),
+            Handle::Standard(stream, file) => {
+                if file.is_none() {
+                    *file = Some(host(state).standard_stream(*stream)?);
+                }
+                Ok(&mut **file.as_mut().unwrap())
+            }
+        }
+    }
+
+    /// Reads more of the file into the buffer, false at the end of the file.
+    fn fill(&mut self, state: &LuaStateReference) -> Fallible<bool> {
+        let mut chunk = [0; READ_CHUNK];
+        let len = self.file(state)?.read(&mut chunk)?;
+        self.buffer.extend_from_slice(&chunk[..len]);
+        Ok(len != 0)
+    }
+
+    fn read_line(&mut self, state: &LuaStateReference, keep_end: bool) -> Fallible<Option<Vec<u8>>> {
+        let mut searched = 0;
+        loop {
+            if let Some(end) = self.buffer[searched..].iter().position(|c| *c == b'\n') {
+                let mut line: Vec<u8> = self.buffer.drain(..searched + end + 1).collect();
+                if !keep_end {
+                    line.pop();
+                }
+                return Ok(Some(line));
+            }
+            searched = self.buffer.len();
+            if !self.fill(state)? {
+                return Ok(if self.buffer.is_empty() { None } else { Some(std::mem::take(&mut self.buffer)) });
+            }
+        }
+    }
+
+    fn read_all(&mut self, state: &LuaStateReference) -> Fallible<Vec<u8>> {
+        while self.fill(state)? {}
+        Ok(std::mem::take(&mut self.buffer))
+    }
+
+    /// Reads `count` bytes, or less at the end of the file, which is nil unless `count` is 0.
+    fn read_count(&mut self, state: &LuaStateReference, count: usize) -> Fallible<Option<Vec<u8>>> {
+        while self.buffer.len() < count.max(1) && self.fill(state)? {}
+        if self.buffer.is_empty() && count != 0 {
+            return Ok(None);
+        }
+        let len = count.min(self.buffer.len());
+        Ok(Some(self.buffer.drain(..len).collect()))
+    }
+
+    fn read_number(&mut self, state: &LuaStateReference) -> Fallible<Option<Number>> {
+        loop {
+            match self.buffer.iter().position(|c| !c.is_ascii_whitespace()) {
+                Some(start) => {
+                    self.buffer.drain(..start);
+                    break;
+                }
+                None => {
+                    self.buffer.clear();
+                    if !self.fill(state)? {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+        let is_numeral = |c: &u8| c.is_ascii_hexdigit() || b"+-.xXpP".contains(c);
+        while self.buffer.len() < MAX_NUMERAL && self.buffer.iter().all(is_numeral) && self.fill(state)? {}
+        let len = self.buffer.iter().position(|c| !is_numeral(c)).unwrap_or(self.buffer.len()).min(MAX_NUMERAL);
+        let numeral: Vec<u8> = self.buffer.drain(..len).collect();
+        Ok(Number::parse(&numeral))
+    }
+
+    /// Moves the position of the file back over the bytes read ahead, before it is written or its position is used.
+    fn unread(&mut self, state: &LuaStateReference) -> Fallible<()> {
+        if !self.buffer.is_empty() {
+            let len = self.buffer.len() as i64;
+            self.buffer.clear();
+            self.file(state)?.seek(SeekFrom::Current(-len))?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, state: &LuaStateReference, bytes: &[u8]) -> Fallible<()> {
+        self.unread(state)?;
+        Ok(self.file(state)?.write(bytes)?)
+    }
+
+    fn seek(&mut self, state: &LuaStateReference, position: SeekFrom) -> Fallible<u64> {
+        self.unread(state)?;
+        Ok(self.file(state)?.seek(position)?)
+    }
+
+    fn flush(&mut self, state: &LuaStateReference) -> Fallible<()> {
+        Ok(self.file(state)?.flush()?)
+    }
+}
+fn files<'s>(state: &LuaStateReference) -> &'s mut Files {
+    unsafe { (*state.as_pointer().as_ptr_mut()).ref_files_mut() }
+}
+fn key(file: &Pointer<LuaTable>) -> usize {
+    file.as_ptr() as usize
+}
+fn nil() -> LuaValueImpl {
+    LuaValueImpl::encode_nil(())
+}
+/// The results of a failed operation, nil and the message.
+fn fail(state: &LuaStateReference, error: Error) -> Fallible<Vec<LuaValueImpl>> {
+    Ok(vec![nil(), new_string(state.as_pointer(), error.to_string().as_bytes())?])
+}
+fn new_file(state: &LuaStateReference, handle: Handle) -> Fallible<Pointer<LuaTable>> {
+    let file = crate::new_table(crate::new_meta_functions()?, 0, true)?.as_pointer();
+    crate::set_metatable(file.clone(), files(state).metatable.clone())?;
+    files(state).open.insert(key(&file), OpenFile { handle, buffer: Vec::new() });
+    Ok(file)
+}
+fn open_file(state: &LuaStateReference, filename: &str, mode: &str) -> Fallible<Pointer<LuaTable>> {
+    let file = host(state).open(filename, mode).map_err(|e| format_err!("{}: {}", filename, e))?;
+    new_file(state, Handle::File(file))
+}
+/// Whether `value` is a file, open or closed.
+fn is_file(state: &LuaStateReference, value: &LuaValueImpl) -> Option<Pointer<LuaTable>> {
+    let table = value.read_table()?;
+    let metatable = crate::get_metatable(table.clone())?;
+    (Some(key(&metatable)) == files(state).metatable.as_ref().map(key)).then_some(table)
+}
+fn check_file<'s>(state: &LuaStateReference, args: &[LuaValueImpl], index: usize, function: &str) -> Fallible<(Pointer<LuaTable>, &'s mut OpenFile)> {
+    let file = args.get(index - 1).and_then(|value| is_file(state, value)).ok_or_else(|| type_error(args, index, function, "FILE*"))?;
+    let open = files(state).open.get_mut(&key(&file)).ok_or_else(|| format_err!("attempt to use a closed file"))?;
+    Ok((file, open))
+}
+pub fn register(state: LuaStateReference) -> Fallible<()> {
+    let library = super::new_library(state.clone(), IO_FUNCTIONS)?;
+    let methods = super::new_library(state.clone(), FILE_METHODS)?;
+    let metatable = super::new_library(state.clone(), META_FUNCTIONS)?;
+    set_field(metatable.as_pointer(), new_string(state.as_pointer(), b"__index")?, LuaValueImpl::encode_table(methods.as_pointer()))?;
+    set_field(metatable.as_pointer(), new_string(state.as_pointer(), b"__name")?, new_string(state.as_pointer(), b"FILE*")?)?;
+    files(&state).metatable = Some(metatable.as_pointer());
+    for (name, stream) in [("stdin", StandardStream::Input), ("stdout", StandardStream::Output), ("stderr", StandardStream::Error)] {
+        let file = new_file(&state, Handle::Standard(stream, None))?;
+        match stream {
+            StandardStream::Input => files(&state).input = Some(file.clone()),
+            StandardStream::Output => files(&state).output = Some(file.clone()),
+            StandardStream::Error => {}
+        }
+        set_field(library.as_pointer(), new_string(state.as_pointer(), name.as_bytes())?, LuaValueImpl::encode_table(file))?;
+    }
+    crate::add_global(state.clone(), new_string(state.as_pointer(), b"io")?, LuaValueImpl::encode_table(library.as_pointer()))
+}
+fn open(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let filename = String::from_utf8_lossy(&check_string(args, 1, "open")?).into_owned();
+    let mode = match args.get(1) {
+        Some(mode) if mode.read_nil().is_none() => String::from_utf8_lossy(&check_string(args, 2, "open")?).into_owned(),
+        _ => "r".to_string(),
+    };
+    if !matches!(mode.trim_end_matches('b'), "r" | "w" | "a" | "r+" | "w+" | "a+") {
+        return Err(argument_error(2, "open", "invalid mode"));
+    }
+    match open_file(&state, &filename, &mode) {
+        Ok(file) => Ok(vec![LuaValueImpl::encode_table(file)]),
+        Err(e) => fail(&state, e),
+    }
+}
+fn type_(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let value = args.first().ok_or_else(|| argument_error(1, "type", "value expected"))?;
+    Ok(vec![match is_file(&state, value) {
+        Some(file) if files(&state).open.contains_key(&key(&file)) => new_string(state.as_pointer(), b"file")?,
+        Some(_) => new_string(state.as_pointer(), b"closed file")?,
+        None => nil(),
+    }])
+}
+/// Sets or returns the default file of `io.read` or `io.write`, a file name is opened with `mode`.
+fn default_file(state: &LuaStateReference, args: &[LuaValueImpl], function: &str, mode: &str) -> Fallible<Pointer<LuaTable>> {
+    let file = match args.first() {
+        Some(value) if value.read_string().is_some() => Some(open_file(state, &String::from_utf8_lossy(&to_string(value)), mode)?),
+        Some(value) if value.read_nil().is_none() => Some(check_file(state, args, 1, function)?.0),
+        _ => None,
+    };
+    let current = if mode == "r" { &mut files(state).input } else { &mut files(state).output };
+    if file.is_some() {
+        *current = file;
+    }
+    current.clone().ok_or_else(|| format_err!("default {} file is closed", if mode == "r" { "input" } else { "output" }))
+}
+fn input(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    Ok(vec![LuaValueImpl::encode_table(default_file(&state, args, "input", "r")?)])
+}
+fn output(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    Ok(vec![LuaValueImpl::encode_table(default_file(&state, args, "output", "w")?)])
+}
+/// The arguments of a function of the library called with the default file in place of the file.
+fn with_default(state: &LuaStateReference, args: &[LuaValueImpl], function: &str, mode: &str) -> Fallible<Vec<LuaValueImpl>> {
+    let file = default_file(state, &[], function, mode)?;
+    Ok(std::iter::once(LuaValueImpl::encode_table(file)).chain(args.iter().cloned()).collect())
+}
+fn close(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    match args.first() {
+        Some(_) => file_close(state, args),
+        None => file_close(state.clone(), &with_default(&state, args, "close", "w")?),
+    }
+}
+fn flush(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    file_flush(state.clone(), &with_default(&state, args, "flush", "w")?)
+}
+fn read(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    file_read(state.clone(), &with_default(&state, args, "read", "r")?)
+}
+fn write(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    file_write(state.clone(), &with_default(&state, args, "write", "w")?)
+}
+static LINES_STEP: LuaClosureRustType = lines_step;
+/// Iterates the lines of a file, or the values read with the given formats. A file opened by `io.lines` is closed at
+/// its end.
+fn lines(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let (file, close_at_end) = match args.first() {
+        Some(filename) if filename.read_nil().is_none() => {
+            let filename = String::from_utf8_lossy(&check_string(args, 1, "lines")?).into_owned();
+            (open_file(&state, &filename, "r")?, true)
+        }
+        _ => (default_file(&state, &[], "lines", "r")?, false),
+    };
+    let formats = args.get(1..).unwrap_or(&[]);
+    let values: Vec<LuaValueImpl> = [LuaValueImpl::encode_table(file), new_boolean(close_at_end)].into_iter().chain(formats.iter().cloned()).collect();
+    Ok(vec![new_native_closure(state, &LINES_STEP, &values)?])
+}
+fn file_lines(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let (file, _) = check_file(&state, args, 1, "lines")?;
+    let values: Vec<LuaValueImpl> = [LuaValueImpl::encode_table(file), new_boolean(false)].into_iter().chain(args[1..].iter().cloned()).collect();
+    Ok(vec![new_native_closure(state, &LINES_STEP, &values)?])
+}
+extern "C" fn lines_step(state: LuaStateReference, closure: LuaClosureReference, _args: &[LuaValueImpl]) -> Pointer<UnsizedArray<LuaValue>> {
+    return_values(next_lines(state, closure))
+}
+fn next_lines(state: LuaStateReference, closure: LuaClosureReference) -> Fallible<Vec<LuaValueImpl>> {
+    let values = unsafe { native_closure_values(&closure) }.to_vec();
+    let file = values[0].read_table().unwrap();
+    let open = files(&state).open.get_mut(&key(&file)).ok_or_else(|| format_err!("file is already closed"))?;
+    let rets = read_formats(&state, open, &check_formats(&values[2..], 2, "lines")?)?;
+    if rets.first().map_or(true, |value| value.read_nil().is_some()) && super::to_boolean(&values[1]) {
+        file_close(state, &values[..1])?;
+    }
+    Ok(rets)
+}
+/// Checks the formats of `read`, the first of them is the argument `first_index`.
+fn check_formats(formats: &[LuaValueImpl], first_index: usize, function: &str) -> Fallible<Vec<Format>> {
+    if formats.is_empty() {
+        return Ok(vec![Format::Line]);
+    }
+    let mut checked = Vec::with_capacity(formats.len());
+    for (index, format) in formats.iter().enumerate() {
+        let index = index + first_index;
+        checked.push(match (read_number(format), read_string(format)) {
+            (Some(count), _) => {
+                let count = count.as_integer().ok_or_else(|| argument_error(index, function, "number has no integer representation"))?;
+                Format::Count(usize::try_from(count).unwrap_or(0))
+            }
+            (None, Some(format)) => match format.strip_prefix(b"*").unwrap_or(&format).first() {
+                Some(b'n') => Format::Number,
+                Some(b'l') => Format::Line,
+                Some(b'L') => Format::LineWithEnd,
+                Some(b'a') => Format::All,
+                _ => return Err(argument_error(index, function, "invalid format")),
+            },
+            (None, None) => return Err(argument_error(index, function, "invalid format")),
+        });
+    }
+    Ok(checked)
+}
+/// Reads a value for each format, up to the first which fails which gives nil.
+fn read_formats(state: &LuaStateReference, open: &mut OpenFile, formats: &[Format]) -> Fallible<Vec<LuaValueImpl>> {
+    let mut rets = Vec::with_capacity(formats.len());
+    for format in formats {
+        let bytes = match format {
+            Format::Number => {
+                match open.read_number(state)? {
+                    Some(number) => rets.push(number.into_value()?),
+                    None => {
+                        rets.push(nil());
+                        break;
+                    }
+                }
+                continue;
+            }
+            Format::Line => open.read_line(state, false)?,
+            Format::LineWithEnd => open.read_line(state, true)?,
+            Format::All => Some(open.read_all(state)?),
+            Format::Count(count) => open.read_count(state, *count)?,
+        };
+        match bytes {
+            Some(bytes) => rets.push(new_string(state.as_pointer(), &bytes)?),
+            None => {
+                rets.push(nil());
+                break;
+            }
+        }
+    }
+    Ok(rets)
+}
+fn file_read(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let (_, open) = check_file(&state, args, 1, "read")?;
+    let formats = check_formats(&args[1..], 2, "read")?;
+    match read_formats(&state, open, &formats) {
+        Ok(rets) => Ok(rets),
+        Err(e) => fail(&state, e),
+    }
+}
+fn file_write(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let (file, open) = check_file(&state, args, 1, "write")?;
+    for index in 2..=args.len() {
+        let bytes = check_string(args, index, "write")?;
+        if let Err(e) = open.write(&state, &bytes) {
+            return fail(&state, e);
+        }
+    }
+    Ok(vec![LuaValueImpl::encode_table(file)])
+}
+fn file_seek(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let (_, open) = check_file(&state, args, 1, "seek")?;
+    let whence = match args.get(1) {
+        Some(whence) if whence.read_nil().is_none() => check_string(args, 2, "seek")?,
+        _ => b"cur".to_vec(),
+    };
+    let offset = opt_integer(args, 3, "seek", 0)?;
+    let position = match whence.as_slice() {
+        b"set" => SeekFrom::Start(u64::try_from(offset).map_err(|_| argument_error(3, "seek", "invalid offset"))?),
+        b"cur" => SeekFrom::Current(offset),
+        b"end" => SeekFrom::End(offset),
+        _ => return Err(argument_error(2, "seek", "invalid option")),
+    };
+    match open.seek(&state, position) {
+        Ok(position) => Ok(vec![new_integer(position as i64)?]),
+        Err(e) => fail(&state, e),
+    }
+}
+/// The buffering of the host files is not controlled, the mode is only checked.
+fn file_setvbuf(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    check_file(&state, args, 1, "setvbuf")?;
+    match check_string(args, 2, "setvbuf")?.as_slice() {
+        b"no" | b"full" | b"line" => Ok(vec![new_boolean(true)]),
+        _ => Err(argument_error(2, "setvbuf", "invalid option")),
+    }
+}
+fn file_flush(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let (file, open) = check_file(&state, args, 1, "flush")?;
+    match open.flush(&state) {
+        Ok(()) => Ok(vec![LuaValueImpl::encode_table(file)]),
+        Err(e) => fail(&state, e),
+    }
+}
+fn file_close(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let (file, open) = check_file(&state, args, 1, "close")?;
+    if let Handle::Standard(..) = open.handle {
+        return fail(&state, format_err!("cannot close standard file"));
+    }
+    let flushed = open.flush(&state);
+    files(&state).open.remove(&key(&file));
+    match flushed {
+        Ok(()) => Ok(vec![new_boolean(true)]),
+        Err(e) => fail(&state, e),
+    }
+}
+/// The `__close` of files, so a `<close>` file is closed at the end of its scope.
+fn close_if_open(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    match args.first().and_then(|value| is_file(&state, value)) {
+        Some(file) if files(&state).open.contains_key(&key(&file)) => file_close(state, &args[..1]),
+        _ => Ok(Vec::new()),
+    }
+}
@@ -0,0 +1,303 @@
+//! The math library, keeping integers and floats apart like the arithmetic does.
+//! https://www.lua.org/manual/5.4/manual.html#6.7
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::Fallible;
+use vm_core::{Pointer, UnsizedArray};
+
+use super::{argument_error, check_integer, check_number, native_functions, opt_integer, read_number, return_values, Number};
+use crate::{get_field, mem::*, native_closure_values, new_float, new_integer, new_native_closure, new_string, set_field};
+
+pub const MATH_FUNCTIONS: &[(&str, &LuaFunctionRustType)] = native_functions![
+    "abs" => abs,
+    "acos" => acos,
+    "asin" => asin,
+    "atan" => atan,
+    "ceil" => ceil,
+    "cos" => cos,
+    "exp" => exp,
+    "floor" => floor,
+    "fmod" => fmod,
+    "log" => log,
+    "max" => max,
+    "min" => min,
+    "modf" => modf,
+    "sin" => sin,
+    "sqrt" => sqrt,
+    "tan" => tan,
+    "tointeger" => tointeger,
+    "type" => type_,
+    "ult" => ult,
+];
+/// Registers the `math` table, `random` and `randomseed` share a table holding the state of the generator.
+pub fn register(state: LuaStateReference) -> Fallible<()> {
+    let library = super::new_library(state.clone(), MATH_FUNCTIONS)?.as_pointer();
+    let constants = [
+        ("pi", new_float(std::f64::consts::PI)?),
+        ("huge", new_float(f64::INFINITY)?),
+        ("maxinteger", new_integer(i64::MAX)?),
+        ("mininteger", new_integer(i64::MIN)?),
+    ];
+    for (name, value) in constants {
+        set_field(library.clone(), new_string(state.as_pointer(), name.as_bytes())?, value)?;
+    }
+    let random_state = crate::new_table(crate::new_meta_functions()?, 0, true)?.as_pointer();
+    let (n1, n2) = random_seed();
+    set_seed(&random_state, n1, n2)?;
+    let random_state = [LuaValueImpl::encode_table(random_state)];
+    for (name, function) in [("random", &RANDOM), ("randomseed", &RANDOM_SEED)] {
+        let closure = new_native_closure(state.clone(), function, &random_state)?;
+        set_field(library.clone(), new_string(state.as_pointer(), name.as_bytes())?, closure)?;
+    }
+    crate::add_global(state.clone(), new_string(state.as_pointer(), b"math")?, LuaValueImpl::encode_table(library))
+}
+fn float_result(value: f64) -> Fallible<Vec<LuaValueImpl>> {
+    Ok(vec![new_float(value)?])
+}
+/// A float with an integral value which fits becomes an integer.
+fn float_to_number(value: f64) -> Number {
+    Number::Float(value).as_integer().map_or(Number::Float(value), Number::Integer)
+}
+fn floor(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let value = match check_number(args, 1, "floor")? {
+        Number::Float(f) => float_to_number(f.floor()),
+        integer => integer,
+    };
+    Ok(vec![value.into_value()?])
+}
+fn ceil(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let value = match check_number(args, 1, "ceil")? {
+        Number::Float(f) => float_to_number(f.ceil()),
+        integer => integer,
+    };
+    Ok(vec![value.into_value()?])
+}
+fn abs(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let value = match check_number(args, 1, "abs")? {
+        Number::Integer(i) => Number::Integer(i.wrapping_abs()),
+        Number::Float(f) => Number::Float(f.abs()),
+    };
+    Ok(vec![value.into_value()?])
+}
+/// Whether `a < b`, comparing integers and floats by their exact values.
+fn number_less(a: Number, b: Number) -> bool {
+    match (a, b) {
+        (Number::Integer(a), Number::Integer(b)) => a < b,
+        (Number::Float(a), Number::Float(b)) => a < b,
+        (Number::Integer(a), Number::Float(b)) => match Number::Float(b.ceil()).as_integer() {
+            Some(b) => a < b,
+            None => b > 0.0,
+        },
+        (Number::Float(a), Number::Integer(b)) => match Number::Float(a.floor()).as_integer() {
+            Some(a) => a < b,
+            None => a < 0.0,
+        },
+    }
+}
+fn max(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let mut max = check_number(args, 1, "max")?;
+    for index in 2..=args.len() {
+        let value = check_number(args, index, "max")?;
+        if number_less(max, value) {
+            max = value;
+        }
+    }
+    Ok(vec![max.into_value()?])
+}
+fn min(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let mut min = check_number(args, 1, "min")?;
+    for index in 2..=args.len() {
+        let value = check_number(args, index, "min")?;
+        if number_less(value, min) {
+            min = value;
+        }
+    }
+    Ok(vec![min.into_value()?])
+}
+fn fmod(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let value = match (check_number(args, 1, "fmod")?, check_number(args, 2, "fmod")?) {
+        (Number::Integer(_), Number::Integer(0)) => return Err(argument_error(2, "fmod", "zero")),
+        // avoids the overflow of `mininteger % -1`
+        (Number::Integer(_), Number::Integer(-1)) => Number::Integer(0),
+        (Number::Integer(a), Number::Integer(b)) => Number::Integer(a % b),
+        (a, b) => Number::Float(a.as_float() % b.as_float()),
+    };
+    Ok(vec![value.into_value()?])
+}
+fn modf(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    match check_number(args, 1, "modf")? {
+        Number::Integer(i) => Ok(vec![new_integer(i)?, new_float(0.0)?]),
+        Number::Float(f) => {
+            let integral = f.trunc();
+            let fraction = if f == integral { 0.0 } else { f - integral };
+            Ok(vec![new_float(integral)?, new_float(fraction)?])
+        }
+    }
+}
+fn sqrt(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    float_result(check_number(args, 1, "sqrt")?.as_float().sqrt())
+}
+fn sin(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    float_result(check_number(args, 1, "sin")?.as_float().sin())
+}
+fn cos(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    float_result(check_number(args, 1, "cos")?.as_float().cos())
+}
+fn tan(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    float_result(check_number(args, 1, "tan")?.as_float().tan())
+}
+fn asin(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    float_result(check_number(args, 1, "asin")?.as_float().asin())
+}
+fn acos(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    float_result(check_number(args, 1, "acos")?.as_float().acos())
+}
+fn atan(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let y = check_number(args, 1, "atan")?.as_float();
+    let x = match args.get(1) {
+        Some(value) if value.read_nil().is_none() => check_number(args, 2, "atan")?.as_float(),
+        _ => 1.0,
+    };
+    float_result(y.atan2(x))
+}
+fn exp(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    float_result(check_number(args, 1, "exp")?.as_float().exp())
+}
+fn log(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let x = check_number(args, 1, "log")?.as_float();
+    let value = match args.get(1) {
+        Some(value) if value.read_nil().is_none() => match check_number(args, 2, "log")?.as_float() {
+            base if base == 2.0 => x.log2(),
+            base if base == 10.0 => x.log10(),
+            base => x.ln() / base.ln(),
+        },
+        _ => x.ln(),
+    };
+    float_result(value)
+}
+fn tointeger(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    if args.is_empty() {
+        return Err(argument_error(1, "tointeger", "value expected"));
+    }
+    match read_number(&args[0]).and_then(Number::as_integer) {
+        Some(i) => Ok(vec![new_integer(i)?]),
+        None => Ok(vec![LuaValueImpl::encode_nil(())]),
+    }
+}
+fn type_(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let name: &[u8] = match args.first().map(|value| (value, read_number(value))) {
+        None => return Err(argument_error(1, "type", "value expected")),
+        Some((_, Some(Number::Integer(_)))) => b"integer",
+        Some((_, Some(Number::Float(_)))) => b"float",
+        Some((_, None)) => return Ok(vec![LuaValueImpl::encode_nil(())]),
+    };
+    Ok(vec![new_string(state.as_pointer(), name)?])
+}
+fn ult(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let (a, b) = (check_integer(args, 1, "ult")?, check_integer(args, 2, "ult")?);
+    Ok(vec![crate::new_boolean((a as u64) < (b as u64))])
+}
+static RANDOM: LuaClosureRustType = random;
+static RANDOM_SEED: LuaClosureRustType = randomseed;
+/// The state of xoshiro256**, the generator of Lua 5.4, is kept in the integers 1 to 4 of a table.
+fn read_state(table: &Pointer<LuaTable>) -> Fallible<[u64; 4]> {
+    let mut state = [0; 4];
+    for (index, word) in state.iter_mut().enumerate() {
+        let value = get_field(table.clone(), &new_integer(index as i64 + 1)?);
+        *word = read_number(&value).and_then(Number::as_integer).unwrap_or(0) as u64;
+    }
+    Ok(state)
+}
+fn write_state(table: &Pointer<LuaTable>, state: [u64; 4]) -> Fallible<()> {
+    for (index, word) in state.into_iter().enumerate() {
+        set_field(table.clone(), new_integer(index as i64 + 1)?, new_integer(word as i64)?)?;
+    }
+    Ok(())
+}
+fn next_random(state: &mut [u64; 4]) -> u64 {
+    let result = state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+    let t = state[1] << 17;
+    state[2] ^= state[0];
+    state[3] ^= state[1];
+    state[1] ^= state[2];
+    state[0] ^= state[3];
+    state[2] ^= t;
+    state[3] = state[3].rotate_left(45);
+    result
+}
+/// Projects a random integer into `0..=n`, drawing again when it falls outside.
+fn project(mut random: u64, n: u64, state: &mut [u64; 4]) -> u64 {
+    if n & n.wrapping_add(1) == 0 {
+        return random & n;
+    }
+    // the smallest 2^b - 1 not smaller than n
+    let limit = u64::MAX >> n.leading_zeros();
+    loop {
+        random &= limit;
+        if random <= n {
+            return random;
+        }
+        random = next_random(state);
+    }
+}
+fn set_seed(table: &Pointer<LuaTable>, n1: i64, n2: i64) -> Fallible<()> {
+    let mut state = [n1 as u64, 0xff, n2 as u64, 0];
+    for _ in 0..16 {
+        next_random(&mut state);
+    }
+    write_state(table, state)
+}
+/// A seed from the time and an address, when the script gives none.
+fn random_seed() -> (i64, i64) {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_nanos() as i64);
+    (time, &time as *const i64 as i64)
+}
+fn generator_state(closure: &LuaClosureReference) -> Pointer<LuaTable> {
+    unsafe { native_closure_values(closure)[0].read_table().unwrap() }
+}
+extern "C" fn random(_state: LuaStateReference, closure: LuaClosureReference, args: &[LuaValueImpl]) -> Pointer<UnsizedArray<LuaValue>> {
+    return_values(random_value(generator_state(&closure), args))
+}
+fn random_value(table: Pointer<LuaTable>, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let mut state = read_state(&table)?;
+    let random = next_random(&mut state);
+    let (low, up) = match args.len() {
+        0 => {
+            write_state(&table, state)?;
+            // the 53 high bits make a float in [0, 1)
+            return Ok(vec![new_float((random >> 11) as f64 * 0.5f64.powi(53))?]);
+        }
+        1 => {
+            let up = check_integer(args, 1, "random")?;
+            if up == 0 {
+                write_state(&table, state)?;
+                return Ok(vec![new_integer(random as i64)?]);
+            }
+            (1, up)
+        }
+        2 => (check_integer(args, 1, "random")?, check_integer(args, 2, "random")?),
+        _ => return Err(format_err!("wrong number of arguments")),
+    };
+    if low > up {
+        return Err(argument_error(1, "random", "interval is empty"));
+    }
+    let value = project(random, (up as u64).wrapping_sub(low as u64), &mut state).wrapping_add(low as u64);
+    write_state(&table, state)?;
+    Ok(vec![new_integer(value as i64)?])
+}
+extern "C" fn randomseed(_state: LuaStateReference, closure: LuaClosureReference, args: &[LuaValueImpl]) -> Pointer<UnsizedArray<LuaValue>> {
+    return_values(seed_random(generator_state(&closure), args))
+}
+fn seed_random(table: Pointer<LuaTable>, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let (n1, n2) = if args.is_empty() {
+        random_seed()
+    } else {
+        let n1 = match check_number(args, 1, "randomseed")? {
+            Number::Integer(i) => i,
+            Number::Float(f) => f as i64,
+        };
+        (n1, opt_integer(args, 2, "randomseed", 0)?)
+    };
+    set_seed(&table, n1, n2)?;
+    Ok(vec![new_integer(n1)?, new_integer(n2)?])
+}
@@ -0,0 +1,190 @@
+//! The os library, the clocks, the environment and the files are reached through the host of the state.
+//! https://www.lua.org/manual/5.4/manual.html#6.9
+use failure::Fallible;
+use vm_core::Pointer;
+
+use super::{argument_error, check_integer, check_number, check_string, check_table, native_functions, read_number, to_boolean};
+use crate::{
+    get_field,
+    host::{host, DateTime},
+    mem::*,
+    new_boolean, new_float, new_integer, new_string, set_field,
+};
+
+pub const OS_FUNCTIONS: &[(&str, &LuaFunctionRustType)] = native_functions![
+    "clock" => clock,
+    "date" => date,
+    "difftime" => difftime,
+    "exit" => exit,
+    "getenv" => getenv,
+    "remove" => remove,
+    "rename" => rename,
+    "time" => time,
+];
+const DAY_NAMES: &[&str] = &["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday"];
+const MONTH_NAMES: &[&str] = &["January", "February", "March", "April", "May", "June", "July", "August", "September", "October", "November", "December"];
+pub fn register(state: LuaStateReference) -> Fallible<()> {
+    let library = super::new_library(state.clone(), OS_FUNCTIONS)?;
+    crate::add_global(state.clone(), new_string(state.as_pointer(), b"os")?, LuaValueImpl::encode_table(library.as_pointer()))
+}
+fn nil() -> LuaValueImpl {
+    LuaValueImpl::encode_nil(())
+}
+fn string_argument(args: &[LuaValueImpl], index: usize, function: &str) -> Fallible<String> {
+    Ok(String::from_utf8_lossy(&check_string(args, index, function)?).into_owned())
+}
+/// The results of an operation on the file `path`, true or nil and the message.
+fn file_result(state: &LuaStateReference, path: &str, result: Fallible<()>) -> Fallible<Vec<LuaValueImpl>> {
+    match result {
+        Ok(()) => Ok(vec![new_boolean(true)]),
+        Err(e) => Ok(vec![nil(), new_string(state.as_pointer(), format!("{}: {}", path, e).as_bytes())?]),
+    }
+}
+fn clock(state: LuaStateReference, _args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    Ok(vec![new_float(host(&state).clock()?)?])
+}
+fn difftime(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let end = check_number(args, 1, "difftime")?.as_float();
+    let start = match args.get(1) {
+        Some(start) if start.read_nil().is_none() => check_number(args, 2, "difftime")?.as_float(),
+        _ => 0.0,
+    };
+    Ok(vec![new_float(end - start)?])
+}
+/// Ends the process when the host can, `true` is a success and `false` a failure like the exit codes.
+fn exit(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let code = match args.first() {
+        None => 0,
+        Some(code) if code.read_nil().is_some() => 0,
+        Some(code) if code.read_boolean().is_some() => (!to_boolean(code)) as i32,
+        Some(_) => {
+            let code = check_integer(args, 1, "exit")?;
+            i32::try_from(code).map_err(|_| argument_error(1, "exit", "exit code out of range"))?
+        }
+    };
+    host(&state).exit(code)?;
+    Ok(Vec::new())
+}
+fn getenv(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let name = string_argument(args, 1, "getenv")?;
+    Ok(vec![match host(&state).getenv(&name)? {
+        Some(value) => new_string(state.as_pointer(), value.as_bytes())?,
+        None => nil(),
+    }])
+}
+fn remove(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let path = string_argument(args, 1, "remove")?;
+    file_result(&state, &path, host(&state).remove(&path))
+}
+fn rename(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let from = string_argument(args, 1, "rename")?;
+    let to = string_argument(args, 2, "rename")?;
+    file_result(&state, &from, host(&state).rename(&from, &to))
+}
+/// Reads a field of the table given to `os.time`, `default` is used when it is missing.
+fn date_field(state: &LuaStateReference, table: &Pointer<LuaTable>, name: &str, default: Option<i64>) -> Fallible<i64> {
+    let value = get_field(table.clone(), &new_string(state.as_pointer(), name.as_bytes())?);
+    if value.read_nil().is_some() {
+        return default.ok_or_else(|| format_err!("field '{}' missing in date table", name));
+    }
+    match read_number(&value).and_then(|number| number.as_integer()) {
+        Some(field) if field.checked_abs().map_or(false, |abs| abs <= i32::MAX as i64) => Ok(field),
+        Some(_) => Err(format_err!("field '{}' is out-of-bound", name)),
+        None => Err(format_err!("field '{}' is not an integer", name)),
+    }
+}
+/// The current time, or the time of the date in the table given.
+fn time(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let table = match args.first() {
+        Some(table) if table.read_nil().is_none() => check_table(args, 1, "time")?,
+        _ => return Ok(vec![new_integer(host(&state).time()?)?]),
+    };
+    let date = DateTime {
+        year: date_field(&state, &table, "year", None)?,
+        month: date_field(&state, &table, "month", None)?,
+        day: date_field(&state, &table, "day", None)?,
+        hour: date_field(&state, &table, "hour", Some(12))?,
+        min: date_field(&state, &table, "min", Some(0))?,
+        sec: date_field(&state, &table, "sec", Some(0))?,
+        wday: 0,
+        yday: 0,
+        isdst: to_boolean(&get_field(table, &new_string(state.as_pointer(), b"isdst")?)),
+    };
+    Ok(vec![new_integer(host(&state).time_from_local(&date)?)?])
+}
+/// Formats a time like `strftime`, or makes its table with `*t`. A format starting with `!` is in UTC.
+fn date(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let format = match args.first() {
+        Some(format) if format.read_nil().is_none() => string_argument(args, 1, "date")?,
+        _ => "%c".to_string(),
+    };
+    let time = match args.get(1) {
+        Some(time) if time.read_nil().is_none() => check_integer(args, 2, "date")?,
+        _ => host(&state).time()?,
+    };
+    let (date, format) = match format.strip_prefix('!') {
+        Some(format) => (DateTime::from_utc(time), format),
+        None => (host(&state).local_time(time)?, format.as_str()),
+    };
+    if format.starts_with("*t") {
+        let table = crate::new_table(crate::new_meta_functions()?, 9, true)?.as_pointer();
+        let fields = [
+            ("year", date.year),
+            ("month", date.month),
+            ("day", date.day),
+            ("hour", date.hour),
+            ("min", date.min),
+            ("sec", date.sec),
+            ("wday", date.wday),
+            ("yday", date.yday),
+        ];
+        for (name, value) in fields {
+            set_field(table.clone(), new_string(state.as_pointer(), name.as_bytes())?, new_integer(value)?)?;
+        }
+        set_field(table.clone(), new_string(state.as_pointer(), b"isdst")?, new_boolean(date.isdst))?;
+        return Ok(vec![LuaValueImpl::encode_table(table)]);
+    }
+    Ok(vec![new_string(state.as_pointer(), format_date(&date, format)?.as_bytes())?])
+}
+fn format_date(date: &DateTime, format: &str) -> Fallible<String> {
+    let day_name = DAY_NAMES[(date.wday - 1) as usize];
+    let month_name = MONTH_NAMES[(date.month - 1) as usize];
+    let hour12 = if date.hour % 12 == 0 { 12 } else { date.hour % 12 };
+    let mut formatted = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            formatted.push(c);
+            continue;
+        }
+        let conversion = chars.next().ok_or_else(|| argument_error(1, "date", "invalid conversion specifier '%'"))?;
+        formatted.push_str(&match conversion {
+            'a' => day_name[..3].to_string(),
+            'A' => day_name.to_string(),
+            'b' | 'h' => month_name[..3].to_string(),
+            'B' => month_name.to_string(),
+            'c' => format!("{} {} {:2} {:02}:{:02}:{:02} {}", &day_name[..3], &month_name[..3], date.day, date.hour, date.min, date.sec, date.year),
+            'd' => format!("{:02}", date.day),
+            'D' | 'x' => format!("{:02}/{:02}/{:02}", date.month, date.day, date.year.rem_euclid(100)),
+            'e' => format!("{:2}", date.day),
+            'F' => format!("{}-{:02}-{:02}", date.year, date.month, date.day),
+            'H' => format!("{:02}", date.hour),
+            'I' => format!("{:02}", hour12),
+            'j' => format!("{:03}", date.yday),
+            'm' => format!("{:02}", date.month),
+            'M' => format!("{:02}", date.min),
+            'n' => "\n".to_string(),
+            'p' => if date.hour < 12 { "AM" } else { "PM" }.to_string(),
+            'S' => format!("{:02}", date.sec),
+            't' => "\t".to_string(),
+            'T' | 'X' => format!("{:02}:{:02}:{:02}", date.hour, date.min, date.sec),
+            'u' => format!("{}", if date.wday == 1 { 7 } else { date.wday - 1 }),
+            'w' => format!("{}", date.wday - 1),
+            'y' => format!("{:02}", date.year.rem_euclid(100)),
+            'Y' => format!("{}", date.year),
+            '%' => "%".to_string(),
+            o => return Err(argument_error(1, "date", &format!("invalid conversion specifier '%{}'", o))),
+        });
+    }
+    Ok(formatted)
+}
@@ -0,0 +1,150 @@
+//! The package library and `require`, modules are found by the [`ModuleSearcher`]s of the state and kept in
+//! `package.loaded`.
+//! https://www.lua.org/manual/5.4/manual.html#6.3
+use failure::Fallible;
+use vm_core::Pointer;
+
+use super::{check_string, load_chunk, native_functions, read_chunk_file, read_string};
+use crate::{call_function, get_field, host::host, mem::*, new_string, set_field};
+
+pub const PACKAGE_FUNCTIONS: &[(&str, &LuaFunctionRustType)] = native_functions![
+    "searchpath" => searchpath,
+];
+/// The functions of the library which are globals.
+const GLOBAL_FUNCTIONS: &[(&str, &LuaFunctionRustType)] = native_functions![
+    "require" => require,
+];
+/// Where `require` looks for Lua files when `package.path` is not changed.
+pub const DEFAULT_PATH: &str = "./?.lua;./?/init.lua";
+/// The libraries registered before the package library, which are in `package.loaded` too.
+const LIBRARIES: &[&str] = &["coroutine", "io", "math", "os", "string", "table"];
+/// The code of a module found by a [`ModuleSearcher`].
+pub struct Module {
+    /// Where the module was found, the chunk gets it as its second argument and `require` returns it after the
+    /// value of the module.
+    pub location: String,
+    /// Source or precompiled code.
+    pub code: Vec<u8>,
+}
+pub enum ModuleSearch {
+    Found(Module),
+    /// Says where the module was looked for, like `\n\tno file './name.lua'`, which is added to the error of
+    /// `require` when no searcher finds the module.
+    NotFound(String),
+}
+/// Finds the code of the modules `require` loads, an embedding application can serve modules from its own storage
+/// with [`add_module_searcher`].
+pub trait ModuleSearcher {
+    fn search(&self, state: &LuaStateReference, name: &str) -> Fallible<ModuleSearch>;
+}
+/// Looks for modules in the files named by the templates of `package.path`.
+pub struct PathSearcher;
+impl ModuleSearcher for PathSearcher {
+    fn search(&self, state: &LuaStateReference, name: &str) -> Fallible<ModuleSearch> {
+        let path = match get_loaded(state, "package")?.read_table() {
+            Some(package) => read_string(&get_field(package, &new_string(state.as_pointer(), b"path")?)),
+            None => None,
+        };
+        let path = path.ok_or_else(|| format_err!("'package.path' must be a string"))?;
+        match search_path(state, name, &String::from_utf8_lossy(&path), ".", "/") {
+            Ok(filename) => Ok(ModuleSearch::Found(Module { code: read_chunk_file(state, &filename)?, location: filename })),
+            Err(tried) => Ok(ModuleSearch::NotFound(tried)),
+        }
+    }
+}
+/// Adds a searcher `require` asks for the modules it has not loaded yet, after the searchers of the state. A state
+/// starts with [`PathSearcher`], the searchers can be changed with `ref_searchers_mut`.
+pub fn add_module_searcher(state: &LuaStateReference, searcher: impl ModuleSearcher + 'static) {
+    unsafe { state.as_pointer().as_ref_mut().ref_searchers_mut().push(Box::new(searcher)) }
+}
+pub fn register(state: LuaStateReference) -> Fallible<()> {
+    let library = super::new_library(state.clone(), PACKAGE_FUNCTIONS)?;
+    let loaded = unsafe { state.as_pointer().as_ref().get_loaded() };
+    set_field(library.as_pointer(), new_string(state.as_pointer(), b"loaded")?, LuaValueImpl::encode_table(loaded.clone()))?;
+    set_field(library.as_pointer(), new_string(state.as_pointer(), b"path")?, new_string(state.as_pointer(), DEFAULT_PATH.as_bytes())?)?;
+    let global = unsafe { state.as_pointer().as_ref().get_global() };
+    for name in LIBRARIES {
+        let name = new_string(state.as_pointer(), name.as_bytes())?;
+        set_field(loaded.clone(), name.clone(), get_field(global.clone(), &name))?;
+    }
+    set_field(loaded.clone(), new_string(state.as_pointer(), b"_G")?, LuaValueImpl::encode_table(global))?;
+    set_field(loaded, new_string(state.as_pointer(), b"package")?, LuaValueImpl::encode_table(library.as_pointer()))?;
+    crate::add_global(state.clone(), new_string(state.as_pointer(), b"package")?, LuaValueImpl::encode_table(library.as_pointer()))?;
+    for (name, function) in GLOBAL_FUNCTIONS {
+        crate::add_global_function(state.clone(), name, function)?;
+    }
+    Ok(())
+}
+fn nil() -> LuaValueImpl {
+    LuaValueImpl::encode_nil(())
+}
+fn get_loaded(state: &LuaStateReference, name: &str) -> Fallible<LuaValueImpl> {
+    let loaded: Pointer<LuaTable> = unsafe { state.as_pointer().as_ref().get_loaded() };
+    Ok(get_field(loaded, &new_string(state.as_pointer(), name.as_bytes())?))
+}
+/// Finds the first template of `path` naming a file the host can open, the `?` in it replaced by `name` with `sep`
+/// replaced by `rep`. The files tried are returned when there is none.
+pub fn search_path(state: &LuaStateReference, name: &str, path: &str, sep: &str, rep: &str) -> Result<String, String> {
+    let name = if sep.is_empty() { name.to_string() } else { name.replace(sep, rep) };
+    let mut tried = String::new();
+    for template in path.split(';').filter(|template| !template.is_empty()) {
+        let filename = template.replace('?', &name);
+        if host(state).open(&filename, "r").is_ok() {
+            return Ok(filename);
+        }
+        tried.push_str(&format!("\n\tno file '{}'", filename));
+    }
+    Err(tried)
+}
+fn searchpath(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let name = String::from_utf8_lossy(&check_string(args, 1, "searchpath")?).into_owned();
+    let path = String::from_utf8_lossy(&check_string(args, 2, "searchpath")?).into_owned();
+    let sep = match args.get(2) {
+        Some(sep) if sep.read_nil().is_none() => String::from_utf8_lossy(&check_string(args, 3, "searchpath")?).into_owned(),
+        _ => ".".to_string(),
+    };
+    let rep = match args.get(3) {
+        Some(rep) if rep.read_nil().is_none() => String::from_utf8_lossy(&check_string(args, 4, "searchpath")?).into_owned(),
+        _ => "/".to_string(),
+    };
+    match search_path(&state, &name, &path, &sep, &rep) {
+        Ok(filename) => Ok(vec![new_string(state.as_pointer(), filename.as_bytes())?]),
+        Err(tried) => Ok(vec![nil(), new_string(state.as_pointer(), tried.as_bytes())?]),
+    }
+}
+/// Returns the value of the module `name`, loading it the first time. The chunk of the module gets the name and
+/// where it was found, a chunk returning nothing makes the module `true`.
+fn require(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let name_bytes = check_string(args, 1, "require")?;
+    let name = String::from_utf8_lossy(&name_bytes).into_owned();
+    let value = get_loaded(&state, &name)?;
+    if value.read_nil().is_none() {
+        return Ok(vec![value]);
+    }
+    let mut tried = String::new();
+    let mut found = None;
+    for searcher in unsafe { state.as_pointer().as_ref().ref_searchers() }.iter() {
+        match searcher.search(&state, &name)? {
+            ModuleSearch::Found(module) => {
+                found = Some(module);
+                break;
+            }
+            ModuleSearch::NotFound(message) => tried.push_str(&message),
+        }
+    }
+    let module = found.ok_or_else(|| format_err!("module '{}' not found:{}", name, tried))?;
+    let function = load_chunk(&state, &module.code, &format!("@{}", module.location), "bt")
+        .map_err(|e| format_err!("error loading module '{}' from file '{}':\n\t{}", name, module.location, e))?;
+    let location = new_string(state.as_pointer(), module.location.as_bytes())?;
+    let key = new_string(state.as_pointer(), &name_bytes)?;
+    let value = call_function(&function, &[key.clone(), location.clone()])?.into_iter().next().unwrap_or_else(nil);
+    let loaded = unsafe { state.as_pointer().as_ref().get_loaded() };
+    if value.read_nil().is_none() {
+        set_field(loaded.clone(), key.clone(), value)?;
+    }
+    // the chunk may have set its value itself
+    if get_field(loaded.clone(), &key).read_nil().is_some() {
+        set_field(loaded.clone(), key.clone(), crate::new_boolean(true))?;
+    }
+    Ok(vec![get_field(loaded, &key), location])
+}
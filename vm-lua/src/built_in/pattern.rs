@@ -0,0 +1,332 @@
+//! Lua patterns, as `string.find`, `string.match`, `string.gmatch` and `string.gsub` use them.
+//! https://www.lua.org/manual/5.4/manual.html#6.4.1
+use failure::Fallible;
+
+const MAX_CAPTURES: usize = 32;
+const MAX_MATCH_CALLS: usize = 200;
+/// Characters making a pattern more than a plain string.
+pub const SPECIALS: &[u8] = b"^$*+?.([%-";
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CaptureLen {
+    Unclosed,
+    Position,
+    Len(usize),
+}
+/// A capture of a successful match.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Capture<'s> {
+    String(&'s [u8]),
+    /// A `()` capture, the position in the source starting from 1.
+    Position(usize),
+}
+pub struct MatchState<'s, 'p> {
+    source: &'s [u8],
+    pattern: &'p [u8],
+    captures: Vec<(usize, CaptureLen)>,
+    depth: usize,
+}
+impl<'s, 'p> MatchState<'s, 'p> {
+    pub fn new(source: &'s [u8], pattern: &'p [u8]) -> Self {
+        Self { source, pattern, captures: Vec::new(), depth: 0 }
+    }
+
+    /// Matches the pattern from `pattern_start` against the source from `start`, returns the end of the match.
+    pub fn match_at(&mut self, start: usize, pattern_start: usize) -> Fallible<Option<usize>> {
+        self.captures.clear();
+        self.depth = 0;
+        self.do_match(start, pattern_start)
+    }
+
+    /// The captures of the last match from `start` to `end`, the whole match when the pattern has none.
+    pub fn captures(&self, start: usize, end: usize) -> Fallible<Vec<Capture<'s>>> {
+        if self.captures.is_empty() {
+            return Ok(vec![Capture::String(self.matched(start, end))]);
+        }
+        (0..self.captures.len()).map(|index| self.capture(index)).collect()
+    }
+
+    /// The captures of the last match, nothing when the pattern has none.
+    pub fn explicit_captures(&self) -> Fallible<Vec<Capture<'s>>> {
+        (0..self.captures.len()).map(|index| self.capture(index)).collect()
+    }
+
+    pub fn matched(&self, start: usize, end: usize) -> &'s [u8] {
+        &self.source[start..end]
+    }
+
+    /// The capture `index` of the last match from `start` to `end`, the first one is the whole match when the
+    /// pattern has none.
+    pub fn one_capture(&self, index: usize, start: usize, end: usize) -> Fallible<Capture<'s>> {
+        if index == 0 && self.captures.is_empty() {
+            Ok(Capture::String(self.matched(start, end)))
+        } else {
+            self.capture(index)
+        }
+    }
+
+    fn capture(&self, index: usize) -> Fallible<Capture<'s>> {
+        let (start, len) = self.captures.get(index).ok_or_else(|| format_err!("invalid capture index %{}", index + 1))?;
+        match len {
+            CaptureLen::Unclosed => Err(format_err!("unfinished capture")),
+            CaptureLen::Position => Ok(Capture::Position(start + 1)),
+            CaptureLen::Len(len) => Ok(Capture::String(&self.source[*start..start + len])),
+        }
+    }
+
+    fn do_match(&mut self, mut s: usize, mut p: usize) -> Fallible<Option<usize>> {
+        self.depth += 1;
+        if self.depth > MAX_MATCH_CALLS {
+            return Err(format_err!("pattern too complex"));
+        }
+        let result = loop {
+            if p == self.pattern.len() {
+                break Some(s);
+            }
+            match self.pattern[p] {
+                b'(' => {
+                    break if self.pattern.get(p + 1) == Some(&b')') {
+                        self.start_capture(s, p + 2, CaptureLen::Position)?
+                    } else {
+                        self.start_capture(s, p + 1, CaptureLen::Unclosed)?
+                    };
+                }
+                b')' => break self.end_capture(s, p + 1)?,
+                b'$' if p + 1 == self.pattern.len() => break if s == self.source.len() { Some(s) } else { None },
+                b'%' if self.pattern.get(p + 1) == Some(&b'b') => match self.match_balance(s, p + 2)? {
+                    Some(end) => {
+                        s = end;
+                        p += 4;
+                    }
+                    None => break None,
+                },
+                b'%' if self.pattern.get(p + 1) == Some(&b'f') => {
+                    p += 2;
+                    if self.pattern.get(p) != Some(&b'[') {
+                        return Err(format_err!("missing '[' after '%f' in pattern"));
+                    }
+                    let class_end = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.source[s - 1] };
+                    let current = self.source.get(s).copied().unwrap_or(0);
+                    if !self.match_bracket_class(previous, p, class_end - 1) && self.match_bracket_class(current, p, class_end - 1) {
+                        p = class_end;
+                    } else {
+                        break None;
+                    }
+                }
+                b'%' if self.pattern.get(p + 1).map_or(false, u8::is_ascii_digit) => match self.match_capture(s, self.pattern[p + 1])? {
+                    Some(end) => {
+                        s = end;
+                        p += 2;
+                    }
+                    None => break None,
+                },
+                _ => {
+                    let class_end = self.class_end(p)?;
+                    let suffix = self.pattern.get(class_end).copied();
+                    if !self.single_match(s, p, class_end) {
+                        if matches!(suffix, Some(b'*' | b'?' | b'-')) {
+                            p = class_end + 1;
+                            continue;
+                        }
+                        break None;
+                    }
+                    match suffix {
+                        Some(b'?') => {
+                            if let Some(end) = self.do_match(s + 1, class_end + 1)? {
+                                break Some(end);
+                            }
+                            p = class_end + 1;
+                        }
+                        Some(b'+') => break self.max_expand(s + 1, p, class_end)?,
+                        Some(b'*') => break self.max_expand(s, p, class_end)?,
+                        Some(b'-') => break self.min_expand(s, p, class_end)?,
+                        _ => {
+                            s += 1;
+                            p = class_end;
+                        }
+                    }
+                }
+            }
+        };
+        self.depth -= 1;
+        Ok(result)
+    }
+
+    /// The end of the single character class starting at `p`.
+    fn class_end(&self, mut p: usize) -> Fallible<usize> {
+        let c = self.pattern[p];
+        p += 1;
+        if c == b'%' {
+            if p >= self.pattern.len() {
+                return Err(format_err!("malformed pattern (ends with '%')"));
+            }
+            return Ok(p + 1);
+        }
+        if c == b'[' {
+            if self.pattern.get(p) == Some(&b'^') {
+                p += 1;
+            }
+            // the first character is part of the set even if it is a ']'
+            loop {
+                if p >= self.pattern.len() {
+                    return Err(format_err!("malformed pattern (missing ']')"));
+                }
+                let c = self.pattern[p];
+                p += 1;
+                if c == b'%' && p < self.pattern.len() {
+                    p += 1;
+                }
+                if self.pattern.get(p) == Some(&b']') {
+                    return Ok(p + 1);
+                }
+            }
+        }
+        Ok(p)
+    }
+
+    fn single_match(&self, s: usize, p: usize, class_end: usize) -> bool {
+        match self.source.get(s) {
+            None => false,
+            Some(&c) => match self.pattern[p] {
+                b'.' => true,
+                b'%' => match_class(c, self.pattern[p + 1]),
+                b'[' => self.match_bracket_class(c, p, class_end - 1),
+                pattern_char => pattern_char == c,
+            },
+        }
+    }
+
+    /// Whether `c` is in the set from the `[` at `p` to the `]` at `end`.
+    fn match_bracket_class(&self, c: u8, mut p: usize, end: usize) -> bool {
+        let mut sig = true;
+        if self.pattern[p + 1] == b'^' {
+            sig = false;
+            p += 1;
+        }
+        p += 1;
+        while p < end {
+            if self.pattern[p] == b'%' {
+                p += 1;
+                if match_class(c, self.pattern[p]) {
+                    return sig;
+                }
+            } else if self.pattern[p + 1] == b'-' && p + 2 < end {
+                if self.pattern[p] <= c && c <= self.pattern[p + 2] {
+                    return sig;
+                }
+                p += 2;
+            } else if self.pattern[p] == c {
+                return sig;
+            }
+            p += 1;
+        }
+        !sig
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, class_end: usize) -> Fallible<Option<usize>> {
+        let mut count = 0;
+        while self.single_match(s + count, p, class_end) {
+            count += 1;
+        }
+        loop {
+            if let Some(end) = self.do_match(s + count, class_end + 1)? {
+                return Ok(Some(end));
+            }
+            if count == 0 {
+                return Ok(None);
+            }
+            count -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, class_end: usize) -> Fallible<Option<usize>> {
+        loop {
+            if let Some(end) = self.do_match(s, class_end + 1)? {
+                return Ok(Some(end));
+            }
+            if !self.single_match(s, p, class_end) {
+                return Ok(None);
+            }
+            s += 1;
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, len: CaptureLen) -> Fallible<Option<usize>> {
+        if self.captures.len() >= MAX_CAPTURES {
+            return Err(format_err!("too many captures"));
+        }
+        self.captures.push((s, len));
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures.pop();
+        }
+        Ok(result)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Fallible<Option<usize>> {
+        let index = self.captures.iter().rposition(|(_, len)| *len == CaptureLen::Unclosed).ok_or_else(|| format_err!("invalid pattern capture"))?;
+        self.captures[index].1 = CaptureLen::Len(s - self.captures[index].0);
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures[index].1 = CaptureLen::Unclosed;
+        }
+        Ok(result)
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> Fallible<Option<usize>> {
+        if p + 1 >= self.pattern.len() {
+            return Err(format_err!("malformed pattern (missing arguments to '%b')"));
+        }
+        let (open, close) = (self.pattern[p], self.pattern[p + 1]);
+        if self.source.get(s) != Some(&open) {
+            return Ok(None);
+        }
+        let mut level = 1;
+        for (offset, &c) in self.source[s + 1..].iter().enumerate() {
+            if c == close {
+                level -= 1;
+                if level == 0 {
+                    return Ok(Some(s + offset + 2));
+                }
+            } else if c == open {
+                level += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    fn match_capture(&self, s: usize, index: u8) -> Fallible<Option<usize>> {
+        let (start, len) = match (index as usize).checked_sub(b'1' as usize).and_then(|index| self.captures.get(index)) {
+            Some((start, CaptureLen::Len(len))) => (*start, *len),
+            // a position capture never matches the source again
+            Some((_, CaptureLen::Position)) => return Ok(None),
+            _ => return Err(format_err!("invalid capture index %{}", index as char)),
+        };
+        if self.source.len() - s >= len && self.source[start..start + len] == self.source[s..s + len] {
+            Ok(Some(s + len))
+        } else {
+            Ok(None)
+        }
+    }
+}
+/// Whether `c` is in the class of `%class`, an upper case class is the complement of the lower case one.
+fn match_class(c: u8, class: u8) -> bool {
+    let matches = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => c == b' ' || (b'\t'..=b'\r').contains(&c),
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => return class == c,
+    };
+    if class.is_ascii_uppercase() {
+        !matches
+    } else {
+        matches
+    }
+}
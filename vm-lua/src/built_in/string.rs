@@ -0,0 +1,629 @@
+//! The string library, also reachable as methods of strings.
+//! https://www.lua.org/manual/5.4/manual.html#6.4
+use std::iter::Peekable;
+
+use failure::Fallible;
+use vm_core::{Pointer, UnsizedArray};
+
+use super::{
+    argument_error, check_integer, check_number, check_string, native_functions, opt_integer,
+    pattern::{Capture, MatchState, SPECIALS},
+    read_number, read_string, return_values, to_boolean, to_string, type_name, Number,
+};
+use crate::{add_global, call_function, get_field, mem::*, native_closure_values, new_integer, new_native_closure, new_string, quota};
+
+pub const STRING_FUNCTIONS: &[(&str, &LuaFunctionRustType)] = native_functions![
+    "byte" => byte,
+    "char" => char,
+    "find" => find,
+    "format" => format,
+    "gmatch" => gmatch,
+    "gsub" => gsub,
+    "len" => len,
+    "lower" => lower,
+    "match" => match_,
+    "rep" => rep,
+    "reverse" => reverse,
+    "sub" => sub,
+    "upper" => upper,
+];
+/// Registers the `string` table and makes it the `__index` of strings.
+pub fn register(state: LuaStateReference) -> Fallible<()> {
+    let library = super::new_library(state.clone(), STRING_FUNCTIONS)?;
+    let library = LuaValueImpl::encode_table(library.as_pointer());
+    add_global(state.clone(), new_string(state.as_pointer(), b"string")?, library.clone())?;
+    let metatable = crate::new_table(crate::new_meta_functions()?, 1, true)?.as_pointer();
+    crate::set_field(metatable.clone(), new_string(state.as_pointer(), b"__index")?, library)?;
+    let meta_functions = crate::meta_functions_of(metatable)?;
+    unsafe {
+        state.as_pointer().as_ref_mut().set_string_meta_functions(meta_functions.as_pointer());
+    }
+    Ok(())
+}
+/// Translates a start position, negative ones count from the end, into a position starting from 1.
+fn start_position(position: i64, len: usize) -> usize {
+    if position > 0 {
+        position as usize
+    } else if position == 0 || position < -(len as i64) {
+        1
+    } else {
+        (len as i64 + position + 1) as usize
+    }
+}
+/// Translates an end position, negative ones count from the end, into a position clamped to `0..=len`.
+fn end_position(position: i64, len: usize) -> usize {
+    if position > len as i64 {
+        len
+    } else if position >= 0 {
+        position as usize
+    } else if position < -(len as i64) {
+        0
+    } else {
+        (len as i64 + position + 1) as usize
+    }
+}
+fn opt_string(args: &[LuaValueImpl], index: usize, function: &str) -> Fallible<Vec<u8>> {
+    match args.get(index - 1) {
+        Some(value) if value.read_nil().is_none() => check_string(args, index, function),
+        _ => Ok(Vec::new()),
+    }
+}
+fn nil() -> LuaValueImpl {
+    LuaValueImpl::encode_nil(())
+}
+fn len(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    Ok(vec![new_integer(check_string(args, 1, "len")?.len() as i64)?])
+}
+fn sub(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let s = check_string(args, 1, "sub")?;
+    let start = start_position(check_integer(args, 2, "sub")?, s.len());
+    let end = end_position(opt_integer(args, 3, "sub", -1)?, s.len());
+    let sub = if start <= end { &s[start - 1..end] } else { &[] };
+    Ok(vec![new_string(state.as_pointer(), sub)?])
+}
+fn upper(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    Ok(vec![new_string(state.as_pointer(), &check_string(args, 1, "upper")?.to_ascii_uppercase())?])
+}
+fn lower(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    Ok(vec![new_string(state.as_pointer(), &check_string(args, 1, "lower")?.to_ascii_lowercase())?])
+}
+fn reverse(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let mut s = check_string(args, 1, "reverse")?;
+    s.reverse();
+    Ok(vec![new_string(state.as_pointer(), &s)?])
+}
+fn rep(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let s = check_string(args, 1, "rep")?;
+    let n = check_integer(args, 2, "rep")?;
+    let separator = opt_string(args, 3, "rep")?;
+    if n <= 0 {
+        return Ok(vec![new_string(state.as_pointer(), b"")?]);
+    }
+    let total = (s.len() + separator.len())
+        .checked_mul(n as usize)
+        .filter(|total| *total <= i32::MAX as usize)
+        .ok_or_else(|| format_err!("resulting string too large"))?;
+    quota::check_allocation(total)?;
+    let mut result = Vec::with_capacity(total);
+    for index in 0..n {
+        if index != 0 {
+            result.extend_from_slice(&separator);
+        }
+        result.extend_from_slice(&s);
+    }
+    Ok(vec![new_string(state.as_pointer(), &result)?])
+}
+fn byte(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let s = check_string(args, 1, "byte")?;
+    let start = start_position(opt_integer(args, 2, "byte", 1)?, s.len());
+    let end = end_position(opt_integer(args, 3, "byte", start as i64)?, s.len());
+    if start > end {
+        return Ok(Vec::new());
+    }
+    s[start - 1..end].iter().map(|c| new_integer(*c as i64)).collect()
+}
+fn char(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let s = (1..=args.len())
+        .map(|index| {
+            let c = check_integer(args, index, "char")?;
+            u8::try_from(c).map_err(|_| argument_error(index, "char", "value out of range"))
+        })
+        .collect::<Fallible<Vec<_>>>()?;
+    Ok(vec![new_string(state.as_pointer(), &s)?])
+}
+fn capture_value(state: &LuaStateReference, capture: Capture) -> Fallible<LuaValueImpl> {
+    match capture {
+        Capture::String(s) => new_string(state.as_pointer(), s),
+        Capture::Position(position) => new_integer(position as i64),
+    }
+}
+fn capture_values(state: &LuaStateReference, captures: Vec<Capture>) -> Fallible<Vec<LuaValueImpl>> {
+    captures.into_iter().map(|capture| capture_value(state, capture)).collect()
+}
+fn find(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    find_or_match(state, args, true)
+}
+fn match_(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    find_or_match(state, args, false)
+}
+/// `find` returns the position of the match before the captures, `match` returns the captures only.
+fn find_or_match(state: LuaStateReference, args: &[LuaValueImpl], find: bool) -> Fallible<Vec<LuaValueImpl>> {
+    let function = if find { "find" } else { "match" };
+    let s = check_string(args, 1, function)?;
+    let pattern = check_string(args, 2, function)?;
+    let init = start_position(opt_integer(args, 3, function, 1)?, s.len()) - 1;
+    if init > s.len() {
+        return Ok(vec![nil()]);
+    }
+    if find && (args.get(3).map_or(false, to_boolean) || !pattern.iter().any(|c| SPECIALS.contains(c))) {
+        let found = if pattern.is_empty() {
+            Some(0)
+        } else {
+            s[init..].windows(pattern.len()).position(|window| window == pattern)
+        };
+        return match found {
+            Some(start) => Ok(vec![new_integer((init + start + 1) as i64)?, new_integer((init + start + pattern.len()) as i64)?]),
+            None => Ok(vec![nil()]),
+        };
+    }
+    let anchor = pattern.first() == Some(&b'^');
+    let mut match_state = MatchState::new(&s, &pattern);
+    let mut start = init;
+    loop {
+        if let Some(end) = match_state.match_at(start, anchor as usize)? {
+            if !find {
+                return capture_values(&state, match_state.captures(start, end)?);
+            }
+            let mut rets = vec![new_integer(start as i64 + 1)?, new_integer(end as i64)?];
+            rets.extend(capture_values(&state, match_state.explicit_captures()?)?);
+            return Ok(rets);
+        }
+        start += 1;
+        if anchor || start > s.len() {
+            return Ok(vec![nil()]);
+        }
+    }
+}
+static GMATCH_STEP: LuaClosureRustType = gmatch_step;
+/// The iterator `gmatch` returns keeps the source, the pattern, where to match next and the end of the last match.
+fn gmatch(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let s = check_string(args, 1, "gmatch")?;
+    let pattern = check_string(args, 2, "gmatch")?;
+    let init = (start_position(opt_integer(args, 3, "gmatch", 1)?, s.len()) - 1).min(s.len() + 1);
+    let values = [new_string(state.as_pointer(), &s)?, new_string(state.as_pointer(), &pattern)?, new_integer(init as i64)?, new_integer(-1)?];
+    Ok(vec![new_native_closure(state, &GMATCH_STEP, &values)?])
+}
+extern "C" fn gmatch_step(state: LuaStateReference, closure: LuaClosureReference, _args: &[LuaValueImpl]) -> Pointer<UnsizedArray<LuaValue>> {
+    return_values(gmatch_next(state, closure))
+}
+fn gmatch_next(state: LuaStateReference, closure: LuaClosureReference) -> Fallible<Vec<LuaValueImpl>> {
+    let values = unsafe { native_closure_values(&closure) };
+    let (s, pattern) = (read_string(&values[0]).unwrap(), read_string(&values[1]).unwrap());
+    let position = read_number(&values[2]).and_then(Number::as_integer).unwrap() as usize;
+    let last_match = read_number(&values[3]).and_then(Number::as_integer).unwrap();
+    let mut match_state = MatchState::new(&s, &pattern);
+    for start in position..=s.len() {
+        match match_state.match_at(start, 0)? {
+            Some(end) if end as i64 != last_match => {
+                values[2] = new_integer(end as i64)?;
+                values[3] = new_integer(end as i64)?;
+                return capture_values(&state, match_state.captures(start, end)?);
+            }
+            _ => {}
+        }
+    }
+    values[2] = new_integer(s.len() as i64 + 1)?;
+    Ok(Vec::new())
+}
+enum Replacement {
+    String(Vec<u8>),
+    Table(Pointer<LuaTable>),
+    Function(LuaValueImpl),
+}
+fn gsub(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let s = check_string(args, 1, "gsub")?;
+    let pattern = check_string(args, 2, "gsub")?;
+    let replacement = match args.get(2) {
+        Some(value) if value.read_string().is_some() || read_number(value).is_some() => Replacement::String(to_string(value)),
+        Some(value) if value.read_table().is_some() => Replacement::Table(value.read_table().unwrap()),
+        Some(value) if value.read_function().is_some() || value.read_closure().is_some() => Replacement::Function(value.clone()),
+        value => {
+            let got = value.map_or("no value", type_name);
+            return Err(argument_error(3, "gsub", &format!("string/function/table expected, got {}", got)));
+        }
+    };
+    let max_count = opt_integer(args, 4, "gsub", s.len() as i64 + 1)?;
+    let anchor = pattern.first() == Some(&b'^');
+    let mut match_state = MatchState::new(&s, &pattern);
+    let mut result = Vec::new();
+    let (mut start, mut last_match, mut count) = (0, None, 0);
+    while count < max_count {
+        match match_state.match_at(start, anchor as usize)? {
+            Some(end) if Some(end) != last_match => {
+                count += 1;
+                add_value(&state, &match_state, &replacement, start, end, &mut result)?;
+                start = end;
+                last_match = Some(end);
+            }
+            _ if start < s.len() => {
+                quota::extend(&mut result, &s[start..start + 1])?;
+                start += 1;
+            }
+            _ => break,
+        }
+        if anchor {
+            break;
+        }
+    }
+    quota::extend(&mut result, &s[start..])?;
+    Ok(vec![new_string(state.as_pointer(), &result)?, new_integer(count)?])
+}
+/// Appends the replacement of the match from `start` to `end`.
+fn add_value(state: &LuaStateReference, match_state: &MatchState, replacement: &Replacement, start: usize, end: usize, result: &mut Vec<u8>) -> Fallible<()> {
+    let value = match replacement {
+        Replacement::String(replacement) => {
+            let mut chars = replacement.iter();
+            while let Some(c) = chars.next() {
+                if *c != b'%' {
+                    quota::extend(result, &[*c])?;
+                    continue;
+                }
+                match chars.next() {
+                    Some(b'%') => quota::extend(result, b"%")?,
+                    Some(b'0') => quota::extend(result, match_state.matched(start, end))?,
+                    Some(d @ b'1'..=b'9') => match match_state.one_capture((d - b'1') as usize, start, end)? {
+                        Capture::String(capture) => quota::extend(result, capture)?,
+                        Capture::Position(position) => quota::extend(result, position.to_string().as_bytes())?,
+                    },
+                    _ => return Err(format_err!("invalid use of '%' in replacement string")),
+                }
+            }
+            return Ok(());
+        }
+        Replacement::Table(table) => get_field(table.clone(), &capture_value(state, match_state.one_capture(0, start, end)?)?),
+        Replacement::Function(function) => {
+            let captures = capture_values(state, match_state.captures(start, end)?)?;
+            call_function(function, &captures)?.into_iter().next().unwrap_or_else(nil)
+        }
+    };
+    if !to_boolean(&value) {
+        quota::extend(result, match_state.matched(start, end))?;
+    } else if value.read_string().is_some() || read_number(&value).is_some() {
+        quota::extend(result, &to_string(&value))?;
+    } else {
+        return Err(format_err!("invalid replacement value (a {})", type_name(&value)));
+    }
+    Ok(())
+}
+/// Room for a conversion of `format` other than of a string, the width and the precision take at most two digits.
+const CONVERSION_SIZE: usize = 512;
+/// A conversion specification of `format`, `%[flags][width][.precision]conversion`.
+#[derive(Default)]
+struct FormatSpec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+impl FormatSpec {
+    /// Appends `sign`, `prefix` and `body` padded to the width, with zeros between the prefix and the body when
+    /// `zero_fill` is set.
+    fn pad(&self, result: &mut Vec<u8>, sign: &str, prefix: &str, body: &[u8], zero_fill: bool) {
+        let fill = self.width.saturating_sub(sign.len() + prefix.len() + body.len());
+        if !self.left && !zero_fill {
+            result.extend(std::iter::repeat(b' ').take(fill));
+        }
+        result.extend_from_slice(sign.as_bytes());
+        result.extend_from_slice(prefix.as_bytes());
+        if !self.left && zero_fill {
+            result.extend(std::iter::repeat(b'0').take(fill));
+        }
+        result.extend_from_slice(body);
+        if self.left {
+            result.extend(std::iter::repeat(b' ').take(fill));
+        }
+    }
+
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    fn integer(&self, result: &mut Vec<u8>, negative: bool, prefix: &str, mut digits: String) {
+        if let Some(precision) = self.precision {
+            if precision == 0 && digits == "0" {
+                digits.clear();
+            }
+            digits.insert_str(0, &"0".repeat(precision.saturating_sub(digits.len())));
+        }
+        self.pad(result, self.sign(negative), prefix, digits.as_bytes(), self.zero && self.precision.is_none());
+    }
+
+    fn float(&self, result: &mut Vec<u8>, value: f64, conversion: u8) {
+        let upper = conversion.is_ascii_uppercase();
+        let sign = self.sign(value.is_sign_negative());
+        let value = value.abs();
+        if !value.is_finite() {
+            let body = match (value.is_nan(), upper) {
+                (true, false) => "nan",
+                (true, true) => "NAN",
+                (false, false) => "inf",
+                (false, true) => "INF",
+            };
+            return self.pad(result, sign, "", body.as_bytes(), false);
+        }
+        let precision = self.precision.unwrap_or(6);
+        let (prefix, body) = match conversion.to_ascii_lowercase() {
+            b'f' => {
+                let mut body = format!("{:.*}", precision, value);
+                if self.alternate && precision == 0 {
+                    body.push('.');
+                }
+                ("", body)
+            }
+            b'e' => ("", format_exponent(value, precision, self.alternate)),
+            b'g' => ("", format_general(value, precision, self.alternate)),
+            _ => ("0x", format_hex_float(value, self.precision, self.alternate)),
+        };
+        let (prefix, body) = if upper {
+            (prefix.to_ascii_uppercase(), body.to_ascii_uppercase())
+        } else {
+            (prefix.to_string(), body)
+        };
+        self.pad(result, sign, &prefix, body.as_bytes(), self.zero);
+    }
+}
+/// `%e` of a finite non-negative value.
+fn format_exponent(value: f64, precision: usize, alternate: bool) -> String {
+    let formatted = format!("{:.*e}", precision, value);
+    let (mantissa, exponent) = formatted.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let point = if alternate && precision == 0 { "." } else { "" };
+    format!("{}{}e{}{:02}", mantissa, point, if exponent < 0 { '-' } else { '+' }, exponent.abs())
+}
+/// `%g` of a finite non-negative value, `%e` for large and tiny exponents, `%f` otherwise.
+pub(crate) fn format_general(value: f64, precision: usize, alternate: bool) -> String {
+    let precision = precision.max(1);
+    let exponent = if value == 0.0 {
+        0
+    } else {
+        let formatted = format!("{:.*e}", precision - 1, value);
+        formatted.split_once('e').unwrap().1.parse::<i32>().unwrap()
+    };
+    let mut formatted = if exponent < -4 || exponent >= precision as i32 {
+        format_exponent(value, precision - 1, alternate)
+    } else {
+        let mut formatted = format!("{:.*}", (precision as i32 - 1 - exponent) as usize, value);
+        if alternate && !formatted.contains('.') {
+            formatted.push('.');
+        }
+        formatted
+    };
+    if !alternate {
+        let exponent_start = formatted.find('e').unwrap_or(formatted.len());
+        let (mantissa, exponent) = formatted.split_at(exponent_start);
+        if mantissa.contains('.') {
+            formatted = format!("{}{}", mantissa.trim_end_matches('0').trim_end_matches('.'), exponent);
+        }
+    }
+    formatted
+}
+/// `%a` of a finite non-negative value without the `0x` prefix, the exact value unless a precision is given.
+fn format_hex_float(value: f64, precision: Option<usize>, alternate: bool) -> String {
+    let bits = value.to_bits();
+    let (mut lead, exponent) = match (bits >> 52) as i64 {
+        0 if bits == 0 => (0, 0),
+        0 => (0, -1022),
+        biased => (1, biased - 1023),
+    };
+    let mantissa = bits & ((1 << 52) - 1);
+    let digits = match precision {
+        None => format!("{:013x}", mantissa).trim_end_matches('0').to_string(),
+        Some(precision) if precision >= 13 => format!("{:013x}{}", mantissa, "0".repeat(precision - 13)),
+        Some(precision) => {
+            // rounds the 13 hexadecimal digits to `precision`, half to even
+            let shift = (13 - precision) * 4;
+            let full = (lead << 52) | mantissa;
+            let (mut rounded, rest, half) = (full >> shift, full & ((1 << shift) - 1), 1 << (shift - 1));
+            if rest > half || (rest == half && rounded & 1 == 1) {
+                rounded += 1;
+            }
+            lead = rounded >> (precision * 4);
+            let fraction = rounded & ((1 << (precision * 4)) - 1);
+            if precision == 0 {
+                String::new()
+            } else {
+                format!("{:0width$x}", fraction, width = precision)
+            }
+        }
+    };
+    let point = if !digits.is_empty() || alternate { "." } else { "" };
+    format!("{}{}{}p{:+}", lead, point, digits, exponent)
+}
+/// Appends a value as Lua code reading it back, for `%q`.
+fn add_literal(result: &mut Vec<u8>, args: &[LuaValueImpl], index: usize) -> Fallible<()> {
+    let value = &args[index - 1];
+    if let Some(s) = read_string(value) {
+        quota::extend(result, b"\"")?;
+        for (i, c) in s.iter().enumerate() {
+            match c {
+                b'"' | b'\\' | b'\n' => quota::extend(result, &[b'\\', *c])?,
+                c if c.is_ascii_control() => {
+                    if s.get(i + 1).map_or(false, u8::is_ascii_digit) {
+                        quota::extend(result, format!("\\{:03}", c).as_bytes())?;
+                    } else {
+                        quota::extend(result, format!("\\{}", c).as_bytes())?;
+                    }
+                }
+                c => quota::extend(result, &[*c])?,
+            }
+        }
+        quota::extend(result, b"\"")?;
+    } else if let Some(number) = read_number(value) {
+        let literal = match number {
+            // the minimum integer is not a numeral, `-` and a positive integer
+            Number::Integer(i64::MIN) => "0x8000000000000000".to_string(),
+            Number::Integer(i) => i.to_string(),
+            Number::Float(f) if f == f64::INFINITY => "1e9999".to_string(),
+            Number::Float(f) if f == f64::NEG_INFINITY => "-1e9999".to_string(),
+            Number::Float(f) if f.is_nan() => "(0/0)".to_string(),
+            Number::Float(f) => {
+                let sign = if f.is_sign_negative() { "-" } else { "" };
+                format!("{}0x{}", sign, format_hex_float(f.abs(), None, false))
+            }
+        };
+        result.extend(literal.bytes());
+    } else if value.read_nil().is_some() || value.read_boolean().is_some() {
+        result.extend(to_string(value));
+    } else {
+        return Err(argument_error(index, "format", "value has no literal form"));
+    }
+    Ok(())
+}
+/// Reads a width or a precision, which have at most two digits.
+fn read_two_digits<'f>(chars: &mut Peekable<impl Iterator<Item = (usize, &'f u8)>>) -> usize {
+    let mut value = 0;
+    for _ in 0..2 {
+        if let Some((_, digit)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+            value = value * 10 + (digit - b'0') as usize;
+        }
+    }
+    value
+}
+fn format(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let format = check_string(args, 1, "format")?;
+    let mut result = Vec::new();
+    let mut arg = 1;
+    let mut chars = format.iter().enumerate().peekable();
+    while let Some((start, c)) = chars.next() {
+        if *c != b'%' {
+            quota::extend(&mut result, &[*c])?;
+            continue;
+        }
+        if chars.next_if(|(_, c)| **c == b'%').is_some() {
+            quota::extend(&mut result, b"%")?;
+            continue;
+        }
+        let mut spec = FormatSpec::default();
+        while let Some((_, flag)) = chars.next_if(|(_, c)| b"-+ #0".contains(c)) {
+            match flag {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'#' => spec.alternate = true,
+                _ => spec.zero = true,
+            }
+        }
+        let flags_end = chars.peek().map_or(format.len(), |(i, _)| *i);
+        spec.width = read_two_digits(&mut chars);
+        let has_precision = chars.next_if(|(_, c)| **c == b'.').is_some();
+        if has_precision {
+            spec.precision = Some(read_two_digits(&mut chars));
+        }
+        let (end, conversion) = match chars.next() {
+            Some((end, conversion)) => (end + 1, *conversion),
+            None => (format.len(), 0),
+        };
+        let flags = &format[start + 1..flags_end];
+        let invalid_conversion = || format_err!("invalid conversion '{}' to 'format'", String::from_utf8_lossy(&format[start..end]));
+        let (allowed_flags, allow_precision): (&[u8], bool) = match conversion {
+            b'c' | b'p' => (b"-", false),
+            b'd' | b'i' => (b"-+0 ", true),
+            b'u' => (b"-0", true),
+            b'o' | b'x' | b'X' => (b"-#0", true),
+            b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G' => (b"-+ #0", true),
+            b's' => (b"-", true),
+            b'q' if end - start == 2 => (b"", false),
+            b'q' => return Err(format_err!("specifier '%q' cannot have modifiers")),
+            _ => return Err(invalid_conversion()),
+        };
+        if !flags.iter().all(|flag| allowed_flags.contains(flag)) || (has_precision && !allow_precision) {
+            return Err(invalid_conversion());
+        }
+        arg += 1;
+        if arg > args.len() {
+            return Err(argument_error(arg, "format", "no value"));
+        }
+        // the longer strings of `%s` and `%q` make room for themselves
+        quota::reserve(&mut result, CONVERSION_SIZE)?;
+        match conversion {
+            b'c' => {
+                let c = check_integer(args, arg, "format")? as u8;
+                spec.pad(&mut result, "", "", &[c], false);
+            }
+            b'd' | b'i' => {
+                let i = check_integer(args, arg, "format")?;
+                spec.integer(&mut result, i < 0, "", i.unsigned_abs().to_string());
+            }
+            b'u' => {
+                let u = check_integer(args, arg, "format")? as u64;
+                spec.integer(&mut result, false, "", u.to_string());
+            }
+            b'o' => {
+                let mut digits = format!("{:o}", check_integer(args, arg, "format")? as u64);
+                if spec.alternate && !digits.starts_with('0') {
+                    digits.insert(0, '0');
+                }
+                spec.integer(&mut result, false, "", digits);
+            }
+            b'x' | b'X' => {
+                let u = check_integer(args, arg, "format")? as u64;
+                let (prefix, digits) = match conversion {
+                    b'x' => ("0x", format!("{:x}", u)),
+                    _ => ("0X", format!("{:X}", u)),
+                };
+                let prefix = if spec.alternate && u != 0 { prefix } else { "" };
+                spec.integer(&mut result, false, prefix, digits);
+            }
+            b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G' => {
+                let f = check_number(args, arg, "format")?.as_float();
+                spec.float(&mut result, f, conversion);
+            }
+            b'p' => {
+                let value = &args[arg - 1];
+                let pointer = if let Some(v) = value.read_table() {
+                    format!("{:p}", v.as_ptr())
+                } else if let Some(v) = value.read_string() {
+                    format!("{:p}", v.as_ptr())
+                } else if let Some(v) = value.read_function() {
+                    format!("{:p}", v.as_ptr())
+                } else if let Some(v) = value.read_closure() {
+                    format!("{:p}", v.as_ptr())
+                } else if let Some(v) = value.read_thread() {
+                    format!("{:p}", v.as_ptr())
+                } else if let Some(v) = value.read_user_data() {
+                    format!("{:p}", v.as_ptr())
+                } else if let Some(v) = value.read_light_user_data() {
+                    format!("{:#x}", v.0 >> 4)
+                } else {
+                    "(null)".to_string()
+                };
+                spec.pad(&mut result, "", "", pointer.as_bytes(), false);
+            }
+            b'q' => add_literal(&mut result, args, arg)?,
+            _ => {
+                let mut s = to_string(&args[arg - 1]);
+                if end - start == 2 || (!has_precision && s.len() >= 100) {
+                    quota::extend(&mut result, &s)?;
+                } else {
+                    if s.contains(&0) {
+                        return Err(argument_error(arg, "format", "string contains zeros"));
+                    }
+                    s.truncate(spec.precision.unwrap_or(s.len()));
+                    spec.pad(&mut result, "", "", &s, false);
+                }
+            }
+        }
+    }
+    Ok(vec![new_string(state.as_pointer(), &result)?])
+}
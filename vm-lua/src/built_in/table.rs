@@ -0,0 +1,235 @@
+//! The table library, working on the raw values of tables.
+//! https://www.lua.org/manual/5.4/manual.html#6.6
+use failure::{format_err, Fallible};
+use vm_core::Pointer;
+
+use super::{argument_error, check_integer, check_table, less_than, native_functions, opt_integer, read_number, to_boolean, to_string, type_name};
+use crate::{call_function, get_field, mem::*, new_integer, new_string, quota, set_field, table_length};
+
+pub const TABLE_FUNCTIONS: &[(&str, &LuaFunctionRustType)] = native_functions![
+    "concat" => concat,
+    "insert" => insert,
+    "move" => move_,
+    "pack" => pack,
+    "remove" => remove,
+    "sort" => sort,
+    "unpack" => unpack,
+];
+/// The most values `unpack` returns.
+const MAX_RESULTS: i64 = 1_000_000;
+pub fn register(state: LuaStateReference) -> Fallible<()> {
+    let library = super::new_library(state.clone(), TABLE_FUNCTIONS)?;
+    crate::add_global(state.clone(), new_string(state.as_pointer(), b"table")?, LuaValueImpl::encode_table(library.as_pointer()))
+}
+fn nil() -> LuaValueImpl {
+    LuaValueImpl::encode_nil(())
+}
+fn get_index(table: &Pointer<LuaTable>, index: i64) -> Fallible<LuaValueImpl> {
+    Ok(get_field(table.clone(), &new_integer(index)?))
+}
+fn set_index(table: &Pointer<LuaTable>, index: i64, value: LuaValueImpl) -> Fallible<()> {
+    set_field(table.clone(), new_integer(index)?, value)
+}
+fn insert(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let table = check_table(args, 1, "insert")?;
+    let end = table_length(table.clone())? + 1;
+    let position = match args.len() {
+        2 => end,
+        3 => {
+            let position = check_integer(args, 2, "insert")?;
+            if (position as u64).wrapping_sub(1) >= end as u64 {
+                return Err(argument_error(2, "insert", "position out of bounds"));
+            }
+            for index in (position + 1..=end).rev() {
+                set_index(&table, index, get_index(&table, index - 1)?)?;
+            }
+            position
+        }
+        _ => return Err(format_err!("wrong number of arguments to 'insert'")),
+    };
+    set_index(&table, position, args[args.len() - 1].clone())?;
+    Ok(Vec::new())
+}
+fn remove(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let table = check_table(args, 1, "remove")?;
+    let size = table_length(table.clone())?;
+    let mut position = opt_integer(args, 2, "remove", size)?;
+    if position != size && (position as u64).wrapping_sub(1) > size as u64 {
+        return Err(argument_error(2, "remove", "position out of bounds"));
+    }
+    let removed = get_index(&table, position)?;
+    while position < size {
+        set_index(&table, position, get_index(&table, position + 1)?)?;
+        position += 1;
+    }
+    set_index(&table, position, nil())?;
+    Ok(vec![removed])
+}
+fn concat(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let table = check_table(args, 1, "concat")?;
+    let separator = match args.get(1) {
+        Some(value) if value.read_nil().is_none() => super::check_string(args, 2, "concat")?,
+        _ => Vec::new(),
+    };
+    let first = opt_integer(args, 3, "concat", 1)?;
+    let last = match args.get(3) {
+        Some(value) if value.read_nil().is_none() => check_integer(args, 4, "concat")?,
+        _ => table_length(table.clone())?,
+    };
+    let mut result = Vec::new();
+    let mut index = first;
+    while index <= last {
+        let value = get_index(&table, index)?;
+        if value.read_string().is_none() && read_number(&value).is_none() {
+            return Err(format_err!("invalid value (at index {}) in table for 'concat'", index));
+        }
+        quota::extend(&mut result, &to_string(&value))?;
+        if index == last {
+            break;
+        }
+        quota::extend(&mut result, &separator)?;
+        index += 1;
+    }
+    Ok(vec![new_string(state.as_pointer(), &result)?])
+}
+fn pack(state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let table = crate::new_table(crate::new_meta_functions()?, 1, true)?;
+    for (index, value) in args.iter().enumerate() {
+        set_index(&table.as_pointer(), index as i64 + 1, value.clone())?;
+    }
+    set_field(table.as_pointer(), new_string(state.as_pointer(), b"n")?, new_integer(args.len() as i64)?)?;
+    Ok(vec![LuaValueImpl::encode_table(table.as_pointer())])
+}
+fn unpack(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let table = check_table(args, 1, "unpack")?;
+    let first = opt_integer(args, 2, "unpack", 1)?;
+    let last = match args.get(2) {
+        Some(value) if value.read_nil().is_none() => check_integer(args, 3, "unpack")?,
+        _ => table_length(table.clone())?,
+    };
+    if first > last {
+        return Ok(Vec::new());
+    }
+    if (last as u64).wrapping_sub(first as u64) >= MAX_RESULTS as u64 {
+        return Err(format_err!("too many results to unpack"));
+    }
+    (first..=last).map(|index| get_index(&table, index)).collect()
+}
+fn move_(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let source = check_table(args, 1, "move")?;
+    let first = check_integer(args, 2, "move")?;
+    let last = check_integer(args, 3, "move")?;
+    let target = check_integer(args, 4, "move")?;
+    let destination = match args.get(4) {
+        Some(value) if value.read_nil().is_none() => check_table(args, 5, "move")?,
+        _ => source.clone(),
+    };
+    if last >= first {
+        if first <= 0 && last >= i64::MAX + first {
+            return Err(argument_error(3, "move", "too many elements to move"));
+        }
+        let count = last - first + 1;
+        if target > i64::MAX - count + 1 {
+            return Err(argument_error(4, "move", "destination wrap around"));
+        }
+        if target > last || target <= first || source.as_ptr() != destination.as_ptr() {
+            for offset in 0..count {
+                set_index(&destination, target + offset, get_index(&source, first + offset)?)?;
+            }
+        } else {
+            for offset in (0..count).rev() {
+                set_index(&destination, target + offset, get_index(&source, first + offset)?)?;
+            }
+        }
+    }
+    Ok(vec![LuaValueImpl::encode_table(destination)])
+}
+fn sort(_state: LuaStateReference, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    let table = check_table(args, 1, "sort")?;
+    let comparator = match args.get(1) {
+        Some(value) if value.read_function().is_some() || value.read_closure().is_some() => Some(value.clone()),
+        Some(value) if value.read_nil().is_none() => {
+            return Err(argument_error(2, "sort", &format!("function expected, got {}", type_name(value))));
+        }
+        _ => None,
+    };
+    let len = table_length(table.clone())?;
+    if len >= i32::MAX as i64 {
+        return Err(argument_error(1, "sort", "array too big"));
+    }
+    let mut values: Vec<_> = (1..=len).map(|index| get_index(&table, index)).collect::<Fallible<_>>()?;
+    let mut less = |a: &LuaValueImpl, b: &LuaValueImpl| -> Fallible<bool> {
+        match &comparator {
+            Some(comparator) => call_function(comparator, &[a.clone(), b.clone()]).map(|rets| rets.first().map_or(false, to_boolean)),
+            None => less_than(a, b),
+        }
+    };
+    if let Some(up) = values.len().checked_sub(1) {
+        sort_range(&mut values, 0, up, &mut less)?;
+    }
+    for (index, value) in values.into_iter().enumerate() {
+        set_index(&table, index as i64 + 1, value)?;
+    }
+    Ok(Vec::new())
+}
+/// The quicksort of the reference implementation over `values[lo..=up]`, `less` is called once per comparison and a comparison
+/// which is not a strict order is reported instead of sorting with it.
+fn sort_range<F: FnMut(&LuaValueImpl, &LuaValueImpl) -> Fallible<bool>>(
+    values: &mut [LuaValueImpl], mut lo: usize, mut up: usize, less: &mut F,
+) -> Fallible<()> {
+    while lo < up {
+        if less(&values[up], &values[lo])? {
+            values.swap(lo, up);
+        }
+        if up - lo == 1 {
+            break;
+        }
+        let middle = lo + (up - lo) / 2;
+        if less(&values[middle], &values[lo])? {
+            values.swap(middle, lo);
+        } else if less(&values[up], &values[middle])? {
+            values.swap(middle, up);
+        }
+        if up - lo == 2 {
+            break;
+        }
+        values.swap(middle, up - 1);
+        let pivot = partition(values, lo, up, less)?;
+        if pivot - lo < up - pivot {
+            if pivot > lo {
+                sort_range(values, lo, pivot - 1, less)?;
+            }
+            lo = pivot + 1;
+        } else {
+            sort_range(values, pivot + 1, up, less)?;
+            up = pivot - 1;
+        }
+    }
+    Ok(())
+}
+/// Partitions `values[lo..=up]` around the pivot at `up - 1`, `values[lo]` and `values[up]` are already on their side.
+fn partition<F: FnMut(&LuaValueImpl, &LuaValueImpl) -> Fallible<bool>>(values: &mut [LuaValueImpl], lo: usize, up: usize, less: &mut F) -> Fallible<usize> {
+    let pivot = values[up - 1].clone();
+    let (mut i, mut j) = (lo, up - 1);
+    loop {
+        i += 1;
+        while less(&values[i], &pivot)? {
+            if i == up - 1 {
+                return Err(format_err!("invalid order function for sorting"));
+            }
+            i += 1;
+        }
+        j -= 1;
+        while less(&pivot, &values[j])? {
+            if j < i {
+                return Err(format_err!("invalid order function for sorting"));
+            }
+            j -= 1;
+        }
+        if j < i {
+            values.swap(up - 1, i);
+            return Ok(i);
+        }
+        values.swap(i, j);
+    }
+}
@@ -0,0 +1,72 @@
+//! To-be-closed variables, the values of `<close>` variables are kept by the running thread until their scope ends.
+//! https://www.lua.org/manual/5.4/manual.html#3.3.8
+use failure::Fallible;
+
+use crate::{
+    built_in::{coroutine::running_thread, to_boolean, to_string},
+    call_function,
+    error::LuaError,
+    mem::*,
+};
+
+fn to_be_closed<'t>(state: &LuaStateReference) -> &'t mut Vec<LuaValueImpl> {
+    unsafe { &mut *running_thread(state).as_ref().ref_to_be_closed().get() }
+}
+/// The `__close` meta function of `value`, nil when it has none.
+fn close_meta_function(value: &LuaValueImpl) -> LuaValueImpl {
+    if let Some(table) = value.read_table() {
+        unsafe { table.as_ref().get_shape().as_ref().get_meta_functions().as_ref().get_close() }
+    } else if let Some(user_data) = value.read_user_data() {
+        unsafe { user_data.as_ref().get_meta_functions().as_ref().get_close() }
+    } else {
+        LuaValueImpl::encode_nil(())
+    }
+}
+fn close_value(value: &LuaValueImpl, error: LuaValueImpl) -> Fallible<()> {
+    // nil and false are kept so the compiled code can count the values, but there is nothing to close
+    if to_boolean(value) {
+        call_function(&close_meta_function(value), &[value.clone(), error])?;
+    }
+    Ok(())
+}
+/// How many values the running thread keeps to close.
+pub fn to_be_closed_len(state: &LuaStateReference) -> usize {
+    to_be_closed(state).len()
+}
+/// Keeps the value of the `<close>` variable `name` until it goes out of scope.
+pub fn push_to_be_closed(state: &LuaStateReference, name: &LuaValueImpl, value: LuaValueImpl) -> Fallible<()> {
+    if to_boolean(&value) && close_meta_function(&value).read_nil().is_some() {
+        return Err(format_err!("variable '{}' got a non-closable value", String::from_utf8_lossy(&to_string(name))));
+    }
+    to_be_closed(state).push(value);
+    Ok(())
+}
+/// Closes the last `count` values kept, when a `__close` meta function raises an error the others are closed with it.
+pub fn close_variables(state: &LuaStateReference, count: usize) -> Fallible<()> {
+    let len = to_be_closed_len(state).saturating_sub(count);
+    while let Some(value) = pop_above(state, len) {
+        if let Err(e) = close_value(&value, LuaValueImpl::encode_nil(())) {
+            let error = LuaError::from_error(e).into_value(state.clone())?;
+            return Err(LuaError::Value(close_on_error(state, len, error)).into());
+        }
+    }
+    Ok(())
+}
+/// Closes the values kept above `len` after `error` was raised, returns the error of the last `__close` meta function
+/// which raised one or `error`.
+pub fn close_on_error(state: &LuaStateReference, len: usize, mut error: LuaValueImpl) -> LuaValueImpl {
+    while let Some(value) = pop_above(state, len) {
+        if let Err(e) = close_value(&value, error.clone()) {
+            error = LuaError::from_error(e).into_value(state.clone()).unwrap_or(error);
+        }
+    }
+    error
+}
+fn pop_above(state: &LuaStateReference, len: usize) -> Option<LuaValueImpl> {
+    let values = to_be_closed(state);
+    if values.len() > len {
+        values.pop()
+    } else {
+        None
+    }
+}
@@ -0,0 +1,325 @@
+//! Precompiled chunks, the function packs of a chunk written in a binary format which is loaded without parsing the
+//! source again.
+//!
+//! The byte code of a pack is position independent except for the constants pointing into the state which compiled
+//! it, the interned strings and the shapes of table constructors, and the absolute relocations to the closures of the
+//! chunk. Those are written by value and put back in place when the chunk is loaded into another state, at the offsets
+//! the instructions recorded for their constants when they were emitted.
+use std::{marker::PhantomData, mem::size_of_val};
+
+use failure::Fallible;
+use ghost_cell::GhostToken;
+use runtime::{
+    code::FunctionPack,
+    instructions::{InstructionSet, InstructionType},
+};
+use vm_core::{
+    FunctionType, FunctionTypeBuilder, ObjectBuilder, ObjectBuilderImport, ObjectBuilderInner, ObjectRef, Pointer, RelocationKind, SymbolBuilder,
+    TypeDeclaration, UnsizedArray,
+};
+
+use crate::{constructor_shape, mem::*, new_string, LuaInstructionSet};
+
+/// Every precompiled chunk starts with it, like the chunks of the reference implementation.
+pub const SIGNATURE: &[u8] = b"\x1bLua";
+/// Changes whenever the layout of the format or the code it holds does.
+pub const FORMAT_VERSION: u8 = 3;
+/// Written in native byte order, a chunk made on a machine with another byte order does not load.
+const BYTE_ORDER_CHECK: u64 = 0x5678;
+const CHUNK: u8 = 0;
+const CLOSURE: u8 = 1;
+const STRING_CONSTANT: u8 = 0;
+const SHAPE_CONSTANT: u8 = 1;
+const CLOSURE_RELOCATION: u8 = 2;
+/// Tells whether `bytes` looks like a precompiled chunk rather than source code.
+pub fn is_binary_chunk(bytes: &[u8]) -> bool {
+    bytes.starts_with(SIGNATURE)
+}
+/// A hash of the opcodes and operands of the instruction set, byte code only runs on the layout it was built for.
+pub fn instruction_set_fingerprint() -> u64 {
+    // FNV-1a, which unlike the hasher of the standard library is stable between builds
+    let mut hash = 0xcbf29ce484222325u64;
+    let mut write = |bytes: &[u8]| {
+        for byte in bytes {
+            hash = (hash ^ *byte as u64).wrapping_mul(0x100000001b3);
+        }
+    };
+    for (opcode, instruction) in LuaInstructionSet::INSTRUCTIONS.iter() {
+        write(&opcode.to_le_bytes());
+        write(instruction.get_name().as_bytes());
+        match instruction {
+            InstructionType::Complex(complex) => write(format!("{:?}", complex.metadata).as_bytes()),
+            InstructionType::Stateful(stateful) => write(format!("{:?}", stateful.metadata).as_bytes()),
+            InstructionType::Bootstrap(_) | InstructionType::Compression(_) => {}
+        }
+    }
+    hash
+}
+fn raw_bytes<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, size_of_val(value)) }
+}
+fn string_constant(value: &LuaValueImpl) -> &[u8] {
+    raw_bytes(&value.0)
+}
+fn shape_constant(shape: &LuaShapeReference) -> usize {
+    shape.as_pointer().as_ptr() as usize
+}
+struct Writer(Vec<u8>);
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: usize) -> Fallible<()> {
+        self.0.extend(u32::try_from(value)?.to_le_bytes());
+        Ok(())
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Fallible<()> {
+        self.u32(bytes.len())?;
+        self.0.extend(bytes);
+        Ok(())
+    }
+}
+struct Reader<'b>(&'b [u8]);
+impl<'b> Reader<'b> {
+    fn take(&mut self, len: usize) -> Fallible<&'b [u8]> {
+        if self.0.len() < len {
+            return Err(format_err!("truncated precompiled chunk"));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Fallible<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Fallible<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Fallible<usize> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?) as usize)
+    }
+
+    fn u64(&mut self) -> Fallible<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn bytes(&mut self) -> Fallible<&'b [u8]> {
+        let len = self.u32()?;
+        self.take(len)
+    }
+}
+/// Writes the packs of a chunk made by [`crate::pack_code`] in `lua_state`, the root function last.
+pub fn dump(lua_state: &LuaStateReference, packs: &[FunctionPack<LuaInstructionSet>]) -> Fallible<Vec<u8>> {
+    let state_pointer = lua_state.as_pointer();
+    let state = unsafe { state_pointer.as_ref() };
+    let strings: Vec<(Vec<u8>, LuaValueImpl)> = state
+        .ref_strings()
+        .iter()
+        .map(|string| (std::borrow::Borrow::<[u8]>::borrow(string).to_vec(), LuaValueImpl::encode_string(string.0.as_pointer())))
+        .collect();
+    let shapes: Vec<(&Vec<String>, usize)> = state.ref_constructor_shapes().iter().map(|(keys, shape)| (keys, shape_constant(shape))).collect();
+    // the closures are called through the output object of their pack
+    let closures: Vec<Option<usize>> = packs
+        .iter()
+        .map(|pack| match (pack.function_type.args.len(), &pack.output) {
+            (2, Some(output)) => Some(output.lock().unwrap().get_export_ptr(0) as usize),
+            _ => None,
+        })
+        .collect();
+    let mut used_strings = Vec::new();
+    let mut used_shapes = Vec::new();
+    let mut bodies = Vec::with_capacity(packs.len());
+    for pack in packs {
+        let object = pack.byte_code.lock().unwrap();
+        let code = unsafe { object.get_buffer().borrow() };
+        let mut constants = Vec::new();
+        for (relocation, _import) in object.get_relocations() {
+            if !matches!(relocation.relocation_kind(), RelocationKind::UsizePtrAbsolute) {
+                continue;
+            }
+            let offset = relocation.offset();
+            let target = usize::from_ne_bytes(code[offset..offset + size_of_val(&offset)].try_into()?);
+            let index =
+                closures.iter().position(|closure| *closure == Some(target)).ok_or_else(|| format_err!("can not dump a relocation to a foreign object"))?;
+            constants.push((offset, CLOSURE_RELOCATION, index));
+        }
+        // the constant operands were recorded when they were emitted, the strings and shapes among them point into the state
+        for (offset, ty) in pack.constants() {
+            let window = code.get(*offset..).ok_or_else(|| format_err!("constant out of the code of a function"))?;
+            if *ty == LuaValue::TYPE {
+                // the other values, like numbers, are written as they are
+                if let Some(index) = strings.iter().position(|(_, value)| window.starts_with(string_constant(value))) {
+                    let used = used_strings.iter().position(|used| *used == index).unwrap_or_else(|| {
+                        used_strings.push(index);
+                        used_strings.len() - 1
+                    });
+                    constants.push((*offset, STRING_CONSTANT, used));
+                }
+            } else if *ty == LuaShapeReference::TYPE {
+                let index = shapes
+                    .iter()
+                    .position(|(_, shape)| window.starts_with(raw_bytes(shape)))
+                    .ok_or_else(|| format_err!("can not dump a shape of another state"))?;
+                let used = used_shapes.iter().position(|used| *used == index).unwrap_or_else(|| {
+                    used_shapes.push(index);
+                    used_shapes.len() - 1
+                });
+                constants.push((*offset, SHAPE_CONSTANT, used));
+            }
+        }
+        let kind = match pack.function_type.args.len() {
+            1 => CHUNK,
+            2 => CLOSURE,
+            o => return Err(format_err!("can not dump a function with {} arguments", o)),
+        };
+        bodies.push((kind, pack.register_count, code.to_vec(), constants));
+    }
+    let mut writer = Writer(Vec::new());
+    writer.0.extend(SIGNATURE);
+    writer.u8(FORMAT_VERSION);
+    writer.u8(size_of_val(&0usize) as u8);
+    writer.0.extend(BYTE_ORDER_CHECK.to_ne_bytes());
+    writer.0.extend(instruction_set_fingerprint().to_le_bytes());
+    writer.u32(used_strings.len())?;
+    for index in used_strings {
+        writer.bytes(&strings[index].0)?;
+    }
+    writer.u32(used_shapes.len())?;
+    for index in used_shapes {
+        let keys = shapes[index].0;
+        writer.u32(keys.len())?;
+        for key in keys {
+            writer.bytes(key.as_bytes())?;
+        }
+    }
+    writer.u32(bodies.len())?;
+    for (kind, register_count, code, constants) in bodies {
+        writer.u8(kind);
+        writer.u16(register_count);
+        writer.bytes(&code)?;
+        writer.u32(constants.len())?;
+        for (offset, constant_kind, index) in constants {
+            writer.u32(offset)?;
+            writer.u8(constant_kind);
+            writer.u32(index)?;
+        }
+    }
+    Ok(writer.0)
+}
+fn function_type(kind: u8) -> Fallible<FunctionType> {
+    let args = match kind {
+        CHUNK => vec![LuaStateReference::TYPE],
+        CLOSURE => vec![LuaStateReference::TYPE, LuaClosureReference::TYPE],
+        o => return Err(format_err!("invalid function kind {} in precompiled chunk", o)),
+    };
+    Ok(FunctionTypeBuilder::default()
+        .args(args.into())
+        .return_type(Some(Pointer::<UnsizedArray<LuaValue>>::TYPE))
+        .va_arg(Some(LuaValue::TYPE))
+        .build()
+        .unwrap())
+}
+/// The object a closure is called through, filled when its pack is loaded by the runtime.
+fn new_output() -> Fallible<ObjectRef> {
+    GhostToken::new(|mut token| {
+        let builder = ObjectBuilder::default();
+        builder.borrow_mut(&mut token).receive::<usize>();
+        builder.borrow_mut(&mut token).add_symbol(SymbolBuilder::default().offset(0).build()?);
+        builder.take(&mut token).build()
+    })
+}
+/// Reads the packs of a precompiled chunk for `lua_state`, rejecting chunks made for another instruction set.
+///
+/// Only the header and the constants pointing into the state are checked, the code and the other constants are taken
+/// as they are. A chunk made up or changed by hand can do anything the process can, so the bytes must come from a
+/// trusted source.
+pub fn undump(lua_state: &LuaStateReference, bytes: &[u8]) -> Fallible<Vec<FunctionPack<LuaInstructionSet>>> {
+    let mut reader = Reader(bytes);
+    if reader.take(SIGNATURE.len())? != SIGNATURE {
+        return Err(format_err!("not a precompiled chunk"));
+    }
+    let version = reader.u8()?;
+    if version != FORMAT_VERSION {
+        return Err(format_err!("precompiled chunk has format version {}, expected {}", version, FORMAT_VERSION));
+    }
+    if reader.u8()? as usize != size_of_val(&0usize) || reader.take(8)? != BYTE_ORDER_CHECK.to_ne_bytes() {
+        return Err(format_err!("precompiled chunk was made for another machine"));
+    }
+    if reader.u64()? != instruction_set_fingerprint() {
+        return Err(format_err!("precompiled chunk was made for another instruction set"));
+    }
+    let mut strings = Vec::new();
+    for _ in 0..reader.u32()? {
+        strings.push(new_string(lua_state.as_pointer(), reader.bytes()?)?);
+    }
+    let mut shapes = Vec::new();
+    for _ in 0..reader.u32()? {
+        let mut keys = Vec::new();
+        for _ in 0..reader.u32()? {
+            keys.push(String::from_utf8(reader.bytes()?.to_vec())?);
+        }
+        shapes.push(constructor_shape(lua_state, keys)?);
+    }
+    let mut packs: Vec<FunctionPack<LuaInstructionSet>> = Vec::new();
+    for _ in 0..reader.u32()? {
+        let kind = reader.u8()?;
+        let register_count = reader.u16()?;
+        let mut code = reader.bytes()?.to_vec();
+        let mut closures = Vec::new();
+        let mut constants = Vec::new();
+        for _ in 0..reader.u32()? {
+            let (offset, constant_kind, index) = (reader.u32()?, reader.u8()?, reader.u32()?);
+            let constant = match constant_kind {
+                STRING_CONSTANT => {
+                    constants.push((offset, LuaValue::TYPE));
+                    string_constant(strings.get(index).ok_or_else(|| format_err!("invalid string in precompiled chunk"))?).to_vec()
+                }
+                SHAPE_CONSTANT => {
+                    constants.push((offset, LuaShapeReference::TYPE));
+                    shape_constant(shapes.get(index).ok_or_else(|| format_err!("invalid shape in precompiled chunk"))?).to_ne_bytes().to_vec()
+                }
+                CLOSURE_RELOCATION => {
+                    // closures are packed before the functions making them
+                    let output = packs
+                        .get(index)
+                        .filter(|pack| pack.function_type.args.len() == 2)
+                        .and_then(|pack| pack.output.clone())
+                        .ok_or_else(|| format_err!("invalid closure in precompiled chunk"))?;
+                    closures.push((offset, output));
+                    continue;
+                }
+                o => return Err(format_err!("invalid constant kind {} in precompiled chunk", o)),
+            };
+            code.get_mut(offset..offset + constant.len())
+                .ok_or_else(|| format_err!("invalid constant offset in precompiled chunk"))?
+                .copy_from_slice(&constant);
+        }
+        let byte_code = GhostToken::new(|mut token| {
+            let builder = ObjectBuilder::default();
+            builder.borrow_mut(&mut token).push_slice(&code);
+            for (offset, output) in closures {
+                ObjectBuilderInner::set_import(&builder, &mut token, offset, ObjectBuilderImport::ObjectRef(output), RelocationKind::UsizePtrAbsolute, 0);
+            }
+            builder.borrow_mut(&mut token).add_symbol(SymbolBuilder::default().offset(0).build()?);
+            builder.take(&mut token).build()
+        })?;
+        let output = match kind {
+            CLOSURE => new_output()?,
+            _ => Default::default(),
+        };
+        packs.push(FunctionPack { _ph: PhantomData, byte_code, function_type: function_type(kind)?, register_count, output: Some(output), constants });
+    }
+    if !reader.0.is_empty() {
+        return Err(format_err!("trailing bytes after precompiled chunk"));
+    }
+    Ok(packs)
+}
@@ -1,4 +1,17 @@
-use failure::Error;
+use std::{
+    cell::{RefCell, UnsafeCell},
+    fmt::{self, Debug, Display},
+    marker::PhantomData,
+    ptr::NonNull,
+};
+
+use failure::{Error, Fail, Fallible};
+use vm_core::{Pointer, UnsizedArray};
+
+use crate::{
+    built_in::{read_number, to_string, type_name},
+    mem::{LuaStateReference, LuaValue, LuaValueImpl},
+};
 
 #[derive(Fail, Debug)]
 pub enum LuaVMError {
@@ -7,3 +20,57 @@ pub enum LuaVMError {
     #[fail(display = "other error:{}", _0)]
     Other(#[cause] Error),
 }
+/// An error raised in Lua, the value given to `error` or the message of a failed operation.
+pub enum LuaError {
+    Value(LuaValueImpl),
+    Message(String),
+}
+unsafe impl Send for LuaError {}
+unsafe impl Sync for LuaError {}
+impl LuaError {
+    /// Turns an error of a built-in function into a Lua error, keeping the value of a Lua error.
+    pub fn from_error(error: Error) -> Self {
+        match error.downcast::<LuaError>() {
+            Ok(error) => error,
+            Err(error) => LuaError::Message(error.to_string()),
+        }
+    }
+
+    /// The value `pcall` returns for the error.
+    pub fn into_value(self, state: LuaStateReference) -> Fallible<LuaValueImpl> {
+        match self {
+            LuaError::Value(value) => Ok(value),
+            LuaError::Message(message) => crate::new_string(state.as_pointer(), message.as_bytes()),
+        }
+    }
+}
+impl Display for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LuaError::Message(message) => f.write_str(message),
+            LuaError::Value(value) if value.read_string().is_some() || read_number(value).is_some() => {
+                f.write_str(&String::from_utf8_lossy(&to_string(value)))
+            }
+            LuaError::Value(value) => write!(f, "(error object is a {} value)", type_name(value)),
+        }
+    }
+}
+impl Debug for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "LuaError({})", self) }
+}
+impl Fail for LuaError {}
+/// The results of a function which raised an error, its length is `usize::MAX`.
+static ERROR_RETURN_INNER: UnsizedArray<LuaValue> = UnsizedArray(usize::MAX, UnsafeCell::new(PhantomData));
+thread_local! {
+    static PENDING_ERROR: RefCell<Option<LuaError>> = RefCell::new(None);
+}
+/// Keeps `error` until a caller takes it and returns the results marking it, which every call passes on.
+pub fn raise(error: LuaError) -> Pointer<UnsizedArray<LuaValue>> {
+    PENDING_ERROR.with(|pending| *pending.borrow_mut() = Some(error));
+    Pointer::new(NonNull::from(&ERROR_RETURN_INNER))
+}
+pub fn is_error_return(rets: &Pointer<UnsizedArray<LuaValue>>) -> bool { unsafe { rets.as_ref().len() == usize::MAX } }
+/// The error raised by the function which returned the results marking it.
+pub fn take_error() -> LuaError {
+    PENDING_ERROR.with(|pending| pending.borrow_mut().take()).unwrap_or_else(|| LuaError::Message("unknown error".to_string()))
+}
@@ -0,0 +1,301 @@
+//! The capabilities of the host a state runs in. The `io` and `os` libraries and the loading of files only reach the
+//! file system, the standard streams and the environment through the [`Host`] of the state, so an embedding
+//! application can disable or virtualize them.
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use failure::{Error, Fallible};
+use lazy_static::lazy_static;
+
+use crate::mem::LuaStateReference;
+
+lazy_static! {
+    static ref START: Instant = Instant::now();
+}
+/// A file opened by a host.
+pub trait HostFile {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize>;
+    fn write(&mut self, buffer: &[u8]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64>;
+
+    fn read_to_end(&mut self) -> io::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        let mut buffer = [0; 4096];
+        loop {
+            match self.read(&mut buffer)? {
+                0 => return Ok(content),
+                n => content.extend_from_slice(&buffer[..n]),
+            }
+        }
+    }
+}
+impl HostFile for std::fs::File {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        Read::read(self, buffer)
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> io::Result<()> {
+        self.write_all(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(self)
+    }
+
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        Seek::seek(self, position)
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StandardStream {
+    Input,
+    Output,
+    Error,
+}
+impl HostFile for StandardStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            StandardStream::Input => io::stdin().read(buffer),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "Bad file descriptor")),
+        }
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> io::Result<()> {
+        match self {
+            StandardStream::Input => Err(io::Error::new(io::ErrorKind::Unsupported, "Bad file descriptor")),
+            StandardStream::Output => io::stdout().write_all(buffer),
+            StandardStream::Error => io::stderr().write_all(buffer),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            StandardStream::Input => Ok(()),
+            StandardStream::Output => io::stdout().flush(),
+            StandardStream::Error => io::stderr().flush(),
+        }
+    }
+
+    fn seek(&mut self, _position: SeekFrom) -> io::Result<u64> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Illegal seek"))
+    }
+}
+/// A broken-down time like the table of `os.date("*t")`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i64,
+    /// From 1 to 12.
+    pub month: i64,
+    pub day: i64,
+    pub hour: i64,
+    pub min: i64,
+    pub sec: i64,
+    /// From 1 to 7, Sunday is 1.
+    pub wday: i64,
+    /// From 1 to 366.
+    pub yday: i64,
+    pub isdst: bool,
+}
+impl DateTime {
+    /// The UTC time `time` seconds after the epoch.
+    pub fn from_utc(time: i64) -> Self {
+        let (days, secs) = (time.div_euclid(86400), time.rem_euclid(86400));
+        let (year, month, day) = civil_from_days(days);
+        Self {
+            year,
+            month,
+            day,
+            hour: secs / 3600,
+            min: secs / 60 % 60,
+            sec: secs % 60,
+            // the epoch was a Thursday
+            wday: (days + 4).rem_euclid(7) + 1,
+            yday: days - days_from_civil(year, 1, 1) + 1,
+            isdst: false,
+        }
+    }
+
+    /// The seconds after the epoch of this UTC time, the fields out of their ranges are normalized.
+    pub fn to_utc(&self) -> i64 {
+        let month = self.month - 1;
+        let year = self.year + month.div_euclid(12);
+        let days = days_from_civil(year, month.rem_euclid(12) + 1, 1) + self.day - 1;
+        days * 86400 + self.hour * 3600 + self.min * 60 + self.sec
+    }
+}
+// the algorithms of http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    (year_of_era + era * 400 + i64::from(month <= 2), month, day)
+}
+/// The error of a capability the host does not give.
+pub fn denied(capability: &str) -> Error {
+    format_err!("{} is not allowed by the host", capability)
+}
+/// What a state can do outside itself. Every capability is denied unless the host gives it, except reading the
+/// clocks, and the local time is UTC.
+pub trait Host {
+    /// Opens a file with a mode of `io.open`, the errors of the file operations do not name the file.
+    fn open(&self, _path: &str, _mode: &str) -> Fallible<Box<dyn HostFile>> {
+        Err(denied("opening files"))
+    }
+
+    fn standard_stream(&self, stream: StandardStream) -> Fallible<Box<dyn HostFile>> {
+        Err(denied(match stream {
+            StandardStream::Input => "reading the standard input",
+            StandardStream::Output => "writing the standard output",
+            StandardStream::Error => "writing the standard error",
+        }))
+    }
+
+    fn remove(&self, _path: &str) -> Fallible<()> {
+        Err(denied("removing files"))
+    }
+
+    fn rename(&self, _from: &str, _to: &str) -> Fallible<()> {
+        Err(denied("renaming files"))
+    }
+
+    fn getenv(&self, _name: &str) -> Fallible<Option<String>> {
+        Err(denied("reading the environment"))
+    }
+
+    /// Ends the process, a host which can not returns an error.
+    fn exit(&self, _code: i32) -> Fallible<()> {
+        Err(denied("exiting"))
+    }
+
+    /// The seconds since the epoch.
+    fn time(&self) -> Fallible<i64> {
+        Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+    }
+
+    /// The seconds of processor time used by the program, the default counts the seconds since it is first called.
+    fn clock(&self) -> Fallible<f64> {
+        Ok(START.elapsed().as_secs_f64())
+    }
+
+    fn local_time(&self, time: i64) -> Fallible<DateTime> {
+        Ok(DateTime::from_utc(time))
+    }
+
+    /// The inverse of `local_time`, the fields out of their ranges are normalized.
+    fn time_from_local(&self, date: &DateTime) -> Fallible<i64> {
+        Ok(date.to_utc())
+    }
+}
+/// A host giving no capability, which states start with.
+pub struct SandboxHost;
+impl Host for SandboxHost {}
+/// A host giving every capability of the process, like a standalone interpreter.
+pub struct NativeHost;
+impl Host for NativeHost {
+    fn open(&self, path: &str, mode: &str) -> Fallible<Box<dyn HostFile>> {
+        let mut options = std::fs::OpenOptions::new();
+        match mode.trim_end_matches('b') {
+            "r" => options.read(true),
+            "w" => options.write(true).create(true).truncate(true),
+            "a" => options.append(true).create(true),
+            "r+" => options.read(true).write(true),
+            "w+" => options.read(true).write(true).create(true).truncate(true),
+            "a+" => options.read(true).append(true).create(true),
+            _ => return Err(format_err!("invalid mode '{}'", mode)),
+        };
+        Ok(Box::new(options.open(path)?))
+    }
+
+    fn standard_stream(&self, stream: StandardStream) -> Fallible<Box<dyn HostFile>> {
+        Ok(Box::new(stream))
+    }
+
+    fn remove(&self, path: &str) -> Fallible<()> {
+        match std::fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() => Ok(std::fs::remove_dir(path)?),
+            _ => Ok(std::fs::remove_file(path)?),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Fallible<()> {
+        Ok(std::fs::rename(from, to)?)
+    }
+
+    fn getenv(&self, name: &str) -> Fallible<Option<String>> {
+        Ok(std::env::var_os(name).map(|value| value.to_string_lossy().into_owned()))
+    }
+
+    fn exit(&self, code: i32) -> Fallible<()> {
+        std::process::exit(code)
+    }
+
+    fn clock(&self) -> Fallible<f64> {
+        let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        if unsafe { libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut time) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(time.tv_sec as f64 + time.tv_nsec as f64 / 1e9)
+    }
+
+    fn local_time(&self, time: i64) -> Fallible<DateTime> {
+        unsafe {
+            let mut tm: libc::tm = std::mem::zeroed();
+            if libc::localtime_r(&(time as libc::time_t), &mut tm).is_null() {
+                return Err(format_err!("time out-of-bounds"));
+            }
+            Ok(DateTime {
+                year: tm.tm_year as i64 + 1900,
+                month: tm.tm_mon as i64 + 1,
+                day: tm.tm_mday as i64,
+                hour: tm.tm_hour as i64,
+                min: tm.tm_min as i64,
+                sec: tm.tm_sec as i64,
+                wday: tm.tm_wday as i64 + 1,
+                yday: tm.tm_yday as i64 + 1,
+                isdst: tm.tm_isdst > 0,
+            })
+        }
+    }
+
+    fn time_from_local(&self, date: &DateTime) -> Fallible<i64> {
+        let field = |value: i64| i32::try_from(value).map_err(|_| format_err!("field out-of-bounds"));
+        unsafe {
+            let mut tm: libc::tm = std::mem::zeroed();
+            tm.tm_year = field(date.year - 1900)?;
+            tm.tm_mon = field(date.month - 1)?;
+            tm.tm_mday = field(date.day)?;
+            tm.tm_hour = field(date.hour)?;
+            tm.tm_min = field(date.min)?;
+            tm.tm_sec = field(date.sec)?;
+            tm.tm_isdst = if date.isdst { 1 } else { -1 };
+            match libc::mktime(&mut tm) {
+                -1 => Err(format_err!("time result cannot be represented in this installation")),
+                time => Ok(time),
+            }
+        }
+    }
+}
+/// Replaces the host of a state, which starts with [`SandboxHost`].
+pub fn set_host(state: &LuaStateReference, host: impl Host + 'static) {
+    unsafe { state.as_pointer().as_ref_mut().set_host(Box::new(host)) }
+}
+/// The host of a state.
+pub fn host<'s>(state: &LuaStateReference) -> &'s dyn Host {
+    unsafe { &**(*state.as_pointer().as_ptr()).ref_host() }
+}
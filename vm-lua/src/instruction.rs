@@ -1,4 +1,5 @@
 use super::mem::*;
+use crate::error::LuaError;
 use log::debug;
 use log::error;
 use runtime::instructions::{bootstrap::{self as b, CallState, GetLength, MakeSlice, Read, SetState, Write}, Instruction};
@@ -15,31 +16,34 @@ make_instruction! { ConstZero->fn()->(o:I64){ entry:{ %o=0; }} }
 make_instruction! { ConstOne->fn()->(o:I64){ entry:{ %o=1; }} }
 make_instruction! { ConstM1->fn()->(o:I64){ entry:{ %o=-1; }} }
 type WriteLuaUpValueRefArray = e::WriteElement<LuaUpValueReference, UnsizedArray<LuaUpValueReference>>;
-make_instruction! { ConstClosure0->fn<mut function:LuaClosureFunctionReference>(state:LuaStateReference,up_value:LuaUpValueReference)->(v:LuaValue){ entry:{
+make_instruction! { ConstClosure0->fn<mut function:LuaClosureFunctionReference>(state:LuaStateReference,env:LuaValue,up_value:LuaUpValueReference)->(v:LuaValue){ entry:{
     %closure=b::AllocUnsized<LuaClosureReference::TYPE>(b::IntTruncate<12,7>(1));
     %closure_ptr=b::Deref<LuaClosureReference::TYPE>(%closure);
     b::SetLength<UnsizedArray::<LuaUpValueReference>::TYPE>(lua_closure::LocateUpValues(%closure_ptr),b::IntTruncate<12,7>(1));
     lua_closure::WriteState(%closure_ptr,b::Clone<LuaStateReference::TYPE>(%state));
     lua_closure::WriteFunction(%closure_ptr,%function);
+    lua_closure::WriteEnv(%closure_ptr,%env);
     WriteLuaUpValueRefArray(lua_closure::LocateUpValues(%closure_ptr),b::IntTruncate<12,7>(0),b::Clone<LuaUpValueReference::TYPE>(%up_value));
     %v=lua_value::EncodeClosure(%closure);
 }} }
 type LuaUpValueRefSliceCopy = SliceCopy<LuaUpValueReference>;
 type LuaUpValueRefSubSlice = SubSlice<LuaUpValueReference>;
 type UnsizedLuaUpValueRefArrayToSlice = UnsizedArrayToSlice<LuaUpValueReference>;
-make_instruction! { ConstClosure->fn<mut function:LuaClosureFunctionReference>(state:LuaStateReference,up_value:LuaUpValueReference,parent_closure:LuaClosureReference)->(v:LuaValue){ entry:{
+// The state is the one of the parent closure.
+make_instruction! { ConstClosure->fn<mut function:LuaClosureFunctionReference>(env:LuaValue,up_value:LuaUpValueReference,parent_closure:LuaClosureReference)->(v:LuaValue){ entry:{
     %parent_closure_ptr=b::Deref<LuaClosureReference::TYPE>(%parent_closure);
     %parent_len=b::GetLength<UnsizedArray::<LuaUpValueReference>::TYPE>(lua_closure::LocateUpValues(%parent_closure_ptr));
     %closure=b::AllocUnsized<LuaClosureReference::TYPE>(UsizeAdd(%parent_len,b::IntTruncate<12,7>(1)));
     %closure_ptr=b::Deref<LuaClosureReference::TYPE>(%closure);
     b::SetLength<UnsizedArray::<LuaUpValueReference>::TYPE>(lua_closure::LocateUpValues(%closure_ptr),b::IntTruncate<12,7>(1));
-    lua_closure::WriteState(%closure_ptr,b::Clone<LuaStateReference::TYPE>(%state));
+    lua_closure::WriteState(%closure_ptr,b::Clone<LuaStateReference::TYPE>(lua_closure::ReadState(%parent_closure_ptr)));
     lua_closure::WriteFunction(%closure_ptr,%function);
+    lua_closure::WriteEnv(%closure_ptr,%env);
     WriteLuaUpValueRefArray(lua_closure::LocateUpValues(%closure_ptr),%parent_len,b::Clone<LuaUpValueReference::TYPE>(%up_value));
     LuaUpValueRefSliceCopy(LuaUpValueRefSubSlice(UnsizedLuaUpValueRefArrayToSlice(lua_closure::LocateUpValues(%closure_ptr)),b::IntTruncate<12,7>(0),%parent_len),UnsizedLuaUpValueRefArrayToSlice(lua_closure::LocateUpValues(%parent_closure_ptr)));
     %v=lua_value::EncodeClosure(%closure);
 }} }
-make_instruction! { MakeTable->fn<const shape:LuaShapeReference,const fast_len:Usize>(fields:Slice<LuaValue>)->(table_value:LuaValue){
+make_instruction! { MakeTable->fn<const shape:LuaShapeReference,const fast_len:Usize,const array_len:Usize>(fields:Slice<LuaValue>)->(table_value:LuaValue){
     entry:{
         %table=b::AllocUnsized<LuaTableReference::TYPE>(%fast_len);
         %table_deref=b::Deref<LuaTableReference::TYPE>(%table);
@@ -47,16 +51,25 @@ make_instruction! { MakeTable->fn<const shape:LuaShapeReference,const fast_len:U
         lua_table::WriteShape(%table_deref,%shape);
         lua_table::WriteSlowFields(%table_deref,NullableLuaValueArrayEncodeNone(b::UninitedStruct<Unit::TYPE>()));
         %fast_fields = lua_table::LocateFastFields(%table_deref);
-        %copy_len=LuaValueSliceLen(%fields);
+        %copy_len=UsizeSub(LuaValueSliceLen(%fields),%array_len);
         %i=b::IntTruncate<12,7>(0);
-        LuaValueSliceCopy(LuaValueSubSlice(UnsizedLuaValueArrayToSlice(%fast_fields),%i,%copy_len),%fields);
-        if UsizeLe(%copy_len,%fast_len) %fill %fill_complete; },
+        LuaValueSliceCopy(LuaValueSubSlice(UnsizedLuaValueArrayToSlice(%fast_fields),%i,%copy_len),LuaValueSubSlice(%fields,%i,%copy_len));
+        if UsizeLt(%copy_len,%fast_len) %fill %fill_complete; },
     fill:{
-       phi %i:Usize={%entry=>%i,%fill=>%i1};
-       WriteLuaValueArray(%fast_fields,%i,ConstNil());
-       %i1=UsizeAdd(%i,b::IntTruncate<12,7>(1));
-       if UsizeLe(%i1,%fast_len) %fill %fill_complete; },
-    fill_complete:{ %table_value = lua_value::EncodeTable(%table); },
+       phi %i1:Usize={%entry=>%copy_len,%fill=>%i2};
+       WriteLuaValueArray(%fast_fields,%i1,ConstNil());
+       %i2=UsizeAdd(%i1,b::IntTruncate<12,7>(1));
+       if UsizeLt(%i2,%fast_len) %fill %fill_complete; },
+    fill_complete:{ if UsizeEq(%array_len,b::IntTruncate<12,7>(0)) %no_array %array; },
+    no_array:{
+        lua_table::WriteArray(%table_deref,NullableLuaValueArrayEncodeNone(b::UninitedStruct<Unit::TYPE>()));
+        %table_value = lua_value::EncodeTable(%table); },
+    array:{
+        %array=b::NonGCAllocUnsized<LuaValueArrayReference::TYPE>(%array_len);
+        b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%array,%array_len);
+        LuaValueSliceCopy(UnsizedLuaValueArrayToSlice(%array),LuaValueSubSlice(%fields,%copy_len,%array_len));
+        lua_table::WriteArray(%table_deref,NullableLuaValueArrayEncodeSome(%array));
+        %table_value = lua_value::EncodeTable(%table); },
 }}
 make_instruction! { MakeTable0->fn(state:LuaStateReference)->(table_value:LuaValue){
     entry:{
@@ -66,6 +79,7 @@ make_instruction! { MakeTable0->fn(state:LuaStateReference)->(table_value:LuaVal
         b::SetLength<UnsizedArray::<LuaValue>::TYPE>(lua_table::LocateFastFields(%table_deref),%fast_len);
         lua_table::WriteShape(%table_deref,b::Clone<LuaShapeReference::TYPE>(lua_state::ReadTableShape(b::Deref<LuaStateReference::TYPE>(%state))));
         lua_table::WriteSlowFields(%table_deref,NullableLuaValueArrayEncodeNone(b::UninitedStruct<Unit::TYPE>()));
+        lua_table::WriteArray(%table_deref,NullableLuaValueArrayEncodeNone(b::UninitedStruct<Unit::TYPE>()));
         %fast_fields = lua_table::LocateFastFields(%table_deref);
         %i1=b::IntTruncate<12,7>(0);
         if UsizeLe(%i1,%fast_len) %fill %fill_complete; },
@@ -106,32 +120,40 @@ make_instruction! {
         is_function:{
             %function=b::Deref<LuaFunctionReference::TYPE>(lua_value::DecodeFunctionUnchecked(%callable));
             %function_ptr=b::Read<LuaFunctionType::TYPE>(lua_function::ReadFunction(%function));
-            %o=b::Call<LuaFunctionType::TYPE>(%function_ptr,lua_function::ReadState(%function),%args);
+            %rets=b::Call<LuaFunctionType::TYPE>(%function_ptr,lua_function::ReadState(%function),%args);
+            branch %check;
         },
         not_function:{ if lua_value::IsClosure(%callable) %is_closure %not_closure; },
         is_closure:{
             %closure=b::Deref<LuaClosureReference::TYPE>(lua_value::DecodeClosureUnchecked(%callable));
             %function_ptr=b::Read<LuaClosureFunctionType::TYPE>(lua_closure::ReadFunction(%closure));
-            %o=b::Call<LuaClosureFunctionType::TYPE>(%function_ptr,lua_closure::ReadState(%closure),lua_value::DecodeClosureUnchecked(%callable),%args);
+            %rets=b::Call<LuaClosureFunctionType::TYPE>(%function_ptr,lua_closure::ReadState(%closure),lua_value::DecodeClosureUnchecked(%callable),%args);
+            branch %check;
         },
-        not_closure:{ if lua_value::IsFunction(%callable) %is_object %other; },
-        is_object:{
+        not_closure:{
             %meta=GetMetaValueCall(%callable);
             if lua_value::IsClosure(%meta) %meta_closure %other;
         },
         meta_closure:{
-            %new_slice=UnsizedLuaValueArrayToSlice(b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(UsizeAdd(b::IntTruncate<12,7>(1),LuaValueSliceLen(%args))));
+            %new_len=UsizeAdd(b::IntTruncate<12,7>(1),LuaValueSliceLen(%args));
+            %new_array=b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(%new_len);
+            b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%new_array,%new_len);
+            %new_slice=UnsizedLuaValueArrayToSlice(%new_array);
             LuaValueSliceCopy(LuaValueSubSlice(%new_slice,b::IntTruncate<12,7>(1),LuaValueSliceLen(%args)),%args);
             LuaValueSliceSet(%new_slice,b::IntTruncate<12,7>(0),%callable);
-            %closure=b::Deref<LuaClosureReference::TYPE>(lua_value::DecodeClosureUnchecked(%callable));
+            %closure=b::Deref<LuaClosureReference::TYPE>(lua_value::DecodeClosureUnchecked(%meta));
             %function_ptr=b::Read<LuaClosureFunctionType::TYPE>(lua_closure::ReadFunction(%closure));
-            %o=b::Call<LuaClosureFunctionType::TYPE>(%function_ptr,lua_closure::ReadState(%closure),lua_value::DecodeClosureUnchecked(%callable),%new_slice);
+            %rets=b::Call<LuaClosureFunctionType::TYPE>(%function_ptr,lua_closure::ReadState(%closure),lua_value::DecodeClosureUnchecked(%meta),%new_slice);
+            branch %check;
         },
         other:{
             %array=b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(b::IntTruncate<12,7>(0));
             b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%array,b::IntTruncate<12,7>(0));
             %o=b::Move<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%array);
             ThrowError();},
+        check:{ if IsErrorReturn(%rets) %propagate %returned; },
+        propagate:{ b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%rets); },
+        returned:{ %o=b::Move<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%rets); },
     }
 }
 make_instruction! {
@@ -142,41 +164,55 @@ make_instruction! {
     CallFunctionVaSlice->fn(callable:LuaValue,args:Slice<LuaValue>,va_args:Slice<LuaValue>)->(o:Pointer<UnsizedArray<LuaValue>>){
         entry:{ if lua_value::IsFunction(%callable) %is_function %not_function; },
         is_function:{
-            %new_slice=UnsizedLuaValueArrayToSlice(b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(UsizeAdd(LuaValueSliceLen(%args),LuaValueSliceLen(%va_args))));
+            %new_len=UsizeAdd(LuaValueSliceLen(%args),LuaValueSliceLen(%va_args));
+            %new_array=b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(%new_len);
+            b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%new_array,%new_len);
+            %new_slice=UnsizedLuaValueArrayToSlice(%new_array);
             LuaValueSliceCopy(LuaValueSubSlice(%new_slice,LuaValueSliceLen(%args),LuaValueSliceLen(%va_args)),%va_args);
             LuaValueSliceCopy(LuaValueSubSlice(%new_slice,b::IntTruncate<12,7>(0),LuaValueSliceLen(%args)),%args);
             %function=b::Deref<LuaFunctionReference::TYPE>(lua_value::DecodeFunctionUnchecked(%callable));
             %function_ptr=b::Read<LuaFunctionType::TYPE>(lua_function::ReadFunction(%function));
-            %o=b::Call<LuaFunctionType::TYPE>(%function_ptr,lua_function::ReadState(%function),%new_slice);
+            %rets=b::Call<LuaFunctionType::TYPE>(%function_ptr,lua_function::ReadState(%function),%new_slice);
+            branch %check;
         },
         not_function:{ if lua_value::IsClosure(%callable) %is_closure %not_closure; },
         is_closure:{
-            %new_slice=UnsizedLuaValueArrayToSlice(b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(UsizeAdd(LuaValueSliceLen(%args),LuaValueSliceLen(%va_args))));
-            LuaValueSliceCopy(LuaValueSubSlice(%new_slice,LuaValueSliceLen(%args),LuaValueSliceLen(%args)),%va_args);
+            %new_len=UsizeAdd(LuaValueSliceLen(%args),LuaValueSliceLen(%va_args));
+            %new_array=b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(%new_len);
+            b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%new_array,%new_len);
+            %new_slice=UnsizedLuaValueArrayToSlice(%new_array);
+            LuaValueSliceCopy(LuaValueSubSlice(%new_slice,LuaValueSliceLen(%args),LuaValueSliceLen(%va_args)),%va_args);
             LuaValueSliceCopy(LuaValueSubSlice(%new_slice,b::IntTruncate<12,7>(0),LuaValueSliceLen(%args)),%args);
             %closure=b::Deref<LuaClosureReference::TYPE>(lua_value::DecodeClosureUnchecked(%callable));
             %function_ptr=b::Read<LuaClosureFunctionType::TYPE>(lua_closure::ReadFunction(%closure));
-            %o=b::Call<LuaClosureFunctionType::TYPE>(%function_ptr,lua_closure::ReadState(%closure),lua_value::DecodeClosureUnchecked(%callable),%new_slice);
+            %rets=b::Call<LuaClosureFunctionType::TYPE>(%function_ptr,lua_closure::ReadState(%closure),lua_value::DecodeClosureUnchecked(%callable),%new_slice);
+            branch %check;
         },
-        not_closure:{ if lua_value::IsFunction(%callable) %is_object %other; },
-        is_object:{
+        not_closure:{
             %meta=GetMetaValueCall(%callable);
             if lua_value::IsClosure(%meta) %meta_closure %other;
         },
         meta_closure:{
-            %new_slice=UnsizedLuaValueArrayToSlice(b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(UsizeAdd(b::IntTruncate<12,7>(1),UsizeAdd(LuaValueSliceLen(%args),LuaValueSliceLen(%va_args)))));
+            %new_len=UsizeAdd(b::IntTruncate<12,7>(1),UsizeAdd(LuaValueSliceLen(%args),LuaValueSliceLen(%va_args)));
+            %new_array=b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(%new_len);
+            b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%new_array,%new_len);
+            %new_slice=UnsizedLuaValueArrayToSlice(%new_array);
             LuaValueSliceSet(%new_slice,b::IntTruncate<12,7>(0),%callable);
-            LuaValueSliceCopy(LuaValueSubSlice(%new_slice,UsizeAdd(b::IntTruncate<12,7>(1),LuaValueSliceLen(%args)),LuaValueSliceLen(%args)),%va_args);
+            LuaValueSliceCopy(LuaValueSubSlice(%new_slice,UsizeAdd(b::IntTruncate<12,7>(1),LuaValueSliceLen(%args)),LuaValueSliceLen(%va_args)),%va_args);
             LuaValueSliceCopy(LuaValueSubSlice(%new_slice,b::IntTruncate<12,7>(1),LuaValueSliceLen(%args)),%args);
-            %closure=b::Deref<LuaClosureReference::TYPE>(lua_value::DecodeClosureUnchecked(%callable));
+            %closure=b::Deref<LuaClosureReference::TYPE>(lua_value::DecodeClosureUnchecked(%meta));
             %function_ptr=b::Read<LuaClosureFunctionType::TYPE>(lua_closure::ReadFunction(%closure));
-            %o=b::Call<LuaClosureFunctionType::TYPE>(%function_ptr,lua_closure::ReadState(%closure),lua_value::DecodeClosureUnchecked(%callable),%new_slice);
+            %rets=b::Call<LuaClosureFunctionType::TYPE>(%function_ptr,lua_closure::ReadState(%closure),lua_value::DecodeClosureUnchecked(%meta),%new_slice);
+            branch %check;
         },
         other:{
             %array=b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(b::IntTruncate<12,7>(0));
             b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%array,b::IntTruncate<12,7>(0));
             %o=b::Move<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%array);
             ThrowError();},
+        check:{ if IsErrorReturn(%rets) %propagate %returned; },
+        propagate:{ b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%rets); },
+        returned:{ %o=b::Move<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%rets); },
     }
 }
 make_instruction! {
@@ -293,7 +329,10 @@ make_instruction! {
         entry:{
             %upvalue=b::AllocUnsized<LuaUpValueReference::TYPE>(%len);
             %upvalue_deref=b::Deref<LuaUpValueReference::TYPE>(%upvalue);
-            lua_up_value::WriteOwned(%upvalue_deref,NullableLuaValueArrayEncodeNone(b::UninitedStruct<Unit::TYPE>()));
+            // the values of the variables are moved here when their scopes end
+            %owned=b::NonGCAllocUnsized<LuaValueArrayReference::TYPE>(%len);
+            b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%owned,%len);
+            lua_up_value::WriteOwned(%upvalue_deref,NullableLuaValueArrayEncodeSome(%owned));
             %i=b::IntTruncate<12,7>(0);
             if UsizeLarge(%len,%i) %loop %end;
         },
@@ -315,10 +354,65 @@ make_instruction! {
         },
     }
 }
-#[make_native_function(ThrowError)]
-pub extern "C" fn __vm_lua_lib_throw_error() {
-    panic!("lua throw error");
+#[make_native_function(RaiseError)]
+pub extern "C" fn __vm_lua_lib_raise_error() -> Pointer<UnsizedArray<LuaValue>> {
+    crate::error::raise(LuaError::Message("attempt to perform an invalid operation".to_string()))
+}
+// Returns from the function with the error, which its callers pass on.
+make_instruction! {ThrowError->fn(){entry:{
+    b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(RaiseError());
+}}}
+make_instruction! {IsErrorReturn->fn(rets:Pointer<UnsizedArray<LuaValue>>)->(o:Bool){entry:{
+    %o=UsizeEq(b::GetLength<UnsizedArray::<LuaValue>::TYPE>(%rets),b::IntTruncate<12,7>(-1));
+}}}
+#[make_native_function(RawPushToBeClosed)]
+pub unsafe extern "C" fn __vm_lua_lib_push_to_be_closed(
+    state: Direct<LuaStateReference>,
+    name: Direct<LuaValue>,
+    value: Direct<LuaValue>,
+) -> Pointer<UnsizedArray<LuaValue>> {
+    let state = LuaStateReference(state.0.as_non_null());
+    crate::built_in::return_values(crate::close::push_to_be_closed(&state, &name.0, value.0).map(|()| Vec::new()))
 }
+// Keeps the value of the `<close>` variable `name` on the running thread.
+make_instruction! {PushToBeClosed->fn(state:LuaStateReference,name:LuaValue,value:LuaValue){
+    entry:{
+        %rets=RawPushToBeClosed(%state,%name,%value);
+        if IsErrorReturn(%rets) %propagate %pushed; },
+    propagate:{ b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%rets); },
+    pushed:{},
+}}
+#[make_native_function(RawCloseVariables)]
+pub unsafe extern "C" fn __vm_lua_lib_close_variables(state: Direct<LuaStateReference>, count: Usize) -> Pointer<UnsizedArray<LuaValue>> {
+    let state = LuaStateReference(state.0.as_non_null());
+    crate::built_in::return_values(crate::close::close_variables(&state, count.0).map(|()| Vec::new()))
+}
+// Calls `__close` of the last `count` `<close>` variables when their scope ends.
+make_instruction! {CloseVariables->fn<const count:Usize>(state:LuaStateReference){
+    entry:{
+        %rets=RawCloseVariables(%state,%count);
+        if IsErrorReturn(%rets) %propagate %closed; },
+    propagate:{ b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%rets); },
+    closed:{},
+}}
+#[make_native_function(RawCheckQuotas)]
+pub unsafe extern "C" fn __vm_lua_lib_check_quotas(state: Direct<LuaStateReference>) -> Pointer<UnsizedArray<LuaValue>> {
+    let state = LuaStateReference(state.0.as_non_null());
+    crate::built_in::return_values(crate::quota::check_quotas(&state).map(|()| Vec::new()))
+}
+// Burns one fuel of the state at a loop back-edge or a call, the quotas are checked once it is burnt.
+make_instruction! {CheckQuotas->fn(state:LuaStateReference){
+    entry:{
+        %state_ptr=b::Deref<LuaStateReference::TYPE>(%state);
+        %fuel=I64Sub(lua_state::ReadFuel(%state_ptr),1);
+        lua_state::WriteFuel(%state_ptr,%fuel);
+        if I64Less(%fuel,0) %check %done; },
+    check:{
+        %rets=RawCheckQuotas(%state);
+        if IsErrorReturn(%rets) %propagate %done; },
+    propagate:{ b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%rets); },
+    done:{},
+}}
 #[make_native_function(IllegalInstruction)]
 pub extern "C" fn __vm_lua_lib_illegal_instruction() {
     panic!("illegal instruction 0");
@@ -329,7 +423,9 @@ pub extern "C" fn __vm_lua_lib_illegal_instruction() {
         table:{ %o= ReadMetaFunction(b::Deref<LuaMetaFunctionsReference::TYPE>(lua_shape::ReadMetaFunctions(b::Deref<LuaShapeReference::TYPE>(lua_table::ReadShape(b::Deref<LuaTableReference::TYPE>(lua_value::DecodeTableUnchecked(%v))))))); },
         not_table:{ if lua_value::IsString(%v) %string %not_string; },
         string:{ %o= ReadMetaFunction(b::Deref<LuaMetaFunctionsReference::TYPE>(lua_state::ReadStringMetaFunctions(b::Deref<LuaStateReference::TYPE>(lua_string::ReadLuaState(b::Deref<LuaStringReference::TYPE>(lua_value::DecodeStringUnchecked(%v))))))); },
-        not_string:{ %o= ConstNil(); },
+        not_string:{ if lua_value::IsUserData(%v) %user_data %other; },
+        user_data:{ %o= ReadMetaFunction(b::Deref<LuaMetaFunctionsReference::TYPE>(lua_user_data::ReadMetaFunctions(b::Deref<LuaUserDataReference::TYPE>(lua_value::DecodeUserDataUnchecked(%v))))); },
+        other:{ %o= ConstNil(); },
 })]
 pub struct GetMetaValue<ReadMetaFunction: Instruction>(PhantomData<ReadMetaFunction>);
 make_instruction! { I64ToValue->fn(i:I64)->(v:LuaValue){
@@ -398,12 +494,50 @@ pub extern "C" fn __vm_lua_lib_f64_div_floor(arg1: F64, arg2: F64) -> F64 { F64(
 make_instruction! {FDivFloor->fn(f1:F64,f2:F64)->(f2:F64){entry:{
     %f2=RawFDivFloor(%f1,%f2);
 }}}
+#[make_native_function(RawIDivFloor)]
+pub extern "C" fn __vm_lua_lib_i64_div_floor(arg1: I64, arg2: I64) -> I64 {
+    let quotient = arg1.0.wrapping_div(arg2.0);
+    I64(if arg1.0.wrapping_rem(arg2.0) != 0 && (arg1.0 ^ arg2.0) < 0 { quotient - 1 } else { quotient })
+}
+#[make_native_function(RawIRem)]
+pub extern "C" fn __vm_lua_lib_i64_rem(arg1: I64, arg2: I64) -> I64 {
+    let remainder = arg1.0.wrapping_rem(arg2.0);
+    I64(if remainder != 0 && (remainder ^ arg2.0) < 0 { remainder + arg2.0 } else { remainder })
+}
+#[make_native_function(RawFRem)]
+pub extern "C" fn __vm_lua_lib_f64_rem(arg1: F64, arg2: F64) -> F64 {
+    let remainder = arg1.0 % arg2.0;
+    F64(if (remainder > 0.0 && arg2.0 < 0.0) || (remainder < 0.0 && arg2.0 > 0.0) { remainder + arg2.0 } else { remainder })
+}
+#[make_native_function(RaiseDivisionByZero)]
+pub extern "C" fn __vm_lua_lib_raise_division_by_zero() -> Pointer<UnsizedArray<LuaValue>> {
+    crate::error::raise(LuaError::Message("attempt to perform 'n//0'".to_string()))
+}
+#[make_native_function(RaiseModuloByZero)]
+pub extern "C" fn __vm_lua_lib_raise_modulo_by_zero() -> Pointer<UnsizedArray<LuaValue>> {
+    crate::error::raise(LuaError::Message("attempt to perform 'n%0'".to_string()))
+}
+// The integer quotient and remainder are rounded towards minus infinity, so the remainder has the sign of the divisor.
+make_instruction! {IDivFloor->fn(i1:I64,i2:I64)->(i2:I64){
+    entry:{ if I64Eq(%i2,0) %by_zero %divide; },
+    by_zero:{ b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(RaiseDivisionByZero()); },
+    divide:{ %i2=RawIDivFloor(%i1,%i2); },
+}}
+make_instruction! {IRem->fn(i1:I64,i2:I64)->(i2:I64){
+    entry:{ if I64Eq(%i2,0) %by_zero %divide; },
+    by_zero:{ b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(RaiseModuloByZero()); },
+    divide:{ %i2=RawIRem(%i1,%i2); },
+}}
+make_instruction! {FRem->fn(f1:F64,f2:F64)->(f2:F64){entry:{
+    %f2=RawFRem(%f1,%f2);
+}}}
 
 type NoneLuaValueArray = e::nullable_pointer::EncodeNone<UnsizedArray<LuaValue>>;
 make_instruction! { BuildTable->fn<const shape:LuaShapeReference,const slots:Usize>()->(o:LuaValue){ entry:{
     %new_table=b::AllocUnsized<LuaTableReference::TYPE>(%slots);
-    %new_table_ptr=b::Deref<LuaTableReference::TYPE>(%shape);
+    %new_table_ptr=b::Deref<LuaTableReference::TYPE>(%new_table);
     lua_table::WriteSlowFields(%new_table_ptr,NoneLuaValueArray(b::UninitedStruct<Unit::TYPE>()));
+    lua_table::WriteArray(%new_table_ptr,NoneLuaValueArray(b::UninitedStruct<Unit::TYPE>()));
     lua_table::WriteShape(%new_table_ptr,%shape);
     %o=lua_value::EncodeTable(%new_table);
 }} }
@@ -417,10 +551,10 @@ pub unsafe fn extend_to_buffer(buffer: &mut Vec<u8>, i: Direct<LuaValue>) -> boo
     } else if let Some(v) = i.read_float() {
         let v = v.0;
         let v = f64::from_le_bytes(i64::to_le_bytes(v));
-        buffer.extend(v.to_string().as_bytes());
+        buffer.extend(crate::built_in::format_float(v).as_bytes());
     } else if let Some(v) = i.read_big_float() {
         let v = v.as_ref().get_value().0;
-        buffer.extend(v.to_string().as_bytes());
+        buffer.extend(crate::built_in::format_float(v).as_bytes());
     } else if let Some(v) = i.read_string() {
         buffer.extend(v.as_ref().ref_data().as_slice().iter().map(|d| d.0));
     } else if let Some(_v) = i.read_nil() {
@@ -434,6 +568,12 @@ pub unsafe fn extend_to_buffer(buffer: &mut Vec<u8>, i: Direct<LuaValue>) -> boo
         buffer.extend(format!("function: {:p}", v.as_ptr()).bytes());
     } else if let Some(v) = i.read_closure() {
         buffer.extend(format!("function: {:p}", v.as_ptr()).bytes());
+    } else if let Some(v) = i.read_thread() {
+        buffer.extend(format!("thread: {:p}", v.as_ptr()).bytes());
+    } else if let Some(v) = i.read_user_data() {
+        buffer.extend(format!("userdata: {:p}", v.as_ptr()).bytes());
+    } else if let Some(v) = i.read_light_user_data() {
+        buffer.extend(format!("userdata: {:#x}", v.0 >> 4).bytes());
     } else {
         error!("invalid lua value: {:X?}", &i.0 .0);
         return false;
@@ -445,12 +585,12 @@ pub unsafe extern "C" fn __vm_lua_lib_raw_concat(
     state: Direct<LuaStateReference>,
     i1: Direct<LuaValue>,
     i2: Direct<LuaValue>,
-) -> Direct<LuaValue> {
+) -> Pointer<UnsizedArray<LuaValue>> {
     let mut buffer = Vec::new();
     if !extend_to_buffer(&mut buffer, i1) || !extend_to_buffer(&mut buffer, i2) {
-        return Direct(LuaValueImpl::encode_nil(()));
+        return crate::built_in::return_values(Ok(vec![LuaValueImpl::encode_nil(())]));
     }
-    Direct(crate::new_string(state.0, &buffer).unwrap())
+    crate::built_in::return_values(crate::new_string(state.0, &buffer).map(|string| vec![string]))
 }
 type GetMetaValueConcat = GetMetaValue<lua_meta_functions::ReadConcat>;
 make_instruction! {
@@ -459,26 +599,36 @@ make_instruction! {
           use_metatable:{
               %i1_meta_function = GetMetaValueConcat(%i1);
               if lua_value::IsNil(%i1_meta_function) %i1_has_no_meta_function %i1_has_meta_function; },
-            i1_has_meta_function:{ %o=CallFunction2Ret1(%i1_meta_function,%i1,%i2); },
+            i1_has_meta_function:{ %i2=CallFunction2Ret1(%i1_meta_function,%i1,%i2); },
             i1_has_no_meta_function:{
                 %i2_meta_function = GetMetaValueConcat(%i2);
                 if lua_value::IsNil(%i2_meta_function) %i2_has_no_meta_function %i2_has_meta_function; },
             i2_has_meta_function:{ %i2=CallFunction2Ret1(%i2_meta_function,%i1,%i2); },
             i2_has_no_meta_function:{%i2=ConstNil();ThrowError();},
-          dont_use_meratable:{ %i2=RawConcat(%state,%i1,%i2); },
+          dont_use_meratable:{
+              %rets=RawConcat(%state,%i1,%i2);
+              if IsErrorReturn(%rets) %propagate %concatenated; },
+          // the new string is charged to the memory quota
+          propagate:{ b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%rets); },
+          concatenated:{ %i2=GetRet0(%rets); },
     }
 }
+type GetMetaValueLen = GetMetaValue<lua_meta_functions::ReadLen>;
 make_instruction! {
     Length->fn(i1:LuaValue)->(i1:LuaValue){
         entry:{ if lua_value::IsString(%i1) %string %not_string; },
-        string:{ %v=I64ToValue(b::IntTruncate<7,12>(GetLength<UnsizedArray::<U8>::TYPE>(lua_string::LocateData(b::Deref<LuaStringReference::TYPE>(lua_value::DecodeStringUnchecked(%i1)))))); },
-        not_string:{ if lua_value::IsTable(%i1) %table %error; },
+        string:{ %i1=I64ToValue(b::IntTruncate<7,12>(GetLength<UnsizedArray::<U8>::TYPE>(lua_string::LocateData(b::Deref<LuaStringReference::TYPE>(lua_value::DecodeStringUnchecked(%i1)))))); },
+        not_string:{ if lua_value::IsTable(%i1) %table %not_table; },
+        not_table:{
+            %meta_function=GetMetaValueLen(%i1);
+            if lua_value::IsNil(%meta_function) %error %use_meta_function;
+        },
         table:{
             %meta_function=lua_meta_functions::ReadLen(b::Deref<LuaMetaFunctionsReference::TYPE>(lua_shape::ReadMetaFunctions(b::Deref<LuaShapeReference::TYPE>(lua_table::ReadShape(b::Deref<LuaTableReference::TYPE>(lua_value::DecodeTableUnchecked(%i1)))))));
             if lua_value::IsNil(%meta_function) %use_int_keys %use_meta_function;
         },
         use_meta_function:{%i1=CallFunction1Ret1(%meta_function,%i1);},
-        use_int_keys:{%i1=I64ToValue(b::IntTruncate<7,8>(lua_shape::ReadMaxIntIndex(b::Deref<LuaShapeReference::TYPE>(lua_table::ReadShape(b::Deref<LuaTableReference::TYPE>(lua_value::DecodeTableUnchecked(%i1)))))));},
+        use_int_keys:{%i1=TableLength(b::Deref<LuaTableReference::TYPE>(lua_value::DecodeTableUnchecked(%i1)));},
         error:{%i2=ConstNil();ThrowError();}
     }
 }
@@ -491,7 +641,7 @@ make_instruction! {
     ForInLoopJump1->fn<block loop,block break>(iter:LuaValue,iterable:LuaValue,state:LuaValue)->(state:LuaValue){ entry:{
         %new_state = CallFunction2Ret1(%iter,%iterable,%state);
         %state = b::Move<LuaValue::TYPE>(%new_state);
-        if lua_value::IsNil(%new_state) %loop %break;
+        if lua_value::IsNil(%new_state) %break %loop;
     }}
 }
 make_instruction! {
@@ -500,7 +650,7 @@ make_instruction! {
         %new_state = GetRet0(%rets);
         %state = b::Move<LuaValue::TYPE>(%new_state);
         %ret1 = DoGetRet(%rets,b::IntTruncate<12,7>(1));
-        if lua_value::IsNil(%new_state) %loop %break;
+        if lua_value::IsNil(%new_state) %break %loop;
     }}
 }
 make_instruction! {
@@ -508,7 +658,7 @@ make_instruction! {
         %rets = CallFunction2(%iter,%iterable,%state);
         %new_state = GetRet0(%rets);
         %state = b::Move<LuaValue::TYPE>(%new_state);
-        if lua_value::IsNil(%new_state) %loop %break;
+        if lua_value::IsNil(%new_state) %break %loop;
     }}
 }
 make_instruction! {
@@ -522,8 +672,11 @@ make_instruction! {
     }}
 }
 make_instruction! { ForLoopInit->fn<block predict>(start:LuaValue,end:LuaValue)->(end:LuaValue,state:LuaValue){
-    entry:{ if BoolOr(IsInteger(%start),IsInteger(%end)) %use_int %use_float; },
+    entry:{ if IsInteger(%start) %int_start %use_float; },
+    int_start:{ if IsInteger(%end) %use_int %float_end; },
     use_int:{ %state=MoveValue(%start); %end=MoveValue(%end); branch %predict;},
+    // the loop counts integers up to the floor of a float limit
+    float_end:{ %state=MoveValue(%start); %end=I64ToValue(e::F64ToI64(F64Floor(ToFloat(%end)))); branch %predict;},
     use_float:{ %state=ValueToFloatValue(%start); %end=ValueToFloatValue(%end); branch %predict; },
 }}
 make_instruction! { ForLoopJump->fn<block loop,block break>(end:LuaValue,state:LuaValue)->(state:LuaValue){
@@ -539,7 +692,7 @@ make_instruction! {ForLoopIncrease->fn<block predict>(v:LuaValue)->(v:LuaValue){
 make_instruction! { ForStepLoopInit->fn<block predict>(start:LuaValue,end:LuaValue,step:LuaValue)->(end:LuaValue,step:LuaValue,state:LuaValue){
     entry:{
         if F64Eq(0.0,ToFloat(%step)) %invalid %valid;},
-    invalid:{%state=ConstNil();ThrowError();branch %predict;},
+    invalid:{%state=ConstNil();ThrowError();},
     valid:{ if BoolAnd(BoolAnd(IsInteger(%start),IsInteger(%end)),IsInteger(%step)) %use_int %use_float; },
     use_int:{ %state=MoveValue(%start); branch %predict;},
     use_float:{
@@ -814,16 +967,14 @@ pub struct NegationBinaryInstruction<
     BinaryInstruction->{(i1:LuaValue,i2:LuaValue)->(i2:LuaValue){
         DoubleSmallInteger:{
             entry:{
-                %i1c=MoveValue(%i1);
-                %i2c=MoveValue(%i2);
-                %i1_tag=lua_value::GetTag(%i1c);
-                %i2_tag=lua_value::GetTag(%i2c);
-                %i1_integer_value=I64Shr(lua_value::DecodeIntegerUnchecked(%i1c),4);
-                %i2_integer_value=I64Shr(lua_value::DecodeIntegerUnchecked(%i2c),4);
+                %i1_tag=lua_value::GetTag(%i1);
+                %i2_tag=lua_value::GetTag(%i2);
                 if UsizeLt(UsizeOr(%i1_tag,%i2_tag),b::IntTruncate<12,7>(1)) %double_integer %other; },
             double_integer:{
+                %i1_integer_value=I64Shr(lua_value::DecodeIntegerUnchecked(%i1),4);
+                %i2_integer_value=I64Shr(lua_value::DecodeIntegerUnchecked(%i2),4);
                 %i2=IntegerInstruction(%i1_integer_value,%i2_integer_value); },
-            other:{  %i2=CallState<%Init>(%i1c,%i2c); },
+            other:{  %i2=CallState<%Init>(%i1,%i2); },
         },
         Init:{
             entry:{
@@ -963,7 +1114,6 @@ pub type OptionLuaMetaFunctionsRefIsSome = nullable_option::IsSome<LuaMetaFuncti
     BinaryIntegerInstruction->{(i1:LuaValue,i2:LuaValue)->(i2:LuaValue){
         Init:{
             entry:{
-                ThrowError();
                 %i1_tag=lua_value::GetTag(%i1);
                 %i2_tag=lua_value::GetTag(%i2);
                 if IsizeLt(b::IntTruncate<11,12>(UsizeOr(%i1_tag,%i2_tag)),b::IntTruncate<11,7>(4)) %double_number %not_double_number; },
@@ -1081,7 +1231,6 @@ pub type Div =
     BinaryInstruction<WrapBinaryIntToFloat<F64Div>, WrapBinaryFloat<F64Div>, GetMetaValue<lua_meta_functions::ReadDiv>>;
 pub type IEqual = WrapBinaryIntToBool<I64Eq>;
 pub type FEqual = WrapBinaryFloatToBool<F64Eq>;
-pub type Equal = BinaryInstruction<IEqual, FEqual, GetMetaValue<lua_meta_functions::ReadEq>>;
 pub type ILessOrEqual = WrapBinaryIntToBool<I64Le>;
 pub type FLessOrEqual = WrapBinaryFloatToBool<F64Le>;
 pub type LessOrEqual = BinaryInstruction<ILessOrEqual, FLessOrEqual, GetMetaValue<lua_meta_functions::ReadLe>>;
@@ -1090,7 +1239,29 @@ pub type FLess = WrapBinaryFloatToBool<F64Lt>;
 pub type Less = BinaryInstruction<ILess, FLess, GetMetaValue<lua_meta_functions::ReadLt>>;
 pub type INotEqual = WrapBinaryIntToBool<I64Ne>;
 pub type FNotEqual = WrapBinaryFloatToBool<F64Ne>;
-pub type NotEqual = NegationBinaryInstruction<INotEqual, FNotEqual, GetMetaValue<lua_meta_functions::ReadEq>>;
+#[make_native_function(RawEqual)]
+pub unsafe extern "C" fn __vm_lua_lib_raw_equal(i1: Direct<LuaValue>, i2: Direct<LuaValue>) -> Pointer<UnsizedArray<LuaValue>> {
+    crate::built_in::return_values(crate::built_in::equal(&i1.0, &i2.0).map(|equal| vec![crate::new_boolean(equal)]))
+}
+// Values of different types are never equal, so unlike the other comparisons nothing raises an error without `__eq`.
+make_instruction! {
+    Equal->fn(i1:LuaValue,i2:LuaValue)->(i2:LuaValue){
+        entry:{
+            %rets=RawEqual(%i1,%i2);
+            if IsErrorReturn(%rets) %propagate %done; },
+        propagate:{ b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%rets); },
+        done:{ %i2=GetRet0(%rets); },
+    }
+}
+make_instruction! {
+    NotEqual->fn(i1:LuaValue,i2:LuaValue)->(i2:LuaValue){
+        entry:{
+            %rets=RawEqual(%i1,%i2);
+            if IsErrorReturn(%rets) %propagate %done; },
+        propagate:{ b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%rets); },
+        done:{ %i2=EncodeBoolean(BoolNot(ToBool(GetRet0(%rets)))); },
+    }
+}
 pub type ILarge = WrapBinaryIntToBool<I64Gt>;
 pub type FLarge = WrapBinaryFloatToBool<F64Gt>;
 pub type Large = FlipBinaryInstruction<ILarge, FLarge, GetMetaValue<lua_meta_functions::ReadLt>>;
@@ -1098,9 +1269,9 @@ pub type ILargeOrEqual = WrapBinaryIntToBool<I64Ge>;
 pub type FLargeOrEqual = WrapBinaryFloatToBool<F64Ge>;
 pub type LargeOrEqual = FlipBinaryInstruction<ILargeOrEqual, FLargeOrEqual, GetMetaValue<lua_meta_functions::ReadLe>>;
 pub type Neg = UniqueInstruction<WrapInteger<I64Neg>, WrapFloat<F64Neg>, GetMetaValue<lua_meta_functions::ReadUnm>>;
-pub type DivFloor = BinaryIntegerInstruction<WrapBinaryInteger<I64Div>, GetMetaValue<lua_meta_functions::ReadIdiv>>;
-pub type Rem =
-    BinaryInstruction<WrapBinaryInteger<I64Rem>, WrapBinaryFloat<F64Rem>, GetMetaValue<lua_meta_functions::ReadMod>>;
+pub type DivFloor =
+    BinaryInstruction<WrapBinaryInteger<IDivFloor>, WrapBinaryFloat<FDivFloor>, GetMetaValue<lua_meta_functions::ReadIdiv>>;
+pub type Rem = BinaryInstruction<WrapBinaryInteger<IRem>, WrapBinaryFloat<FRem>, GetMetaValue<lua_meta_functions::ReadMod>>;
 pub type BitAnd = BinaryIntegerInstruction<WrapBinaryInteger<I64And>, GetMetaValue<lua_meta_functions::ReadBand>>;
 pub type BitOr = BinaryIntegerInstruction<WrapBinaryInteger<I64Or>, GetMetaValue<lua_meta_functions::ReadBor>>;
 pub type BitXor = BinaryIntegerInstruction<WrapBinaryInteger<I64Xor>, GetMetaValue<lua_meta_functions::ReadBxor>>;
@@ -1129,6 +1300,7 @@ impl<'l> MoveIntoObject<'l> for InlineCacheLineImpl {
 type NullableBoolReferenceDecodeSomeUnchecked = nullable_option::DecodeSomeUnchecked<BoolReference>;
 type NullableTableReferenceIsSome = nullable_option::IsSome<LuaTableReference>;
 type NullableTableReferenceDecodeSome = nullable_option::DecodeSomeUnchecked<LuaTableReference>;
+type NullableMetaFunctionsIsSome = nullable_option::IsSome<LuaMetaFunctionsReference>;
 type NullableShapeReferenceEncodeSome = nullable_option::EncodeSome<LuaShapeReference>;
 make_instruction! {GetByCache->fn<mut cache:InlineCacheLine>(table:Pointer<LuaTable>)->(o:LuaValue){
     entry:{
@@ -1141,18 +1313,23 @@ make_instruction! {GetByCache->fn<mut cache:InlineCacheLine>(table:Pointer<LuaTa
     use_metatable:{%o=Read<LuaValue::TYPE>(LocateSlot(b::Deref<LuaTableReference::TYPE>(NullableTableReferenceDecodeSome(inline_cache_line::ReadTable(%cache))),b::UIntExtend<12,6>(inline_cache_line::ReadSlot(%cache))));},
     none:{%o=ConstNil();},
 }}
+// Misses on an empty slot, which may go to `__newindex`, and on a metatable, whose fields change its meta functions.
 make_instruction! {SetByCache->fn<mut cache:InlineCacheLine>(table:Pointer<LuaTable>,value:LuaValue)->(o:Bool){
     entry:{
         %shape=lua_table::ReadShape(%table);
         if UsizeEq(b::CastUnchecked<Usize::TYPE,NullableOption::<LuaShapeReference>::TYPE>(inline_cache_line::ReadShape(%cache)),b::CastUnchecked<Usize::TYPE,NullableOption::<LuaShapeReference>::TYPE>(NullableShapeReferenceEncodeSome(%shape))) %correct_shape %none;
     },
     correct_shape:{ if b::Read<Bool::TYPE>(b::Deref<BoolReference::TYPE>(NullableBoolReferenceDecodeSomeUnchecked(inline_cache_line::ReadInvalid(%cache)))) %none %valid; },
-    valid:{if NullableTableReferenceIsSome(inline_cache_line::ReadTable(%cache)) %use_metatable %use_raw;},
+    valid:{if NullableMetaFunctionsIsSome(lua_shape::ReadAsMetaTable(b::Deref<LuaShapeReference::TYPE>(%shape))) %none %not_metatable;},
+    not_metatable:{if NullableTableReferenceIsSome(inline_cache_line::ReadTable(%cache)) %use_metatable %use_raw;},
     use_raw:{
-        Write<LuaValue::TYPE>(LocateSlot(%table,b::UIntExtend<12,6>(inline_cache_line::ReadSlot(%cache))),%value);
-        %o=true; },
+        %slot_pointer=LocateSlot(%table,b::UIntExtend<12,6>(inline_cache_line::ReadSlot(%cache)));
+        if lua_value::IsNil(Read<LuaValue::TYPE>(%slot_pointer)) %none %write; },
     use_metatable:{
-        Write<LuaValue::TYPE>(LocateSlot(b::Deref<LuaTableReference::TYPE>(NullableTableReferenceDecodeSome(inline_cache_line::ReadTable(%cache))),b::UIntExtend<12,6>(inline_cache_line::ReadSlot(%cache))),%value);
+        %slot_pointer=LocateSlot(b::Deref<LuaTableReference::TYPE>(NullableTableReferenceDecodeSome(inline_cache_line::ReadTable(%cache))),b::UIntExtend<12,6>(inline_cache_line::ReadSlot(%cache)));
+        if lua_value::IsNil(Read<LuaValue::TYPE>(%slot_pointer)) %none %write; },
+    write:{
+        Write<LuaValue::TYPE>(%slot_pointer,%value);
         %o=true; },
     none:{%o=false;},
 }}
@@ -1164,18 +1341,67 @@ pub unsafe extern "C" fn __vm_lua_lib_get_slot(shape: Pointer<LuaShape>, key: Di
         .map(|slot_metadata| I64(slot_metadata.get_slot().0 as i64))
         .unwrap_or(I64(-1))
 }
+#[make_native_function(GetArraySlot)]
+pub unsafe extern "C" fn __vm_lua_lib_get_array_slot(table: Pointer<LuaTable>, key: Direct<LuaValue>) -> I64 {
+    crate::array_index(table, &key.0).map(|index| I64(index as i64)).unwrap_or(I64(-1))
+}
+#[make_native_function(RawAppendArraySlot)]
+pub unsafe extern "C" fn __vm_lua_lib_append_array_slot(table: Pointer<LuaTable>, key: Direct<LuaValue>) -> Pointer<UnsizedArray<LuaValue>> {
+    let slot = crate::append_array_slot(table, &key.0).and_then(|slot| crate::new_integer(slot.map_or(-1, |index| index as i64)));
+    crate::built_in::return_values(slot.map(|slot| vec![slot]))
+}
+// The slot of the array part `key` is appended to, or -1 when it is not the next index of the array.
+make_instruction! {AppendArraySlot->fn(table:Pointer<LuaTable>,key:LuaValue)->(slot:I64){
+    entry:{
+        %rets=RawAppendArraySlot(%table,%key);
+        if IsErrorReturn(%rets) %propagate %done; },
+    propagate:{ b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%rets); },
+    done:{ %slot=GetIntegerValue(GetRet0(%rets)); },
+}}
+#[make_native_function(UpdateMetaFunctions)]
+pub unsafe extern "C" fn __vm_lua_lib_update_meta_functions(shape: Pointer<LuaShape>, key: Direct<LuaValue>, value: Direct<LuaValue>) {
+    crate::update_meta_functions(shape, &key.0, value.0)
+}
+#[make_native_function(RawTableLength)]
+pub unsafe extern "C" fn __vm_lua_lib_table_length(table: Pointer<LuaTable>) -> Pointer<UnsizedArray<LuaValue>> {
+    crate::built_in::return_values(crate::table_length(table).and_then(crate::new_integer).map(|length| vec![length]))
+}
+make_instruction! {TableLength->fn(table:Pointer<LuaTable>)->(length:LuaValue){
+    entry:{
+        %rets=RawTableLength(%table);
+        if IsErrorReturn(%rets) %propagate %done; },
+    propagate:{ b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%rets); },
+    done:{ %length=GetRet0(%rets); },
+}}
+#[make_native_function(RaiseIndexChainTooLong)]
+pub extern "C" fn __vm_lua_lib_raise_index_chain_too_long() -> Pointer<UnsizedArray<LuaValue>> {
+    crate::error::raise(LuaError::Message("'__index' chain too long; possibly a loop".to_string()))
+}
+#[make_native_function(RaiseNewIndexChainTooLong)]
+pub extern "C" fn __vm_lua_lib_raise_new_index_chain_too_long() -> Pointer<UnsizedArray<LuaValue>> {
+    crate::error::raise(LuaError::Message("'__newindex' chain too long; possibly a loop".to_string()))
+}
 type EncodeSomeShapeReference = nullable_option::EncodeSome<LuaShapeReference>;
 type EncodeSomeBoolReference = nullable_option::EncodeSome<BoolReference>;
 type EncodeSomeTableReference = nullable_option::EncodeSome<LuaTableReference>;
 make_instruction! {
     GetElement->fn<mut cache:InlineCacheLine>(obj:LuaValue,key:LuaValue)->(value:LuaValue){
       entry:{
+          %depth=0;
           branch %loop;},
       loop:{
-          phi %obj:LuaValue={%entry=>%obj,%use_index_table=>%index};
-          if lua_value::IsTable(%obj) %is_table %not_found; },
+          phi %current:LuaValue={%entry=>%obj,%use_index_table=>%index};
+          phi %depth:I64={%entry=>%depth,%use_index_table=>%next_depth};
+          if lua_value::IsTable(%current) %is_table %not_found; },
         is_table:{
-          %table=b::Deref<LuaTableReference::TYPE>(lua_value::DecodeTableUnchecked(%obj));
+          %table=b::Deref<LuaTableReference::TYPE>(lua_value::DecodeTableUnchecked(%current));
+          %array_slot=GetArraySlot(%table,%key);
+          if I64Less(%array_slot,0) %not_in_array %in_array;},
+        in_array:{
+            %array_value=Read<LuaValue::TYPE>(LocateArraySlot(%table,%array_slot));
+            if lua_value::IsNil(%array_value) %not_found %array_found; },
+        array_found:{ %value=b::Move<LuaValue::TYPE>(%array_value); },
+        not_in_array:{
           %shape=b::Deref<LuaShapeReference::TYPE>(lua_table::ReadShape(%table));
           %slot=GetSlot(%shape,%key);
           if I64Eq(%slot,-1) %not_found %found;},
@@ -1187,18 +1413,23 @@ make_instruction! {
             inline_cache_line::WriteShape(%cache,EncodeSomeShapeReference(b::Clone<LuaShapeReference::TYPE>(lua_table::ReadShape(%table))));
             inline_cache_line::WriteSlot(%cache,b::IntTruncate<6,7>(%slot));
             inline_cache_line::WriteInvalid(%cache,EncodeSomeBoolReference(b::Clone<BoolReference::TYPE>(lua_shape::ReadInvalid(%shape))));
-            inline_cache_line::WriteTable(%cache,EncodeSomeTableReference(lua_value::DecodeTableUnchecked(%obj)));
+            inline_cache_line::WriteTable(%cache,EncodeSomeTableReference(lua_value::DecodeTableUnchecked(%current)));
             %value=b::Move<LuaValue::TYPE>(%v); },
         not_found:{
-            %index=GetMetaValueIndex(%obj);
+            %index=GetMetaValueIndex(%current);
             if lua_value::IsTable(%index) %use_index_table %not_index_table; },
           not_index_table:{if lua_value::IsNil(%index) %use_nil %index_callable;},
-          index_callable:{ %value=CallFunction2Ret1(%index,%obj,%key); },
+          index_callable:{ %value=CallFunction2Ret1(%index,%current,%key); },
         use_nil:{%value=ConstNil();},
       use_index_table:{
           %index_table=lua_value::DecodeTableUnchecked(%index);
-          branch %loop;
+          // the tables followed are bounded like MAXTAGLOOP of the reference implementation, a cycle is an error
+          %next_depth=I64Add(%depth,1);
+          if I64Less(%next_depth,2000) %loop %chain_too_long;
       },
+      chain_too_long:{
+          %value=ConstNil();
+          b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(RaiseIndexChainTooLong()); },
     }
 }
 #[make_native_function(ShapeAction)]
@@ -1253,7 +1484,6 @@ pub unsafe extern "C" fn __vm_lua_lib_clone_shape(dest: Pointer<LuaShape>, src:
     ));
     dest.as_ref_mut().set_meta_functions(src.as_ref().get_meta_functions());
     dest.as_ref_mut().set_as_meta_table(src.as_ref().get_as_meta_table());
-    dest.as_ref_mut().set_max_int_index(src.as_ref().get_max_int_index());
     dest.as_ref_mut().set_is_owned(src.as_ref().get_is_owned());
     dest.as_ref_mut().set_invalid(src.as_ref().get_invalid());
     dest.as_ref_mut().set_action_of_field(Default::default());
@@ -1265,39 +1495,67 @@ type NullableShapeIsSome = e::nullable_option::IsSome<LuaShapeReference>;
 type NullableShapeDecodeSome = e::nullable_option::DecodeSomeUnchecked<LuaShapeReference>;
 make_instruction! {
     SetElement->fn<mut cache:InlineCacheLine>(value:LuaValue,key:LuaValue,elem:LuaValue){
-      entry:{branch %loop;},
+      entry:{
+          %depth=0;
+          branch %loop;},
       loop:{
-          phi %value:LuaValue={%entry=>%value,%use_new_index_table=>%new_index};
-          if lua_value::IsTable(%value) %is_table %not_table; },
+          phi %target:LuaValue={%entry=>%value,%use_new_index_table=>%new_index};
+          phi %depth:I64={%entry=>%depth,%use_new_index_table=>%next_depth};
+          if lua_value::IsTable(%target) %is_table %not_table; },
         is_table:{
-          %table=b::Deref<LuaTableReference::TYPE>(lua_value::DecodeTableUnchecked(%value));
+          %table=b::Deref<LuaTableReference::TYPE>(lua_value::DecodeTableUnchecked(%target));
           %shape=b::Deref<LuaShapeReference::TYPE>(lua_table::ReadShape(%table));
+          %array_slot=GetArraySlot(%table,%key);
+          if I64Less(%array_slot,0) %not_in_array %in_array;},
+        in_array:{ if lua_value::IsNil(Read<LuaValue::TYPE>(LocateArraySlot(%table,%array_slot))) %array_hole %write_array; },
+        array_hole:{
+            %new_index=lua_meta_functions::ReadNewindex(b::Deref<LuaMetaFunctionsReference::TYPE>(lua_shape::ReadMetaFunctions(%shape)));
+            if lua_value::IsNil(%new_index) %write_array %use_new_index; },
+        write_array:{ Write<LuaValue::TYPE>(LocateArraySlot(%table,%array_slot),%elem); },
+        not_in_array:{
           %slot=GetSlot(%shape,%key);
           if I64Less(%slot,0) %not_found %found;},
         not_table:{
-            %new_index=GetMetaValueNewIndex(%value);
+            %new_index=GetMetaValueNewIndex(%target);
             if lua_value::IsNil(%new_index) %other %use_new_index; },
         other:{ThrowError();},
-        found:{
+        found:{ if lua_value::IsNil(Read<LuaValue::TYPE>(LocateSlot(%table,b::IntTruncate<12,7>(%slot)))) %field_hole %write_field; },
+        field_hole:{
+            %new_index=lua_meta_functions::ReadNewindex(b::Deref<LuaMetaFunctionsReference::TYPE>(lua_shape::ReadMetaFunctions(%shape)));
+            if lua_value::IsNil(%new_index) %write_field %use_new_index; },
+        write_field:{
             inline_cache_line::WriteKey(%cache,%key);
             inline_cache_line::WriteShape(%cache,EncodeSomeShapeReference(b::Clone<LuaShapeReference::TYPE>(lua_table::ReadShape(%table))));
             inline_cache_line::WriteSlot(%cache,b::IntTruncate<6,7>(%slot));
             inline_cache_line::WriteInvalid(%cache,EncodeSomeBoolReference(b::Clone<BoolReference::TYPE>(lua_shape::ReadInvalid(%shape))));
-            inline_cache_line::WriteTable(%cache,EncodeSomeTableReference(lua_value::DecodeTableUnchecked(%value)));
-            Write<LuaValue::TYPE>(LocateSlot(%table,b::IntTruncate<12,7>(%slot)),%elem); },
+            inline_cache_line::WriteTable(%cache,EncodeSomeTableReference(lua_value::DecodeTableUnchecked(%target)));
+            Write<LuaValue::TYPE>(LocateSlot(%table,b::IntTruncate<12,7>(%slot)),%elem);
+            if NullableMetaFunctionsIsSome(lua_shape::ReadAsMetaTable(%shape)) %update_meta_functions %written; },
+        update_meta_functions:{ UpdateMetaFunctions(%shape,%key,%elem); },
+        written:{},
         not_found:{
             %new_index=lua_meta_functions::ReadNewindex(b::Deref<LuaMetaFunctionsReference::TYPE>(lua_shape::ReadMetaFunctions(%shape)));
             if lua_value::IsNil(%new_index) %add_slot %use_new_index;
         },
-          use_new_index:{ if lua_value::IsTable(%new_index) %use_new_index_table %use_new_index_function; },
-          use_new_index_function:{ %r=CallFunction3Ret1(%new_index,%value,%key,%elem); },
+          use_new_index:{
+              // the paths reaching `__newindex` join here, so is the table they write to
+              %receiver=b::Move<LuaValue::TYPE>(%target);
+              if lua_value::IsTable(%new_index) %use_new_index_table %use_new_index_function; },
+          use_new_index_function:{ %r=CallFunction3Ret1(%new_index,%receiver,%key,%elem); },
           use_new_index_table:{
               %new_index_table=lua_value::DecodeTableUnchecked(%new_index);
-              branch %loop; },
-          add_slot:{ if lua_shape::ReadIsOwned(%shape) %extend %other_slot; },
+              %next_depth=I64Add(%depth,1);
+              if I64Less(%next_depth,2000) %loop %chain_too_long; },
+          chain_too_long:{ b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(RaiseNewIndexChainTooLong()); },
+          add_slot:{
+              %append_slot=AppendArraySlot(%table,%key);
+              if I64Less(%append_slot,0) %add_field %append; },
+            append:{ Write<LuaValue::TYPE>(LocateArraySlot(%table,%append_slot),%elem); },
+            add_field:{ if lua_shape::ReadIsOwned(%shape) %extend %other_slot; },
             extend:{
               %slot = InsertField(%shape,%key);
-              Write<LuaValue::TYPE>(LocateNewSlot(%table,%slot),%elem); },
+              Write<LuaValue::TYPE>(LocateNewSlot(%table,%slot),%elem);
+              if NullableMetaFunctionsIsSome(lua_shape::ReadAsMetaTable(%shape)) %update_meta_functions %written; },
             other_slot:{
                 %goto_action=ShapeAction(%shape,%key);
                 if NullableShapeIsSome(%goto_action) %goto_shape %clone_shape; },
@@ -1328,6 +1586,11 @@ make_instruction! {
         slow:{ %o = b::LocateElement<UnsizedArray::<LuaValue>::TYPE>(NullableLuaValueArrayDecodeSome(lua_table::ReadSlowFields(%object)),UsizeSub(%slot,%fast_len)); }
     }
 }
+make_instruction! {
+    LocateArraySlot->fn(object:Pointer<LuaTable>,slot:I64)->(o:Pointer<LuaValue>){
+        entry:{ %o = b::LocateElement<UnsizedArray::<LuaValue>::TYPE>(NullableLuaValueArrayDecodeSome(lua_table::ReadArray(%object)),b::IntTruncate<12,7>(%slot)); }
+    }
+}
 type ReadLuaValueArray = e::ReadElement<LuaValue, UnsizedArray<LuaValue>>;
 type WriteLuaValueArray = e::WriteElement<LuaValue, UnsizedArray<LuaValue>>;
 make_instruction! {
@@ -1446,46 +1709,65 @@ make_instruction! {
     }}
 }
 make_instruction! {
-    GetGlobal->fn<const field:LuaValue,mut cache:InlineCacheLine>(state:LuaStateReference)->(o:LuaValue){
+    GetEnv->fn(state:LuaStateReference)->(o:LuaValue){
         entry:{
-            %value=GetByCache<%cache>(b::Deref<LuaTableReference::TYPE>(lua_state::ReadGlobal(b::Deref<LuaStateReference::TYPE>(%state))));
+            %state_ptr=b::Deref<LuaStateReference::TYPE>(%state);
+            if lua_state::ReadChunkEnvGiven(%state_ptr) %given %global; },
+        given:{
+            %chunk_env=lua_state::ReadChunkEnv(%state_ptr);
+            lua_state::WriteChunkEnv(%state_ptr,ConstNil());
+            lua_state::WriteChunkEnvGiven(%state_ptr,false);
+            %o=b::Move<LuaValue::TYPE>(%chunk_env); },
+        global:{ %o=lua_value::EncodeTable(lua_state::ReadGlobal(%state_ptr)); },
+    }
+}
+make_instruction! {GetClosureEnv->fn(closure:LuaClosureReference)->(o:LuaValue){entry:{
+    %o=lua_closure::ReadEnv(b::Deref<LuaClosureReference::TYPE>(%closure));
+}}}
+make_instruction! {
+    GetGlobal->fn<const field:LuaValue,mut cache:InlineCacheLine>(env:LuaValue)->(o:LuaValue){
+        entry:{ if lua_value::IsTable(%env) %table %miss; },
+        table:{
+            %value=GetByCache<%cache>(b::Deref<LuaTableReference::TYPE>(lua_value::DecodeTableUnchecked(%env)));
             if lua_value::IsNil(%value) %miss %hit; },
         hit:{ %o=b::Move<LuaValue::TYPE>(%value); },
-        miss:{ %o=GetElement<%cache>(lua_value::EncodeTable(lua_state::ReadGlobal(b::Deref<LuaStateReference::TYPE>(%state))),%field); },
+        miss:{ %o=GetElement<%cache>(%env,%field); },
     }
 }
 make_instruction! {
-    SetGlobal->fn<const field:LuaValue,mut cache:InlineCacheLine>(state:LuaStateReference,value:LuaValue){
-        entry:{
-            %cached=SetByCache<%cache>(b::Deref<LuaTableReference::TYPE>(lua_state::ReadGlobal(b::Deref<LuaStateReference::TYPE>(%state))),%value);
+    SetGlobal->fn<const field:LuaValue,mut cache:InlineCacheLine>(env:LuaValue,value:LuaValue){
+        entry:{ if lua_value::IsTable(%env) %table %miss; },
+        table:{
+            %cached=SetByCache<%cache>(b::Deref<LuaTableReference::TYPE>(lua_value::DecodeTableUnchecked(%env)),%value);
             if BoolNot(%cached) %miss %hit; },
         hit:{ },
-        miss:{ SetElement<%cache>(lua_value::EncodeTable(lua_state::ReadGlobal(b::Deref<LuaStateReference::TYPE>(%state))),%field,%value); },
+        miss:{ SetElement<%cache>(%env,%field,%value); },
     }
 }
 make_instruction! {Return->fn(r:Pointer<UnsizedArray<LuaValue>>){entry:{
     b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%r);
 }}}
+// The results outlive the frame returning them, so they are not allocated on its stack.
 make_instruction! {Return0->fn(){entry:{
-    %array=b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(b::IntTruncate<12,7>(0));
+    %array=b::NonGCAllocUnsized<LuaValueArrayReference::TYPE>(b::IntTruncate<12,7>(0));
     b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%array,b::IntTruncate<12,7>(0));
     b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%array);
 }}}
 make_instruction! {Return1->fn(r0:LuaValue){entry:{
-    %array=b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(b::IntTruncate<12,7>(1));
+    %array=b::NonGCAllocUnsized<LuaValueArrayReference::TYPE>(b::IntTruncate<12,7>(1));
     b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%array,b::IntTruncate<12,7>(1));
     LuaValueArraySet(%array,b::IntTruncate<12,7>(0),%r0);
     b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%array);
 }}}
 make_instruction! {Return2->fn(r0:LuaValue,r1:LuaValue){entry:{
-    %array=b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(b::IntTruncate<12,7>(2));
+    %array=b::NonGCAllocUnsized<LuaValueArrayReference::TYPE>(b::IntTruncate<12,7>(2));
     b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%array,b::IntTruncate<12,7>(2));
     LuaValueArraySet(%array,b::IntTruncate<12,7>(0),%r0);
     LuaValueArraySet(%array,b::IntTruncate<12,7>(1),%r1);
     b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%array);
 }}}
 make_instruction! {Return3->fn(r0:LuaValue,r1:LuaValue,r2:LuaValue){entry:{
-    %array=b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(b::IntTruncate<12,7>(3));
+    %array=b::NonGCAllocUnsized<LuaValueArrayReference::TYPE>(b::IntTruncate<12,7>(3));
     b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%array,b::IntTruncate<12,7>(3));
     LuaValueArraySet(%array,b::IntTruncate<12,7>(0),%r0);
     LuaValueArraySet(%array,b::IntTruncate<12,7>(1),%r1);
@@ -1493,28 +1775,28 @@ make_instruction! {Return3->fn(r0:LuaValue,r1:LuaValue,r2:LuaValue){entry:{
     b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%array);
 }}}
 make_instruction! {Return0VaSlice->fn(va_rets:Slice<LuaValue>){entry:{
-    %array=b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(UsizeAdd(b::IntTruncate<12,7>(0),LuaValueSliceLen(%va_rets)));
+    %array=b::NonGCAllocUnsized<LuaValueArrayReference::TYPE>(UsizeAdd(b::IntTruncate<12,7>(0),LuaValueSliceLen(%va_rets)));
     b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%array,UsizeAdd(b::IntTruncate<12,7>(0),LuaValueSliceLen(%va_rets)));
     LuaValueSliceCopy(LuaValueSubSlice(UnsizedLuaValueArrayToSlice(%array),b::IntTruncate<12,7>(0),LuaValueSliceLen(%va_rets)),%va_rets);
     b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%array);
 }}}
 make_instruction! {Return1VaSlice->fn(r0:LuaValue,va_rets:Slice<LuaValue>){entry:{
-    %array=b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(UsizeAdd(b::IntTruncate<12,7>(1),LuaValueSliceLen(%va_rets)));
+    %array=b::NonGCAllocUnsized<LuaValueArrayReference::TYPE>(UsizeAdd(b::IntTruncate<12,7>(1),LuaValueSliceLen(%va_rets)));
     b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%array,UsizeAdd(b::IntTruncate<12,7>(1),LuaValueSliceLen(%va_rets)));
     LuaValueSliceCopy(LuaValueSubSlice(UnsizedLuaValueArrayToSlice(%array),b::IntTruncate<12,7>(1),LuaValueSliceLen(%va_rets)),%va_rets);
     LuaValueArraySet(%array,b::IntTruncate<12,7>(0),%r0);
     b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%array);
 }}}
 make_instruction! {Return2VaSlice->fn(r0:LuaValue,r1:LuaValue,va_rets:Slice<LuaValue>){entry:{
-    %array=b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(UsizeAdd(b::IntTruncate<12,7>(2),LuaValueSliceLen(%va_rets)));
-    b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%array,UsizeAdd(b::IntTruncate<12,7>(1),LuaValueSliceLen(%va_rets)));
+    %array=b::NonGCAllocUnsized<LuaValueArrayReference::TYPE>(UsizeAdd(b::IntTruncate<12,7>(2),LuaValueSliceLen(%va_rets)));
+    b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%array,UsizeAdd(b::IntTruncate<12,7>(2),LuaValueSliceLen(%va_rets)));
     LuaValueSliceCopy(LuaValueSubSlice(UnsizedLuaValueArrayToSlice(%array),b::IntTruncate<12,7>(2),LuaValueSliceLen(%va_rets)),%va_rets);
     LuaValueArraySet(%array,b::IntTruncate<12,7>(0),%r0);
     LuaValueArraySet(%array,b::IntTruncate<12,7>(1),%r1);
     b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%array);
 }}}
 make_instruction! {Return3VaSlice->fn(r0:LuaValue,r1:LuaValue,r2:LuaValue,va_rets:Slice<LuaValue>){entry:{
-    %array=b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(UsizeAdd(b::IntTruncate<12,7>(3),LuaValueSliceLen(%va_rets)));
+    %array=b::NonGCAllocUnsized<LuaValueArrayReference::TYPE>(UsizeAdd(b::IntTruncate<12,7>(3),LuaValueSliceLen(%va_rets)));
     b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%array,UsizeAdd(b::IntTruncate<12,7>(3),LuaValueSliceLen(%va_rets)));
     LuaValueSliceCopy(LuaValueSubSlice(UnsizedLuaValueArrayToSlice(%array),b::IntTruncate<12,7>(3),LuaValueSliceLen(%va_rets)),%va_rets);
     LuaValueArraySet(%array,b::IntTruncate<12,7>(0),%r0);
@@ -1523,7 +1805,7 @@ make_instruction! {Return3VaSlice->fn(r0:LuaValue,r1:LuaValue,r2:LuaValue,va_ret
     b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%array);
 }}}
 make_instruction! {ReturnVaSlice->fn(rets:Slice<LuaValue>,va_rets:Slice<LuaValue>){entry:{
-    %array=b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(UsizeAdd(LuaValueSliceLen(%rets),LuaValueSliceLen(%va_rets)));
+    %array=b::NonGCAllocUnsized<LuaValueArrayReference::TYPE>(UsizeAdd(LuaValueSliceLen(%rets),LuaValueSliceLen(%va_rets)));
     b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%array,UsizeAdd(LuaValueSliceLen(%rets),LuaValueSliceLen(%va_rets)));
     LuaValueSliceCopy(LuaValueSubSlice(UnsizedLuaValueArrayToSlice(%array),LuaValueSliceLen(%rets),LuaValueSliceLen(%va_rets)),%va_rets);
     LuaValueSliceCopy(LuaValueSubSlice(UnsizedLuaValueArrayToSlice(%array),b::IntTruncate<12,7>(0),LuaValueSliceLen(%rets)),%rets);
@@ -1547,7 +1829,7 @@ make_instruction! {Return3VA->fn(r0:LuaValue,r1:LuaValue,r2:LuaValue,va_rets:Poi
 make_instruction! {
     GetRet0->fn(rets:Pointer<UnsizedArray<LuaValue>>)->(r:LuaValue){
         entry:{
-            if UsizeGt(b::IntTruncate<12,7>(0),b::GetLength<UnsizedArray::<LuaValue>::TYPE>(%rets)) %empty %not_empty; },
+            if UsizeLt(b::IntTruncate<12,7>(0),b::GetLength<UnsizedArray::<LuaValue>::TYPE>(%rets)) %not_empty %empty; },
         empty:{%r=ConstNil();},
         not_empty:{%r=LuaValueArrayGet(%rets,b::IntTruncate<12,7>(0));},
     }
@@ -1555,7 +1837,7 @@ make_instruction! {
 make_instruction! {
     DoGetRet->fn(rets:Pointer<UnsizedArray<LuaValue>>,index:Usize)->(r:LuaValue){
         entry:{
-            if UsizeGt(%index,b::GetLength<UnsizedArray::<LuaValue>::TYPE>(%rets)) %empty %not_empty; },
+            if UsizeLt(%index,b::GetLength<UnsizedArray::<LuaValue>::TYPE>(%rets)) %not_empty %empty; },
         empty:{%r=ConstNil();},
         not_empty:{%r=LuaValueArrayGet(%rets,%index);},
     }
@@ -1563,7 +1845,7 @@ make_instruction! {
 make_instruction! {
     GetRet->fn<const index:Usize>(rets:Pointer<UnsizedArray<LuaValue>>)->(r:LuaValue){
         entry:{
-            if UsizeGt(%index,b::GetLength<UnsizedArray::<LuaValue>::TYPE>(%rets)) %empty %not_empty; },
+            if UsizeLt(%index,b::GetLength<UnsizedArray::<LuaValue>::TYPE>(%rets)) %not_empty %empty; },
         empty:{%r=ConstNil();},
         not_empty:{%r=LuaValueArrayGet(%rets,%index);},
     }
@@ -1571,14 +1853,54 @@ make_instruction! {
 make_instruction! {
     GetArg->fn<const index:Usize>(args:Slice<LuaValue>)->(r:LuaValue){
         entry:{
-            if UsizeGt(%index,LuaValueSliceLen(%args)) %empty %not_empty; },
+            if UsizeLt(%index,LuaValueSliceLen(%args)) %not_empty %empty; },
         empty:{%r=ConstNil();},
         not_empty:{%r=LuaValueSliceGet(%args,%index);},
     }
 }
-make_instruction! {GetVaArgs->fn<const index:Usize>(args:Slice<LuaValue>)->(va_args:Slice<LuaValue>){ entry:{
-        %va_args=LuaValueSubSlice(%args,%index,UsizeSub(LuaValueSliceLen(%args),%index));
-}}}
+make_instruction! {
+    GetVaArgs->fn<const index:Usize>(args:Slice<LuaValue>)->(va_args:Slice<LuaValue>){
+        entry:{
+            %len=LuaValueSliceLen(%args);
+            if UsizeLt(%len,%index) %none %some; },
+        // the slice is copied first, the sub slice must not be written back to the argument register
+        none:{
+            %empty=LuaValueSubSlice(b::Move<Slice::<LuaValue>::TYPE>(%args),%len,b::IntTruncate<12,7>(0));
+            %va_args=b::Move<Slice::<LuaValue>::TYPE>(%empty); },
+        some:{
+            %rest=LuaValueSubSlice(b::Move<Slice::<LuaValue>::TYPE>(%args),%index,UsizeSub(%len,%index));
+            %va_args=b::Move<Slice::<LuaValue>::TYPE>(%rest); },
+    }
+}
+#[make_native_function(RawSetList)]
+pub unsafe extern "C" fn __vm_lua_lib_set_list(table: Direct<LuaValue>, first: Usize, values: Pointer<UnsizedArray<LuaValue>>) -> Pointer<UnsizedArray<LuaValue>> {
+    let set_values = || {
+        let table = table.0.read_table().ok_or_else(|| failure::format_err!("attempt to set the list of a non-table value"))?;
+        for (index, value) in values.as_ref().as_slice().iter().enumerate() {
+            crate::set_field(table.clone(), crate::new_integer((first.0 + index) as i64)?, value.clone())?;
+        }
+        Ok(Vec::new())
+    };
+    crate::built_in::return_values(set_values())
+}
+// Sets the positional fields of a table constructor from the call ending it, the first one at the index `first`.
+make_instruction! {SetListVA->fn<const first:Usize>(table:LuaValue,values:Pointer<UnsizedArray<LuaValue>>){
+    entry:{
+        %rets=RawSetList(%table,%first,%values);
+        if IsErrorReturn(%rets) %propagate %set; },
+    propagate:{ b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%rets); },
+    set:{},
+}}
+make_instruction! {SetListVaSlice->fn<const first:Usize>(table:LuaValue,values:Slice<LuaValue>){
+    entry:{
+        %array=b::StackAllocUnsized<UnsizedArray::<LuaValue>::TYPE>(LuaValueSliceLen(%values));
+        b::SetLength<UnsizedArray::<LuaValue>::TYPE>(%array,LuaValueSliceLen(%values));
+        LuaValueSliceCopy(UnsizedLuaValueArrayToSlice(%array),%values);
+        %rets=RawSetList(%table,%first,%array);
+        if IsErrorReturn(%rets) %propagate %set; },
+    propagate:{ b::Return<Pointer::<UnsizedArray<LuaValue>>::TYPE>(%rets); },
+    set:{},
+}}
 #[make_native_function(PrintDebug)]
 pub extern "C" fn __vm_lua_lib_print_debug(value: Direct<LuaValue>) {
     let mut buffer = Vec::new();
@@ -19,8 +19,8 @@ make_instruction_set! {
     IMul->e::I64Mul, FMul->e::F64Mul, Mul->i::Mul,//30
     FPow->i::FPow, Pow->i::Pow,//32
     IDiv->i::IDiv, FDiv->e::F64Div, Div->i::Div,//35
-    IDivFloor->e::I64Div, FDivFloor->i::FDivFloor, DivFloor->i::DivFloor,//38
-    IRem->e::I64Rem, FRem->e::F64Rem, Rem->i::Rem,//41
+    IDivFloor->i::IDivFloor, FDivFloor->i::FDivFloor, DivFloor->i::DivFloor,//38
+    IRem->i::IRem, FRem->i::FRem, Rem->i::Rem,//41
     IBitXor->e::I64Xor, BitXor->i::BitXor,//43
     IBitAnd->e::I64And, BitAnd->i::BitAnd,//45
     IBitOr->e::I64Or, BitOr->i::BitOr,//47
@@ -59,9 +59,12 @@ make_instruction_set! {
     ReturnVA->i::ReturnVA,Return0VA->i::Return0VA,Return1VA->i::Return1VA,Return2VA->i::Return2VA,Return3VA->i::Return3VA,
     Return->i::Return,Return0->i::Return0,Return1->i::Return1,Return2->i::Return2,Return3->i::Return3,
     BreakPoint->i::BreakPoint,
-    MakeTable->i::MakeTable,MakeTable0->i::MakeTable0,
+    MakeTable->i::MakeTable,MakeTable0->i::MakeTable0,SetListVA->i::SetListVA,SetListVaSlice->i::SetListVaSlice,
     ForInLoopJump1->i::ForInLoopJump1,ForInLoopJump2->i::ForInLoopJump2,ForInLoopJump->i::ForInLoopJump,
     ConstClosure0->i::ConstClosure0,ConstClosure->i::ConstClosure,SetUpRef->i::SetUpRef,NewUpValue->i::NewUpValue,
+    PushToBeClosed->i::PushToBeClosed,CloseVariables->i::CloseVariables,
+    GetEnv->i::GetEnv,GetClosureEnv->i::GetClosureEnv,
+    CheckQuotas->i::CheckQuotas,
     Print->i::PrintDebug,
   ]
 }
@@ -18,7 +18,7 @@ pub use util;
 use vm_core::DynRuntimeTrait;
 
 use std::sync::Arc;
-use std::{cell::UnsafeCell, collections::{HashMap, HashSet}, ptr::NonNull};
+use std::{cell::{Cell, UnsafeCell}, collections::{HashMap, HashSet}, io::Read, mem::MaybeUninit, ptr::NonNull};
 
 use failure::Fallible;
 
@@ -27,7 +27,7 @@ use lua_lexical::LuaLexical;
 use mem::*;
 
 use runtime::code::FunctionPack;
-use runtime_extra::{Bool, NullableOptionImpl, NullablePointerImpl, Usize, U64, U8};
+use runtime_extra::{Bool, NullableOptionImpl, NullablePointerImpl, Usize, F64, I64, U8};
 use vm_core::{ObjectRef, Pointer, UnsizedArray};
 
 pub use crate::ir::LuaInstructionSet;
@@ -42,14 +42,20 @@ extern crate runtime_derive;
 extern crate derive_builder;
 extern crate static_assertions;
 pub(crate) type TypeResourceImpl = memory_mmmu::RegistedType;
+pub mod api;
 pub mod builder;
 pub mod built_in;
+pub mod close;
+pub mod dump;
 pub mod error;
+pub mod host;
 pub mod instruction;
 pub mod ir;
 pub mod lua_lexical;
 pub mod mem;
+pub mod quota;
 pub mod syntax;
+pub mod user_data;
 pub fn add_global_function(state: LuaStateReference, key: &str, function: &LuaFunctionRustType) -> Fallible<()> {
     add_global(
         state.clone(),
@@ -61,6 +67,9 @@ pub fn new_string(state: Pointer<LuaState>, buffer: &[u8]) -> Fallible<LuaValueI
     unsafe {
         let mut state_ptr_clone = state.clone();
         let strings = state_ptr_clone.as_ref_mut().ref_strings_mut();
+        if !strings.contains(buffer) {
+            quota::check_allocation(buffer.len())?;
+        }
         let string = strings.get_or_insert_with(buffer, |buffer| {
             let string = LuaStringReference(
                 LuaStringReference::get()
@@ -85,24 +94,44 @@ pub fn new_string(state: Pointer<LuaState>, buffer: &[u8]) -> Fallible<LuaValueI
     }
 }
 pub fn add_global(state: LuaStateReference, key: LuaValueImpl, value: LuaValueImpl) -> Fallible<()> {
+    unsafe { set_field(state.as_pointer().as_ref().get_global(), key, value) }
+}
+/// Sets a field of a table without looking at its metatable, like `rawset`. A table with a shared shape gets its
+/// own copy of the shape when the key is new.
+pub fn set_field(mut table: Pointer<LuaTable>, key: LuaValueImpl, value: LuaValueImpl) -> Fallible<()> {
+    let index = match array_index(table.clone(), &key) {
+        Some(index) => Some(index),
+        None => append_array_slot(table.clone(), &key)?,
+    };
+    if let Some(index) = index {
+        array_part(&table).unwrap()[index] = value;
+        return Ok(());
+    }
     unsafe {
-        let mut state_ptr = state.as_pointer();
-        let state_ref = state_ptr.as_ref_mut();
-        let mut global = state_ref.get_global();
-        let global_ref = global.as_ref_mut();
-        let mut shape = global_ref.get_shape();
+        let table_ref = table.as_ref_mut();
+        if !table_ref.get_shape().as_ref().get_is_owned().0 && get_field_slot(table_ref.get_shape(), &key).is_none() {
+            table_ref.set_shape(own_shape(table_ref.get_shape())?.as_pointer());
+        }
+        let mut shape = table_ref.get_shape();
+        update_meta_functions(shape.clone(), &key, value.clone());
         let shape_ref = shape.as_ref_mut();
         let key_map = shape_ref.ref_fields_mut().get_mut();
-        let slot = key_map.len();
-        let mut slot_impl = LuaSlotMetadataImpl(Default::default());
-        slot_impl.set_slot(Usize(slot));
-        key_map.insert(key, slot_impl);
-        let fast_fields = global_ref.ref_fast_fields_mut().as_slice_mut();
+        let slot = match key_map.get(&key) {
+            Some(slot_impl) => slot_impl.get_slot().0,
+            None => {
+                let slot = key_map.len();
+                let mut slot_impl = LuaSlotMetadataImpl(Default::default());
+                slot_impl.set_slot(Usize(slot));
+                key_map.insert(key, slot_impl);
+                slot
+            }
+        };
+        let fast_fields = table_ref.ref_fast_fields_mut().as_slice_mut();
         if fast_fields.len() > slot {
             fast_fields[slot] = value;
         } else {
             let index = slot - fast_fields.len();
-            if let Some(slow_fields) = global_ref.get_slow_fields().read_some() {
+            if let Some(slow_fields) = table_ref.get_slow_fields().read_some() {
                 let mut slow_fields = Pointer::<UnsizedArray<LuaValue>>::new(slow_fields.cast());
                 if slow_fields.as_ref_mut().len() <= index {
                     let slow_fields_slice = slow_fields.as_ref_mut().as_slice();
@@ -110,26 +139,306 @@ pub fn add_global(state: LuaStateReference, key: LuaValueImpl, value: LuaValueIm
                     let mut new_slow_fields = Pointer::<UnsizedArray<LuaValue>>::new(
                         LuaValueArrayReference::get()?.alloc_unsized(len)?.cast(),
                     );
-                    let (copy_slice, fill_slice) = new_slow_fields.as_ref_mut().as_slice_mut().split_at_mut(index);
+                    new_slow_fields.as_ref_mut().set_len(len);
+                    let (copy_slice, fill_slice) =
+                        new_slow_fields.as_ref_mut().as_slice_mut().split_at_mut(slow_fields_slice.len());
                     copy_slice.clone_from_slice(slow_fields_slice);
                     fill_slice.fill(LuaValueImpl::encode_nil(()));
-                    global_ref.set_slow_fields(NullablePointerImpl::encode_some(new_slow_fields.as_non_null().cast()));
+                    table_ref.set_slow_fields(NullablePointerImpl::encode_some(new_slow_fields.as_non_null().cast()));
+                    slow_fields = new_slow_fields;
                 }
                 slow_fields.as_ref_mut().as_slice_mut()[index] = value;
             } else {
-                let len = 7;
+                let len = 7.max(index + 1);
                 let mut new_slow_fields =
                     Pointer::<UnsizedArray<LuaValue>>::new(LuaValueArrayReference::get()?.alloc_unsized(len)?.cast());
+                new_slow_fields.as_ref_mut().set_len(len);
                 let new_slow_fields_ptr = new_slow_fields.as_non_null();
                 let new_slow_fields_slice = new_slow_fields.as_ref_mut().as_slice_mut();
                 new_slow_fields_slice.fill(LuaValueImpl::encode_nil(()));
-                global_ref.set_slow_fields(NullablePointerImpl::encode_some(new_slow_fields_ptr.cast()));
+                table_ref.set_slow_fields(NullablePointerImpl::encode_some(new_slow_fields_ptr.cast()));
                 new_slow_fields_slice[index] = value;
             }
         }
     }
     Ok(())
 }
+/// Reads a field of a table without looking at its metatable, like `rawget`.
+pub fn get_field(table: Pointer<LuaTable>, key: &LuaValueImpl) -> LuaValueImpl {
+    if let Some(index) = array_index(table.clone(), key) {
+        return array_part(&table).unwrap()[index].clone();
+    }
+    unsafe {
+        get_field_slot(table.as_ref().get_shape(), key)
+            .and_then(|slot| field_value(&table, slot))
+            .map_or(LuaValueImpl::encode_nil(()), |value| value.clone())
+    }
+}
+fn get_field_slot(shape: Pointer<LuaShape>, key: &LuaValueImpl) -> Option<usize> {
+    unsafe { shape.as_ref().ref_fields().get().as_ref().unwrap().get(key).map(|slot_impl| slot_impl.get_slot().0) }
+}
+fn field_value<'t>(table: &Pointer<LuaTable>, slot: usize) -> Option<&'t mut LuaValueImpl> {
+    unsafe {
+        let table_ref = &mut *table.as_ptr_mut();
+        let fast_len = table_ref.ref_fast_fields().len();
+        if slot < fast_len {
+            return Some(&mut table_ref.ref_fast_fields_mut().as_slice_mut()[slot]);
+        }
+        let slow_fields = table_ref.get_slow_fields().read_some()?;
+        (*Pointer::<UnsizedArray<LuaValue>>::new(slow_fields.cast()).as_ptr_mut()).as_slice_mut().get_mut(slot - fast_len)
+    }
+}
+/// A copy of a shared shape for one table.
+fn own_shape(shape: Pointer<LuaShape>) -> Fallible<LuaShapeReference> {
+    unsafe {
+        let shape_ref = shape.as_ref();
+        let owned = new_shape(LuaMetaFunctionsReference(shape_ref.get_meta_functions().as_non_null()), true)?;
+        let mut owned_ptr = owned.as_pointer();
+        let owned_ref = owned_ptr.as_ref_mut();
+        owned_ref.set_fields(UnsafeCell::new(shape_ref.ref_fields().get().as_ref().unwrap().clone()));
+        owned_ref.set_as_meta_table(shape_ref.get_as_meta_table());
+        owned_ref.set_invalid(shape_ref.get_invalid());
+        Ok(owned)
+    }
+}
+/// The meta functions of `metatable`, which follow the later changes of its fields. The metatable gets its own
+/// shape to know them.
+pub fn meta_functions_of(mut metatable: Pointer<LuaTable>) -> Fallible<LuaMetaFunctionsReference> {
+    unsafe {
+        if let Some(meta_functions) = metatable.as_ref().get_shape().as_ref().get_as_meta_table().read_some() {
+            return Ok(LuaMetaFunctionsReference(meta_functions.as_non_null()));
+        }
+        if !metatable.as_ref().get_shape().as_ref().get_is_owned().0 {
+            let owned = own_shape(metatable.as_ref().get_shape())?;
+            metatable.as_ref_mut().set_shape(owned.as_pointer());
+        }
+        let meta_functions = new_meta_functions()?;
+        let mut meta_functions_ptr = meta_functions.as_pointer();
+        meta_functions_ptr.as_ref_mut().set_meta_table(metatable.clone());
+        let mut shape = metatable.as_ref().get_shape();
+        for (key, slot_impl) in shape.as_ref().ref_fields().get().as_ref().unwrap() {
+            if let Some(value) = field_value(&metatable, slot_impl.get_slot().0) {
+                set_meta_function(meta_functions_ptr.clone(), key, value.clone());
+            }
+        }
+        shape.as_ref_mut().set_as_meta_table(NullableOptionImpl::encode_some(meta_functions.as_pointer()));
+        Ok(meta_functions)
+    }
+}
+/// Keeps the meta functions in step when a field of a metatable with the shape `shape` changes.
+pub fn update_meta_functions(shape: Pointer<LuaShape>, key: &LuaValueImpl, value: LuaValueImpl) {
+    unsafe {
+        if let Some(meta_functions) = shape.as_ref().get_as_meta_table().read_some() {
+            set_meta_function(meta_functions, key, value);
+        }
+    }
+}
+fn set_meta_function(mut meta_functions: Pointer<LuaMetaFunctions>, key: &LuaValueImpl, value: LuaValueImpl) {
+    let name = match built_in::read_string(key) {
+        Some(name) => name,
+        None => return,
+    };
+    unsafe {
+        let meta_functions_ref = meta_functions.as_ref_mut();
+        match &name[..] {
+            b"__add" => meta_functions_ref.set_add(value),
+            b"__sub" => meta_functions_ref.set_sub(value),
+            b"__mul" => meta_functions_ref.set_mul(value),
+            b"__div" => meta_functions_ref.set_div(value),
+            b"__mod" => meta_functions_ref.set_mod_(value),
+            b"__pow" => meta_functions_ref.set_pow(value),
+            b"__unm" => meta_functions_ref.set_unm(value),
+            b"__idiv" => meta_functions_ref.set_idiv(value),
+            b"__band" => meta_functions_ref.set_band(value),
+            b"__bor" => meta_functions_ref.set_bor(value),
+            b"__bxor" => meta_functions_ref.set_bxor(value),
+            b"__bnot" => meta_functions_ref.set_bnot(value),
+            b"__shl" => meta_functions_ref.set_shl(value),
+            b"__shr" => meta_functions_ref.set_shr(value),
+            b"__concat" => meta_functions_ref.set_concat(value),
+            b"__len" => meta_functions_ref.set_len(value),
+            b"__eq" => meta_functions_ref.set_eq(value),
+            b"__lt" => meta_functions_ref.set_lt(value),
+            b"__le" => meta_functions_ref.set_le(value),
+            b"__index" => meta_functions_ref.set_index(value),
+            b"__newindex" => meta_functions_ref.set_newindex(value),
+            b"__call" => meta_functions_ref.set_call(value),
+            b"__metatable" => meta_functions_ref.set_metadata(value),
+            b"__gc" => meta_functions_ref.set_gc(value),
+            b"__mode" => meta_functions_ref.set_mode(value),
+            b"__name" => meta_functions_ref.set_name(value),
+            b"__tostring" => meta_functions_ref.set_tostring(value),
+            b"__pairs" => meta_functions_ref.set_pairs(value),
+            b"__close" => meta_functions_ref.set_close(value),
+            _ => {}
+        }
+    }
+}
+/// The metatable of `table`, if it has one.
+pub fn get_metatable(table: Pointer<LuaTable>) -> Option<Pointer<LuaTable>> {
+    unsafe { metatable_of_meta_functions(table.as_ref().get_shape().as_ref().get_meta_functions()) }
+}
+/// The table `meta_functions` are made from, the default meta functions have none.
+pub fn metatable_of_meta_functions(meta_functions: Pointer<LuaMetaFunctions>) -> Option<Pointer<LuaTable>> {
+    unsafe {
+        let metatable = meta_functions.as_ref().get_meta_table();
+        let own_meta_functions = metatable.as_ref().get_shape().as_ref().get_as_meta_table().read_some()?;
+        (own_meta_functions.as_ptr() == meta_functions.as_ptr()).then_some(metatable)
+    }
+}
+/// Sets the metatable of `table`. A table with a shared shape moves to the shape reached from it by the metatable,
+/// so tables built alike keep sharing shapes.
+pub fn set_metatable(mut table: Pointer<LuaTable>, metatable: Option<Pointer<LuaTable>>) -> Fallible<()> {
+    unsafe {
+        let meta_functions = match &metatable {
+            Some(metatable) => meta_functions_of(metatable.clone())?,
+            None => new_meta_functions()?,
+        };
+        let mut shape = table.as_ref().get_shape();
+        let metatable = match metatable {
+            Some(metatable) if !shape.as_ref().get_is_owned().0 => metatable,
+            _ => {
+                if !shape.as_ref().get_is_owned().0 {
+                    shape = own_shape(shape)?.as_pointer();
+                    table.as_ref_mut().set_shape(shape.clone());
+                }
+                shape.as_ref_mut().set_meta_functions(meta_functions.as_pointer());
+                return Ok(());
+            }
+        };
+        let actions = shape.as_ref().ref_action_of_metatable().get().as_mut().unwrap();
+        let next_shape = match actions.get(&LuaTableReference(metatable.as_non_null())) {
+            Some(next_shape) => next_shape.clone(),
+            None => {
+                let next_shape = new_shape(meta_functions, false)?;
+                let mut next_shape_ptr = next_shape.as_pointer();
+                let next_shape_ref = next_shape_ptr.as_ref_mut();
+                next_shape_ref.set_fields(UnsafeCell::new(shape.as_ref().ref_fields().get().as_ref().unwrap().clone()));
+                next_shape_ref.set_as_meta_table(shape.as_ref().get_as_meta_table());
+                next_shape_ref.set_invalid(shape.as_ref().get_invalid());
+                actions.insert(LuaTableReference(metatable.as_non_null()), next_shape.clone());
+                next_shape
+            }
+        };
+        table.as_ref_mut().set_shape(next_shape.as_pointer());
+        Ok(())
+    }
+}
+fn array_part<'t>(table: &Pointer<LuaTable>) -> Option<&'t mut [LuaValueImpl]> {
+    unsafe {
+        let array = table.as_ref().get_array().read_some()?;
+        Some((*Pointer::<UnsizedArray<LuaValue>>::new(array.cast()).as_ptr_mut()).as_slice_mut())
+    }
+}
+fn integer_key(key: &LuaValueImpl) -> Option<i64> { built_in::read_number(key)?.as_integer() }
+/// The index of `key` in the array part of `table`, when it is an integer from 1 to the capacity of the array part.
+pub fn array_index(table: Pointer<LuaTable>, key: &LuaValueImpl) -> Option<usize> {
+    let capacity = array_part(&table).map_or(0, |array| array.len());
+    match integer_key(key)? {
+        index if index >= 1 && index <= capacity as i64 => Some(index as usize - 1),
+        _ => None,
+    }
+}
+/// Grows the array part of `table` when `key` is the integer right after its end, and moves the integer keys it
+/// covers now out of the fields. Returns the index of `key` in the new array part.
+pub fn append_array_slot(mut table: Pointer<LuaTable>, key: &LuaValueImpl) -> Fallible<Option<usize>> {
+    let old_array = array_part(&table);
+    let capacity = old_array.as_ref().map_or(0, |array| array.len());
+    if integer_key(key) != Some(capacity as i64 + 1) {
+        return Ok(None);
+    }
+    let new_capacity = (capacity * 2).max(4);
+    unsafe {
+        let mut array = Pointer::<UnsizedArray<LuaValue>>::new(LuaValueArrayReference::get()?.alloc_unsized(new_capacity)?.cast());
+        array.as_ref_mut().set_len(new_capacity);
+        let array_slice = array.as_ref_mut().as_slice_mut();
+        let (copy_slice, fill_slice) = array_slice.split_at_mut(capacity);
+        if let Some(old_array) = old_array {
+            copy_slice.clone_from_slice(old_array);
+        }
+        fill_slice.fill(LuaValueImpl::encode_nil(()));
+        for (field_key, slot_impl) in table.as_ref().get_shape().as_ref().ref_fields().get().as_ref().unwrap() {
+            match integer_key(field_key) {
+                Some(index) if index > capacity as i64 && index <= new_capacity as i64 => {
+                    if let Some(value) = field_value(&table, slot_impl.get_slot().0) {
+                        array_slice[index as usize - 1] = std::mem::replace(value, LuaValueImpl::encode_nil(()));
+                    }
+                }
+                _ => {}
+            }
+        }
+        table.as_ref_mut().set_array(NullablePointerImpl::encode_some(array.as_non_null().cast()));
+    }
+    Ok(Some(capacity))
+}
+/// The length of `table` without its `__len` meta function, a border of its integer keys.
+pub fn table_length(table: Pointer<LuaTable>) -> Fallible<i64> {
+    let mut border = 0;
+    if let Some(array) = array_part(&table) {
+        if array.last().map_or(false, |value| value.read_nil().is_some()) {
+            // `low` is 0 or has a value, `high` has none
+            let (mut low, mut high) = (0, array.len());
+            while high - low > 1 {
+                let middle = (low + high) / 2;
+                if array[middle - 1].read_nil().is_some() {
+                    high = middle;
+                } else {
+                    low = middle;
+                }
+            }
+            return Ok(low as i64);
+        }
+        border = array.len() as i64;
+    }
+    while get_field(table.clone(), &new_integer(border + 1)?).read_nil().is_none() {
+        border += 1;
+    }
+    Ok(border)
+}
+/// The keys and values of `table` without its `__pairs` meta function, the array part first and then the fields in
+/// the order they were added.
+pub fn table_entries(table: Pointer<LuaTable>) -> Fallible<Vec<(LuaValueImpl, LuaValueImpl)>> {
+    let mut entries = Vec::new();
+    for (index, value) in array_part(&table).unwrap_or_default().iter().enumerate() {
+        if value.read_nil().is_none() {
+            entries.push((new_integer(index as i64 + 1)?, value.clone()));
+        }
+    }
+    let mut fields: Vec<_> = unsafe { table.as_ref().get_shape().as_ref().ref_fields().get().as_ref().unwrap() }
+        .iter()
+        .map(|(key, slot_impl)| (slot_impl.get_slot().0, key.clone()))
+        .collect();
+    fields.sort_by_key(|(slot, _)| *slot);
+    for (slot, key) in fields {
+        match field_value(&table, slot) {
+            Some(value) if value.read_nil().is_none() => entries.push((key, value.clone())),
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+pub fn new_integer(value: i64) -> Fallible<LuaValueImpl> {
+    if (value << 4) >> 4 == value {
+        return Ok(LuaValueImpl::encode_integer(I64(value << 4)));
+    }
+    unsafe {
+        let integer = LuaI64Reference(LuaI64Reference::get()?.alloc()?.cast());
+        integer.as_pointer().as_ref_mut().set_value(I64(value));
+        Ok(LuaValueImpl::encode_big_int(integer.as_pointer()))
+    }
+}
+pub fn new_float(value: f64) -> Fallible<LuaValueImpl> {
+    let bits = i64::from_le_bytes(value.to_le_bytes());
+    if bits & 15 == 0 {
+        return Ok(LuaValueImpl::encode_float(I64(bits)));
+    }
+    unsafe {
+        let float = LuaF64Reference(LuaF64Reference::get()?.alloc()?.cast());
+        float.as_pointer().as_ref_mut().set_value(F64(value));
+        Ok(LuaValueImpl::encode_big_float(float.as_pointer()))
+    }
+}
+pub fn new_boolean(value: bool) -> LuaValueImpl { LuaValueImpl::encode_boolean(I64((value as i64) << 7)) }
 pub fn new_function(state: LuaStateReference, native_function: &LuaFunctionRustType) -> Fallible<LuaValueImpl> {
     unsafe {
         let function = LuaFunctionReference(LuaFunctionReference::get()?.alloc()?.cast());
@@ -141,6 +450,82 @@ pub fn new_function(state: LuaStateReference, native_function: &LuaFunctionRustT
         Ok(lua_value)
     }
 }
+/// Makes a closure calling `native_function` with its own copy of `values`, which it reads and updates with
+/// [`native_closure_values`].
+pub fn new_native_closure(
+    state: LuaStateReference,
+    native_function: &'static LuaClosureRustType,
+    values: &[LuaValueImpl],
+) -> Fallible<LuaValueImpl> {
+    unsafe {
+        let mut owned =
+            Pointer::<UnsizedArray<LuaValue>>::new(LuaValueArrayReference::get()?.alloc_unsized(values.len())?.cast());
+        owned.as_ref_mut().set_len(values.len());
+        owned.as_ref_mut().as_slice_mut().clone_from_slice(values);
+        let up_value = LuaUpValueReference(LuaUpValueReference::get()?.alloc_unsized(values.len())?.cast());
+        let mut up_value_ptr = up_value.as_pointer();
+        let up_value_ref = up_value_ptr.as_ref_mut();
+        up_value_ref.set_owned(NullablePointerImpl::encode_some(owned.as_non_null().cast()));
+        up_value_ref.ref_pointers_mut().set_len(values.len());
+        for (pointer, value) in up_value_ref.ref_pointers_mut().as_slice_mut().iter_mut().zip(owned.as_ref_mut().as_slice_mut()) {
+            *pointer = NullablePointerImpl::encode_some(NonNull::from(value).cast());
+        }
+        let closure = LuaClosureReference(LuaClosureReference::get()?.alloc_unsized(1)?.cast());
+        let mut closure_ptr = closure.as_pointer();
+        let closure_ref = closure_ptr.as_ref_mut();
+        closure_ref.set_state(state.as_pointer());
+        closure_ref.set_function(NonNull::from(native_function).cast());
+        closure_ref.set_env(LuaValueImpl::encode_nil(()));
+        closure_ref.ref_up_values_mut().set_len(1);
+        closure_ref.ref_up_values_mut().as_slice_mut()[0] = up_value.as_pointer();
+        Ok(LuaValueImpl::encode_closure(closure.as_pointer()))
+    }
+}
+/// The values a closure made by [`new_native_closure`] carries.
+#[allow(clippy::mut_from_ref)]
+pub unsafe fn native_closure_values(closure: &LuaClosureReference) -> &mut [LuaValueImpl] {
+    let up_value = closure.as_pointer().as_ref().ref_up_values().as_slice()[0].clone();
+    let owned = up_value.as_ref().get_owned().read_some().unwrap();
+    (*Pointer::<UnsizedArray<LuaValue>>::new(owned.cast()).as_ptr_mut()).as_slice_mut()
+}
+/// Makes a thread running `function` in `coroutine`, which can be set later.
+pub fn new_thread(function: LuaValueImpl, coroutine: LuaCoroutine) -> Fallible<LuaThreadReference> {
+    unsafe {
+        let thread = LuaThreadReference(LuaThreadReference::get()?.alloc()?.cast());
+        let mut thread_ptr = thread.as_pointer();
+        let thread_ref = thread_ptr.as_ref_mut();
+        thread_ref.set_function(function);
+        thread_ref.set_coroutine(UnsafeCell::new(coroutine));
+        thread_ref.set_yielder(Cell::new(None));
+        thread_ref.set_transfer(UnsafeCell::new(Vec::new()));
+        thread_ref.set_to_be_closed(UnsafeCell::new(Vec::new()));
+        Ok(thread)
+    }
+}
+/// Calls a function or a closure from native code and collects its results.
+pub fn call_function(callable: &LuaValueImpl, args: &[LuaValueImpl]) -> Fallible<Vec<LuaValueImpl>> {
+    unsafe {
+        let rets = if let Some(function) = callable.read_function() {
+            let function_ref = function.as_ref();
+            let native_function = *function_ref.get_function().cast::<LuaFunctionRustType>().as_ref();
+            let state = LuaStateReference(function_ref.get_state().as_non_null());
+            let _running = quota::enter(&state);
+            native_function(state, args)
+        } else if let Some(closure) = callable.read_closure() {
+            let closure_ref = closure.as_ref();
+            let closure_function = *closure_ref.get_function().cast::<LuaClosureRustType>().as_ref();
+            let state = LuaStateReference(closure_ref.get_state().as_non_null());
+            let _running = quota::enter(&state);
+            closure_function(state, LuaClosureReference(closure.as_non_null()), args)
+        } else {
+            return Err(format_err!("attempt to call a {} value", built_in::type_name(callable)));
+        };
+        if error::is_error_return(&rets) {
+            return Err(error::take_error().into());
+        }
+        Ok(rets.as_ref().as_slice().to_vec())
+    }
+}
 pub fn new_shape(metas: LuaMetaFunctionsReference, is_owned: bool) -> Fallible<LuaShapeReference> {
     unsafe {
         let shape = LuaShapeReference(LuaShapeReference::get()?.alloc()?.cast());
@@ -149,7 +534,6 @@ pub fn new_shape(metas: LuaMetaFunctionsReference, is_owned: bool) -> Fallible<L
         shape_ref.set_fields(UnsafeCell::new(HashMap::new()));
         shape_ref.set_meta_functions(metas.as_pointer());
         shape_ref.set_as_meta_table(NullableOptionImpl::encode_none(()));
-        shape_ref.set_max_int_index(U64(0));
         shape_ref.set_is_owned(Bool(is_owned));
         shape_ref.set_action_of_field(UnsafeCell::new(HashMap::new()));
         shape_ref.set_action_of_metatable(UnsafeCell::new(HashMap::new()));
@@ -159,6 +543,27 @@ pub fn new_shape(metas: LuaMetaFunctionsReference, is_owned: bool) -> Fallible<L
         Ok(shape)
     }
 }
+/// The shape of the tables made by a constructor with the string `keys`, which take the slots in order.
+pub fn constructor_shape(state: &LuaStateReference, keys: Vec<String>) -> Fallible<LuaShapeReference> {
+    unsafe {
+        let mut state_ptr = state.as_pointer();
+        if let Some(shape) = state_ptr.as_ref().ref_constructor_shapes().get(&keys) {
+            return Ok(shape.clone());
+        }
+        let shape = new_shape(new_meta_functions()?, false)?;
+        let hash_map = shape.as_pointer().as_ref().ref_fields().get().as_mut().unwrap();
+        for key in keys.iter() {
+            let key = new_string(state.as_pointer(), key.as_bytes())?;
+            let len = hash_map.len();
+            let mut slot_metadata = MaybeUninit::<LuaSlotMetadataImpl>::zeroed();
+            let slot_metadata_ref = slot_metadata.assume_init_mut();
+            slot_metadata_ref.set_slot(Usize(len));
+            hash_map.insert(key, slot_metadata.assume_init_read());
+        }
+        state_ptr.as_ref_mut().ref_constructor_shapes_mut().insert(keys, shape.clone());
+        Ok(shape)
+    }
+}
 pub fn new_table(metas: LuaMetaFunctionsReference, cap: usize, use_owned_shape: bool) -> Fallible<LuaTableReference> {
     unsafe {
         let table = LuaTableReference(LuaTableReference::get()?.alloc_unsized(cap)?.cast());
@@ -166,6 +571,7 @@ pub fn new_table(metas: LuaMetaFunctionsReference, cap: usize, use_owned_shape:
         let table_ref = table_ptr.as_ref_mut();
         table_ref.set_shape(new_shape(metas, use_owned_shape)?.as_pointer());
         table_ref.set_slow_fields(NullablePointerImpl::encode_none(()));
+        table_ref.set_array(NullablePointerImpl::encode_none(()));
         table_ref.ref_fast_fields_mut().0 = cap;
         for i in 0..cap {
             table_ref.ref_fast_fields_mut().as_slice_mut()[i] = LuaValueImpl::encode_nil(());
@@ -210,6 +616,7 @@ pub fn new_meta_functions() -> Fallible<LuaMetaFunctionsReference> {
         meta_functions_ref.set_name(LuaValueImpl::encode_nil(()));
         meta_functions_ref.set_tostring(LuaValueImpl::encode_nil(()));
         meta_functions_ref.set_pairs(LuaValueImpl::encode_nil(()));
+        meta_functions_ref.set_close(LuaValueImpl::encode_nil(()));
         Ok(meta_functions)
     }
 }
@@ -225,12 +632,112 @@ pub fn new_state(runtime: LuaRuntime) -> Fallible<LuaStateReference> {
         state_ref.set_string_meta_functions(string_meta_functions.as_pointer());
         state_ref.set_gc_mark(Bool(false));
         state_ref.set_table_shape(new_shape(new_meta_functions()?, false)?.as_pointer());
+        state_ref.set_constructor_shapes(HashMap::new());
         let global_table = new_table(new_meta_functions()?, 64, true)?.as_pointer();
         state_ref.set_global(global_table);
+        state_ref.set_chunk_env(LuaValueImpl::encode_nil(()));
+        state_ref.set_chunk_env_given(Bool(false));
+        state_ref.set_loaded(new_table(new_meta_functions()?, 16, true)?.as_pointer());
+        state_ref.set_searchers(vec![Box::new(built_in::package::PathSearcher) as Box<dyn built_in::package::ModuleSearcher>]);
+        state_ref.set_chunks(Vec::new());
+        state_ref.set_host(Box::new(host::SandboxHost));
+        state_ref.set_files(Default::default());
+        state_ref.set_user_data_meta_functions(HashMap::new());
+        state_ref.set_user_data(Vec::new());
+        state_ref.set_fuel(I64(0));
+        state_ref.set_quotas(Default::default());
+        state_ref.set_main_thread(new_thread(LuaValueImpl::encode_nil(()), None)?.as_pointer());
+        new_string(state.as_pointer(), quota::MEMORY_ERROR.as_bytes())?;
         built_in::register_built_in_functions(state.clone())?;
         Ok(state)
     }
 }
+/// Finalizes the userdata of a state, the state should not run code after. The objects are not collected while the
+/// state runs, so this is when the Rust values of the userdata are dropped, see [`OwnedLuaState`] to do it on drop.
+pub fn close_state(state: LuaStateReference) {
+    user_data::finalize_all(&state)
+}
+/// A state closed by [`close_state`] when dropped, for the hosts which own a state. The references to the state
+/// cloned from it should not be used after.
+pub struct OwnedLuaState(LuaStateReference);
+impl OwnedLuaState {
+    pub fn new(runtime: LuaRuntime) -> Fallible<Self> {
+        Ok(Self(new_state(runtime)?))
+    }
+}
+impl std::ops::Deref for OwnedLuaState {
+    type Target = LuaStateReference;
+
+    fn deref(&self) -> &LuaStateReference {
+        &self.0
+    }
+}
+impl Drop for OwnedLuaState {
+    fn drop(&mut self) {
+        close_state(self.0.clone())
+    }
+}
+/// Makes an environment for untrusted chunks, given to `load` or [`LuaStateReference::load`], holding only the
+/// `allowed_globals` of the state. A name like `string.format` allows one field of a library. The libraries are
+/// copied so the chunks cannot change the ones of the state, and `_G` is the sandbox itself. `load`, `loadstring` and
+/// `dofile` enter the chunks they load with the sandbox, `require`, `package` and `exec_lua` cannot be allowed since
+/// they reach the global table. Strings still index the `string` library of the state, whose metatable
+/// `getmetatable` does not return in the sandbox.
+pub fn new_sandbox(state: LuaStateReference, allowed_globals: &[&str]) -> Fallible<Pointer<LuaTable>> {
+    let global = unsafe { state.as_pointer().as_ref().get_global() };
+    let sandbox = new_table(new_meta_functions()?, allowed_globals.len(), true)?.as_pointer();
+    for name in allowed_globals {
+        let (name, field) = match name.split_once('.') {
+            Some((name, field)) => (name, Some(field)),
+            None => (*name, None),
+        };
+        let key = new_string(state.as_pointer(), name.as_bytes())?;
+        if matches!(name, "require" | "package" | "exec_lua") {
+            return Err(format_err!("'{}' cannot be allowed in a sandbox", name));
+        }
+        if name == "_G" && field.is_none() {
+            set_field(sandbox.clone(), key, LuaValueImpl::encode_table(sandbox.clone()))?;
+            continue;
+        }
+        let value = get_field(global.clone(), &key);
+        if value.read_nil().is_some() {
+            return Err(format_err!("no global '{}' to allow", name));
+        }
+        let field = match field {
+            Some(field) => field,
+            None => {
+                let value = match value.read_table() {
+                    Some(library) => LuaValueImpl::encode_table(copy_table(library)?),
+                    None => built_in::sandbox_global(state.clone(), name, value, &sandbox)?,
+                };
+                set_field(sandbox.clone(), key, value)?;
+                continue;
+            }
+        };
+        let library = value.read_table().ok_or_else(|| format_err!("global '{}' is not a table", name))?;
+        let field_key = new_string(state.as_pointer(), field.as_bytes())?;
+        let field_value = get_field(library, &field_key);
+        if field_value.read_nil().is_some() {
+            return Err(format_err!("no field '{}' in '{}' to allow", field, name));
+        }
+        let mut copied = get_field(sandbox.clone(), &key);
+        if copied.read_nil().is_some() {
+            copied = LuaValueImpl::encode_table(new_table(new_meta_functions()?, 4, true)?.as_pointer());
+            set_field(sandbox.clone(), key, copied.clone())?;
+        }
+        set_field(copied.read_table().unwrap(), field_key, field_value)?;
+    }
+    Ok(sandbox)
+}
+/// A table with the fields of `table`, without its metatable.
+fn copy_table(table: Pointer<LuaTable>) -> Fallible<Pointer<LuaTable>> {
+    let entries = table_entries(table)?;
+    let copy = new_table(new_meta_functions()?, entries.len(), true)?.as_pointer();
+    for (key, value) in entries {
+        set_field(copy.clone(), key, value)?;
+    }
+    Ok(copy)
+}
 #[cfg(feature = "runtime")]
 mod runtime_feature {
     use crate::LuaInstructionSet;
@@ -262,15 +769,57 @@ mod runtime_feature {
 pub use runtime_feature::*;
 pub fn pack_code(lua_state: LuaStateReference, code: &str) -> Fallible<Vec<FunctionPack<LuaInstructionSet>>> {
     debug!(target:"vm_lua::pack_code","code: {:?}", code);
-    let lexical = LuaLexical::parse(code)?;
+    let lexical = LuaLexical::parse_spanned(code)?;
     debug!(target:"vm_lua::pack_code","lexical: {:?}", lexical);
-    let pack = crate::syntax::parse(lua_state, lexical)?;
+    let pack = crate::syntax::parse(lua_state, lexical.into_iter().map(Ok))?;
     debug!(target:"vm_lua::pack_code","function pack: {:?}", pack);
     Ok(pack)
 }
+/// Same as `pack_code`, tokenizes the code while reading it.
+pub fn pack_reader(lua_state: LuaStateReference, reader: impl Read) -> Fallible<Vec<FunctionPack<LuaInstructionSet>>> {
+    let pack = crate::syntax::parse(lua_state, LuaLexical::stream(reader))?;
+    debug!(target:"vm_lua::pack_code","function pack: {:?}", pack);
+    Ok(pack)
+}
+/// Compiles a chunk into the binary format of [`dump::dump`], which [`load_binary`] loads without parsing it again.
+pub fn dump_code(lua_state: LuaStateReference, code: &str) -> Fallible<Vec<u8>> {
+    let pack = pack_code(lua_state.clone(), code)?;
+    dump::dump(&lua_state, &pack)
+}
 pub fn load_code(lua_state: LuaStateReference, code: &str) -> Fallible<ObjectRef> {
-    let mut pack = pack_code(lua_state.clone(), code)?;
-    let root_function = pack.pop().unwrap();
+    let pack = pack_code(lua_state.clone(), code)?;
+    load_packs(lua_state, pack)
+}
+/// Same as `load_code`, for a chunk precompiled by [`dump_code`]. The code of the chunk is run as it is read, a chunk
+/// must come from a trusted source, see [`dump::undump`].
+pub fn load_binary(lua_state: LuaStateReference, binary: &[u8]) -> Fallible<ObjectRef> {
+    let pack = dump::undump(&lua_state, binary)?;
+    load_packs(lua_state, pack)
+}
+/// Loads the packs of a chunk as a function value, the code of the chunk is kept as long as the state.
+pub fn new_chunk_function(lua_state: LuaStateReference, pack: Vec<FunctionPack<LuaInstructionSet>>) -> Fallible<LuaValueImpl> {
+    let object = load_packs(lua_state.clone(), pack)?;
+    keep_chunk(lua_state, object)
+}
+/// The function value entering a loaded chunk, the code of the chunk is kept as long as the state. A chunk entered
+/// again reuses what was kept the first time.
+pub fn chunk_function(lua_state: LuaStateReference, object: ObjectRef) -> Fallible<LuaValueImpl> {
+    let state = lua_state.as_pointer();
+    let chunks = unsafe { state.as_ref().ref_chunks() };
+    match chunks.iter().rev().find(|(chunk, _)| *chunk == object) {
+        Some((_, function)) => new_function(lua_state, function),
+        None => keep_chunk(lua_state, object),
+    }
+}
+/// Keeps the code of a chunk loaded for the first time as long as the state, with the function entering it.
+fn keep_chunk(lua_state: LuaStateReference, object: ObjectRef) -> Fallible<LuaValueImpl> {
+    let function: Box<LuaFunctionRustType> = Box::new(unsafe { std::mem::transmute(object.lock().unwrap().get_export_ptr(0)) });
+    let value = new_function(lua_state.clone(), &function)?;
+    unsafe { lua_state.as_pointer().as_ref_mut().ref_chunks_mut().push((object, function)) };
+    Ok(value)
+}
+fn load_packs(lua_state: LuaStateReference, mut pack: Vec<FunctionPack<LuaInstructionSet>>) -> Fallible<ObjectRef> {
+    let root_function = pack.pop().ok_or_else(|| format_err!("chunk without function"))?;
     let lua_state_pointer = lua_state.as_pointer();
     let runtime = unsafe { lua_state_pointer.as_ref().ref_runtime() };
     let resource = runtime.create_dyn(root_function)?;
@@ -280,12 +829,21 @@ pub fn load_code(lua_state: LuaStateReference, code: &str) -> Fallible<ObjectRef
     let object = resource.get_object()?;
     Ok(object)
 }
+/// Runs a chunk, a Lua error raised by it is returned as a [`error::LuaError`] carrying the error value once the
+/// `<close>` variables the chunk left in scope are closed.
 pub fn run_code(lua_state: LuaStateReference, code: &str) -> Fallible<()> {
     let resource = load_code(lua_state.clone(), code)?;
     unsafe {
         let function: LuaFunctionRustType = std::mem::transmute(resource.lock().unwrap().get_export_ptr(0));
         let args = &[];
-        function(lua_state, args);
+        let _running = quota::enter(&lua_state);
+        // the chunk may run inside a Lua call, whose `<close>` variables stay in scope
+        let to_be_closed = close::to_be_closed_len(&lua_state);
+        let rets = function(lua_state.clone(), args);
+        if error::is_error_return(&rets) {
+            let error = error::take_error().into_value(lua_state.clone())?;
+            return Err(error::LuaError::Value(close::close_on_error(&lua_state, to_be_closed, error)).into());
+        }
     }
     Ok(())
 }
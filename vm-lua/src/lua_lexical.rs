@@ -60,6 +60,7 @@ fn parse_string(iter: &mut Chars) -> Option<String> {
                 if prefix.match_end(iter, c) {
                     return String::from_utf8(lit).ok();
                 }
+                lit.push(c as u8);
             }
             '\n' => {
                 if prefix == LitStringPrefix::Apostrophe || prefix == LitStringPrefix::DoubleQuotes {
@@ -109,15 +110,19 @@ fn parse_string(iter: &mut Chars) -> Option<String> {
                         }
                         lit.extend_from_slice(&buffer);
                     }
-                    '0'..='9' => {
-                        let c2 = iter.next()?;
-                        let c3 = iter.next()?;
-                        if !matches!(c2, '0'..='9') || !matches!(c3, '0'..='9') {
-                            return None;
+                    d @ '0'..='9' => {
+                        // up to three decimal digits
+                        let mut value = d as u32 - '0' as u32;
+                        for _ in 0..2 {
+                            match iter.clone().next() {
+                                Some(d @ '0'..='9') => {
+                                    iter.next();
+                                    value = value * 10 + (d as u32 - '0' as u32);
+                                }
+                                _ => break,
+                            }
                         }
-                        lit.extend_from_slice(&[((c as u8 - b'0') << 6)
-                            | ((c2 as u8 - b'0') << 3)
-                            | (c3 as u8 - b'0')]);
+                        lit.push(u8::try_from(value).ok()?);
                     }
                     _ => return None,
                 };
@@ -135,8 +140,35 @@ fn parse_string(iter: &mut Chars) -> Option<String> {
     }
     None
 }
-fn parse_single_line_annotation(_iter: &mut Chars) -> Option<String> {
-    todo!();
+/// `--[==[ .. ]==]` or `--` up to the end of the line.
+fn parse_annotation(iter: &mut Chars) -> Option<()> {
+    if !iter.as_str().starts_with("--") {
+        return None;
+    }
+    iter.nth(1);
+    let mut long_bracket = iter.clone();
+    if long_bracket.next() == Some('[') {
+        let mut level = 0;
+        while long_bracket.as_str().starts_with('=') {
+            long_bracket.next();
+            level += 1;
+        }
+        if long_bracket.next() == Some('[') {
+            let prefix = LitStringPrefix::LongBracket(level);
+            while let Some(c) = long_bracket.next() {
+                if prefix.match_end(&mut long_bracket, c) {
+                    *iter = long_bracket;
+                    return Some(());
+                }
+            }
+            *iter = long_bracket;
+            return None;
+        }
+    }
+    while iter.as_str().chars().next().map_or(false, |c| c != '\n') {
+        iter.next();
+    }
+    Some(())
 }
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum LuaNumberLit {
@@ -283,12 +315,10 @@ pub enum LuaLexical {
     Number(LuaNumberLit),
     #[lexical(regex = r"[a-zA-Z_][a-zA-Z_0-9]*")]
     Name(String),
-    #[lexical(fn = "parse_string")]
+    #[lexical(first, fn = "parse_string")]
     String(String),
-    #[lexical(ignore, regex = r#"--[^\n]*\n"#)]
-    SingleLineAnnotation,
-    #[lexical(ignore, regex = r#"--\[\[[*--]]\n"#)]
-    MultiLineAnnotation,
+    #[lexical(ignore, first, fn = "parse_annotation")]
+    Annotation,
     #[lexical(string = "~=")]
     NotEqual,
 }
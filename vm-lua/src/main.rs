@@ -9,6 +9,7 @@ fn main() -> Fallible<()> {
     set_signal_handler();
     let _ = &*LUA_INTERPRETER;
     let vm = vm_lua::new_state(LUA_INTERPRETER.clone())?;
+    vm_lua::host::set_host(&vm, vm_lua::host::NativeHost);
     println!("[ zitao lua 虚拟机 v{} ]", &env!("CARGO_PKG_VERSION"));
     loop {
         print!("");
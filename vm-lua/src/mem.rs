@@ -1,11 +1,12 @@
-use crate::{ir::LuaInstructionSet, TypeResourceImpl};
+use crate::{built_in::{io::Files, package::ModuleSearcher}, host::Host, ir::LuaInstructionSet, quota::Quotas, TypeResourceImpl};
 
 use lexical::_lazy_static::lazy_static;
 use runtime::code::FunctionPack;
-use vm_core::{make_reference, Aligned, Direct, DynRuntimeTrait, FunctionType, MoveIntoObject, Native, ObjectBuilder, ObjectBuilderImport, ObjectBuilderInner, Pointer, Reference, Resource, SymbolRef, Type, TypeDeclaration, TypeLayout, UnsizedArray};
+use vm_core::{make_reference, Aligned, CoroutineTrait, CoroutineYielder, Direct, DynRuntimeTrait, FunctionType, MoveIntoObject, Native, ObjectBuilder, ObjectBuilderImport, ObjectBuilderInner, ObjectRef, Pointer, Reference, Resource, SymbolRef, Type, TypeDeclaration, TypeLayout, UnsizedArray};
 
 use runtime_extra::ty::*;
-use std::cell::UnsafeCell;
+use std::any::{Any, TypeId};
+use std::cell::{Cell, RefCell, UnsafeCell};
 use std::collections::HashSet;
 use std::hash::Hasher;
 
@@ -21,7 +22,31 @@ pub struct LuaState {
     pub runtime: Native<Arc<dyn DynRuntimeTrait<FunctionPack<LuaInstructionSet>>>>,
     pub string_meta_functions: LuaMetaFunctionsReference,
     pub table_shape: LuaShapeReference,
+    /// The shapes of the tables made by constructors, by their string keys in slot order.
+    pub constructor_shapes: Native<HashMap<Vec<String>, LuaShapeReference>>,
     pub global: LuaTableReference,
+    /// The `_ENV` of the next chunk entered, taken by the chunk when `chunk_env_given` is set, the chunks entered
+    /// without it use `global`. A given `nil` stays `nil`.
+    pub chunk_env: LuaValue,
+    pub chunk_env_given: Bool,
+    /// The modules loaded by `require` by their names, which is `package.loaded`.
+    pub loaded: LuaTableReference,
+    /// Where `require` looks for the modules missing from `loaded`, in order.
+    pub searchers: Native<Vec<Box<dyn ModuleSearcher>>>,
+    /// The code of the chunks made by `load` with the functions entering them, kept as long as the state.
+    pub chunks: Native<Vec<(ObjectRef, Box<LuaFunctionRustType>)>>,
+    /// What the state can reach outside itself, see [`crate::host`].
+    pub host: Native<Box<dyn Host>>,
+    /// The files opened by the `io` library.
+    pub files: Native<Files>,
+    /// The meta functions of the userdata of each Rust type.
+    pub user_data_meta_functions: Native<HashMap<TypeId, LuaMetaFunctionsReference>>,
+    /// The userdata made by the state, finalized by [`crate::close_state`] or when an [`crate::OwnedLuaState`] drops.
+    pub user_data: Native<Vec<LuaUserDataReference>>,
+    /// Counted down at the loop back-edges and the calls of the compiled code, see [`crate::quota`].
+    pub fuel: I64,
+    pub quotas: Native<Quotas>,
+    pub main_thread: LuaThreadReference,
     pub gc_mark: Bool,
 }
 make_reference!(LuaStateReference, LuaState, TypeResourceImpl);
@@ -61,6 +86,7 @@ pub struct LuaMetaFunctions {
     pub name: LuaValue,
     pub tostring: LuaValue,
     pub pairs: LuaValue,
+    pub close: LuaValue,
 }
 make_reference!(LuaMetaFunctionsReference, LuaMetaFunctions, TypeResourceImpl);
 #[derive(TypeDeclaration)]
@@ -112,6 +138,8 @@ pub struct LuaClosure {
     pub align: Aligned<16>,
     pub function: Pointer<LuaClosureFunctionType>,
     pub state: LuaStateReference,
+    /// The `_ENV` of the chunk where the closure was made.
+    pub env: LuaValue,
     #[make_type(unsized)]
     pub up_values: UnsizedArray<LuaUpValueReference>,
 }
@@ -163,7 +191,6 @@ pub struct LuaShape {
     pub fields: Native<UnsafeCell<HashMap<LuaValueImpl, LuaSlotMetadataImpl>>>,
     pub meta_functions: LuaMetaFunctionsReference,
     pub as_meta_table: NullableOption<LuaMetaFunctionsReference>,
-    pub max_int_index: U64,
     pub is_owned: Bool,
     pub action_of_field: Native<UnsafeCell<HashMap<LuaValueImpl, (LuaShapeReference, usize)>>>,
     pub action_of_metatable: Native<UnsafeCell<HashMap<LuaTableReference, LuaShapeReference>>>,
@@ -177,10 +204,46 @@ pub struct LuaTable {
     pub align: Aligned<16>,
     pub shape: LuaShapeReference,
     pub slow_fields: NullablePointer<UnsizedArray<LuaValue>>,
+    /// The values of the integer keys from 1 to its length.
+    pub array: NullablePointer<UnsizedArray<LuaValue>>,
     #[make_type(unsized)]
     pub fast_fields: UnsizedArray<LuaValue>,
 }
 make_reference!(LuaTableReference, LuaTable, TypeResourceImpl);
+impl Hash for LuaTableReference {
+    fn hash<H: Hasher>(&self, state: &mut H) { self.0.hash(state); }
+}
+impl PartialEq for LuaTableReference {
+    fn eq(&self, other: &Self) -> bool { self.0 == other.0 }
+}
+impl Eq for LuaTableReference {}
+/// The coroutine of a thread, the main thread of a state has none.
+pub type LuaCoroutine = Option<Box<dyn CoroutineTrait>>;
+pub type LuaYielder = Option<NonNull<dyn CoroutineYielder>>;
+#[derive(TypeDeclaration)]
+#[make_type(make_instruction)]
+pub struct LuaThread {
+    pub align: Aligned<16>,
+    pub function: LuaValue,
+    pub coroutine: Native<UnsafeCell<LuaCoroutine>>,
+    /// Suspends the coroutine, set once it starts.
+    pub yielder: Native<Cell<LuaYielder>>,
+    /// The values passed by the last resume, yield or return.
+    pub transfer: Native<UnsafeCell<Vec<LuaValueImpl>>>,
+    /// The values of the `<close>` variables in scope, the last one is closed first.
+    pub to_be_closed: Native<UnsafeCell<Vec<LuaValueImpl>>>,
+}
+make_reference!(LuaThreadReference, LuaThread, TypeResourceImpl);
+/// A Rust value owned by Lua, see [`crate::user_data`].
+#[derive(TypeDeclaration)]
+#[make_type(make_instruction)]
+pub struct LuaUserData {
+    pub align: Aligned<16>,
+    pub meta_functions: LuaMetaFunctionsReference,
+    /// Taken when the userdata is finalized.
+    pub value: Native<RefCell<Option<Box<dyn Any>>>>,
+}
+make_reference!(LuaUserDataReference, LuaUserData, TypeResourceImpl);
 make_reference!(LuaValueArrayReference, UnsizedArray<LuaValue>, TypeResourceImpl);
 #[derive(TypeDeclaration)]
 #[make_type(make_instruction)]
@@ -188,14 +251,14 @@ pub struct LuaI64 {
     pub value: I64,
     pub align: Aligned<16>,
 }
-pub type LuaI64Reference = Reference<LuaI64, TypeResourceImpl>;
+make_reference!(LuaI64Reference, LuaI64, TypeResourceImpl);
 #[derive(TypeDeclaration)]
 #[make_type(make_instruction)]
 pub struct LuaF64 {
     pub value: F64,
     pub align: Aligned<16>,
 }
-pub type LuaF64Reference = Reference<LuaF64, TypeResourceImpl>;
+make_reference!(LuaF64Reference, LuaF64, TypeResourceImpl);
 pub type I64Reference = Reference<I64, TypeResourceImpl>;
 pub type F64Reference = Reference<F64, TypeResourceImpl>;
 type LuaBool = I64;
@@ -212,6 +275,10 @@ pub enum LuaValue {
     Table(LuaTableReference),
     Function(LuaFunctionReference),
     Closure(LuaClosureReference),
+    Thread(LuaThreadReference),
+    UserData(LuaUserDataReference),
+    /// A pointer shifted by 4 bits.
+    LightUserData(I64),
 }
 impl<'l> MoveIntoObject<'l> for LuaValueImpl {
     type Carrier = Self;
@@ -0,0 +1,142 @@
+//! Limits of the work and the memory of the code a state runs, for untrusted scripts. The compiled code counts down
+//! the fuel of the state at the loop back-edges and the calls, and checks the quotas when it reaches zero, raising a
+//! Lua error once one of them ran out. The quotas stay exhausted, the code catching the error cannot run further
+//! until they are raised.
+//!
+//! The bytes are counted by `memory_mmmu` for the thread, the memory quota of a state is installed there while the
+//! state runs Lua code.
+use std::{cell::Cell, ptr::NonNull};
+
+use failure::Fallible;
+use memory_mmmu::quota::{allocation_quota, set_allocation_quota};
+use runtime_extra::I64;
+
+use crate::mem::{LuaStateImpl, LuaStateReference};
+
+/// The message of the memory error, made with the state so raising the error does not allocate.
+pub(crate) const MEMORY_ERROR: &str = "not enough memory";
+/// How much fuel the compiled code burns between two checks of the quotas, the memory is checked as often.
+const FUEL_SLICE: u64 = 1 << 10;
+/// The quotas of a state.
+#[derive(Default)]
+pub struct Quotas {
+    /// The fuel left once the one of the state is burnt.
+    fuel: Option<u64>,
+    /// The bytes the state may still allocate, while it does not run.
+    memory: Option<i64>,
+}
+thread_local! {
+    /// The state running Lua code on the thread, whose memory quota is installed.
+    static RUNNING: Cell<Option<NonNull<LuaStateImpl>>> = Cell::new(None);
+}
+fn quotas<'a>(state: NonNull<LuaStateImpl>) -> &'a mut Quotas {
+    unsafe { (*state.as_ptr()).ref_quotas_mut() }
+}
+fn is_running(state: &LuaStateReference) -> bool {
+    RUNNING.with(|running| running.get() == Some(state.as_pointer().as_non_null()))
+}
+/// Limits the number of loop iterations and calls the state runs, `None` removes the limit.
+pub fn set_fuel(state: &LuaStateReference, fuel: Option<u64>) {
+    quotas(state.as_pointer().as_non_null()).fuel = fuel;
+    unsafe { (*state.as_pointer().as_ptr_mut()).set_fuel(I64(0)) };
+}
+/// The loop iterations and calls the state may still run, `None` without a limit.
+pub fn remaining_fuel(state: &LuaStateReference) -> Option<u64> {
+    let burning = unsafe { state.as_pointer().as_ref().get_fuel() }.0.max(0) as u64;
+    quotas(state.as_pointer().as_non_null()).fuel.map(|fuel| fuel + burning)
+}
+/// Limits the bytes the state allocates from now on, `None` removes the limit.
+pub fn set_memory_quota(state: &LuaStateReference, bytes: Option<usize>) {
+    let bytes = bytes.map(|bytes| i64::try_from(bytes).unwrap_or(i64::MAX));
+    if is_running(state) {
+        set_allocation_quota(bytes);
+    } else {
+        quotas(state.as_pointer().as_non_null()).memory = bytes;
+    }
+    unsafe { (*state.as_pointer().as_ptr_mut()).set_fuel(I64(0)) };
+}
+/// The bytes the state may still allocate, `None` without a limit.
+pub fn remaining_memory(state: &LuaStateReference) -> Option<usize> {
+    let memory = match is_running(state) {
+        true => allocation_quota(),
+        false => quotas(state.as_pointer().as_non_null()).memory,
+    };
+    memory.map(|memory| memory.max(0) as usize)
+}
+/// Refuses to allocate more bytes than the quota leaves, before a native function makes a large object.
+pub(crate) fn check_allocation(bytes: usize) -> Fallible<()> {
+    match allocation_quota() {
+        Some(remaining) if i64::try_from(bytes).map_or(true, |bytes| bytes > remaining) => Err(format_err!("{}", MEMORY_ERROR)),
+        _ => Ok(()),
+    }
+}
+/// Makes room for `additional` bytes in a buffer a native function builds, the buffer grows like a vector does but
+/// the quota is checked before it is allocated.
+pub(crate) fn reserve(buffer: &mut Vec<u8>, additional: usize) -> Fallible<()> {
+    let len = buffer.len().saturating_add(additional);
+    if len > buffer.capacity() {
+        // the doubled capacity may not fit the quota while the length still does
+        let capacity = Some(len.max(buffer.capacity().saturating_mul(2)))
+            .filter(|capacity| check_allocation(*capacity).is_ok())
+            .map_or_else(|| check_allocation(len).map(|_| len), Ok)?;
+        buffer.reserve_exact(capacity - buffer.len());
+    }
+    Ok(())
+}
+/// Appends `bytes` to a buffer a native function builds, see [`reserve`].
+pub(crate) fn extend(buffer: &mut Vec<u8>, bytes: &[u8]) -> Fallible<()> {
+    reserve(buffer, bytes.len())?;
+    buffer.extend_from_slice(bytes);
+    Ok(())
+}
+/// Called by the compiled code once the fuel of the state is burnt, it gets another slice when the quotas allow it.
+pub(crate) fn check_quotas(state: &LuaStateReference) -> Fallible<()> {
+    let quotas = quotas(state.as_pointer().as_non_null());
+    let mut state_pointer = state.as_pointer();
+    let memory = if is_running(state) { allocation_quota() } else { quotas.memory };
+    if memory.map_or(false, |memory| memory < 0) {
+        unsafe { state_pointer.as_ref_mut().set_fuel(I64(0)) };
+        return Err(format_err!("{}", MEMORY_ERROR));
+    }
+    let slice = match (quotas.fuel, memory) {
+        (Some(0), _) => {
+            unsafe { state_pointer.as_ref_mut().set_fuel(I64(0)) };
+            return Err(format_err!("instruction quota exceeded"));
+        }
+        (Some(fuel), _) => {
+            let slice = fuel.min(FUEL_SLICE);
+            quotas.fuel = Some(fuel - slice);
+            slice
+        }
+        (None, Some(_)) => FUEL_SLICE,
+        (None, None) => i64::MAX as u64,
+    };
+    // the check burns the first of the slice
+    unsafe { state_pointer.as_ref_mut().set_fuel(I64(slice as i64 - 1)) };
+    Ok(())
+}
+/// Installs the memory quota of a state while it runs Lua code, the one of the state running before is installed
+/// again when it is dropped.
+pub(crate) struct Running {
+    state: NonNull<LuaStateImpl>,
+    previous: Option<NonNull<LuaStateImpl>>,
+}
+pub(crate) fn enter(state: &LuaStateReference) -> Running {
+    let state = state.as_pointer().as_non_null();
+    let previous = RUNNING.with(|running| running.replace(Some(state)));
+    if previous != Some(state) {
+        if let Some(previous) = previous {
+            quotas(previous).memory = allocation_quota();
+        }
+        set_allocation_quota(quotas(state).memory);
+    }
+    Running { state, previous }
+}
+impl Drop for Running {
+    fn drop(&mut self) {
+        if self.previous != Some(self.state) {
+            quotas(self.state).memory = set_allocation_quota(self.previous.and_then(|previous| quotas(previous).memory));
+        }
+        RUNNING.with(|running| running.set(self.previous));
+    }
+}
@@ -4,14 +4,15 @@ use super::lua_lexical::LuaLexical;
 use crate::mem::{LuaStateReference};
 use failure::Fallible;
 use ghost_cell::GhostToken;
+use lexical::Spanned;
 
 use runtime::code::FunctionPack;
 
 use syntax_derive::{lalr1_analyser};
-pub fn parse(lua_state: LuaStateReference, source: Vec<LuaLexical>) -> Fallible<Vec<FunctionPack<LuaInstructionSet>>> {
+pub fn parse(lua_state: LuaStateReference, source: impl IntoIterator<Item = Fallible<Spanned<LuaLexical>>>) -> Fallible<Vec<FunctionPack<LuaInstructionSet>>> {
     use super::{builder::*, ir::*};
     GhostToken::new(|token| {
-        let mut ctx = new_ctx(token, lua_state);
+        let mut ctx = new_ctx(token, lua_state)?;
         macro_rules! const_value {
             ($Instruction:ident) => {
                 ctx.emit_const_value($Instruction::emit)
@@ -59,13 +60,15 @@ pub fn parse(lua_state: LuaStateReference, source: Vec<LuaLexical>) -> Fallible<
             };
         }
         lalr1_analyser! {
+          #[recover(t!(;),t!(end))]
+          #[stream]
           lua_parser:LuaLexical->(){
             chunk=>()->{
               [stat_list,return_expr(r)]=>ctx.emit_return(r);
                 | [stat_list]=>ctx.emit_return(None);
             },
-            block=>(LuaBlockRef<'_>,LuaBlockRef<'_>)->{ [scopt_begin(b),block_inner(i)]=>Ok(i); },
-            loop_block=>(LuaBlockRef<'_>,LuaBlockRef<'_>)->{ [loop_scopt_begin(b),block_inner(i)]=>Ok(i); },
+            block=>(LuaBlockRef<'_>,LuaBlockRef<'_>)->{ [scopt_begin(b),block_inner(i)]=>ctx.finish_scopt(i); },
+            loop_block=>(LuaBlockRef<'_>,LuaBlockRef<'_>)->{ [loop_scopt_begin(b),block_inner(i)]=>ctx.finish_scopt(i); },
             block_inner=>(LuaBlockRef<'_>,LuaBlockRef<'_>)->{
               [stat_list,return_expr(r),block_split(s)]=>ctx.return_(r,s);
                 | [stat_list,block_split(s)]=>Ok(s);
@@ -73,7 +76,8 @@ pub fn parse(lua_state: LuaStateReference, source: Vec<LuaLexical>) -> Fallible<
             for_prepare=>()->{[t!(for)]=>ctx.loop_head();},
             while_prepare=>()->{[t!(while)]=>ctx.loop_head();},
             repeat_prepare=>()->{[t!(repeat)]=>ctx.loop_head();},
-            stat_list=>()->{ []|[stat,stat_list] },
+            local_function_name=>String->{[t!(local),t!(function),Name(n)]=>ctx.local_function_name(n);},
+            stat_list=>()->{ []=>ctx.end_statements(); | [stat,stat_list]=>Ok(()); },
             block_split=>(LuaBlockRef<'_>,LuaBlockRef<'_>)->{ []=>ctx.split_block(); },
             current_block=>LuaBlockRef<'_>->{ []=>Ok(ctx.current_block.clone()); },
             scopt_begin=>LuaScoptRef<'_>->{ []=>ctx.new_scopt(ScoptKind::Other); },
@@ -89,12 +93,12 @@ pub fn parse(lua_state: LuaStateReference, source: Vec<LuaLexical>) -> Fallible<
               | [if_prefix(p),block_split(b),t!(else),block(a),t!(end)]=>ctx.else_(p,b,a);
               | [if_prefix(p),t!(end),block_split(b)]=>ctx.end_if(p,b);
               | [t!(function),Name(n),function_boby(f)]=>ctx.set_function(n,f);
-              | [t!(local),t!(function),Name(n),function_boby(f)]=>ctx.local_function(n,f);
+              | [local_function_name(n),function_boby(f)]=>ctx.local_function(n,f);
               | [t!(local),att_name_list(a)]=>ctx.local_variable(a);
               | [t!(local),att_name_list(a),t!(=),expr_list(e)]=>ctx.local_variable_with_values(a,e);
               | [t!(do),block(b),t!(end)]=>ctx.finish_block(b);
               | [while_prepare,block_split(p),expr(e),block_split(s),t!(do),loop_block(b),t!(end)]=>ctx.while_((p,e),s,b);
-              | [repeat_prepare,block_split(s),loop_block(b),t!(until),block_split(p),expr(e)]=>ctx.repeat(s,b,(p,e));
+              | [repeat_prepare,block_split(s),loop_scopt_begin,block_inner(b),t!(until),block_split(p),expr(e)]=>ctx.repeat(s,b,(p,e));
               | [for_prepare,for_head(h),block_inner(b),t!(end)]=>ctx.for_(h,b);
               | [for_prepare,for_each_head(h),block_inner(b),t!(end)]=>ctx.for_step(h,b);
               | [for_prepare,for_in_head(h),block_inner(b),t!(end)]=>ctx.for_in(h,b);
@@ -133,7 +137,7 @@ pub fn parse(lua_state: LuaStateReference, source: Vec<LuaLexical>) -> Fallible<
                 | [name_list(mut l),t!(,),Name(n)]=>Ok({l.push(n);l});
             },
             expr_list=>LuaExprList<'_>->{
-              [expr(e)]=>Ok(LuaExprList::from(e));
+              [expr(e)]=>ctx.expr_list(e);
               | [expr_list(l),t!(,),expr(e)]=>ctx.extend_expr_list(l,e);
             },
             expr=>LuaExprRef->{
@@ -243,8 +247,8 @@ pub fn parse(lua_state: LuaStateReference, source: Vec<LuaLexical>) -> Fallible<
             },
             prefix_expr=>LuaExprRef->{
               [var(v)]=>ctx.load_var(v);
-                | [function_call(c)]=>ctx.get_from_slice(0,c);
-                  | [LeftParen,expr(e),RightParen]=>Ok(e);
+                | [function_call(c)]=>ctx.call_value(c);
+                  | [LeftParen,expr(e),RightParen]=>ctx.single_value(e);
             },
             stat_prefix_expr=>LuaExprRef->{
               [stat_var(v)]=>ctx.load_var(v);
@@ -304,7 +308,7 @@ pub fn parse(lua_state: LuaStateReference, source: Vec<LuaLexical>) -> Fallible<
             }
           }
         }
-        lua_parser(source)?;
+        lua_parser(&mut ::syntax::token_stream::<LuaLexical, _, _>(source))?;
         ctx.pack()
     })
 }
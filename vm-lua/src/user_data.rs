@@ -0,0 +1,295 @@
+//! Userdata, Rust values given to Lua. A type implementing [`LuaUserData`] is converted to a full userdata, whose
+//! metatable is made once per state from the methods, fields and meta functions the type registers. A
+//! [`LightUserData`] is a bare pointer without a metatable.
+//!
+//! The objects of a state are not collected, so a userdata is only finalized by [`crate::close_state`], which an
+//! [`crate::OwnedLuaState`] calls when dropped: its `__gc` meta function runs and its value is dropped then, even when
+//! Lua code stopped using it long before. A userdata which should release its value earlier can do it in `__close`,
+//! for a `<close>` variable, or in a method.
+use std::{
+    any::{Any, TypeId},
+    cell::{Ref, RefCell, RefMut},
+    collections::HashMap,
+    ffi::c_void,
+    marker::PhantomData,
+};
+
+use failure::Fallible;
+use runtime_extra::I64;
+use vm_core::Pointer;
+
+use crate::{
+    api::{FromLua, FromLuaMulti, ToLua, ToLuaMulti},
+    built_in::{argument_error, read_string, type_name},
+    call_function, get_field,
+    mem::{LuaMetaFunctionsReference, LuaStateReference, LuaTable, LuaUserData as LuaUserDataObject, LuaUserDataReference, LuaValueImpl},
+    meta_functions_of, metatable_of_meta_functions, new_meta_functions, new_string, new_table, set_field,
+};
+
+/// A Rust type Lua code can hold as a userdata.
+pub trait LuaUserData: Any + Sized {
+    /// The `__name` of the metatable, which the errors about the userdata mention.
+    fn name() -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Registers the methods, fields and meta functions of the type, once for each state.
+    fn add_methods(_methods: &mut UserDataMethods<Self>) -> Fallible<()> {
+        Ok(())
+    }
+}
+/// The methods, fields and meta functions of the userdata of type `T`.
+pub struct UserDataMethods<T> {
+    state: LuaStateReference,
+    methods: Vec<(String, LuaValueImpl)>,
+    meta_functions: Vec<(String, LuaValueImpl)>,
+    getters: HashMap<Vec<u8>, LuaValueImpl>,
+    setters: HashMap<Vec<u8>, LuaValueImpl>,
+    user_data: PhantomData<T>,
+}
+impl<T: LuaUserData> UserDataMethods<T> {
+    /// Adds a method called with `:`, which borrows the value.
+    pub fn add_method<A, R>(&mut self, name: &str, method: impl Fn(&LuaStateReference, &T, A) -> Fallible<R> + 'static) -> Fallible<()>
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti,
+    {
+        let function =
+            self.method(name, move |state, this, args| method(state, &*this.borrow::<T>()?, A::from_lua_multi(state, args)?)?.to_lua_multi(state))?;
+        self.methods.push((name.to_string(), function));
+        Ok(())
+    }
+
+    /// Same as `add_method`, for a method changing the value.
+    pub fn add_method_mut<A, R>(&mut self, name: &str, method: impl Fn(&LuaStateReference, &mut T, A) -> Fallible<R> + 'static) -> Fallible<()>
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti,
+    {
+        let function =
+            self.method(name, move |state, this, args| method(state, &mut *this.borrow_mut::<T>()?, A::from_lua_multi(state, args)?)?.to_lua_multi(state))?;
+        self.methods.push((name.to_string(), function));
+        Ok(())
+    }
+
+    /// Adds a function to the methods, which is not given the userdata unless called with `:`.
+    pub fn add_function<A, R>(&mut self, name: &str, function: impl Fn(&LuaStateReference, A) -> Fallible<R> + 'static) -> Fallible<()>
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti,
+    {
+        let function = self.state.create_function(function)?;
+        self.methods.push((name.to_string(), function.value().clone()));
+        Ok(())
+    }
+
+    /// Adds a meta function like `__tostring` or `__gc` taking the userdata first.
+    pub fn add_meta_method<A, R>(&mut self, name: &str, method: impl Fn(&LuaStateReference, &T, A) -> Fallible<R> + 'static) -> Fallible<()>
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti,
+    {
+        let function =
+            self.method(name, move |state, this, args| method(state, &*this.borrow::<T>()?, A::from_lua_multi(state, args)?)?.to_lua_multi(state))?;
+        self.meta_functions.push((name.to_string(), function));
+        Ok(())
+    }
+
+    /// Adds a meta function like `__add`, whose userdata may be any of its arguments.
+    pub fn add_meta_function<A, R>(&mut self, name: &str, function: impl Fn(&LuaStateReference, A) -> Fallible<R> + 'static) -> Fallible<()>
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti,
+    {
+        let function = self.state.create_function(function)?;
+        self.meta_functions.push((name.to_string(), function.value().clone()));
+        Ok(())
+    }
+
+    /// Adds a field read by `getter` when it is indexed.
+    pub fn add_field_getter<R: ToLua>(&mut self, name: &str, getter: impl Fn(&LuaStateReference, &T) -> Fallible<R> + 'static) -> Fallible<()> {
+        let function = self.method(name, move |state, this, _| Ok(vec![getter(state, &*this.borrow::<T>()?)?.to_lua(state)?]))?;
+        self.getters.insert(name.as_bytes().to_vec(), function);
+        Ok(())
+    }
+
+    /// Adds a field written by `setter` when it is assigned.
+    pub fn add_field_setter<A: FromLua>(&mut self, name: &str, setter: impl Fn(&LuaStateReference, &mut T, A) -> Fallible<()> + 'static) -> Fallible<()> {
+        let function = self.method(name, move |state, this, args| {
+            let value = args.into_iter().next().unwrap_or_else(|| LuaValueImpl::encode_nil(()));
+            setter(state, &mut *this.borrow_mut::<T>()?, A::from_lua(state, value)?)?;
+            Ok(Vec::new())
+        })?;
+        self.setters.insert(name.as_bytes().to_vec(), function);
+        Ok(())
+    }
+
+    /// A function checking its first argument is a userdata of type `T`.
+    fn method(
+        &self, name: &str, method: impl Fn(&LuaStateReference, UserData, Vec<LuaValueImpl>) -> Fallible<Vec<LuaValueImpl>> + 'static,
+    ) -> Fallible<LuaValueImpl> {
+        let name = name.to_string();
+        let function = self.state.create_raw_function(move |state, args| {
+            let this = match args.first().and_then(|value| value.read_user_data()) {
+                Some(this) if UserData(this.clone()).is::<T>() => UserData(this),
+                _ => {
+                    let got = args.first().map_or("no value", type_name);
+                    return Err(argument_error(1, &name, &format!("{} expected, got {}", T::name(), got)));
+                }
+            };
+            method(state, this, args[1..].to_vec())
+        })?;
+        Ok(function.value().clone())
+    }
+
+    /// Makes the metatable, `__index` and `__newindex` look up the fields before the methods.
+    fn into_metatable(self) -> Fallible<Pointer<LuaTable>> {
+        let state = self.state.clone();
+        let string = |text: &str| new_string(state.as_pointer(), text.as_bytes());
+        let metatable = new_table(new_meta_functions()?, self.meta_functions.len() + 3, true)?.as_pointer();
+        let methods = new_table(new_meta_functions()?, self.methods.len(), true)?.as_pointer();
+        for (name, function) in self.methods {
+            set_field(methods.clone(), string(&name)?, function)?;
+        }
+        set_field(metatable.clone(), string("__name")?, string(T::name())?)?;
+        let index = if self.getters.is_empty() {
+            LuaValueImpl::encode_table(methods)
+        } else {
+            let getters = self.getters;
+            let index = state.create_raw_function(move |_state, args| {
+                let key = args.get(1).cloned().unwrap_or_else(|| LuaValueImpl::encode_nil(()));
+                match read_string(&key).and_then(|key| getters.get(&key)) {
+                    Some(getter) => call_function(getter, &args[..1]),
+                    None => Ok(vec![get_field(methods.clone(), &key)]),
+                }
+            })?;
+            index.value().clone()
+        };
+        set_field(metatable.clone(), string("__index")?, index)?;
+        if !self.setters.is_empty() {
+            let setters = self.setters;
+            let new_index = state.create_raw_function(move |_state, args| {
+                let key = args.get(1).and_then(read_string).unwrap_or_default();
+                let setter =
+                    setters.get(&key).ok_or_else(|| format_err!("attempt to set the unknown field '{}' of a {}", String::from_utf8_lossy(&key), T::name()))?;
+                call_function(setter, &[args[0].clone(), args.get(2).cloned().unwrap_or_else(|| LuaValueImpl::encode_nil(()))])
+            })?;
+            set_field(metatable.clone(), string("__newindex")?, new_index.value().clone())?;
+        }
+        for (name, function) in self.meta_functions {
+            set_field(metatable.clone(), string(&name)?, function)?;
+        }
+        Ok(metatable)
+    }
+}
+/// A full userdata held by Rust code.
+#[derive(Clone)]
+pub struct UserData(Pointer<LuaUserDataObject>);
+impl UserData {
+    fn cell(&self) -> &RefCell<Option<Box<dyn Any>>> {
+        unsafe { (*self.0.as_ptr()).ref_value() }
+    }
+
+    /// Whether the userdata holds a value of type `T`, which it does not once finalized.
+    pub fn is<T: 'static>(&self) -> bool {
+        self.cell().try_borrow().map_or(true, |value| value.as_ref().map_or(false, |value| value.is::<T>()))
+    }
+
+    pub fn borrow<T: 'static>(&self) -> Fallible<Ref<'_, T>> {
+        let value = self.cell().try_borrow().map_err(|_| format_err!("userdata is being changed"))?;
+        Ref::filter_map(value, |value| value.as_ref().and_then(|value| value.downcast_ref())).map_err(|value| type_error::<T>(value.is_none()))
+    }
+
+    pub fn borrow_mut<T: 'static>(&self) -> Fallible<RefMut<'_, T>> {
+        let value = self.cell().try_borrow_mut().map_err(|_| format_err!("userdata is already borrowed"))?;
+        RefMut::filter_map(value, |value| value.as_mut().and_then(|value| value.downcast_mut())).map_err(|value| type_error::<T>(value.is_none()))
+    }
+}
+fn type_error<T>(finalized: bool) -> failure::Error {
+    match finalized {
+        true => format_err!("attempt to use a finalized userdata"),
+        false => format_err!("userdata is not a {}", std::any::type_name::<T>()),
+    }
+}
+impl ToLua for UserData {
+    fn to_lua(self, _state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        Ok(LuaValueImpl::encode_user_data(self.0))
+    }
+}
+impl FromLua for UserData {
+    fn from_lua(_state: &LuaStateReference, value: LuaValueImpl) -> Fallible<Self> {
+        value.read_user_data().map(UserData).ok_or_else(|| format_err!("cannot convert a {} value to userdata", type_name(&value)))
+    }
+}
+impl<T: LuaUserData> ToLua for T {
+    fn to_lua(self, state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        new_user_data(state, self)
+    }
+}
+/// A pointer Lua code can hold but not use, the pointers are compared by their addresses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LightUserData(pub *mut c_void);
+impl ToLua for LightUserData {
+    fn to_lua(self, _state: &LuaStateReference) -> Fallible<LuaValueImpl> {
+        Ok(LuaValueImpl::encode_light_user_data(I64((self.0 as i64) << 4)))
+    }
+}
+impl FromLua for LightUserData {
+    fn from_lua(_state: &LuaStateReference, value: LuaValueImpl) -> Fallible<Self> {
+        let pointer = value.read_light_user_data().ok_or_else(|| format_err!("cannot convert a {} value to light userdata", type_name(&value)))?;
+        Ok(LightUserData((pointer.0 >> 4) as *mut c_void))
+    }
+}
+/// Makes a full userdata owning `value`.
+pub fn new_user_data<T: LuaUserData>(state: &LuaStateReference, value: T) -> Fallible<LuaValueImpl> {
+    let meta_functions = meta_functions_for::<T>(state)?;
+    unsafe {
+        let user_data = LuaUserDataReference(LuaUserDataReference::get()?.alloc()?.cast());
+        let mut user_data_ptr = user_data.as_pointer();
+        let user_data_ref = user_data_ptr.as_ref_mut();
+        user_data_ref.set_meta_functions(meta_functions.as_pointer());
+        user_data_ref.set_value(RefCell::new(Some(Box::new(value))));
+        (*state.as_pointer().as_ptr_mut()).ref_user_data_mut().push(user_data.clone());
+        Ok(LuaValueImpl::encode_user_data(user_data.as_pointer()))
+    }
+}
+/// The metatable of a userdata.
+pub fn metatable_of(user_data: &Pointer<LuaUserDataObject>) -> Option<Pointer<LuaTable>> {
+    metatable_of_meta_functions(unsafe { user_data.as_ref().get_meta_functions() })
+}
+fn meta_functions_for<T: LuaUserData>(state: &LuaStateReference) -> Fallible<LuaMetaFunctionsReference> {
+    if let Some(meta_functions) = unsafe { state.as_pointer().as_ref().ref_user_data_meta_functions() }.get(&TypeId::of::<T>()) {
+        return Ok(meta_functions.clone());
+    }
+    let mut methods = UserDataMethods {
+        state: state.clone(),
+        methods: Vec::new(),
+        meta_functions: Vec::new(),
+        getters: HashMap::new(),
+        setters: HashMap::new(),
+        user_data: PhantomData,
+    };
+    T::add_methods(&mut methods)?;
+    let meta_functions = meta_functions_of(methods.into_metatable()?)?;
+    unsafe { (*state.as_pointer().as_ptr_mut()).ref_user_data_meta_functions_mut().insert(TypeId::of::<T>(), meta_functions.clone()) };
+    Ok(meta_functions)
+}
+/// Runs the `__gc` meta functions of the userdata of a state and drops their values, the last made first. The
+/// errors of `__gc` are ignored like Lua does.
+pub(crate) fn finalize_all(state: &LuaStateReference) {
+    let user_data = std::mem::take(unsafe { (*state.as_pointer().as_ptr_mut()).ref_user_data_mut() });
+    for user_data in user_data.into_iter().rev() {
+        let user_data = user_data.as_pointer();
+        if let Some(metatable) = metatable_of(&user_data) {
+            if let Ok(key) = new_string(state.as_pointer(), b"__gc") {
+                let gc = get_field(metatable, &key);
+                if gc.read_nil().is_none() {
+                    let _ = call_function(&gc, &[LuaValueImpl::encode_user_data(user_data.clone())]);
+                }
+            }
+        }
+        if let Ok(mut value) = UserData(user_data).cell().try_borrow_mut() {
+            value.take();
+        }
+    }
+}
@@ -0,0 +1,40 @@
+local closed = {}
+local function closable(name)
+  return setmetatable({}, { __close = function(_, err) closed[#closed + 1] = name print("close", name, err) end })
+end
+do
+  local a <close> = closable("a")
+  local b <close> = closable("b")
+  local limit <const> = 10
+  print("in scope", limit)
+end
+print(#closed, closed[1], closed[2])
+for i = 1, 3 do
+  local c <close> = closable("loop" .. i)
+  if i == 2 then
+    break
+  end
+end
+local function returns()
+  local r <close> = closable("return")
+  return "returned"
+end
+print(returns())
+print(pcall(function()
+  local e <close> = closable("error")
+  error("failed", 0)
+end))
+local n <close> = nil
+print(pcall(function() local x <close> = 1 end))
+local i = 1
+::top::
+do
+  local g <close> = closable("goto" .. i)
+  i = i + 1
+  if i <= 2 then
+    goto top
+  end
+  goto done
+end
+::done::
+print(#closed)
@@ -0,0 +1,46 @@
+local co = coroutine.create(function(a, b)
+  print("start", a, b)
+  local c = coroutine.yield(a + b)
+  print("got", c)
+  local d, e = coroutine.yield(c * 2)
+  return d + e
+end)
+print(coroutine.status(co))
+print(coroutine.resume(co, 1, 2))
+print(coroutine.resume(co, 10))
+print(coroutine.resume(co, 3, 4))
+print(coroutine.status(co), coroutine.resume(co))
+local function range(n)
+  return coroutine.wrap(function()
+    for i = 1, n do
+      coroutine.yield(i)
+    end
+  end)
+end
+local sum = 0
+for i in range(5) do
+  sum = sum + i
+end
+print(sum)
+local failing = coroutine.create(function() error("oops") end)
+print(coroutine.resume(failing))
+print(pcall(coroutine.wrap(function() error({ code = 7 }) end)))
+local inner = coroutine.create(function()
+  print(pcall(coroutine.yield, "through pcall"))
+  return "finished"
+end)
+print(coroutine.resume(inner))
+print(coroutine.resume(inner, "resumed"))
+local main, is_main = coroutine.running()
+print(coroutine.status(main), is_main, coroutine.isyieldable())
+print(pcall(coroutine.yield, 1))
+local outer
+outer = coroutine.create(function()
+  local nested = coroutine.wrap(function()
+    print(coroutine.status(outer), coroutine.isyieldable())
+    coroutine.yield("from nested")
+  end)
+  print(nested())
+  print(coroutine.status(outer))
+end)
+coroutine.resume(outer)
@@ -0,0 +1,16 @@
+local env = { value = "env", print = print }
+local chunk = load("get = function() return value end value = value .. '!' return get", "env", "t", env)
+local get = chunk()
+print(get(), env.value, value)
+local function sandboxed()
+    local _ENV = { x = 1 }
+    y = x + 1
+    return _ENV
+end
+print(sandboxed().y, y)
+local t = {}
+do
+    local _ENV = setmetatable(t, { __index = _G })
+    z = tostring(42)
+end
+print(t.z, z, _ENV == _G)
@@ -0,0 +1,15 @@
+print(pcall(function(a, b) return a + b end, 1, 2))
+print(pcall(error, "boom"))
+local ok, err = pcall(function() error({ code = 42 }) end)
+print(ok, err.code)
+print(pcall(function() error("inner", 0) end))
+print(xpcall(function() error("failed") end, function(m) return "handled: " .. m end))
+print(xpcall(function(x) return x * 2 end, print, 21))
+print(pcall(pcall, error, "nested"))
+local caught = 0
+for i = 1, 3 do
+  if not pcall(error, i) then
+    caught = caught + 1
+  end
+end
+print(caught, pcall(error))
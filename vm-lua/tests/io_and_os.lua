@@ -0,0 +1,8 @@
+local time = os.time({ year = 2000, month = 1, day = 1, hour = 0 })
+print(os.date("!%Y-%m-%d %H:%M:%S", 946684800))
+print(os.date("!*t", 946684800).wday, os.difftime(time, time))
+print(type(os.time()), type(os.clock()))
+print(io.type(io.stdout), io.type(42))
+print(io.open("io_and_os.lua"))
+print(pcall(os.getenv, "HOME"))
+print(os.remove("no_such_file"))
@@ -0,0 +1,16 @@
+local add = load("local a, b = ... return a + b")
+print(add(1, 2))
+local pieces = { "return ", "'pieces'" }
+local index = 0
+print(load(function() index = index + 1 return pieces[index] end)())
+print(load("return +", "=broken"))
+print(load("return 1", "text", "b"))
+local env = { value = "from env" }
+print(load("return value", "env", "t", env)())
+print(loadstring("return 'loadstring'")())
+print(package.loaded.string == string, package.loaded._G == _G, package.loaded.package == package)
+package.loaded.answer = 42
+print(require("answer"))
+local ok, message = pcall(require, "no.such.module")
+print(ok, message)
+print(package.searchpath("no.such.module", "./?.lua;./?/init.lua"))
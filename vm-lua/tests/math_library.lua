@@ -0,0 +1,24 @@
+print(math.floor(3.7), math.ceil(3.2), math.floor(-3.5))
+print(math.abs(-4), math.abs(-2.5), math.abs(math.mininteger) == math.mininteger)
+print(math.max(1, 2.0, 2), math.min(3, 1.0, 1))
+print(math.fmod(7, 3), math.fmod(-7, 3), math.fmod(7.5, 2))
+print(math.modf(3.25))
+print(math.sqrt(16), math.exp(0), math.log(8, 2), math.log(100, 10))
+print(math.sin(0), math.cos(0), math.tan(0))
+print(math.tointeger(3.0), math.tointeger(3.5), math.type(1), math.type(1.0), math.type("1"))
+print(math.ult(1, -1), math.huge, -math.huge, math.pi)
+print(math.maxinteger, math.mininteger)
+math.randomseed(42)
+local first = math.random(1, 100)
+math.randomseed(42)
+print(first == math.random(1, 100))
+for i = 1, 100 do
+  local value = math.random(6)
+  if value < 1 or value > 6 then
+    print("out of range", value)
+  end
+  local float = math.random()
+  if float < 0 or float >= 1 then
+    print("out of range", float)
+  end
+end
@@ -0,0 +1,31 @@
+local defaults = { color = "red", size = 1 }
+local point = setmetatable({ x = 1 }, { __index = defaults })
+print(point.x, point.color, rawget(point, "color"))
+local calls = 0
+local counter = setmetatable({}, {
+  __index = function(t, k)
+    calls = calls + 1
+    return k .. "!"
+  end,
+})
+print(counter.a, counter.b, calls)
+local proxy = setmetatable({}, { __newindex = function(t, k, v) rawset(t, k, v * 2) end })
+proxy.value = 21
+print(proxy.value, rawlen({ 1, 2, 3 }), rawlen("abcd"))
+local store = {}
+local forward = setmetatable({}, { __newindex = store })
+forward.key = "stored"
+print(rawget(forward, "key"), store.key)
+local base = { greet = function() return "hello" end }
+local middle = setmetatable({}, { __index = base })
+local leaf = setmetatable({}, { __index = middle })
+print(leaf.greet())
+local mt = {}
+local late = setmetatable({}, mt)
+print(late.missing)
+mt.__index = function() return "late" end
+print(late.missing, getmetatable(late) == mt)
+mt.__metatable = "locked"
+print(getmetatable(late))
+print(rawequal(late, late), rawequal(1, 1.0), rawequal("a", "b"))
+print(getmetatable("").__index == string, ("x"):rep(3))
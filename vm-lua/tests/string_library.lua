@@ -0,0 +1,16 @@
+local s = "Hello, Lua"
+print(string.len(s), #s, s:len())
+print(s:upper(), s:lower(), s:reverse())
+print(s:sub(1, 5), s:sub(-3), ("x"):rep(3, "-"))
+print(s:byte(1, 3), string.char(76, 117, 97))
+print(s:find("Lua"), s:find("l+"), s:find(".", 1, true))
+print(s:match("(%a+), (%a+)"))
+print(("key = value"):match("^(%w+)%s*=%s*(%w+)$"))
+for word in s:gmatch("%a+") do
+  print(word)
+end
+print(s:gsub("l", "L"))
+print(("hello world"):gsub("(%w+)", "<%1>"))
+print(("$name is $age"):gsub("%$(%w+)", { name = "Lua", age = "30" }))
+print(("hello world"):gsub("%w+", string.upper, 1))
+print(string.format("%d %5.2f %-5s| %x %q", 42, 3.14159, "ab", 255, "a\nb"))
@@ -0,0 +1,25 @@
+local t = { 10, 20, 30 }
+print(#t, t[1], t[3])
+table.insert(t, 40)
+table.insert(t, 1, 5)
+print(#t, table.concat(t, ", "))
+print(table.remove(t), table.remove(t, 1), #t)
+print(table.unpack(t))
+local packed = table.pack(1, nil, 3)
+print(packed.n, packed[1], packed[3])
+print(table.concat(table.move({ 1, 2, 3 }, 1, 3, 2), " "))
+local words = { "pear", "apple", "fig" }
+table.sort(words)
+print(table.concat(words, " "))
+table.sort(words, function(a, b) return #a < #b end)
+print(words[1], words[3])
+local squares = {}
+for i = 1, 10 do
+  squares[i] = i * i
+end
+print(#squares, squares[10])
+local sum = 0
+for i, v in ipairs(squares) do
+  sum = sum + v
+end
+print(sum)
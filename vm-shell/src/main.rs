@@ -31,7 +31,8 @@ fn main() -> Fallible<()> {
     env_logger::init();
     let opt = cli::Opt::from_args();
     let lua_runtime: LuaRuntime = if opt.jit { Arc::new(LuaJIT::new()?) } else { Arc::new(LuaInterpreter::new()?) };
-    let lua_state = vm_lua::new_state(lua_runtime)?;
+    let lua_state = vm_lua::OwnedLuaState::new(lua_runtime)?;
+    vm_lua::host::set_host(&lua_state, vm_lua::host::NativeHost);
     vm_wenyan::加入虚拟机(lua_state.clone())?;
     vm_lua::hello();
     vm_wenyan::打招呼();
@@ -48,10 +49,8 @@ fn main() -> Fallible<()> {
             };
             match std::thread::spawn(move || {
                 let start = SystemTime::now();
-                unsafe {
-                    let function: vm_lua::mem::LuaFunctionRustType = std::mem::transmute(resource.lock().unwrap().get_export_ptr(0));
-                    let args = &[];
-                    function(lua_state, args);
+                if let Err(e) = lua_state.call::<()>(&resource, ()) {
+                    error!("{}", e);
                 }
                 if bench {
                     let end = SystemTime::now();
@@ -2,11 +2,16 @@ use failure::Fallible;
 use llvm_runtime::{Interpreter, JITCompiler};
 
 use memory_mmmu::MemoryMMMU;
-use std::{
-    sync::Arc,
-};
+use std::{cell::Cell, collections::HashMap, rc::Rc, sync::Arc};
 
-use vm_lua::util::set_signal_handler;
+use vm_lua::{
+    api::Function,
+    built_in::package::{add_module_searcher, Module, ModuleSearch, ModuleSearcher},
+    mem::LuaStateReference,
+    user_data::{LuaUserData, UserDataMethods},
+    util::set_signal_handler,
+    LuaRuntime,
+};
 
 use vm_lua::ir::LuaInstructionSet;
 
@@ -21,3 +26,403 @@ fn run_lua_script() -> Fallible<()> {
     vm_lua::run_code(state, code)?;
     Ok(())
 }
+#[test]
+fn report_every_syntax_error() -> Fallible<()> {
+    let state = vm_lua::new_state(Arc::new(LuaInterpreter::new()?))?;
+    let code = "local a = = 1;\nwhile true do local b = + end\nprint(a)";
+    let message = vm_lua::pack_code(state, code).unwrap_err().to_string();
+    let lines: Vec<_> = message.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", message);
+    assert!(lines[0].contains("line 1:") && lines[1].contains("line 2:"), "{}", message);
+    Ok(())
+}
+#[test]
+fn call_lua_function() -> Fallible<()> {
+    let state = vm_lua::new_state(Arc::new(LuaInterpreter::new()?))?;
+    vm_lua::run_code(state.clone(), "function add(a, b) return a + b, a .. b end")?;
+    let (sum, concatenated): (i64, String) = state.call("add", (1, 2))?;
+    assert_eq!((sum, concatenated.as_str()), (3, "12"));
+    let max: f64 = state.call("math.max", (1.5, 2.5))?;
+    assert_eq!(max, 2.5);
+    let lengths: HashMap<String, usize> = state.eval("return { a = #'x', b = #'yy' }")?;
+    assert_eq!(lengths, HashMap::from([("a".to_string(), 1), ("b".to_string(), 2)]));
+    state.set_global_value("numbers", vec![3, 1, 2])?;
+    let sorted: Vec<i32> = state.eval("table.sort(numbers) return numbers")?;
+    assert_eq!(sorted, vec![1, 2, 3]);
+    assert!(state.call::<()>("error", "failed").is_err());
+    // the `<close>` variables of a function raising an error are closed when Rust called it
+    let raising: Function =
+        state.eval("return function() local value <close> = setmetatable({}, { __close = function() closed = true end }) error('raised') end")?;
+    let error = state.call::<()>(&raising, ()).unwrap_err();
+    assert!(error.to_string().contains("raised"), "{}", error);
+    assert!(state.global_value::<bool>("closed")?);
+    Ok(())
+}
+#[test]
+fn call_rust_closure() -> Fallible<()> {
+    let state = vm_lua::new_state(Arc::new(LuaInterpreter::new()?))?;
+    let count = Rc::new(Cell::new(0));
+    let counter = count.clone();
+    state.set_global_function("count", move |_, step: Option<i64>| {
+        counter.set(counter.get() + step.unwrap_or(1));
+        Ok(counter.get())
+    })?;
+    let total: i64 = state.eval("count() count(2) return count(3)")?;
+    assert_eq!((total, count.get()), (6, 6));
+    state.set_global_function("divide", |_, (a, b): (i64, i64)| match b {
+        0 => Err(failure::format_err!("division by zero")),
+        b => Ok((a / b, a % b)),
+    })?;
+    let (ok, message): (bool, String) = state.eval("return pcall(divide, 1, 0)")?;
+    assert_eq!((ok, message.as_str()), (false, "division by zero"));
+    let (quotient, remainder): (i64, i64) = state.call("divide", (7, 2))?;
+    assert_eq!((quotient, remainder), (3, 1));
+    // the closures are dropped with the other userdata when the state is closed, even after `count = nil`
+    state.eval::<()>("count = nil")?;
+    assert_eq!(Rc::strong_count(&count), 2);
+    vm_lua::close_state(state);
+    assert_eq!(Rc::strong_count(&count), 1);
+    Ok(())
+}
+struct Counter {
+    count: i64,
+    closed: Cell<bool>,
+    dropped: Rc<Cell<bool>>,
+}
+impl Drop for Counter {
+    fn drop(&mut self) {
+        self.dropped.set(true);
+    }
+}
+impl LuaUserData for Counter {
+    fn name() -> &'static str {
+        "Counter"
+    }
+
+    fn add_methods(methods: &mut UserDataMethods<Self>) -> Fallible<()> {
+        methods.add_method_mut("increment", |_, counter, step: Option<i64>| {
+            counter.count += step.unwrap_or(1);
+            Ok(counter.count)
+        })?;
+        methods.add_field_getter("count", |_, counter| Ok(counter.count))?;
+        methods.add_field_getter("closed", |_, counter| Ok(counter.closed.get()))?;
+        methods.add_field_setter("count", |_, counter, count: i64| {
+            counter.count = count;
+            Ok(())
+        })?;
+        methods.add_meta_method("__len", |_, counter, ()| Ok(counter.count))?;
+        methods.add_meta_method("__close", |_, counter, ()| {
+            counter.closed.set(true);
+            Ok(())
+        })
+    }
+}
+#[test]
+fn use_user_data() -> Fallible<()> {
+    let state = vm_lua::OwnedLuaState::new(Arc::new(LuaInterpreter::new()?))?;
+    let dropped = Rc::new(Cell::new(false));
+    state.set_global_value("counter", Counter { count: 0, closed: Cell::new(false), dropped: dropped.clone() })?;
+    let (count, field): (i64, i64) = state.eval("counter:increment() counter:increment(2) return counter.count, #counter")?;
+    assert_eq!((count, field), (3, 3));
+    let count: i64 = state.eval("counter.count = 10 return counter:increment()")?;
+    assert_eq!(count, 11);
+    let (ok, message): (bool, String) = state.eval("return pcall(counter.increment, 1)")?;
+    assert!(!ok && message.contains("bad argument #1 to 'increment' (Counter expected, got number)"));
+    assert!(state.eval::<bool>("do local counter <close> = counter end return counter.closed")?);
+    // userdata are only finalized when the state is closed, here by dropping it, even when Lua code cannot reach
+    // them anymore
+    let lost = Rc::new(Cell::new(false));
+    state.set_global_value("lost", Counter { count: 0, closed: Cell::new(false), dropped: lost.clone() })?;
+    state.eval::<()>("lost = nil")?;
+    assert!(!dropped.get() && !lost.get());
+    drop(state);
+    assert!(dropped.get() && lost.get());
+    Ok(())
+}
+#[test]
+fn run_in_sandbox() -> Fallible<()> {
+    let state = vm_lua::new_state(Arc::new(LuaInterpreter::new()?))?;
+    let sandbox = vm_lua::new_sandbox(state.clone(), &["print", "string.format", "_G"])?;
+    let script = "leaked = true return os == nil, string.upper == nil, string.format('%d', 7), _G == _ENV, function() return os == nil end";
+    let (no_os, no_upper, formatted, own_g, later): (bool, bool, String, bool, Function) = state.load(script, Some(sandbox))?.call(())?;
+    assert_eq!((no_os, no_upper, formatted.as_str(), own_g), (true, true, "7", true));
+    assert!(later.call::<bool>(())?);
+    assert_eq!(state.global_value::<Option<bool>>("leaked")?, None);
+    assert!(state.eval::<bool>("return string.upper ~= nil and os ~= nil")?);
+    let sandbox = vm_lua::new_sandbox(state.clone(), &["load", "getmetatable"])?;
+    let script = "return load('escaped = true return os == nil')(), getmetatable('') == nil, load('return os', 'os', 't', {os = 1})()";
+    let (no_os, no_string_metatable, given_env): (bool, bool, i64) = state.load(script, Some(sandbox.clone()))?.call(())?;
+    assert_eq!((no_os, no_string_metatable, given_env), (true, true, 1));
+    assert_eq!(state.global_value::<Option<bool>>("escaped")?, None);
+    // a nil environment given to `load` stays nil, and precompiled chunks are refused
+    let binary = vm_lua::new_string(state.as_pointer(), &vm_lua::dump_code(state.clone(), "return os")?)?;
+    let script = "local function message(_, message) return message end
+        return load('return _ENV == nil and os == nil', nil, 't', nil)(), message(load(...)), message(load(..., nil, 'b'))";
+    let (nil_env, binary_message, binary_mode_message): (bool, String, String) = state.load(script, Some(sandbox))?.call(binary)?;
+    assert!(nil_env);
+    assert!(binary_message.contains("attempt to load a binary chunk"), "{}", binary_message);
+    assert!(binary_mode_message.contains("attempt to load a binary chunk"), "{}", binary_mode_message);
+    assert!(vm_lua::new_sandbox(state.clone(), &["require"]).is_err());
+    assert!(vm_lua::new_sandbox(state, &["no_such_global"]).is_err());
+    Ok(())
+}
+#[test]
+fn stop_at_quotas() -> Fallible<()> {
+    for runtime in [Arc::new(LuaInterpreter::new()?) as LuaRuntime, Arc::new(LuaJIT::new()?)] {
+        let state = vm_lua::new_state(runtime)?;
+        vm_lua::quota::set_fuel(&state, Some(10_000));
+        let (ok, message): (bool, String) = state.eval("return pcall(function() while true do end end)")?;
+        assert!(!ok && message.contains("instruction quota exceeded"), "{}", message);
+        assert_eq!(vm_lua::quota::remaining_fuel(&state), Some(0));
+        vm_lua::quota::set_fuel(&state, None);
+        vm_lua::quota::set_memory_quota(&state, Some(1 << 20));
+        let (ok, message): (bool, String) = state.eval("return pcall(function() local t = {} for i = 1, 1e7 do t[i] = 'item' .. i end end)")?;
+        assert!(!ok && message.contains("not enough memory"), "{}", message);
+        assert_eq!(vm_lua::quota::remaining_memory(&state), Some(0));
+        // the strings built by the libraries are refused before they outgrow the quota
+        for code in [
+            "return pcall(string.rep, 'x', 1 << 30)",
+            "local t = {} for i = 1, 100 do t[i] = ('x'):rep(1 << 14) end return pcall(table.concat, t)",
+            "local s = ('x'):rep(1 << 18) return pcall(string.format, '%s%s%s%s', s, s, s, s)",
+            "return pcall(string.gsub, ('x'):rep(1 << 18), 'x', 'xxxx')",
+        ] {
+            vm_lua::quota::set_memory_quota(&state, Some(1 << 20));
+            let (ok, message): (bool, String) = state.eval(code)?;
+            assert!(!ok && message.contains("not enough memory"), "{}: {}", code, message);
+        }
+        vm_lua::quota::set_memory_quota(&state, None);
+        assert_eq!(state.eval::<i64>("local n = 0 for i = 1, 100 do n = n + i end return n")?, 5050);
+    }
+    Ok(())
+}
+#[test]
+fn match_string_patterns() -> Fallible<()> {
+    let state = vm_lua::new_state(Arc::new(LuaInterpreter::new()?))?;
+    let (balanced, words, count): (String, String, i64) =
+        state.eval(r#"local words, count = ("THE (quick) fox"):gsub("%f[%a]%a+", "w") return ("f(a(b)c) d"):match("%b()"), words, count"#)?;
+    assert_eq!((balanced.as_str(), words.as_str(), count), ("(a(b)c)", "w (w) w", 3));
+    let (start, end, repeated): (i64, i64, String) = state.eval(r#"return ("xabcabcx"):find("(abc)%1")"#)?;
+    assert_eq!((start, end, repeated.as_str()), (2, 7, "abc"));
+    let (first, not_first, last, prefixed, prefixes): (Option<String>, Option<String>, Option<i64>, String, i64) =
+        state.eval(r#"return ("hello"):match("^h"), ("ahello"):match("^h"), ("hello"):find("o$"), ("hello"):gsub("^", ">")"#)?;
+    assert_eq!((first.as_deref(), not_first, last, prefixed.as_str(), prefixes), (Some("h"), None, Some(5), ">hello", 1));
+    let (ok, message): (bool, String) = state.eval(r#"return pcall(string.match, "x", "(%1)")"#)?;
+    assert!(!ok && message.contains("invalid capture index"), "{}", message);
+    Ok(())
+}
+#[test]
+fn format_strings() -> Fallible<()> {
+    let state = vm_lua::new_state(Arc::new(LuaInterpreter::new()?))?;
+    let formatted: String = state.eval(r#"return string.format("%d|%5.2f|%-4s|%x|%X|%o|%e|%g|%%", 42, 3.14159, "ab", 255, 255, 8, 12345.678, 0.1)"#)?;
+    assert_eq!(formatted, "42| 3.14|ab  |ff|FF|10|1.234568e+04|0.1|%");
+    let quoted: String = state.eval(r#"return string.format("%q", "a\nb\"" .. string.char(0))"#)?;
+    assert_eq!(quoted, "\"a\\\nb\\\"\\0\"");
+    let (ok, message): (bool, String) = state.eval(r#"return pcall(string.format, "%d", 1.5)"#)?;
+    assert!(!ok && message.contains("number has no integer representation"), "{}", message);
+    let (ok, message): (bool, String) = state.eval(r#"return pcall(string.format, "%d")"#)?;
+    assert!(!ok && message.contains("bad argument #2 to 'format'"), "{}", message);
+    Ok(())
+}
+#[test]
+fn sort_tables() -> Fallible<()> {
+    let state = vm_lua::new_state(Arc::new(LuaInterpreter::new()?))?;
+    let sorted: Vec<i64> = state.eval("local t = {3, 1, 5, 2, 4} table.sort(t, function(a, b) return a > b end) return t")?;
+    assert_eq!(sorted, vec![5, 4, 3, 2, 1]);
+    let (ok, message): (bool, String) = state.eval("return pcall(table.sort, {3, 1, 'x'})")?;
+    assert!(!ok && message.contains("attempt to compare"), "{}", message);
+    let (ok, message): (bool, String) = state.eval("return pcall(table.sort, {3, 2, 1, 5, 4}, function(a, b) return true end)")?;
+    assert!(!ok && message.contains("invalid order function for sorting"), "{}", message);
+    let (ok, message): (bool, String) = state.eval("return pcall(table.sort, {1, 2}, 3)")?;
+    assert!(!ok && message.contains("bad argument #2 to 'sort'"), "{}", message);
+    let (ok, message): (bool, String) = state.eval("return pcall(table.sort, {1, 2, 3}, function(a, b) error('stop') end)")?;
+    assert!(!ok && message.contains("stop"), "{}", message);
+    Ok(())
+}
+#[test]
+fn keep_integers_and_floats_apart() -> Fallible<()> {
+    let state = vm_lua::new_state(Arc::new(LuaInterpreter::new()?))?;
+    let types: Vec<String> = state.eval("return {math.type(1), math.type(1.0), math.type(7 // 2), math.type(7 / 2), math.type(2 ^ 2), math.type(7 // 2.0)}")?;
+    assert_eq!(types, ["integer", "float", "integer", "float", "float", "float"]);
+    let (quotient, division, floor, modulo): (i64, f64, i64, f64) = state.eval("return 7 // 2, 7 / 2, -7 // 2, 7 % -3.0")?;
+    assert_eq!((quotient, division, floor, modulo), (3, 3.5, -4, -2.0));
+    let (wrapped, equal, integer, not_integer, floored): (bool, bool, i64, Option<i64>, String) =
+        state.eval("return math.maxinteger + 1 == math.mininteger, 1 == 1.0, math.tointeger(3.0), math.tointeger(3.5), math.type(math.floor(3.7))")?;
+    assert_eq!((wrapped, equal, integer, not_integer, floored.as_str()), (true, true, 3, None, "integer"));
+    let (ok, message): (bool, String) = state.eval("return pcall(function() return 1 // 0 end)")?;
+    assert!(!ok && message.contains("attempt to perform 'n//0'"), "{}", message);
+    Ok(())
+}
+#[test]
+fn index_through_metatables() -> Fallible<()> {
+    for runtime in [Arc::new(LuaInterpreter::new()?) as LuaRuntime, Arc::new(LuaJIT::new()?)] {
+        let state = vm_lua::new_state(runtime)?;
+        let (inherited, missing, method): (i64, Option<i64>, String) = state.eval(
+            "local base = setmetatable({ x = 1 }, { __index = { y = 2 } })
+            local t = setmetatable({}, { __index = base })
+            return t.x + t.y, t.z, ('ab'):upper()",
+        )?;
+        assert_eq!((inherited, missing, method.as_str()), (3, None, "AB"));
+        let (stored, own): (i64, Option<i64>) =
+            state.eval("local store = {} local t = setmetatable({}, { __newindex = store }) t.x = 1 return store.x, rawget(t, 'x')")?;
+        assert_eq!((stored, own), (1, None));
+        // a cycle of metatables is followed a bounded number of times
+        let (ok, message): (bool, String) = state.eval("local mt = {} mt.__index = mt setmetatable(mt, mt) return pcall(function() return mt.x end)")?;
+        assert!(!ok && message.contains("'__index' chain too long; possibly a loop"), "{}", message);
+        let (ok, message): (bool, String) = state.eval("local mt = {} mt.__newindex = mt setmetatable(mt, mt) return pcall(function() mt.x = 1 end)")?;
+        assert!(!ok && message.contains("'__newindex' chain too long; possibly a loop"), "{}", message);
+    }
+    Ok(())
+}
+#[test]
+fn catch_error_values() -> Fallible<()> {
+    let state = vm_lua::new_state(Arc::new(LuaInterpreter::new()?))?;
+    let (ok, value): (bool, HashMap<String, i64>) = state.eval("return pcall(error, { code = 7 })")?;
+    assert_eq!((ok, value), (false, HashMap::from([("code".to_string(), 7)])));
+    let (ok, value): (bool, Option<i64>) = state.eval("return pcall(error)")?;
+    assert_eq!((ok, value), (false, None));
+    let (ok, inner_ok, message): (bool, bool, String) = state.eval("return pcall(pcall, error, 'inner')")?;
+    assert_eq!((ok, inner_ok, message.as_str()), (true, false, "inner"));
+    let (ok, message): (bool, String) = state.eval("return xpcall(function() error('boom') end, function(m) return 'handled: ' .. m end)")?;
+    assert_eq!((ok, message.as_str()), (false, "handled: boom"));
+    let (ok, message): (bool, String) = state.eval("return pcall(function() local n = nil return n + 1 end)")?;
+    assert!(!ok && message.contains("attempt to perform"), "{}", message);
+    let (ok, results): (bool, i64) = state.eval("return pcall(function(a, b) return a + b end, 1, 2)")?;
+    assert_eq!((ok, results), (true, 3));
+    // the level adds no position to the message
+    let (ok, message): (bool, String) = state.eval("return pcall(error, 'msg', 2)")?;
+    assert_eq!((ok, message.as_str()), (false, "msg"));
+    let (ok, message): (bool, String) = state.eval("return pcall(error, 'msg', 'x')")?;
+    assert!(!ok && message.contains("bad argument #2 to 'error'"), "{}", message);
+    // a chunk run by `exec_lua` only closes its own `<close>` variables when it fails
+    let (ok, inner, outer): (bool, bool, bool) = state.eval(
+        "local outer = false
+        do
+            local value <close> = setmetatable({}, { __close = function() outer = true end })
+            local ok = pcall(exec_lua, 'local value <close> = setmetatable({}, { __close = function() inner = true end }) error(1)')
+            return ok, inner, outer
+        end",
+    )?;
+    assert_eq!((ok, inner, outer), (false, true, false));
+    Ok(())
+}
+#[test]
+fn resume_coroutines() -> Fallible<()> {
+    for runtime in [Arc::new(LuaInterpreter::new()?) as LuaRuntime, Arc::new(LuaJIT::new()?)] {
+        let state = vm_lua::new_state(runtime)?;
+        let values: Vec<i64> = state.eval(
+            "local co = coroutine.create(function(a, b) local c = coroutine.yield(a + b) local d, e = coroutine.yield(c * 2) return d + e end)
+            local values = {}
+            for _, args in ipairs({{1, 2}, {10}, {3, 4}}) do
+                local ok, value = coroutine.resume(co, table.unpack(args))
+                values[#values + 1] = value
+            end
+            values[#values + 1] = coroutine.status(co) == 'dead' and 1 or 0
+            return values",
+        )?;
+        assert_eq!(values, vec![3, 20, 7, 1]);
+        let (ok, message): (bool, String) = state.eval("local co = coroutine.create(function() end) coroutine.resume(co) return coroutine.resume(co)")?;
+        assert_eq!((ok, message.as_str()), (false, "cannot resume dead coroutine"));
+        let (ok, message): (bool, String) = state.eval("return coroutine.resume(coroutine.create(function() error('inside') end))")?;
+        assert!(!ok && message.contains("inside"), "{}", message);
+        let generated: Vec<i64> = state.eval(
+            "local next_value = coroutine.wrap(function() for i = 1, 3 do coroutine.yield(i * i) end end)
+            return {next_value(), next_value(), next_value()}",
+        )?;
+        assert_eq!(generated, vec![1, 4, 9]);
+        let (yieldable, ok, message): (bool, bool, String) = state.eval("return coroutine.isyieldable(), pcall(coroutine.yield, 1)")?;
+        assert!(!yieldable && !ok && message.contains("outside a coroutine"), "{}", message);
+        // yields from a function the coroutine calls and from inside a `pcall`
+        let values: Vec<i64> = state.eval(
+            "local function inner(x) return coroutine.yield(x) + 1 end
+            local co = coroutine.wrap(function()
+                local a = inner(1)
+                local ok, b = pcall(function() return coroutine.yield(a) * 2 end)
+                return ok and b
+            end)
+            return {co(), co(10), co(20)}",
+        )?;
+        assert_eq!(values, vec![1, 11, 40]);
+    }
+    Ok(())
+}
+#[test]
+fn reject_const_assignments_and_bad_gotos() -> Fallible<()> {
+    let state = vm_lua::new_state(Arc::new(LuaInterpreter::new()?))?;
+    let compile_error = |code: &str| vm_lua::pack_code(state.clone(), code).map(|_| ()).unwrap_err().to_string();
+    let message = compile_error("local x <const> = 1 x = 2");
+    assert!(message.contains("attempt to assign to const variable 'x'"), "{}", message);
+    let message = compile_error("local x <const> = 1 local function f() x = 2 end");
+    assert!(message.contains("attempt to assign to const variable 'x'"), "{}", message);
+    let message = compile_error("local x <unknown> = 1");
+    assert!(message.contains("illegal attribute") && message.contains("got unknown"), "{}", message);
+    let message = compile_error("goto missing");
+    assert!(message.contains("no visible label 'missing' for <goto>"), "{}", message);
+    let message = compile_error("goto skip local x = 1 ::skip:: print(x)");
+    assert!(message.contains("jumps into the scope of local 'x'"), "{}", message);
+    let message = compile_error("::twice:: ::twice::");
+    assert!(message.contains("label 'twice' already defined"), "{}", message);
+    assert!(vm_lua::pack_code(state.clone(), "do goto done local x = 1 end ::done::").is_ok());
+    Ok(())
+}
+#[test]
+fn load_precompiled_chunks() -> Fallible<()> {
+    let code = "local t = { name = 'dumped', n = 2 }
+        local function twice(x) return x * t.n end
+        greeting = 'hello'
+        return t.name, twice(21), greeting .. '!'";
+    let binary = vm_lua::dump_code(vm_lua::new_state(Arc::new(LuaInterpreter::new()?))?, code)?;
+    assert!(vm_lua::dump::is_binary_chunk(&binary));
+    // loaded into another state, which interns its own strings and shapes
+    let state = vm_lua::new_state(Arc::new(LuaInterpreter::new()?))?;
+    let chunk = vm_lua::load_binary(state.clone(), &binary)?;
+    let (name, doubled, greeting): (String, i64, String) = state.call(&chunk, ())?;
+    assert_eq!((name.as_str(), doubled, greeting.as_str()), ("dumped", 42, "hello!"));
+    assert_eq!(state.global_value::<String>("greeting")?, "hello");
+    // entering the chunk again keeps no other copy of it
+    let chunks = || unsafe { state.as_pointer().as_ref().ref_chunks().len() };
+    let kept = chunks();
+    let (_name, doubled): (String, i64) = state.call(&chunk, ())?;
+    assert_eq!((doubled, chunks()), (42, kept));
+    let header = vm_lua::dump::SIGNATURE.len();
+    let mut other_version = binary.clone();
+    other_version[header] += 1;
+    let message = vm_lua::load_binary(state.clone(), &other_version).unwrap_err().to_string();
+    assert!(message.contains("format version"), "{}", message);
+    // after the version, the size of pointers and the byte order check
+    let mut other_instructions = binary.clone();
+    other_instructions[header + 10] ^= 1;
+    let message = vm_lua::load_binary(state.clone(), &other_instructions).unwrap_err().to_string();
+    assert!(message.contains("another instruction set"), "{}", message);
+    let message = vm_lua::load_binary(state.clone(), &binary[..binary.len() - 1]).unwrap_err().to_string();
+    assert!(message.contains("truncated precompiled chunk"), "{}", message);
+    Ok(())
+}
+/// Serves the module `counted` from memory, counting how often it is looked for.
+struct CountingSearcher(Rc<Cell<u32>>);
+impl ModuleSearcher for CountingSearcher {
+    fn search(&self, _state: &LuaStateReference, name: &str) -> Fallible<ModuleSearch> {
+        self.0.set(self.0.get() + 1);
+        Ok(match name {
+            "counted" => ModuleSearch::Found(Module { location: "memory:counted".to_string(), code: b"loads = (loads or 0) + 1 return { name = ... }".to_vec() }),
+            "empty" => ModuleSearch::Found(Module { location: "memory:empty".to_string(), code: Vec::new() }),
+            _ => ModuleSearch::NotFound(format!("\n\tno module '{}' in memory", name)),
+        })
+    }
+}
+#[test]
+fn cache_required_modules() -> Fallible<()> {
+    let state = vm_lua::new_state(Arc::new(LuaInterpreter::new()?))?;
+    let searches = Rc::new(Cell::new(0));
+    add_module_searcher(&state, CountingSearcher(searches.clone()));
+    let (same, loads, name, location, cached): (bool, i64, String, String, bool) = state.eval(
+        "local first, location = require('counted') local second = require('counted')
+        return first == second, loads, first.name, location, package.loaded.counted == first",
+    )?;
+    assert_eq!((same, loads, name.as_str(), location.as_str(), cached), (true, 1, "counted", "memory:counted", true));
+    assert_eq!(searches.get(), 1);
+    let (empty, again): (bool, bool) = state.eval("return require('empty'), package.loaded.empty")?;
+    assert_eq!((empty, again), (true, true));
+    let (ok, message): (bool, String) = state.eval("return pcall(require, 'missing')")?;
+    assert!(!ok && message.contains("module 'missing' not found") && message.contains("no module 'missing' in memory"), "{}", message);
+    Ok(())
+}
@@ -3,7 +3,7 @@ use std::{str::Chars, sync::Arc};
 use chinese_number::{ChineseNumber, ChineseNumberCountMethod, ChineseVariant};
 use failure::Fallible;
 use ghost_cell::GhostToken;
-use lexical::Lexical;
+use lexical::{Lexical, Spanned};
 use lexical_derive::{lexical, Lexical};
 use log::{debug, error};
 use runtime::code::{BlockBuilder, BuddyRegisterPool, FunctionPack};
@@ -113,7 +113,7 @@ pub enum 比较 {
     等于,
     不等于,
 }
-pub fn 解析语法(vm: 虚拟机, 源代码: Vec<文言词法>) -> Fallible<程序> {
+pub fn 解析语法(vm: 虚拟机, 源代码: Vec<Spanned<文言词法>>) -> Fallible<程序> {
     GhostToken::new(|token| {
         let mut 代码 = 中间码构建器::创建(vm, token);
         lalr1_analyser! {
@@ -431,7 +431,7 @@ pub fn 打招呼() {
 }
 pub fn 加载代码(vm: 虚拟机, code: &str) -> Fallible<ObjectRef> {
     debug!("code: {:?}", code);
-    let lexical = 文言词法::parse(code)?;
+    let lexical = 文言词法::parse_spanned(code)?;
     debug!("lexical: {:?}", &lexical);
     let mut pack = 解析语法(vm.clone(), lexical)?;
     let root_function = pack.pop().unwrap();